@@ -0,0 +1,358 @@
+/// Direct messages to the admin when the same kind of error keeps happening
+///
+/// An alert is sent once `threshold` errors of a class happen within `window_mins`, and a recovery
+/// message once that class succeeds again. A class isn't alerted again before `cooldown_mins`
+///
+/// During quiet hours, messages wait for the window to end. Only the last one of each class is sent
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Display, Formatter};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+use crate::quiet_hours::{Clock, QuietHours};
+use crate::supervisor::TaskContext;
+
+use nostr_sdk::prelude::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AlertConfig {
+    pub admin_npub: String,
+    #[serde(default = "default_threshold")]
+    pub threshold: usize,
+    #[serde(default = "default_window_mins")]
+    pub window_mins: u64,
+    #[serde(default = "default_cooldown_mins")]
+    pub cooldown_mins: u64,
+}
+
+fn default_threshold() -> usize {
+    5
+}
+
+fn default_window_mins() -> u64 {
+    10
+}
+
+fn default_cooldown_mins() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AlertClass {
+    /// Mentions couldn't be read from any relay
+    RelaysDown,
+    /// The network or the config couldn't be saved
+    StateUnwritable,
+    /// A mention handler panicked
+    HandlerPanic,
+    /// No relay accepted a reply, after retrying
+    RepliesRefused,
+}
+
+impl Display for AlertClass {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            AlertClass::RelaysDown => write!(f, "relays failing"),
+            AlertClass::StateUnwritable => write!(f, "state not saved"),
+            AlertClass::HandlerPanic => write!(f, "mention handlers panicking"),
+            AlertClass::RepliesRefused => write!(f, "replies refused by relays"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AlertMessage {
+    /// Errors in the window, with the last one
+    Failing(AlertClass, usize, String),
+    Recovered(AlertClass),
+}
+
+impl Display for AlertMessage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            AlertMessage::Failing(class, count, last) => {
+                write!(f, "Six degrees bot: {class}, {count} errors. Last: {last}")
+            }
+            AlertMessage::Recovered(class) => write!(f, "Six degrees bot: {class} recovered"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ClassState {
+    errors: VecDeque<Instant>,
+    alerting: bool,
+    last_alert: Option<Instant>,
+}
+
+/// When to alert, without sending anything
+#[derive(Debug)]
+pub struct Alerts {
+    threshold: usize,
+    window: Duration,
+    cooldown: Duration,
+    classes: HashMap<AlertClass, ClassState>,
+}
+
+impl Alerts {
+    pub fn new(threshold: usize, window: Duration, cooldown: Duration) -> Alerts {
+        Alerts {
+            threshold,
+            window,
+            cooldown,
+            classes: HashMap::new(),
+        }
+    }
+
+    /// Record an error of class, returning the alert to send, if any
+    pub fn error(&mut self, class: AlertClass, detail: &str, now: Instant) -> Option<AlertMessage> {
+        let state = self.classes.entry(class).or_default();
+        state.errors.push_back(now);
+        while let Some(first) = state.errors.front() {
+            if now.duration_since(*first) <= self.window {
+                break;
+            }
+            state.errors.pop_front();
+        }
+        let cooling = state
+            .last_alert
+            .is_some_and(|x| now.duration_since(x) < self.cooldown);
+        if state.alerting || cooling || state.errors.len() < self.threshold {
+            return None;
+        }
+        state.alerting = true;
+        state.last_alert = Some(now);
+        Some(AlertMessage::Failing(
+            class,
+            state.errors.len(),
+            detail.to_string(),
+        ))
+    }
+
+    /// Record a success of class, returning the recovery to send if it was alerted
+    pub fn success(&mut self, class: AlertClass) -> Option<AlertMessage> {
+        let state = self.classes.get_mut(&class)?;
+        state.errors.clear();
+        if !state.alerting {
+            return None;
+        }
+        state.alerting = false;
+        Some(AlertMessage::Recovered(class))
+    }
+}
+
+/// Sends the alerts to the admin. Does nothing when alerts aren't configured
+#[derive(Debug)]
+pub struct Alerter {
+    admin: Option<(PublicKey, Arc<Client>)>,
+    alerts: Mutex<Alerts>,
+    quiet_hours: Option<(QuietHours, Clock)>,
+    /// Messages held during quiet hours, by class
+    deferred: Mutex<HashMap<AlertClass, AlertMessage>>,
+}
+
+impl Alerter {
+    pub fn disabled() -> Alerter {
+        Alerter {
+            admin: None,
+            alerts: Mutex::new(Alerts::new(0, Duration::ZERO, Duration::ZERO)),
+            quiet_hours: None,
+            deferred: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn new(config: &AlertConfig, client: Arc<Client>) -> Result<Alerter, nostr::key::Error> {
+        Ok(Alerter {
+            admin: Some((PublicKey::parse(&config.admin_npub)?, client)),
+            alerts: Mutex::new(Alerts::new(
+                config.threshold,
+                Duration::from_secs(config.window_mins * 60),
+                Duration::from_secs(config.cooldown_mins * 60),
+            )),
+            quiet_hours: None,
+            deferred: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Hold the messages during the quiet hours, see run_deferred
+    pub fn with_quiet_hours(self, quiet_hours: QuietHours, clock: Clock) -> Alerter {
+        Alerter {
+            quiet_hours: Some((quiet_hours, clock)),
+            ..self
+        }
+    }
+
+    fn is_quiet(&self) -> bool {
+        self.quiet_hours
+            .is_some_and(|(hours, clock)| hours.is_quiet(Timestamp::now(), clock))
+    }
+
+    /// Send the messages held during the quiet hours once they end, checking every minute so
+    /// clock changes are followed
+    pub async fn run_deferred(self: Arc<Self>, mut context: TaskContext) {
+        while context.sleep(|_| Some(Duration::from_secs(60))).await {
+            if self.is_quiet() {
+                continue;
+            }
+            let deferred = std::mem::take(&mut *self.deferred.lock().await);
+            for message in deferred.into_values() {
+                self.send(Some(message)).await;
+            }
+        }
+    }
+
+    pub async fn error(&self, class: AlertClass, detail: impl Display) {
+        if self.admin.is_none() {
+            return;
+        }
+        let message = {
+            let mut alerts = self.alerts.lock().await;
+            alerts.error(class, &detail.to_string(), Instant::now())
+        };
+        self.send(message).await;
+    }
+
+    pub async fn success(&self, class: AlertClass) {
+        if self.admin.is_none() {
+            return;
+        }
+        let message = self.alerts.lock().await.success(class);
+        self.send(message).await;
+    }
+
+    async fn send(&self, message: Option<AlertMessage>) {
+        let (Some((admin, client)), Some(message)) = (&self.admin, message) else {
+            return;
+        };
+        if self.is_quiet() {
+            eprintln!("Alert held until the quiet hours end: {message}");
+            let mut deferred = self.deferred.lock().await;
+            match message {
+                // The admin doesn't need to hear of a failure that's already over
+                AlertMessage::Recovered(class)
+                    if matches!(deferred.get(&class), Some(AlertMessage::Failing(..))) =>
+                {
+                    deferred.remove(&class);
+                }
+                AlertMessage::Failing(class, ..) | AlertMessage::Recovered(class) => {
+                    deferred.insert(class, message);
+                }
+            }
+            return;
+        }
+        eprintln!("Alert: {message}");
+        if let Err(err) = client
+            .send_private_msg(*admin, message.to_string(), None)
+            .await
+        {
+            eprintln!("Alert not sent to the admin: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mins(n: u64) -> Duration {
+        Duration::from_secs(n * 60)
+    }
+
+    /// Errors of class at the minutes given from start, with the alerts they gave
+    fn errors(
+        alerts: &mut Alerts,
+        class: AlertClass,
+        start: Instant,
+        at: &[u64],
+    ) -> Vec<(u64, AlertMessage)> {
+        at.iter()
+            .filter_map(|x| {
+                let detail = format!("error at {x}");
+                alerts
+                    .error(class, &detail, start + mins(*x))
+                    .map(|message| (*x, message))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn alerts_once_the_threshold_is_reached_within_the_window() {
+        let start = Instant::now();
+        let mut alerts = Alerts::new(3, mins(10), mins(60));
+        // The first error is out of the window by the third one, and the fourth reaches it
+        assert_eq!(
+            errors(
+                &mut alerts,
+                AlertClass::RelaysDown,
+                start,
+                &[0, 5, 11, 12, 13]
+            ),
+            [(
+                12,
+                AlertMessage::Failing(AlertClass::RelaysDown, 3, "error at 12".to_string())
+            )]
+        );
+        // Other classes are counted apart
+        assert_eq!(
+            errors(&mut alerts, AlertClass::HandlerPanic, start, &[14, 15]),
+            []
+        );
+    }
+
+    #[test]
+    fn recovery_is_told_once_and_only_after_an_alert() {
+        let start = Instant::now();
+        let mut alerts = Alerts::new(2, mins(10), mins(0));
+        assert_eq!(alerts.success(AlertClass::StateUnwritable), None);
+        errors(&mut alerts, AlertClass::StateUnwritable, start, &[0]);
+        // Below the threshold, which the success resets
+        assert_eq!(alerts.success(AlertClass::StateUnwritable), None);
+        assert_eq!(
+            errors(&mut alerts, AlertClass::StateUnwritable, start, &[1]),
+            []
+        );
+
+        assert_eq!(
+            errors(&mut alerts, AlertClass::StateUnwritable, start, &[2]).len(),
+            1
+        );
+        assert_eq!(
+            alerts.success(AlertClass::StateUnwritable),
+            Some(AlertMessage::Recovered(AlertClass::StateUnwritable))
+        );
+        assert_eq!(alerts.success(AlertClass::StateUnwritable), None);
+    }
+
+    #[test]
+    fn failing_class_isnt_alerted_again_before_the_cooldown() {
+        let start = Instant::now();
+        let mut alerts = Alerts::new(2, mins(10), mins(60));
+        // Still failing: a single alert
+        let alerted = errors(
+            &mut alerts,
+            AlertClass::RepliesRefused,
+            start,
+            &[0, 1, 2, 3],
+        );
+        assert_eq!(alerted.iter().map(|x| x.0).collect::<Vec<_>>(), [1]);
+
+        // Failing again soon after recovering is within the cooldown
+        alerts.success(AlertClass::RepliesRefused);
+        assert_eq!(
+            errors(
+                &mut alerts,
+                AlertClass::RepliesRefused,
+                start,
+                &[30, 31, 32]
+            ),
+            []
+        );
+        assert_eq!(alerts.success(AlertClass::RepliesRefused), None);
+
+        // After the cooldown it's alerted again
+        let alerted = errors(&mut alerts, AlertClass::RepliesRefused, start, &[70, 71]);
+        assert_eq!(alerted.iter().map(|x| x.0).collect::<Vec<_>>(), [71]);
+    }
+}
@@ -0,0 +1,738 @@
+/// Arguments of the command line. Each one shot mode is an argument of the "Mutually exclusive"
+/// group, the other arguments change how they run
+use clap::{value_parser, Arg, ArgAction, ArgGroup, Command, ValueHint};
+use std::net::SocketAddr;
+
+use crate::client_utils::validate_relay_url;
+use crate::sep_degrees;
+use crate::target_lists;
+
+use nostr_sdk::prelude::{EventId, Timestamp};
+
+pub fn command() -> Command {
+    clap::command!()
+    .arg(
+        Arg::new("print rank")
+            .long("print-rank")
+            .action(ArgAction::SetTrue)
+            .help("Pretty print recommendations rank, based on --user-key value")
+            .requires("user key"),
+    )
+    .arg(
+        Arg::new("publish follow pack")
+            .long("publish-follow-pack")
+            .action(ArgAction::SetTrue)
+            .help("Publish the best ranked users as a follow pack, signed with the connection key")
+            .requires("print rank"),
+    )
+    .arg(
+        Arg::new("publish list")
+            .long("publish-list")
+            .help("Publish the best ranked users as a follow set (NIP-51) with this name, signed with the connection key. Publishing again with the same name replaces it")
+            .value_name("name")
+            .requires("print rank"),
+    )
+    .arg(
+        Arg::new("pack id")
+            .long("pack-id")
+            .help("Identifier of the follow pack. Publishing again with the same one replaces it")
+            .default_value("six-degrees-recommendations"),
+    )
+    .arg(
+        Arg::new("pack title")
+            .long("pack-title")
+            .default_value("Recommended follows"),
+    )
+    .arg(
+        Arg::new("pack description")
+            .long("pack-description"),
+    )
+    .arg(
+        Arg::new("pack size")
+            .long("pack-size")
+            .help("How many of the best ranked users go in the follow pack or the follow set")
+            .value_parser(value_parser!(usize))
+            .default_value("50"),
+    )
+    .arg(
+        Arg::new("pack level")
+            .long("pack-level")
+            .help("Make the follow pack of the users at this distance of the user instead, e.g. 1 for their follows")
+            .value_parser(value_parser!(usize)),
+    )
+    .arg(
+        Arg::new("rank cache")
+            .long("rank-cache")
+            .help("Save the network to this file while ranking, and resume from it if it exists")
+            .value_name("path")
+            .value_hint(ValueHint::FilePath)
+            .requires("print rank"),
+    )
+    .arg(
+        Arg::new("checkpoint chunks")
+            .long("checkpoint-chunks")
+            .help("Save the --rank-cache file after this many chunks of users")
+            .value_parser(value_parser!(usize))
+            .default_value("10"),
+    )
+    .arg(
+        Arg::new("export dot")
+            .long("export-dot")
+            .help("After ranking, write the network as a Graphviz DOT file")
+            .value_name("path")
+            .value_hint(ValueHint::FilePath)
+            .requires("print rank"),
+    )
+    .arg(
+        Arg::new("dot distance")
+            .long("dot-max-distance")
+            .help("Only export the users at most this many levels away from the user")
+            .value_parser(value_parser!(usize))
+            .requires("export dot"),
+    )
+    .arg(
+        Arg::new("edge labels")
+            .long("edge-labels")
+            .help("Text on the edges of the DOT file: none, or petnames, the names followers gave in their contact lists")
+            .value_parser(["none", "petnames"])
+            .default_value("none")
+            .requires("export dot"),
+    )
+    .arg(
+        Arg::new("no petnames")
+            .long("no-petnames")
+            .action(ArgAction::SetTrue)
+            .help("Don't keep the petnames of contact lists while ranking, saving memory in big networks")
+            .conflicts_with("edge labels")
+            .requires("print rank"),
+    )
+    .arg(
+        Arg::new("diff")
+            .long("diff")
+            .help("After ranking, print who the user followed and unfollowed since the network in this cache")
+            .value_name("old cache")
+            .value_hint(ValueHint::FilePath)
+            .requires("print rank"),
+    )
+    .arg(
+        Arg::new("diff follows")
+            .long("diff-follows")
+            .action(ArgAction::SetTrue)
+            .help("Also print what changed for every user the user follows")
+            .requires("diff"),
+    )
+    .arg(
+        Arg::new("stats")
+            .long("stats")
+            .action(ArgAction::SetTrue)
+            .help("After ranking, print how many follows users of the network have, and the most followed ones")
+            .requires("print rank"),
+    )
+    .arg(
+        Arg::new("rank config")
+            .long("rank-config")
+            .help("TOML file with the weights of each rank reason, min_rank and max_results. The weight flags override it")
+            .value_name("path")
+            .value_hint(ValueHint::FilePath)
+            .requires("print rank"),
+    )
+    .arg(
+        Arg::new("mutual weight")
+            .long("mutual-weight")
+            .help("Rank given for each mutual among the users the user follows [default: 10]")
+            .value_parser(value_parser!(f64))
+            .requires("print rank"),
+    )
+    .arg(
+        Arg::new("popularity dampening")
+            .long("popularity-dampening")
+            .help("How much being followed by many in the crawled network lowers the rank of the mutuals, 0 to not lower it [default: 1]")
+            .value_parser(value_parser!(f64))
+            .requires("print rank"),
+    )
+    .arg(
+        Arg::new("recency weight")
+            .long("recency-weight")
+            .help("How much more a mutual counts when their contact list was just updated, halved every --recency-half-life days [default: 1]")
+            .value_parser(value_parser!(f64))
+            .requires("print rank"),
+    )
+    .arg(
+        Arg::new("recency half life")
+            .long("recency-half-life")
+            .help("Days after which an update of the contact list of a mutual counts half as much [default: 30]")
+            .value_name("days")
+            .value_parser(value_parser!(f64))
+            .requires("print rank"),
+    )
+    .arg(
+        Arg::new("similarity weight")
+            .long("similarity-weight")
+            .help("Rank given for following exactly the same users as the user, less the fewer follows are in common [default: 50]")
+            .value_parser(value_parser!(f64))
+            .requires("print rank"),
+    )
+    .arg(
+        Arg::new("community weight")
+            .long("community-weight")
+            .help("Rank given for having every mutual be mutuals with each other, less the fewer are [default: 20]")
+            .value_parser(value_parser!(f64))
+            .requires("print rank"),
+    )
+    .arg(
+        Arg::new("min rank")
+            .long("min-rank")
+            .help("Leave out the users ranked lower than this")
+            .value_parser(value_parser!(i32))
+            .allow_negative_numbers(true)
+            .requires("print rank"),
+    )
+    .arg(
+        Arg::new("max results")
+            .long("max-results")
+            .help("Only print this many of the best ranked users")
+            .value_parser(value_parser!(usize))
+            .requires("print rank"),
+    )
+    .arg(
+        Arg::new("depth")
+            .long("depth")
+            .help("Rank the users this many follows away from you, by their mutuals one follow closer. Each level more fetches many more contact lists")
+            .value_name("levels")
+            .value_parser(value_parser!(usize))
+            .default_value("2")
+            .requires("print rank"),
+    )
+    .arg(
+        Arg::new("max level size")
+            .long("max-level-size")
+            .help("Keep at most this many users in each level of the crawl, the ones followed by the most users of the level before")
+            .value_name("users")
+            .value_parser(value_parser!(usize))
+            .requires("print rank"),
+    )
+    .arg(
+        Arg::new("max follows")
+            .long("max-follows")
+            .help("Don't crawl through nor rank users following more than this many, probably bots. 0 keeps them [default: 5000]")
+            .value_name("follows")
+            .value_parser(value_parser!(usize))
+            .requires("print rank"),
+    )
+    .arg(
+        Arg::new("require name")
+            .long("require-name")
+            .action(ArgAction::SetTrue)
+            .help("Don't crawl through nor rank users whose profile has neither a name nor a NIP-05 address")
+            .requires("print rank"),
+    )
+    .arg(
+        Arg::new("name blocklist")
+            .long("name-blocklist")
+            .help("Don't crawl through nor rank users whose name matches this regex")
+            .value_name("regex")
+            .requires("print rank"),
+    )
+    .arg(
+        Arg::new("include followed")
+            .long("include-followed")
+            .action(ArgAction::SetTrue)
+            .help("Also rank the users you already follow")
+            .requires("print rank"),
+    )
+    .arg(
+        Arg::new("exclusion file")
+            .long("exclusion-file")
+            .help("Never rank the users in this file, one npub per line. They're still crawled, unlike --exclude-user")
+            .value_name("path")
+            .value_hint(ValueHint::FilePath)
+            .requires("print rank"),
+    )
+    .arg(
+        Arg::new("interactions days")
+            .long("interactions-days")
+            .help("Also rank users by their replies and reactions to the users you follow in this many last days. Fetches many more events")
+            .value_name("days")
+            .value_parser(value_parser!(u64))
+            .requires("print rank"),
+    )
+    .arg(
+        Arg::new("interaction weight")
+            .long("interaction-weight")
+            .help("Rank given for each doubling of the replies and reactions to the users you follow, with --interactions-days [default: 5]")
+            .value_parser(value_parser!(f64))
+            .requires("interactions days"),
+    )
+    .arg(
+        Arg::new("zaps days")
+            .long("zaps-days")
+            .help("Also rank users by the zaps they got from the users you follow in this many last days")
+            .value_name("days")
+            .value_parser(value_parser!(u64))
+            .requires("print rank"),
+    )
+    .arg(
+        Arg::new("zap weight")
+            .long("zap-weight")
+            .help("Rank given for each doubling of the sats zapped by the users you follow, with --zaps-days [default: 3]")
+            .value_parser(value_parser!(f64))
+            .requires("zaps days"),
+    )
+    .arg(
+        Arg::new("connector sample")
+            .long("connector-sample")
+            .help("Also print how much each ranked user connects others, estimated from the shortest paths from this many users")
+            .value_name("users")
+            .value_parser(value_parser!(usize))
+            .requires("print rank"),
+    )
+    .arg(
+        Arg::new("exclude user")
+            .long("exclude-user")
+            .help("Leave this user out of the ranking and the network, e.g. a spam account. Can be repeated")
+            .value_name("npub")
+            .action(ArgAction::Append)
+            .requires("print rank"),
+    )
+    .arg(
+        Arg::new("yes")
+            .long("yes")
+            .action(ArgAction::SetTrue)
+            .help("Publish without asking for confirmation"),
+    )
+    .arg(
+        Arg::new("connection key")
+            .long("connection-key")
+            .help("Set connection authentication key, or `new` to generate one. Otherwise it's read from SIX_DEGREES_NSEC or --connection-key-file"),
+    )
+    .arg(
+        Arg::new("connection key file")
+            .long("connection-key-file")
+            .help("File with the connection key. A key generated with `--connection-key new` is saved here if it doesn't exist")
+            .value_name("path")
+            .value_hint(ValueHint::FilePath),
+    )
+    .arg(
+        Arg::new("user key")
+            .long("user-key")
+            .help("User Nostr npub or nsec"),
+    )
+    .arg(
+        Arg::new("relays config")
+            .long("relays-config")
+            .help("TOML file with a `relays` list of relay URLs to connect to. Uses the default relays otherwise")
+            .value_name("config path")
+            .value_hint(ValueHint::FilePath)
+            .num_args(1),
+    )
+    .arg(
+        Arg::new("relay")
+            .long("relay")
+            .help("Also connect to this relay. Can be given multiple times")
+            .value_name("url")
+            .value_parser(validate_relay_url)
+            .action(ArgAction::Append),
+    )
+    .arg(
+        Arg::new("proxy")
+            .long("proxy")
+            .help("Connect to relays through this SOCKS5 proxy, e.g. 127.0.0.1:9050 for Tor. Required for .onion relays")
+            .value_name("ip:port")
+            .value_parser(value_parser!(SocketAddr)),
+    )
+    .arg(
+        Arg::new("no default relays")
+            .long("no-default-relays")
+            .action(ArgAction::SetTrue)
+            .help("Don't fall back to the built-in relays, only use the configured ones and --relay"),
+    )
+    .arg(
+        Arg::new("timeout")
+            .long("timeout-secs")
+            .help("Timeout of contact list queries. 0 uses the library default. In listen mode, overrides the config file [default: 20]")
+            .value_name("seconds")
+            .value_parser(value_parser!(u64)),
+    )
+    .arg(
+        Arg::new("metadata timeout")
+            .long("metadata-timeout-secs")
+            .help("Timeout of metadata queries. 0 uses the library default. In listen mode, overrides the config file [default: 20]")
+            .value_name("seconds")
+            .value_parser(value_parser!(u64)),
+    )
+    .arg(
+        Arg::new("min corroborating relays")
+            .long("min-corroborating-relays")
+            .help("Mark answers whose path has a contact list returned by fewer relays as low confidence. In listen mode, overrides the config file [default: 0, disabled]")
+            .value_name("count")
+            .value_parser(value_parser!(usize)),
+    )
+    .arg(
+        Arg::new("contact list ttl")
+            .long("contact-list-ttl-secs")
+            .help("Fetch again the contact lists in the cache fetched longer ago. 0 keeps them forever. In listen mode, overrides the config file [default: 86400]")
+            .value_name("seconds")
+            .value_parser(value_parser!(u64)),
+    )
+    .arg(
+        Arg::new("search budget")
+            .long("search-budget-secs")
+            .help("Split this many seconds among the fetches of a search, so a slow one can't take all of it. Paths found may then not be the shortest. 0 doesn't limit it. In listen mode, overrides the config file")
+            .value_name("seconds")
+            .value_parser(value_parser!(u64)),
+    )
+    .arg(
+        Arg::new("avoid muted")
+            .long("avoid-muted")
+            .help("Don't find paths through users muted by the first user of a search. In listen mode, overrides the config file")
+            .action(ArgAction::SetTrue),
+    )
+    .arg(
+        Arg::new("avoid hubs")
+            .long("avoid-hubs")
+            .help("Among paths as short, prefer the ones that don't go through this many of the users most paths go through. In listen mode, overrides the config file")
+            .value_name("count")
+            .value_parser(value_parser!(usize)),
+    )
+    .arg(
+        Arg::new("max depth")
+            .long("max-depth")
+            .help("Most hops searched, up to 12. In listen mode, overrides the config file")
+            .value_name("hops")
+            .value_parser(value_parser!(u32).range(1..=sep_degrees::MAX_DEPTH_CEILING.0 as i64)),
+    )
+    .arg(
+        Arg::new("path mode")
+            .long("path-mode")
+            .help("What joins the users of a path: mutual follows, follows from the first user to the second (directed) or follows in either direction. In listen mode, overrides the config file")
+            .value_name("mode")
+            .value_parser(["mutual", "directed", "either"]),
+    )
+    .arg(
+        Arg::new("prefetch metadata")
+            .long("prefetch-metadata")
+            .help("Fetch the metadata of users along with their contact lists while searching, using more bandwidth. In listen mode, overrides the config file")
+            .action(ArgAction::SetTrue),
+    )
+    .arg(
+        Arg::new("external graph url")
+            .long("external-graph-url")
+            .help("Ask this follow graph indexer, over http, for the contact lists of the searched users no relay has. Answers using them are flagged. In listen mode, overrides the config file")
+            .value_name("url")
+            .value_hint(ValueHint::Url),
+    )
+    .arg(
+        Arg::new("external graph api key")
+            .long("external-graph-api-key")
+            .help("Bearer token sent to the --external-graph-url indexer")
+            .value_name("key")
+            .requires("external graph url"),
+    )
+    .arg(
+        Arg::new("external graph timeout")
+            .long("external-graph-timeout-secs")
+            .help("Timeout of each query to the --external-graph-url indexer")
+            .value_name("seconds")
+            .value_parser(value_parser!(u64))
+            .default_value("5")
+            .requires("external graph url"),
+    )
+    .arg(
+        Arg::new("external graph hops")
+            .long("external-graph-hops")
+            .help("Also ask the --external-graph-url indexer for the users between the searched ones")
+            .action(ArgAction::SetTrue)
+            .requires("external graph url"),
+    )
+    .arg(
+        Arg::new("relay stats")
+            .long("relay-stats")
+            .action(ArgAction::SetTrue)
+            .help("When done, print how many events each relay contributed and how many queries it answered"),
+    )
+    .arg(
+        Arg::new("run old")
+            .long("run-old")
+            .action(ArgAction::SetTrue)
+            .hide(true),
+    )
+    .arg(
+        Arg::new("separation degrees")
+            .long("sep-degree")
+            .help("Find degree of separation between two users. Also outputs which users make the shortest path, if found")
+            .value_name("npub")
+            .num_args(2),
+    )
+    .arg(
+        Arg::new("explain not found")
+            .long("explain-not-found")
+            .help("When --sep-degree finds no connection, tell how far the search got from each user")
+            .action(ArgAction::SetTrue)
+            .requires("separation degrees"),
+    )
+    .arg(
+        Arg::new("event expiration")
+            .long("event-expiration-secs")
+            .help("Make the events the bot publishes expire after this many seconds")
+            .value_parser(value_parser!(u64)),
+    )
+    .arg(
+        Arg::new("client tag")
+            .long("client-tag")
+            .action(ArgAction::SetTrue)
+            .help("Tag the events the bot publishes with the bot as their client"),
+    )
+    .arg(
+        Arg::new("hashtag")
+            .long("hashtag")
+            .action(ArgAction::Append)
+            .help("Hashtag added to the notes the bot publishes, can be repeated"),
+    )
+    .arg(
+        Arg::new("pow max difficulty")
+            .long("pow-max-difficulty")
+            .help("Mine the proof of work relays require on replies, up to this many bits. Replies needing more aren't sent to those relays")
+            .value_name("bits")
+            .value_parser(value_parser!(u8))
+            .default_value("0"),
+    )
+    .arg(
+        Arg::new("pow budget")
+            .long("pow-budget-secs")
+            .help("Give up mining the proof of work of a reply after this many seconds, then it isn't sent to the relays requiring it")
+            .value_name("seconds")
+            .value_parser(value_parser!(u64))
+            .default_value("10"),
+    )
+    .arg(
+        Arg::new("export")
+            .long("export")
+            .help("Write the network in the cache for other tools, as CSV (edges, plus nodes in <name>_nodes.csv) or JSONL, from the file extension")
+            .value_name("path")
+            .value_hint(ValueHint::FilePath),
+    )
+    .arg(
+        Arg::new("ego")
+            .long("ego")
+            .help("Only export the users around this one, see --radius")
+            .value_name("npub")
+            .requires("export"),
+    )
+    .arg(
+        Arg::new("radius")
+            .long("radius")
+            .help("Most follows away from the --ego user, in either direction, of the users exported")
+            .value_parser(value_parser!(usize))
+            .default_value("2")
+            .requires("ego"),
+    )
+    .arg(
+        Arg::new("components")
+            .long("components")
+            .action(ArgAction::SetTrue)
+            .help("Print the sizes of the parts of the network in the cache not connected to each other"),
+    )
+    .arg(
+        Arg::new("mutual only")
+            .long("mutual-only")
+            .action(ArgAction::SetTrue)
+            .help("Only connect users that are mutuals, as searches do")
+            .requires("components"),
+    )
+    .arg(
+        Arg::new("as of")
+            .long("as-of")
+            .help("Answer --sep-degree offline, from the newest cache generation taken by this date (YYYY-MM-DD, UTC) or unix time")
+            .value_name("date")
+            .value_parser(parse_as_of)
+            .requires("separation degrees"),
+    )
+    .arg(
+        Arg::new("db")
+            .long("db")
+            .help("SQLite database of contact lists and metadata, read before asking relays and kept up to date. Needs the sqlite feature")
+            .value_name("path")
+            .value_hint(ValueHint::FilePath),
+    )
+    .arg(
+        Arg::new("convert cache")
+            .long("convert-cache")
+            .help("Write a cache file in the format of the output extension: .json, .zst (compressed) or anything else for bincode")
+            .value_names(["input", "output"])
+            .value_hint(ValueHint::FilePath)
+            .num_args(2),
+    )
+    .arg(
+        Arg::new("check cache")
+            .long("check-cache")
+            .help("Check a cache file for entries that don't agree with each other, e.g. after a crash. Exits with 1 when it can't be loaded")
+            .value_name("path")
+            .value_hint(ValueHint::FilePath),
+    )
+    .arg(
+        Arg::new("repair")
+            .long("repair")
+            .action(ArgAction::SetTrue)
+            .help("Drop the entries found by --check-cache and write the cache again, keeping the old one as <path>.corrupt")
+            .requires("check cache"),
+    )
+    .arg(
+        Arg::new("import events")
+            .long("import-events")
+            .help("Fill the network with the contact lists and metadata of a JSONL dump of events before searching or ranking")
+            .value_name("path")
+            .value_hint(ValueHint::FilePath)
+            .conflicts_with("listen mentions"),
+    )
+    .arg(
+        Arg::new("cache")
+            .long("cache")
+            .help("Network cache file, whose older generations are used by --as-of")
+            .value_name("cache path")
+            .value_hint(ValueHint::FilePath)
+            .default_value("cache.bin"),
+    )
+    .arg(
+        Arg::new("listen mentions")
+            .long("listen-mentions")
+            .help("Listen for events that mention the client pubkey")
+            .value_name("config path")
+            .value_hint(ValueHint::FilePath)
+            .num_args(1)
+    )
+    .arg(
+        Arg::new("force takeover")
+            .long("force-takeover")
+            .action(ArgAction::SetTrue)
+            .help("Take the lock of the listen config even if its holder can't be told to be dead")
+            .requires("listen mentions"),
+    )
+    .arg(
+        Arg::new("requeue mention")
+            .long("requeue-mention")
+            .help("Answer again a mention whose handler panicked, after fixing it. Can be repeated")
+            .value_name("event id")
+            .value_parser(|x: &str| EventId::parse(x))
+            .action(ArgAction::Append)
+            .requires("listen mentions"),
+    )
+    .arg(
+        Arg::new("self test")
+            .long("self-test")
+            .help("Search between the two users of the config and check the degree is in the expected range. Exits with 1 if not")
+            .value_name("config path")
+            .value_hint(ValueHint::FilePath)
+            .num_args(1),
+    )
+    .arg(
+        Arg::new("leaderboard")
+            .long("leaderboard")
+            .help("Find the degrees of separation among the members of a list from --lists-config, and summarize them")
+            .value_name("list name")
+            .num_args(1),
+    )
+    .arg(
+        Arg::new("lists config")
+            .long("lists-config")
+            .help("TOML file with named lists of npubs")
+            .value_name("config path")
+            .value_hint(ValueHint::FilePath)
+            .default_value(target_lists::DEFAULT_LISTS_PATH),
+    )
+    .arg(
+        Arg::new("community distance")
+            .long("community-distance")
+            .help("Find the member of a list from --lists-config closest to the user, and the path to them")
+            .value_names(["npub", "list name"])
+            .num_args(2),
+    )
+    .arg(
+        Arg::new("common")
+            .long("common")
+            .help("List the accounts two users both follow and both are mutuals with")
+            .value_names(["npub", "npub"])
+            .num_args(2),
+    )
+    .arg(
+        Arg::new("replay history")
+            .long("replay-history")
+            .help("Search again a request from the history of --listen-mentions, with its options and relays, and compare the answers")
+            .value_name("history path")
+            .value_hint(ValueHint::FilePath)
+            .requires("request"),
+    )
+    .arg(
+        Arg::new("request")
+            .long("request")
+            .help("Id of the mention to replay")
+            .value_name("event id")
+            .value_parser(|x: &str| EventId::parse(x))
+            .requires("replay history"),
+    )
+    .arg(
+        Arg::new("followers")
+            .long("followers")
+            .help("List the users that follow the user, with their names")
+            .value_name("npub"),
+    )
+    .arg(
+        Arg::new("followers pages")
+            .long("followers-pages")
+            .help("Most pages of followers asked for, of 500 contact lists each")
+            .value_parser(value_parser!(usize))
+            .default_value("20")
+            .requires("followers"),
+    )
+    .arg(
+        Arg::new("leaderboard budget")
+            .long("leaderboard-budget-secs")
+            .help("Stop searching more pairs of the leaderboard after this many seconds")
+            .value_parser(value_parser!(u64))
+            .default_value("600"),
+    )
+    .arg(
+        Arg::new("json")
+            .long("json")
+            .action(ArgAction::SetTrue)
+            .help("Output the leaderboard or the separation degree as JSON"),
+    )
+    .arg(
+        Arg::new("publish")
+            .long("publish")
+            .action(ArgAction::SetTrue)
+            .help("Also publish the leaderboard as a note, signed with the connection key")
+            .requires("leaderboard"),
+    )
+    .group(
+        ArgGroup::new("Mutually exclusive")
+            .args(["run old", "print rank", "separation degrees", "listen mentions", "leaderboard", "community distance", "self test", "export", "components", "convert cache", "check cache", "followers", "common", "replay history"])
+            .multiple(false),
+    )
+}
+
+/// Parse a date as YYYY-MM-DD, meaning the end of that day in UTC, or as unix time
+fn parse_as_of(date: &str) -> std::result::Result<Timestamp, String> {
+    if let Ok(secs) = date.parse::<u64>() {
+        return Ok(Timestamp::from(secs));
+    }
+    let parts = date
+        .split('-')
+        .map(|x| x.parse::<i64>())
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|_| format!("invalid date {date}: use YYYY-MM-DD"))?;
+    let (year, month, day) = match parts[..] {
+        [y, m, d] if (1..=12).contains(&m) && (1..=31).contains(&d) => (y, m, d),
+        _ => return Err(format!("invalid date {date}: use YYYY-MM-DD")),
+    };
+    // Days since the unix epoch of a proleptic gregorian date
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    let end_of_day = (days + 1) * 86400 - 1;
+    u64::try_from(end_of_day)
+        .map(Timestamp::from)
+        .map_err(|_| format!("invalid date {date}: before 1970"))
+}
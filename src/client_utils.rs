@@ -1,13 +1,260 @@
 /// Useful function to interact with client API
 use itertools::Itertools;
 use nostr_sdk::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
-use std::time::Duration;
+use std::fs;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 
-pub async fn build_client(keys: impl Into<NostrSigner>) -> Client {
+use crate::network::PetnameInfo;
+use crate::pow::{self, PowLimits};
+
+pub const DEFAULT_RELAYS: [&str; 4] = [
+    "wss://relay.damus.io",
+    "wss://relay.primal.net",
+    "wss://nos.lol",
+    "wss://strfry.iris.to",
+];
+
+/// Timeout of contact list and metadata queries, unless configured otherwise
+pub const DEFAULT_QUERY_TIMEOUT_SECS: u64 = 20;
+
+/// Timeouts of the queries made by searches. None uses the library default
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueryTimeouts {
+    pub contact_lists: Option<Duration>,
+    pub metadata: Option<Duration>,
+}
+
+impl QueryTimeouts {
+    /// Build from seconds, where 0 means the library default
+    pub fn from_secs(contact_lists: u64, metadata: u64) -> QueryTimeouts {
+        let to_duration = |secs| (secs > 0).then(|| Duration::from_secs(secs));
+        QueryTimeouts {
+            contact_lists: to_duration(contact_lists),
+            metadata: to_duration(metadata),
+        }
+    }
+}
+
+impl Default for QueryTimeouts {
+    fn default() -> Self {
+        QueryTimeouts::from_secs(DEFAULT_QUERY_TIMEOUT_SECS, DEFAULT_QUERY_TIMEOUT_SECS)
+    }
+}
+
+/// A relay in the config, either its URL or a table with options
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum RelayEntry {
+    Url(String),
+    WithOptions {
+        url: String,
+        /// Answer the relay's NIP-42 auth challenges with the connection key
+        #[serde(default)]
+        auth: bool,
+    },
+}
+
+impl RelayEntry {
+    fn url(&self) -> &str {
+        match self {
+            RelayEntry::Url(url) => url,
+            RelayEntry::WithOptions { url, .. } => url,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RelaysConfig {
+    #[serde(default)]
+    relays: Vec<RelayEntry>,
+    /// SOCKS5 proxy used for every relay connection, e.g. a local Tor daemon
+    proxy: Option<SocketAddr>,
+}
+
+/// Parse a relay URL, accepting only the ws and wss schemes
+pub fn validate_relay_url(relay: &str) -> Result<Url, String> {
+    match Url::parse(relay) {
+        Ok(url) if url.scheme() == "ws" || url.scheme() == "wss" => Ok(url),
+        Ok(url) => Err(format!("invalid relay {url}: scheme must be ws or wss")),
+        Err(err) => Err(format!("invalid relay {relay}: {err}")),
+    }
+}
+
+fn default_relays() -> Vec<Url> {
+    DEFAULT_RELAYS
+        .iter()
+        .map(|x| Url::parse(x).expect("Default relay parse error"))
+        .collect_vec()
+}
+
+/// Read the relays from a TOML file with a `relays` list
+///
+/// If the file is missing or has no valid relay, gives the default relays when `use_defaults` is set,
+/// or nothing otherwise
+pub fn load_relays(path: Option<&str>, use_defaults: bool) -> Vec<Url> {
+    let fallback = || {
+        if use_defaults {
+            default_relays()
+        } else {
+            vec![]
+        }
+    };
+    let path = match path {
+        Some(s) => s,
+        None => return fallback(),
+    };
+    let config = match fs::read_to_string(path) {
+        Ok(text) => match toml::from_str::<RelaysConfig>(&text) {
+            Ok(ok) => ok,
+            Err(err) => {
+                eprintln!("Relays config parse error:\n{}", err);
+                return fallback();
+            }
+        },
+        Err(err) => {
+            eprintln!("Relays config missing: {}", err);
+            return fallback();
+        }
+    };
+    let relays = config
+        .relays
+        .iter()
+        .filter_map(|x| match validate_relay_url(x.url()) {
+            Ok(url) => Some(url),
+            Err(err) => {
+                eprintln!("Relay ignored, {err}");
+                None
+            }
+        })
+        .collect_vec();
+    if relays.is_empty() {
+        eprintln!("No valid relays in {path}");
+        return fallback();
+    }
+    relays
+}
+
+#[derive(Debug)]
+pub enum BuildClientError {
+    NoRelayAdded,
+    OnionWithoutProxy(Url),
+    ProxyUnreachable(SocketAddr, std::io::Error),
+}
+
+impl std::fmt::Display for BuildClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildClientError::NoRelayAdded => write!(f, "None of the relays could be added"),
+            BuildClientError::OnionWithoutProxy(url) => {
+                write!(f, "Onion relay {url} can only be used with a proxy")
+            }
+            BuildClientError::ProxyUnreachable(addr, error) => {
+                write!(f, "Proxy {addr} unreachable: {error}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for BuildClientError {}
+
+/// The proxy set in the relays config, if any
+pub fn load_proxy(path: Option<&str>) -> Option<SocketAddr> {
+    let text = fs::read_to_string(path?).ok()?;
+    toml::from_str::<RelaysConfig>(&text).ok()?.proxy
+}
+
+/// Relays of the relays config with `auth = true`
+pub fn load_auth_relays(path: Option<&str>) -> Vec<Url> {
+    let config = match path.and_then(|x| fs::read_to_string(x).ok()) {
+        Some(text) => match toml::from_str::<RelaysConfig>(&text) {
+            Ok(ok) => ok,
+            Err(_err) => return vec![],
+        },
+        None => return vec![],
+    };
+    config
+        .relays
+        .iter()
+        .filter_map(|x| match x {
+            RelayEntry::WithOptions { url, auth: true } => validate_relay_url(url).ok(),
+            _ => None,
+        })
+        .collect_vec()
+}
+
+/// Answer NIP-42 auth challenges, only of the relays in `auth_relays`
+///
+/// Failures are logged, the relay is then used unauthenticated
+async fn answer_auth_challenges(client: Client, auth_relays: Vec<Url>) {
+    let mut notifications = client.notifications();
+    while let Ok(notification) = notifications.recv().await {
+        let (relay_url, challenge) = match notification {
+            RelayPoolNotification::Message {
+                relay_url,
+                message: RelayMessage::Auth { challenge },
+            } => (relay_url, challenge),
+            RelayPoolNotification::Shutdown => break,
+            _ => continue,
+        };
+        if !auth_relays.contains(&relay_url) {
+            eprintln!("Relay {relay_url} asked for authentication, which isn't enabled for it");
+            continue;
+        }
+        match client.auth(challenge, relay_url.clone()).await {
+            Ok(()) => {
+                eprintln!("Authenticated to {relay_url}");
+                // Subscriptions sent before authenticating may have been refused
+                if let Ok(relay) = client.relay(&relay_url).await {
+                    let opts = RelaySendOptions::new().skip_send_confirmation(true);
+                    if let Err(err) = relay.resubscribe(opts).await {
+                        eprintln!("Resubscribe to {relay_url} error: {err}");
+                    }
+                }
+            }
+            Err(err) => eprintln!("Authentication to {relay_url} failed: {err}"),
+        }
+    }
+}
+
+pub fn is_onion(relay: &Url) -> bool {
+    relay.host_str().is_some_and(|x| x.ends_with(".onion"))
+}
+
+/// Relays that failed to be added are logged and skipped, it's only an error if none could be added
+///
+/// With a proxy, every relay is connected through it. The proxy is checked to be reachable first,
+/// so a wrong address fails here instead of stalling the first query
+///
+/// Only the relays in `auth_relays` are authenticated to, when they ask for it
+pub async fn build_client(
+    keys: impl Into<NostrSigner>,
+    relays: &[Url],
+    proxy: Option<SocketAddr>,
+    auth_relays: &[Url],
+) -> Result<Client, BuildClientError> {
     // Configure client
-    let connection: Connection = Connection::new();
-    let opts = Options::new().connection(connection);
+    let connection: Connection = match proxy {
+        Some(addr) => {
+            if let Err(err) = std::net::TcpStream::connect_timeout(&addr, Duration::from_secs(5)) {
+                return Err(BuildClientError::ProxyUnreachable(addr, err));
+            }
+            Connection::new().proxy(addr)
+        }
+        None => {
+            if let Some(onion) = relays.iter().find(|x| is_onion(x)) {
+                return Err(BuildClientError::OnionWithoutProxy(onion.clone()));
+            }
+            Connection::new()
+        }
+    };
+    let opts = Options::new()
+        .connection(connection)
+        .automatic_authentication(false);
 
     // Create new client with custom options.
     // Use `Client::new(signer)` to construct the client with a custom signer and default options
@@ -15,32 +262,304 @@ pub async fn build_client(keys: impl Into<NostrSigner>) -> Client {
     let client = Client::with_opts(keys, opts);
 
     // Add relays
-    // TODO: configure file to select relays
-    client
-        .add_relay("wss://relay.damus.io")
-        .await
-        .expect("Relay parse error");
-    client
-        .add_relay("wss://relay.primal.net")
-        .await
-        .expect("Relay parse error");
-    client
-        .add_relay("wss://nos.lol")
-        .await
-        .expect("Relay parse error");
-    client
-        .add_relay("wss://strfry.iris.to")
-        .await
-        .expect("Relay parse error");
-    // client.add_relay("wss://purplepag.es").await?;
-    // client
-    //     .add_relay("wss://lnbits.aruku.kro.kr/nostrrelay/private")
-    //     .await?;
+    let mut added = 0;
+    for relay in relays {
+        match client.add_relay(relay.clone()).await {
+            Ok(_) => added += 1,
+            Err(err) => eprintln!("Relay {relay} not added: {err}"),
+        }
+    }
+    if added == 0 {
+        return Err(BuildClientError::NoRelayAdded);
+    }
+    tokio::spawn(answer_auth_challenges(client.clone(), auth_relays.to_vec()));
 
     // Connect to relays
-    client.connect().await;
+    client.connect_with_timeout(Duration::from_secs(10)).await;
+
+    let mut connected = 0;
+    for relay in client.relays().await.values() {
+        if relay.is_connected().await {
+            connected += 1;
+        }
+    }
+    eprintln!("Connected to {connected}/{added} relays");
+    if !auth_relays.is_empty() {
+        eprintln!(
+            "Authentication enabled for {}",
+            auth_relays.iter().map(|x| x.to_string()).join(", ")
+        );
+    }
+
+    Ok(client)
+}
+
+/// Health of a relay, from how it answered the queries sent through the client
+#[derive(Debug, Clone, Default)]
+pub struct RelayHealth {
+    pub consecutive_failures: u32,
+    pub answered: u32,
+    /// Queries the relay didn't answer in time
+    pub timeouts: u32,
+    /// Events the relay sent that were part of query results
+    pub events_received: u64,
+    pub average_response: Duration,
+    /// Set while the relay is disconnected for failing too much
+    pub disabled_since: Option<Instant>,
+    /// Last time the relay answered or was reconnected
+    pub last_active: Option<Instant>,
+    /// Times the relay was reconnected by the watchdog
+    pub reconnects: u32,
+}
+
+/// Tracks the health of every relay the queries in this module are sent to
+///
+/// Relays that fail `max_failures` queries in a row are disconnected, and reconnected after `retry_after`
+#[derive(Debug)]
+pub struct RelayHealthTracker {
+    relays: std::sync::Mutex<HashMap<Url, RelayHealth>>,
+    pub max_failures: u32,
+    pub retry_after: Duration,
+}
+
+static RELAY_HEALTH: OnceLock<RelayHealthTracker> = OnceLock::new();
+
+pub fn relay_health() -> &'static RelayHealthTracker {
+    RELAY_HEALTH.get_or_init(|| RelayHealthTracker {
+        relays: std::sync::Mutex::new(HashMap::new()),
+        max_failures: 3,
+        retry_after: Duration::from_secs(300),
+    })
+}
+
+impl RelayHealthTracker {
+    fn record_answer(&self, relay: &Url, elapsed: Duration) {
+        let mut relays = self.relays.lock().unwrap();
+        let health = relays.entry(relay.clone()).or_default();
+        health.average_response =
+            (health.average_response * health.answered + elapsed) / (health.answered + 1);
+        health.answered += 1;
+        health.consecutive_failures = 0;
+        health.last_active = Some(Instant::now());
+    }
 
-    client
+    pub(crate) fn record_reconnect(&self, relay: &Url) {
+        let mut relays = self.relays.lock().unwrap();
+        let health = relays.entry(relay.clone()).or_default();
+        health.reconnects += 1;
+        health.last_active = Some(Instant::now());
+    }
+
+    /// The relay health, if any query was sent to it
+    pub fn get(&self, relay: &Url) -> Option<RelayHealth> {
+        self.relays.lock().unwrap().get(relay).cloned()
+    }
+
+    fn record_failure(&self, relay: &Url) {
+        let mut relays = self.relays.lock().unwrap();
+        let health = relays.entry(relay.clone()).or_default();
+        health.consecutive_failures += 1;
+        health.timeouts += 1;
+    }
+
+    fn record_events(&self, relay: &Url, count: u64) {
+        let mut relays = self.relays.lock().unwrap();
+        relays.entry(relay.clone()).or_default().events_received += count;
+    }
+
+    /// Health of every relay seen, sorted by URL
+    pub fn report(&self) -> Vec<(Url, RelayHealth)> {
+        let relays = self.relays.lock().unwrap();
+        relays
+            .iter()
+            .map(|(url, health)| (url.clone(), health.clone()))
+            .sorted_by(|x, y| x.0.cmp(&y.0))
+            .collect_vec()
+    }
+
+    /// Print the health of the relays. If `only_unhealthy`, relays without failures are left out
+    pub fn log(&self, only_unhealthy: bool) {
+        for (url, health) in self.report() {
+            if only_unhealthy && health.consecutive_failures == 0 {
+                continue;
+            }
+            eprintln!(
+                "Relay {url}: {} answered, average {:.1}s, {} failures in a row{}",
+                health.answered,
+                health.average_response.as_secs_f64(),
+                health.consecutive_failures,
+                if health.disabled_since.is_some() {
+                    ", disconnected"
+                } else {
+                    ""
+                }
+            );
+        }
+    }
+}
+
+impl RelayHealthTracker {
+    /// Table of what each relay contributed, most events first
+    pub fn stats_table(&self) -> String {
+        let report = self
+            .report()
+            .into_iter()
+            .sorted_by(|x, y| y.1.events_received.cmp(&x.1.events_received))
+            .collect_vec();
+        let width = report
+            .iter()
+            .map(|(url, _)| url.as_str().len())
+            .max()
+            .unwrap_or(0)
+            .max("relay".len());
+        let mut table = format!(
+            "{:width$}  {:>8}  {:>8}  {:>8}  {:>8}  {:>10}\n",
+            "relay", "events", "answered", "timeouts", "avg", "reconnects"
+        );
+        for (url, health) in report {
+            table += &format!(
+                "{:width$}  {:>8}  {:>8}  {:>8}  {:>7.1}s  {:>10}\n",
+                url.as_str(),
+                health.events_received,
+                health.answered,
+                health.timeouts,
+                health.average_response.as_secs_f64(),
+                health.reconnects
+            );
+        }
+        let malformed = malformed_p_tags();
+        if malformed > 0 {
+            table += &format!("{malformed} malformed p tags skipped\n");
+        }
+        table
+    }
+}
+
+/// Disconnect relays that failed too many queries in a row, and reconnect the ones whose retry time came
+pub async fn maintain_relays(client: &Client) {
+    let tracker = relay_health();
+    let (to_disconnect, to_retry) = {
+        let mut relays = tracker.relays.lock().unwrap();
+        let mut to_disconnect = vec![];
+        let mut to_retry = vec![];
+        for (url, health) in relays.iter_mut() {
+            match health.disabled_since {
+                None if health.consecutive_failures >= tracker.max_failures => {
+                    health.disabled_since = Some(Instant::now());
+                    to_disconnect.push(url.clone());
+                }
+                Some(since) if since.elapsed() >= tracker.retry_after => {
+                    health.disabled_since = None;
+                    // A single failure disconnects it again
+                    health.consecutive_failures = tracker.max_failures.saturating_sub(1);
+                    to_retry.push(url.clone());
+                }
+                _ => (),
+            }
+        }
+        (to_disconnect, to_retry)
+    };
+    for url in to_disconnect {
+        eprintln!("Disconnecting relay {url}: too many failures");
+        if let Err(err) = client.disconnect_relay(url.clone()).await {
+            eprintln!("Relay {url} disconnect error: {err}");
+        }
+    }
+    for url in to_retry {
+        eprintln!("Reconnecting relay {url}");
+        if let Err(err) = client.connect_relay(url.clone()).await {
+            eprintln!("Relay {url} reconnect error: {err}");
+        }
+    }
+}
+
+/// Query the relays like Client::get_events_of, recording in the health tracker which relays answered
+///
+/// A relay answered if it sent the end of stored events before the query finished
+async fn get_events(
+    client: &Client,
+    filters: Vec<Filter>,
+    timeout: Option<Duration>,
+) -> Result<Vec<Event>, Error> {
+    maintain_relays(client).await;
+
+    let mut queried = vec![];
+    for (url, relay) in client.relays().await {
+        if relay.is_connected().await {
+            queried.push(url);
+        }
+    }
+
+    let mut notifications = client.notifications();
+    let (done_sender, mut done) = tokio::sync::oneshot::channel::<()>();
+    let start = Instant::now();
+    let query = async {
+        let res = client
+            .get_events_of(filters, EventSource::relays(timeout))
+            .await;
+        _ = done_sender.send(());
+        res
+    };
+    let watch = async {
+        let mut answered: HashMap<Url, Duration> = HashMap::new();
+        let mut received: Vec<(Url, EventId)> = vec![];
+        let mut complete = true;
+        loop {
+            tokio::select! {
+                _ = &mut done => break,
+                notification = notifications.recv() => match notification {
+                    Ok(RelayPoolNotification::Message {
+                        relay_url,
+                        message: RelayMessage::EndOfStoredEvents(_),
+                    }) => {
+                        answered.entry(relay_url).or_insert(start.elapsed());
+                    }
+                    Ok(RelayPoolNotification::Message {
+                        relay_url,
+                        message: RelayMessage::Event { event, .. },
+                    }) => received.push((relay_url, event.id())),
+                    Ok(_) => (),
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => complete = false,
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                },
+            }
+        }
+        (answered, received, complete)
+    };
+    let (res, (answered, received, complete)) = tokio::join!(query, watch);
+
+    let tracker = relay_health();
+    // Events of other queries running at the same time are left out
+    if let Ok(events) = &res {
+        let ids: HashSet<EventId> = events.iter().map(|x| x.id()).collect();
+        let counts = received
+            .into_iter()
+            .filter(|(_, id)| ids.contains(id))
+            .counts_by(|(url, _)| url);
+        for (url, count) in counts {
+            tracker.record_events(&url, count as u64);
+        }
+    }
+    for url in queried {
+        match answered.get(&url) {
+            Some(elapsed) => tracker.record_answer(&url, *elapsed),
+            // Missed notifications can't tell a failure apart
+            None if complete => tracker.record_failure(&url),
+            None => (),
+        }
+    }
+
+    res
+}
+
+/// Whether a query failed only because the relays didn't answer in time. A query to a single
+/// relay fails this way, where one to many relays gives what arrived
+pub fn is_timeout(err: &nostr_sdk::client::Error) -> bool {
+    use nostr_sdk::pool::{pool, relay};
+    matches!(
+        err,
+        nostr_sdk::client::Error::RelayPool(pool::Error::Relay(relay::Error::Timeout))
+    )
 }
 
 pub async fn listen_mentions(
@@ -49,71 +568,354 @@ pub async fn listen_mentions(
     timeout: Option<Duration>,
 ) -> Result<impl Iterator<Item = Event>, Error> {
     let filter_mention = Filter::new().pubkey(pubkey).kind(Kind::TextNote);
-    let mention_mark = "nostr:".to_string() + &pubkey.to_bech32().unwrap();
-    let events = client
-        .get_events_of(vec![filter_mention], EventSource::relays(timeout))
-        .await?;
+    let events = get_events(client, vec![filter_mention], timeout).await?;
 
-    // Filter events that mention the pubkey directly
+    // Filter events that mention the pubkey directly, in any of the forms of
+    // find_pubkeys_in_message
     let events = events
         .into_iter()
-        .filter(move |event| event.content.contains(&mention_mark));
+        .filter(move |event| find_pubkeys_in_message(&event.content).contains(&pubkey));
 
     Ok(events)
 }
 
 use regex::Regex;
+/// Users referenced in `content`, in the order they first appear and without repeating them
+///
+/// Clients write them as nostr:npub or nostr:nprofile URIs, and people paste bare npubs,
+/// nprofiles and hex public keys, so all of them count. Hex is only read after nostr: or as a word
+/// of its own, since 64 hex characters inside a link or other text are more likely an event id
 pub fn find_pubkeys_in_message(content: &str) -> Vec<PublicKey> {
-    let pubkey_regex: Regex = Regex::new(r"nostr:npub[a-zA-Z0-9]*").unwrap();
+    find_pubkeys_in_message_with_offsets(content)
+        .into_iter()
+        .map(|(_, user)| user)
+        .collect()
+}
+
+/// Like find_pubkeys_in_message, with the offset in `content` where each user first appears
+pub fn find_pubkeys_in_message_with_offsets(content: &str) -> Vec<(usize, PublicKey)> {
+    static PUBKEY_REGEX: OnceLock<Regex> = OnceLock::new();
+    let pubkey_regex = PUBKEY_REGEX.get_or_init(|| {
+        Regex::new(r"\b(?:nostr:)?(?:npub|nprofile)1[a-zA-Z0-9]+|\b(?:nostr:)?[0-9a-fA-F]{64}\b")
+            .unwrap()
+    });
+    let standalone = |start: usize, end: usize| {
+        let before = content[..start].chars().next_back();
+        let after = content[end..].chars().next();
+        before.is_none_or(char::is_whitespace)
+            && after.is_none_or(|c| c.is_whitespace() || ".,!?;:)".contains(c))
+    };
     pubkey_regex
         .find_iter(content)
-        .filter_map(|x| PublicKey::from_nostr_uri(x.as_str()).ok())
+        .filter_map(|x| {
+            let (key, uri) = match x.as_str().strip_prefix("nostr:") {
+                Some(key) => (key, true),
+                None => (x.as_str(), false),
+            };
+            let user = if key.len() == 64 && key.chars().all(|c| c.is_ascii_hexdigit()) {
+                if !uri && !standalone(x.start(), x.end()) {
+                    return None;
+                }
+                PublicKey::from_hex(key).ok()
+            } else {
+                match Nip19::from_bech32(key) {
+                    Ok(Nip19::Pubkey(public_key)) => Some(public_key),
+                    Ok(Nip19::Profile(profile)) => Some(profile.public_key),
+                    _ => None,
+                }
+            };
+            user.map(|user| (x.start(), user))
+        })
+        .unique_by(|(_, user)| *user)
         .collect_vec()
 }
 
-/// Get the old event tags and build the tags of reply
-pub fn map_event_tags_to_reply(event: &Event) -> Vec<Tag> {
-    if event
+/// Which part of a mention is read first for the users to search
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TargetPrecedence {
+    /// Users referenced in the content, then p tags when the content has too few
+    #[default]
+    ContentFirst,
+    /// p tags, then the content when the tags don't have exactly the users needed
+    TagsFirst,
+}
+
+/// Users a mention asks about, and whether they were read from its p tags or found by name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MentionTargets {
+    pub targets: Vec<PublicKey>,
+    pub from_tags: bool,
+    pub from_names: bool,
+}
+
+/// Find the `count` users that a mention to `bot` asks about
+///
+/// In the content, every user referenced other than the bot counts, see
+/// find_pubkeys_in_message. In the p tags, the bot and the author are left out, since tags also
+/// list the participants of the thread. Order is kept either way. When neither has exactly `count`
+/// users, the content ones are given
+pub fn find_targets_in_mention(
+    event: &Event,
+    bot: &PublicKey,
+    count: usize,
+    precedence: TargetPrecedence,
+) -> MentionTargets {
+    let from_content = find_pubkeys_in_message(&event.content)
+        .into_iter()
+        .filter(|x| x != bot)
+        .collect_vec();
+    let from_tags = event
         .tags
         .iter()
-        .all(|tag| !(tag.is_root() || tag.is_reply()))
-    {
-        // Event doesn't reference other events
-        let event_ref = Tag::custom(
-            TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::E)),
-            [&event.id.to_hex(), "", "root"],
-        );
-        let author_ref = Tag::public_key(event.pubkey);
-        let mut to_return = vec![event_ref, author_ref];
-        return to_return;
+        .filter_map(|tag| match tag.as_standardized() {
+            Some(TagStandard::PublicKey { public_key, .. }) => Some(*public_key),
+            _ => None,
+        })
+        .filter(|x| x != bot && *x != event.author())
+        .unique()
+        .collect_vec();
+
+    let use_tags = match precedence {
+        TargetPrecedence::ContentFirst => from_content.len() < count && from_tags.len() == count,
+        TargetPrecedence::TagsFirst => from_tags.len() == count,
+    };
+    if use_tags {
+        MentionTargets {
+            targets: from_tags,
+            from_tags: true,
+            from_names: false,
+        }
     } else {
-        let event_ref = Tag::custom(
-            TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::E)),
-            [&event.id.to_hex(), "", "reply"],
+        MentionTargets {
+            targets: from_content,
+            from_tags: false,
+            from_names: false,
+        }
+    }
+}
+
+/// Words of a mention other than its nostr: references, without the punctuation around them but
+/// keeping a leading # or @
+pub fn mention_words(text: &str) -> impl Iterator<Item = &str> {
+    let keep = |c: char| c.is_alphanumeric() || c == '_';
+    text.split_whitespace()
+        .filter(|x| !x.starts_with("nostr:"))
+        .map(move |x| {
+            x.trim_end_matches(|c| !keep(c))
+                .trim_start_matches(|c| !keep(c) && c != '#' && c != '@')
+        })
+        .filter(|x| !x.is_empty())
+}
+
+/// Whether a mention asks who a user unfollowed, with the word unfollows or #unfollows
+pub fn is_unfollows_request(content: &str) -> bool {
+    mention_words(content).any(|x| x.trim_start_matches('#').eq_ignore_ascii_case("unfollows"))
+}
+
+/// Whether a mention asks for the connectivity score of a user, with the word score or #score
+pub fn is_score_request(content: &str) -> bool {
+    mention_words(content).any(|x| x.trim_start_matches('#').eq_ignore_ascii_case("score"))
+}
+
+/// Whether a mention asks for users to follow, with the word recommend or #recommend
+pub fn is_recommend_request(content: &str) -> bool {
+    mention_words(content).any(|x| x.trim_start_matches('#').eq_ignore_ascii_case("recommend"))
+}
+
+/// Whether a mention asks how far along its recommendation is, with the word status or #status
+pub fn is_status_request(content: &str) -> bool {
+    mention_words(content).any(|x| x.trim_start_matches('#').eq_ignore_ascii_case("status"))
+}
+
+/// Whether the tag is an e tag with the marker, see NIP-10
+fn is_marked_e_tag(tag: &[String], marker: &str) -> bool {
+    matches!(tag, [e, _, _, m, ..] if e == "e" && m == marker)
+}
+
+/// 64 hex characters, as an event id
+fn is_event_id_hex(id: &str) -> bool {
+    id.len() == 64 && id.chars().all(|x| x.is_ascii_hexdigit())
+}
+
+/// Get the old event tags and build the tags of reply
+///
+/// Root and reply e tags without a well formed event id are dropped. When what's left doesn't
+/// make sense, like more than one root or a reply without a root, the mention is taken as the root
+pub fn map_event_tags_to_reply(event: &Event) -> Vec<Tag> {
+    let marked = |marker: &str| {
+        event
+            .tags
+            .iter()
+            .filter(|tag| is_marked_e_tag(tag.as_vec(), marker))
+            .collect_vec()
+    };
+    let (roots, replies) = (marked("root"), marked("reply"));
+    let valid = |tags: &[&Tag]| {
+        tags.iter()
+            .filter(|tag| is_event_id_hex(&tag.as_vec()[1]))
+            .unique_by(|tag| tag.as_vec()[1].to_lowercase())
+            .map(|tag| (*tag).clone())
+            .collect_vec()
+    };
+    let (valid_roots, valid_replies) = (valid(&roots), valid(&replies));
+
+    let mut changes = vec![];
+    let malformed = roots.len() + replies.len() - valid_roots.len() - valid_replies.len();
+    if malformed > 0 {
+        changes.push(format!("dropped {malformed} malformed or repeated e tags"));
+    }
+    let root = match (&valid_roots[..], valid_replies.len()) {
+        ([], 0) => None,
+        ([root], _) => Some(root.clone()),
+        ([], _) => {
+            changes.push("reply without a root".to_string());
+            None
+        }
+        (_, _) => {
+            changes.push(format!("{} roots", valid_roots.len()));
+            None
+        }
+    };
+    if !changes.is_empty() {
+        eprintln!(
+            "Reply tags of {} sanitized: {}",
+            event.id.to_bech32().unwrap(),
+            changes.join(", ")
         );
-        let author_ref = Tag::public_key(event.pubkey);
-        let mut to_return = vec![event_ref, author_ref];
-        to_return.extend(event.tags.clone().into_iter().filter_map(|x| {
-            if x.is_root()
-                || x.kind() == TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::P))
-            {
-                Some(x.clone())
-            } else {
-                None
-            }
-        }));
-        return to_return;
+    }
+
+    let author_ref = Tag::public_key(event.pubkey);
+    match root {
+        None => {
+            // Event doesn't reference other events
+            let event_ref = Tag::custom(
+                TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::E)),
+                [&event.id.to_hex(), "", "root"],
+            );
+            vec![event_ref, author_ref]
+        }
+        Some(root) => {
+            let event_ref = Tag::custom(
+                TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::E)),
+                [&event.id.to_hex(), "", "reply"],
+            );
+            let mut to_return = vec![event_ref, author_ref, root];
+            to_return.extend(
+                event
+                    .tags
+                    .iter()
+                    .filter(|x| {
+                        x.kind() == TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::P))
+                    })
+                    .cloned(),
+            );
+            to_return
+        }
     }
 }
 
-pub async fn reply_to_text(
-    client: &Client,
-    event: &Event,
-    content: &str,
-) -> Result<Output<EventId>, Error> {
-    client
-        .publish_text_note(content, map_event_tags_to_reply(&event))
-        .await
+/// Name in the client tag of published events
+pub const CLIENT_TAG_NAME: &str = "six-degrees-bot";
+
+/// Builds every event the bot publishes, so all of them get the same optional tags
+///
+/// Events not meant to be read as notes get a NIP-31 alt tag describing them
+#[derive(Debug, Clone, Default)]
+pub struct EventFactory {
+    /// Events expire (NIP-40) this long after being built
+    pub expiration: Option<Duration>,
+    /// Tag events with the bot as their client
+    pub client_tag: bool,
+    /// Hashtags added to notes and replies
+    pub hashtags: Vec<String>,
+    /// Proof of work mined for relays requiring it, see sign
+    pub pow: PowLimits,
+}
+
+impl EventFactory {
+    fn common_tags(&self) -> Vec<Tag> {
+        let mut tags = vec![];
+        if self.client_tag {
+            tags.push(Tag::custom(TagKind::from("client"), [CLIENT_TAG_NAME]));
+        }
+        if let Some(expiration) = self.expiration {
+            tags.push(Tag::expiration(Timestamp::now() + expiration));
+        }
+        tags
+    }
+
+    fn hashtags(&self) -> impl Iterator<Item = Tag> + '_ {
+        self.hashtags.iter().map(Tag::hashtag)
+    }
+
+    /// Text note replying to `event`, see map_event_tags_to_reply
+    pub fn reply(&self, event: &Event, content: &str) -> EventBuilder {
+        EventBuilder::text_note(content, map_event_tags_to_reply(event))
+            .add_tags(self.hashtags())
+            .add_tags(self.common_tags())
+    }
+
+    /// Text note that doesn't reply to anything
+    pub fn note(&self, content: &str) -> EventBuilder {
+        EventBuilder::text_note(content, self.hashtags()).add_tags(self.common_tags())
+    }
+
+    /// Sign `builder` with the signer of `client`, mining `required` bits of proof of work first.
+    /// When that's over the limits of `pow` it's signed without, and relays requiring it refuse it
+    pub async fn sign(
+        &self,
+        client: &Client,
+        builder: EventBuilder,
+        required: u8,
+    ) -> Result<Event, nostr_sdk::client::Error> {
+        let signer = client.signer().await?;
+        let unsigned = builder.to_unsigned_event(signer.public_key().await?);
+        let unsigned = if required == 0 {
+            unsigned
+        } else if required > self.pow.max_difficulty {
+            eprintln!(
+                "Not mining {required} bits of proof of work, over the most of {}",
+                self.pow.max_difficulty
+            );
+            unsigned
+        } else {
+            let start = Instant::now();
+            let budget = self.pow.budget;
+            let mining = unsigned.clone();
+            match tokio::task::spawn_blocking(move || pow::mine(mining, required, budget)).await {
+                Ok(Some(mined)) => {
+                    eprintln!(
+                        "Mined {required} bits of proof of work in {:.1}s",
+                        start.elapsed().as_secs_f64()
+                    );
+                    mined
+                }
+                Ok(None) => {
+                    eprintln!("Gave up mining {required} bits of proof of work after {budget:?}");
+                    unsigned
+                }
+                Err(err) => {
+                    eprintln!("Proof of work error: {err}");
+                    unsigned
+                }
+            }
+        };
+        Ok(signer.sign_event(unsigned).await?)
+    }
+
+    /// Replaceable event of `kind`. The tags should have its identifier when it's parameterized
+    pub fn replaceable(
+        &self,
+        kind: Kind,
+        content: &str,
+        tags: Vec<Tag>,
+        alt: &str,
+    ) -> EventBuilder {
+        EventBuilder::new(kind, content, tags)
+            .add_tags([Tag::alt(alt)])
+            .add_tags(self.common_tags())
+    }
 }
 
 pub async fn send_text(my_keys: &Keys, client: &Client, content: &str) -> Result<(), Error> {
@@ -140,14 +942,366 @@ pub async fn get_following_multiple_users_with_timestamp_and_timeout(
     timeout: Option<Duration>,
 ) -> Result<HashMap<PublicKey, (Vec<PublicKey>, Timestamp)>, Error> {
     let filter_following = Filter::new().authors(users).kind(Kind::ContactList);
-    let events = client
-        .get_events_of(vec![filter_following], EventSource::relays(timeout))
-        .await?;
+    let events = get_events(client, vec![filter_following], timeout).await?;
+
+    Ok(contact_lists_from_events(events))
+}
+
+/// Like get_following_multiple_users_with_timestamp_and_timeout, with the relay hint and petname
+/// of each follow
+pub async fn get_contact_list_entries_multiple_users_with_timeout(
+    users: impl IntoIterator<Item = PublicKey>,
+    client: &Client,
+    timeout: Option<Duration>,
+) -> Result<HashMap<PublicKey, (Vec<(PublicKey, PetnameInfo)>, Timestamp)>, Error> {
+    let filter_following = Filter::new().authors(users).kind(Kind::ContactList);
+    let events = get_events(client, vec![filter_following], timeout).await?;
+
+    Ok(contact_list_entries_from_events(events))
+}
+
+/// Public entries of the newest mute list (kind 10000) of each user
+pub async fn get_mute_lists_multiple_users_with_timeout(
+    users: impl IntoIterator<Item = PublicKey>,
+    client: &Client,
+    timeout: Option<Duration>,
+) -> Result<HashMap<PublicKey, (Vec<PublicKey>, Timestamp)>, Error> {
+    let filter_muted = Filter::new().authors(users).kind(Kind::MuteList);
+    let events = get_events(client, vec![filter_muted], timeout).await?;
+
+    // Public mutes are p tags, like follows
+    Ok(contact_lists_from_events(events))
+}
+
+/// How many times each of the users replied to or reacted to others, by (author, other user)
+///
+/// Only text notes replying to another, with an e tag, count, not every mention. An event counts
+/// once for each user it p tags
+pub async fn get_interactions_multiple_users_with_timeout(
+    users: impl IntoIterator<Item = PublicKey>,
+    client: &Client,
+    kinds: &[Kind],
+    since: Option<Timestamp>,
+    timeout: Option<Duration>,
+) -> Result<HashMap<(PublicKey, PublicKey), u32>, Error> {
+    let mut filter = Filter::new().authors(users).kinds(kinds.iter().copied());
+    if let Some(since) = since {
+        filter = filter.since(since);
+    }
+    let events = get_events(client, vec![filter], timeout).await?;
+
+    let mut interactions = HashMap::new();
+    for event in events {
+        let e = TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::E));
+        let is_reply = event.tags.iter().any(|tag| tag.kind() == e);
+        if event.kind == Kind::TextNote && !is_reply {
+            continue;
+        }
+        let users = event
+            .tags
+            .iter()
+            .filter_map(|tag| match tag.as_standardized() {
+                Some(TagStandard::PublicKey { public_key, .. }) => Some(*public_key),
+                _ => None,
+            })
+            .filter(|x| *x != event.pubkey)
+            .unique();
+        for user in users {
+            *interactions.entry((event.pubkey, user)).or_default() += 1;
+        }
+    }
+    Ok(interactions)
+}
+
+/// A zap from a zap receipt (kind 9735), see NIP-57
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Zap {
+    pub sender: PublicKey,
+    pub recipient: PublicKey,
+    pub amount_msat: u64,
+}
+
+/// Zaps to the users, from the zap receipts made since `since`. Receipts that don't say who sent
+/// them or how much are left out
+pub async fn get_zaps_to_users_with_timeout(
+    users: impl IntoIterator<Item = PublicKey>,
+    client: &Client,
+    since: Option<Timestamp>,
+    timeout: Option<Duration>,
+) -> Result<Vec<Zap>, Error> {
+    let mut filter = Filter::new().kind(Kind::ZapReceipt).pubkeys(users);
+    if let Some(since) = since {
+        filter = filter.since(since);
+    }
+    let events = get_events(client, vec![filter], timeout).await?;
+
+    Ok(events.iter().filter_map(zap_from_receipt).collect())
+}
+
+/// The zap of a receipt. The sender is the author of the zap request in the description tag, and
+/// the amount is the one of the bolt11 invoice, or else the one the request asked for
+pub fn zap_from_receipt(receipt: &Event) -> Option<Zap> {
+    let tag_value = |name: &str| {
+        receipt
+            .tags
+            .iter()
+            .map(|x| x.as_vec())
+            .find(|x| x.len() >= 2 && x[0] == name)
+            .map(|x| x[1].clone())
+    };
+    let recipient = PublicKey::from_hex(tag_value("p")?).ok()?;
+    let request = Event::from_json(tag_value("description")?).ok()?;
+    let requested = request
+        .tags
+        .iter()
+        .map(|x| x.as_vec())
+        .find(|x| x.len() >= 2 && x[0] == "amount")
+        .and_then(|x| x[1].parse::<u64>().ok());
+    let amount_msat = tag_value("bolt11")
+        .and_then(|x| bolt11_amount_msat(&x))
+        .or(requested)?;
+    Some(Zap {
+        sender: request.pubkey,
+        recipient,
+        amount_msat,
+    })
+}
+
+/// Amount of a bolt11 invoice in millisatoshis, from its human readable part. None when the
+/// invoice doesn't set one
+///
+/// The part is "ln", the currency, then the amount in bitcoin with an optional multiplier: m
+/// (milli), u (micro), n (nano) or p (pico). It ends at the last 1, the separator of bech32
+pub fn bolt11_amount_msat(invoice: &str) -> Option<u64> {
+    let invoice = invoice.trim().to_lowercase();
+    let invoice = invoice.strip_prefix("lightning:").unwrap_or(&invoice);
+    let hrp = invoice.get(..invoice.rfind('1')?)?.strip_prefix("ln")?;
+    let amount = hrp.trim_start_matches(|c: char| c.is_ascii_alphabetic());
+    let (digits, multiplier) = match amount.chars().last()? {
+        c if c.is_ascii_digit() => (amount, None),
+        c => (&amount[..amount.len() - 1], Some(c)),
+    };
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let digits = digits.parse::<u64>().ok()?;
+    // 1 bitcoin is 10^11 millisatoshis
+    match multiplier {
+        None => digits.checked_mul(100_000_000_000),
+        Some('m') => digits.checked_mul(100_000_000),
+        Some('u') => digits.checked_mul(100_000),
+        Some('n') => digits.checked_mul(100),
+        // Pico amounts below a millisatoshi are invalid
+        Some('p') if digits % 10 == 0 => Some(digits / 10),
+        Some(_) => None,
+    }
+}
+
+/// Most contact lists asked for in each page of get_followers_of_user_batched
+pub const FOLLOWERS_PAGE_SIZE: usize = 500;
+
+/// Contact lists of the users that follow `pubkey`
+///
+/// Popular users have more followers than relays give back at once, so they're asked in pages
+/// going back in time, at most `max_pages` of them
+pub async fn get_followers_of_user_batched(
+    pubkey: PublicKey,
+    client: &Client,
+    timeout: Option<Duration>,
+    max_pages: usize,
+) -> Result<HashMap<PublicKey, (Vec<PublicKey>, Timestamp)>, Error> {
+    let mut events = vec![];
+    let mut until: Option<Timestamp> = None;
+    for page in 1..=max_pages {
+        let mut filter = Filter::new()
+            .kind(Kind::ContactList)
+            .pubkey(pubkey)
+            .limit(FOLLOWERS_PAGE_SIZE);
+        if let Some(until) = until {
+            filter = filter.until(until);
+        }
+        let page_events = get_events(client, vec![filter], timeout).await?;
+        let oldest = page_events.iter().map(|x| x.created_at()).min();
+        let full = page_events.len() >= FOLLOWERS_PAGE_SIZE;
+        eprintln!("followers page {page}: {} contact lists", page_events.len());
+        events.extend(page_events);
+        until = match oldest {
+            // until is inclusive, so without older events the same page would come again
+            Some(oldest) if full && until.is_some_and(|x| oldest >= x) => {
+                Some(oldest - Duration::from_secs(1))
+            }
+            Some(oldest) if full => Some(oldest),
+            _ => break,
+        };
+    }
+    let mut lists = contact_lists_from_events(events);
+    // Newer contact lists of the same users may not follow anymore
+    lists.retain(|_, (follows, _)| follows.contains(&pubkey));
+    Ok(lists)
+}
+
+/// Most relays added at once to look for contact lists in the users' own relays
+pub const MAX_DISCOVERED_RELAYS: usize = 20;
+
+/// Like get_following_multiple_users_with_timestamp_and_timeout, but users whose contact list wasn't found
+/// are looked up again in the write relays of their NIP-65 relay list
+///
+/// Relays that the client didn't have are added only for this lookup and removed afterwards.
+/// Also returns every relay that was asked for the contact lists
+pub async fn get_following_multiple_users_with_relay_discovery(
+    users: impl IntoIterator<Item = PublicKey>,
+    client: &Client,
+    timeout: Option<Duration>,
+) -> Result<(HashMap<PublicKey, (Vec<PublicKey>, Timestamp)>, Vec<Url>), Error> {
+    let users = users.into_iter().collect_vec();
+    let mut checked_relays = client.relays().await.into_keys().collect_vec();
+    let mut map =
+        get_following_multiple_users_with_timestamp_and_timeout(users.clone(), client, timeout)
+            .await?;
+    let missing = users
+        .into_iter()
+        .filter(|x| !map.contains_key(x))
+        .unique()
+        .collect_vec();
+    if missing.is_empty() {
+        return Ok((map, checked_relays));
+    }
+
+    let write_relays: HashSet<Url> =
+        get_write_relays_multiple_users(missing.clone(), client, timeout)
+            .await?
+            .into_values()
+            .flatten()
+            .collect();
+    if write_relays.is_empty() {
+        return Ok((map, checked_relays));
+    }
+
+    let known_relays = client.relays().await;
+    let mut added_relays = vec![];
+    for relay in write_relays
+        .iter()
+        .filter(|x| !known_relays.contains_key(x))
+        .take(MAX_DISCOVERED_RELAYS)
+    {
+        match client.add_relay(relay.clone()).await {
+            Ok(_) => {
+                if let Ok(r) = client.relay(relay.clone()).await {
+                    r.connect(Some(Duration::from_secs(5))).await;
+                }
+                added_relays.push(relay.clone());
+            }
+            Err(err) => eprintln!("Discovered relay {relay} not added: {err}"),
+        }
+    }
+    let relays = write_relays
+        .into_iter()
+        .filter(|x| known_relays.contains_key(x) || added_relays.contains(x))
+        .collect_vec();
+
+    eprintln!(
+        "Looking for {} missing contact lists in {} discovered relays",
+        missing.len(),
+        relays.len()
+    );
+    let filter_following = Filter::new().authors(missing).kind(Kind::ContactList);
+    let res = client
+        .get_events_from(relays.clone(), vec![filter_following], timeout)
+        .await;
+
+    for relay in added_relays {
+        if let Err(err) = client.remove_relay(relay.clone()).await {
+            eprintln!("Discovered relay {relay} not removed: {err}");
+        }
+    }
+
+    checked_relays.extend(relays.into_iter().filter(|x| !known_relays.contains_key(x)));
+    map.extend(contact_lists_from_events(res?));
+    Ok((map, checked_relays))
+}
+
+/// Write relays of each user from their NIP-65 relay list
+///
+/// Relays without a read or write marker count as write relays
+pub async fn get_write_relays_multiple_users(
+    users: impl IntoIterator<Item = PublicKey>,
+    client: &Client,
+    timeout: Option<Duration>,
+) -> Result<HashMap<PublicKey, Vec<Url>>, Error> {
+    let filter_relays = Filter::new().authors(users).kind(Kind::RelayList);
+    let events = get_events(client, vec![filter_relays], timeout).await?;
+
+    let mut newest: HashMap<PublicKey, Event> = HashMap::new();
+    for event in events {
+        match newest.get(&event.author()) {
+            Some(s) if s.created_at() >= event.created_at() => (),
+            _ => _ = newest.insert(event.author(), event),
+        }
+    }
+
+    Ok(newest
+        .into_iter()
+        .map(|(pubkey, event)| {
+            let relays = nip65::extract_relay_list(&event)
+                .filter(|(_, metadata)| metadata != &&Some(RelayMetadata::Read))
+                .map(|(url, _)| url.clone())
+                .collect_vec();
+            (pubkey, relays)
+        })
+        .collect())
+}
+
+/// Newest contact list of each author among the events
+/// Relays that returned the newest contact list of each user
+///
+/// Asks every connected relay, and relies on the client database tracking where events were seen
+pub async fn get_contact_list_sources(
+    users: impl IntoIterator<Item = PublicKey>,
+    client: &Client,
+    timeout: Option<Duration>,
+) -> Result<HashMap<PublicKey, HashSet<Url>>, Error> {
+    let filter_following = Filter::new().authors(users).kind(Kind::ContactList);
+    let events = get_events(client, vec![filter_following], timeout).await?;
 
+    let mut newest: HashMap<PublicKey, Event> = HashMap::new();
+    for event in events {
+        match newest.get(&event.author()) {
+            Some(s) if s.created_at() >= event.created_at() => (),
+            _ => _ = newest.insert(event.author(), event),
+        }
+    }
+    let mut sources = HashMap::with_capacity(newest.len());
+    for (user, event) in newest {
+        let relays = client
+            .database()
+            .event_seen_on_relays(event.id())
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        sources.insert(user, relays);
+    }
+    Ok(sources)
+}
+
+fn contact_lists_from_events(
+    events: Vec<Event>,
+) -> HashMap<PublicKey, (Vec<PublicKey>, Timestamp)> {
+    contact_list_entries_from_events(events)
+        .into_iter()
+        .map(|(user, (entries, time))| {
+            (user, (entries.into_iter().map(|(x, _)| x).collect(), time))
+        })
+        .collect()
+}
+
+fn contact_list_entries_from_events(
+    events: Vec<Event>,
+) -> HashMap<PublicKey, (Vec<(PublicKey, PetnameInfo)>, Timestamp)> {
     let mut map = HashMap::new();
 
     if events.len() == 0 {
-        return Ok(map);
+        return map;
     }
 
     // Get all events associated with an user
@@ -176,24 +1330,63 @@ pub async fn get_following_multiple_users_with_timestamp_and_timeout(
     });
 
     // Map event3 into list of pubkeys
+    let mut malformed = 0;
     for (pubkey, event3) in newest_events {
         let created_at = event3.created_at();
 
-        let tags_3 = event3.tags();
-        let mut pubkeys = vec![];
-        for tag in tags_3 {
-            match tag.as_vec() {
-                [p, pubkey] if p == "p" => match PublicKey::parse(pubkey) {
-                    Ok(ok) => pubkeys.push(ok),
-                    Err(err) => eprintln!("Public key {pubkey} parse error: {err}"),
-                },
-                _ => (),
-            }
-        }
-        map.insert(*pubkey, (pubkeys, created_at));
+        let (entries, malformed_event) = contact_list_entries(event3);
+        malformed += malformed_event;
+        map.insert(*pubkey, (entries, created_at));
     }
+    record_malformed_p_tags(malformed);
+
+    map
+}
+
+static MALFORMED_P_TAGS: AtomicU64 = AtomicU64::new(0);
 
-    Ok(map)
+/// p tags skipped in all the contact lists fetched, for not having a valid public key
+pub fn malformed_p_tags() -> u64 {
+    MALFORMED_P_TAGS.load(Ordering::Relaxed)
+}
+
+/// Log the p tags skipped in a fetch in a single line
+fn record_malformed_p_tags(count: usize) {
+    if count > 0 {
+        eprintln!("Skipped {count} malformed p tags");
+        MALFORMED_P_TAGS.fetch_add(count as u64, Ordering::Relaxed);
+    }
+}
+
+/// Users followed in a contact list event, and how many p tags didn't have a valid public key
+pub fn contact_list_pubkeys(event3: &Event) -> (Vec<PublicKey>, usize) {
+    let (entries, malformed) = contact_list_entries(event3);
+    (entries.into_iter().map(|(x, _)| x).collect(), malformed)
+}
+
+/// Users followed in a contact list event with the relay hint and petname of their p tag, as in
+/// `["p", <pubkey>, <relay>, <petname>]`, and how many p tags didn't have a valid public key
+pub fn contact_list_entries(event3: &Event) -> (Vec<(PublicKey, PetnameInfo)>, usize) {
+    let mut entries = vec![];
+    let mut malformed = 0;
+    // Clients leave the relay empty when there's only a petname
+    let non_empty = |x: Option<&String>| x.filter(|x| !x.trim().is_empty()).cloned();
+    for tag in event3.tags() {
+        match tag.as_vec() {
+            [p, pubkey, rest @ ..] if p == "p" => match PublicKey::parse(pubkey) {
+                Ok(ok) => entries.push((
+                    ok,
+                    PetnameInfo {
+                        relay_hint: non_empty(rest.first()),
+                        petname: non_empty(rest.get(1)),
+                    },
+                )),
+                Err(_err) => malformed += 1,
+            },
+            _ => (),
+        }
+    }
+    (entries, malformed)
 }
 
 pub async fn get_following_user_with_timestamp_and_timeout(
@@ -202,9 +1395,7 @@ pub async fn get_following_user_with_timestamp_and_timeout(
     timeout: Option<Duration>,
 ) -> Result<Option<(Vec<PublicKey>, Timestamp)>, Error> {
     let filter_following = Filter::new().author(pubkey).kind(Kind::ContactList);
-    let events = client
-        .get_events_of(vec![filter_following], EventSource::relays(timeout))
-        .await?;
+    let events = get_events(client, vec![filter_following], timeout).await?;
 
     if events.len() == 0 {
         return Ok(None);
@@ -223,17 +1414,8 @@ pub async fn get_following_user_with_timestamp_and_timeout(
 
     let created_at = event_3.created_at();
 
-    let tags_3 = event_3.tags();
-    let mut pubkeys = vec![];
-    for tag in tags_3 {
-        match tag.as_vec() {
-            [p, pubkey] if p == "p" => match PublicKey::parse(pubkey) {
-                Ok(ok) => pubkeys.push(ok),
-                Err(err) => (), //eprintln!("Public key {pubkey} parse error: {err}"),
-            },
-            _ => (),
-        }
-    }
+    let (pubkeys, malformed) = contact_list_pubkeys(event_3);
+    record_malformed_p_tags(malformed);
     Ok(Some((pubkeys, created_at)))
 }
 
@@ -256,39 +1438,6 @@ pub async fn get_following_user(
     get_following_user_with_timeout(pubkey, client, None).await
 }
 
-/// Not recommended
-#[deprecated]
-pub async fn get_followers_user(
-    pubkey: PublicKey,
-    client: &Client,
-) -> Result<Vec<PublicKey>, Error> {
-    let filter_followers = Filter::new().kind(Kind::ContactList).pubkey(pubkey);
-    let timeout = Some(Duration::from_secs(30));
-    let events = client
-        .get_events_of(vec![filter_followers], EventSource::relays(timeout))
-        .await?;
-
-    let users: Vec<PublicKey> = events.iter().map(|event| event.author()).unique().collect();
-    Ok(users)
-}
-
-#[deprecated]
-pub async fn get_mutuals_user(pubkey: PublicKey, client: &Client) -> Result<Vec<PublicKey>, Error> {
-    let following = get_following_user(pubkey, &client).await?.unwrap_or(vec![]);
-    #[allow(deprecated)]
-    let followers = get_followers_user(pubkey, &client).await?;
-
-    let set_following: HashSet<PublicKey> = following.into_iter().collect();
-    let set_followers: HashSet<PublicKey> = followers.into_iter().collect();
-
-    let mutuals: Vec<PublicKey> = set_followers
-        .intersection(&set_following)
-        .cloned()
-        .collect_vec();
-
-    Ok(mutuals)
-}
-
 pub async fn get_metadata_users(
     pubkeys: &[PublicKey],
     client: &Client,
@@ -296,24 +1445,34 @@ pub async fn get_metadata_users(
     get_metadata_users_with_timeout(pubkeys, client, None).await
 }
 
-pub async fn get_metadata_users_fake(
-    _pubkeys: &[PublicKey],
-    _client: &Client,
-) -> Result<HashMap<PublicKey, Option<(Metadata, Timestamp)>>, Error> {
-    Ok(HashMap::new())
-}
-
 pub async fn get_metadata_users_with_timeout(
     pubkeys: &[PublicKey],
     client: &Client,
     timeout: Option<Duration>,
 ) -> Result<HashMap<PublicKey, Option<(Metadata, Timestamp)>>, Error> {
     let user_metadata = Filter::new().authors(pubkeys.to_vec()).kind(Kind::Metadata);
-    let events = client
-        .get_events_of(vec![user_metadata], EventSource::relays(timeout))
-        .await?;
+    let events = get_events(client, vec![user_metadata], timeout).await?;
     // eprintln!("{:?}", events);
-    let mut map_pubkey_meta = HashMap::with_capacity(pubkeys.len());
+    let mut map_pubkey_meta: HashMap<_, _> = metadata_from_events(events)
+        .into_iter()
+        .map(|(pubkey, meta)| (pubkey, Some(meta)))
+        .collect();
+    for pubkey in pubkeys {
+        match map_pubkey_meta.get(pubkey) {
+            None => {
+                map_pubkey_meta.insert(*pubkey, None);
+                eprintln!("No metadata from pubkey {}", pubkey.to_bech32().unwrap());
+            }
+            Some(_m) => (), //eprintln!("Ye metadata from pubkey {}", pubkey.to_bech32().unwrap()),
+        };
+    }
+
+    Ok(map_pubkey_meta)
+}
+
+/// Newest metadata of each author of the events, skipping the ones that don't parse
+fn metadata_from_events(events: Vec<Event>) -> HashMap<PublicKey, (Metadata, Timestamp)> {
+    let mut map_pubkey_meta = HashMap::with_capacity(events.len());
     for event in events {
         let pubkey = event.pubkey;
         let created_at = event.created_at();
@@ -325,30 +1484,595 @@ pub async fn get_metadata_users_with_timeout(
             }
         };
         match map_pubkey_meta.get(&pubkey) {
-            Some(Some((m, t))) => {
-                // Considera eventos mais recentes prioritariamente
-                if t > &created_at {
-                    // eprintln!("Multiple metadata received from pubkey {pubkey}:\n{metadata:#?}\ncreated at: {created_at:#?}\n\n");
-                } else {
-                    // eprintln!("Multiple metadata received from pubkey {pubkey}:\n{m:#?}\ncreated at: {t:#?}\n\n");
-                    map_pubkey_meta.insert(pubkey, Some((metadata, created_at)));
-                }
-            }
-            Some(None) => unreachable!(),
-            None => _ = map_pubkey_meta.insert(pubkey, Some((metadata, created_at))),
+            // Considera eventos mais recentes prioritariamente
+            Some((_, t)) if t > &created_at => (),
+            _ => _ = map_pubkey_meta.insert(pubkey, (metadata, created_at)),
         };
     }
-    for pubkey in pubkeys {
-        match map_pubkey_meta.get(&pubkey) {
-            None => {
-                _ = {
-                    map_pubkey_meta.insert(*pubkey, None);
-                    eprintln!("No metadata from pubkey {}", pubkey.to_bech32().unwrap());
-                }
-            }
-            Some(_m) => (), //eprintln!("Ye metadata from pubkey {}", pubkey.to_bech32().unwrap()),
+    map_pubkey_meta
+}
+
+/// Contact lists and metadata of the users, asked in a single query
+pub async fn get_following_and_metadata_multiple_users_with_timeout(
+    users: impl IntoIterator<Item = PublicKey>,
+    client: &Client,
+    timeout: Option<Duration>,
+) -> Result<
+    (
+        HashMap<PublicKey, (Vec<PublicKey>, Timestamp)>,
+        HashMap<PublicKey, (Metadata, Timestamp)>,
+    ),
+    Error,
+> {
+    let filter = Filter::new()
+        .authors(users)
+        .kinds([Kind::ContactList, Kind::Metadata]);
+    let events = get_events(client, vec![filter], timeout).await?;
+
+    let (contact_lists, metadata): (Vec<_>, Vec<_>) = events
+        .into_iter()
+        .partition(|x| x.kind == Kind::ContactList);
+    Ok((
+        contact_lists_from_events(contact_lists),
+        metadata_from_events(metadata),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Network;
+    use crate::test_utils::*;
+
+    const TIMEOUT: Option<Duration> = Some(Duration::from_secs(2));
+
+    fn relay_list(author: &Keys, relay: &FakeRelay) -> Event {
+        EventBuilder::relay_list([(relay.url.clone(), Some(RelayMetadata::Write))])
+            .to_event(author)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn missing_contact_list_is_found_in_the_users_own_relays() {
+        let keys = keys(2);
+        let own = FakeRelay::start(contact_lists(&keys, &[&[1]])).await;
+        let known = FakeRelay::start(vec![relay_list(&keys[0], &own)]).await;
+        let client = client(&[&known]).await;
+
+        let (map, checked) = get_following_multiple_users_with_relay_discovery(
+            [keys[0].public_key()],
+            &client,
+            TIMEOUT,
+        )
+        .await
+        .unwrap();
+        assert_eq!(map[&keys[0].public_key()].0, vec![keys[1].public_key()]);
+        assert_eq!(checked, vec![known.url.clone(), own.url.clone()]);
+        // Only added for the lookup
+        assert!(!client.relays().await.contains_key(&own.url));
+    }
+
+    #[tokio::test]
+    async fn missing_contact_list_lists_every_relay_checked() {
+        let keys = keys(1);
+        let own = FakeRelay::start(vec![]).await;
+        let known = FakeRelay::start(vec![relay_list(&keys[0], &own)]).await;
+        let client = client(&[&known]).await;
+
+        let (map, checked) = get_following_multiple_users_with_relay_discovery(
+            [keys[0].public_key()],
+            &client,
+            TIMEOUT,
+        )
+        .await
+        .unwrap();
+        assert!(map.is_empty());
+        assert_eq!(checked, vec![known.url.clone(), own.url.clone()]);
+        assert_eq!(own.queries().len(), 1);
+    }
+
+    /// Mention by `author` with `content` and p tags of `tagged`
+    fn mention(author: &Keys, content: &str, tagged: &[PublicKey]) -> Event {
+        let tags = tagged.iter().map(|x| Tag::public_key(*x));
+        EventBuilder::text_note(content, tags)
+            .to_event(author)
+            .unwrap()
+    }
+
+    fn nostr_uri(user: &PublicKey) -> String {
+        format!("nostr:{}", user.to_bech32().unwrap())
+    }
+
+    #[test]
+    fn targets_of_a_mention_with_only_content() {
+        let keys = keys(4);
+        let [bot, a, b] = [1, 2, 3].map(|x| keys[x].public_key());
+        let content = format!("{} {} {}", nostr_uri(&bot), nostr_uri(&a), nostr_uri(&b));
+        let event = mention(&keys[0], &content, &[]);
+        for precedence in [TargetPrecedence::ContentFirst, TargetPrecedence::TagsFirst] {
+            let found = find_targets_in_mention(&event, &bot, 2, precedence);
+            assert_eq!((found.targets, found.from_tags), (vec![a, b], false));
+        }
+    }
+
+    #[test]
+    fn targets_of_a_mention_with_only_tags() {
+        let keys = keys(4);
+        let [author, bot, a, b] = [0, 1, 2, 3].map(|x| keys[x].public_key());
+        // The author and the bot are tagged too, but aren't targets
+        let event = mention(&keys[0], "how far apart are they?", &[bot, b, author, a]);
+        for precedence in [TargetPrecedence::ContentFirst, TargetPrecedence::TagsFirst] {
+            let found = find_targets_in_mention(&event, &bot, 2, precedence);
+            assert_eq!((found.targets, found.from_tags), (vec![b, a], true));
+        }
+    }
+
+    #[test]
+    fn targets_of_a_mention_with_content_and_thread_tags() {
+        let keys = keys(6);
+        let [bot, a, b, c, d] = [1, 2, 3, 4, 5].map(|x| keys[x].public_key());
+        // A reply in a thread: the content asks about a and b, the tags are c and d of the thread
+        let content = format!("{} {} {}", nostr_uri(&bot), nostr_uri(&a), nostr_uri(&b));
+        let event = mention(&keys[0], &content, &[c, d, bot]);
+        let found = find_targets_in_mention(&event, &bot, 2, TargetPrecedence::ContentFirst);
+        assert_eq!((found.targets, found.from_tags), (vec![a, b], false));
+        let found = find_targets_in_mention(&event, &bot, 2, TargetPrecedence::TagsFirst);
+        assert_eq!((found.targets, found.from_tags), (vec![c, d], true));
+
+        // Too few in the content, so the tags count, but only if they're exactly what's needed
+        let content = format!("{} {}", nostr_uri(&bot), nostr_uri(&a));
+        let event = mention(&keys[0], &content, &[c, d]);
+        let found = find_targets_in_mention(&event, &bot, 2, TargetPrecedence::ContentFirst);
+        assert_eq!((found.targets, found.from_tags), (vec![c, d], true));
+        let event = mention(&keys[0], &content, &[b, c, d]);
+        let found = find_targets_in_mention(&event, &bot, 2, TargetPrecedence::ContentFirst);
+        assert_eq!((found.targets, found.from_tags), (vec![a], false));
+    }
+
+    #[test]
+    fn targets_of_mentions_written_by_common_clients() {
+        let keys = keys(5);
+        let [bot, a, b, c] = [1, 2, 3, 4].map(|x| keys[x].public_key());
+        let npub = |user: &PublicKey| user.to_bech32().unwrap();
+        let nprofile = |user: &PublicKey| {
+            Nip19Profile::new(*user, ["wss://relay.damus.io"])
+                .unwrap()
+                .to_bech32()
+                .unwrap()
         };
+        let quoted = mention(&keys[0], "gm", &[]);
+        let note = quoted.id.to_bech32().unwrap();
+        let nevent = Nip19Event::new(quoted.id, ["wss://nos.lol"])
+            .to_bech32()
+            .unwrap();
+
+        let cases = [
+            (
+                "Damus",
+                format!(
+                    "{} how far apart are {} and {}?",
+                    nostr_uri(&bot),
+                    nostr_uri(&a),
+                    nostr_uri(&b)
+                ),
+                vec![a, b],
+            ),
+            (
+                "Damus quoting a note",
+                format!(
+                    "{} {} {}\n\nnostr:{note}",
+                    nostr_uri(&bot),
+                    nostr_uri(&a),
+                    nostr_uri(&b)
+                ),
+                vec![a, b],
+            ),
+            (
+                "Amethyst",
+                format!(
+                    "nostr:{} nostr:{} nostr:{}",
+                    nprofile(&bot),
+                    nprofile(&a),
+                    npub(&b)
+                ),
+                vec![a, b],
+            ),
+            (
+                "Amethyst quoting a note",
+                format!("nostr:{} nostr:{} nostr:{nevent}", npub(&bot), nprofile(&a)),
+                vec![a],
+            ),
+            (
+                "Primal",
+                format!(
+                    "{} {} {} https://primal.net/e/{note}",
+                    nostr_uri(&bot),
+                    nostr_uri(&a),
+                    nostr_uri(&b)
+                ),
+                vec![a, b],
+            ),
+            (
+                "pasted npubs",
+                format!("@sixdegrees {} {}, {}!", npub(&bot), npub(&a), npub(&b)),
+                vec![a, b],
+            ),
+            (
+                "hex",
+                format!("{} {}\nnostr:{}.", bot.to_hex(), a.to_hex(), b.to_hex()),
+                vec![a, b],
+            ),
+            (
+                "hex in links and other text",
+                format!(
+                    "{} {} https://njump.me/{} e:{} #{}",
+                    nostr_uri(&bot),
+                    nostr_uri(&a),
+                    c.to_hex(),
+                    c.to_hex(),
+                    c.to_hex()
+                ),
+                vec![a],
+            ),
+            (
+                "the same user twice",
+                format!(
+                    "{} {} {} {}",
+                    nostr_uri(&a),
+                    npub(&a),
+                    a.to_hex(),
+                    nprofile(&b)
+                ),
+                vec![a, b],
+            ),
+        ];
+        for (client, content, expected) in cases {
+            let event = mention(&keys[0], &content, &[]);
+            let found = find_targets_in_mention(&event, &bot, 2, TargetPrecedence::ContentFirst);
+            assert_eq!(found.targets, expected, "{client}: {content}");
+        }
     }
 
-    Ok(map_pubkey_meta)
+    fn tag_set(builder: EventBuilder, keys: &Keys) -> Vec<Vec<String>> {
+        let event = builder.to_event(keys).unwrap();
+        event.tags.iter().map(|x| x.as_vec().to_vec()).collect()
+    }
+
+    fn strings(tag: &[&str]) -> Vec<String> {
+        tag.iter().map(|x| x.to_string()).collect()
+    }
+
+    fn configured_factory() -> EventFactory {
+        EventFactory {
+            expiration: Some(Duration::from_secs(3600)),
+            client_tag: true,
+            hashtags: vec!["sixdegrees".to_string()],
+            pow: PowLimits::default(),
+        }
+    }
+
+    /// The expiration tag, checked to be an hour from now and replaced by a fixed value
+    fn fixed_expiration(mut tags: Vec<Vec<String>>) -> Vec<Vec<String>> {
+        let now = Timestamp::now().as_u64();
+        for tag in tags.iter_mut().filter(|x| x[0] == "expiration") {
+            let expiration = tag[1].parse::<u64>().unwrap();
+            assert!((now + 3590..=now + 3600).contains(&expiration));
+            tag[1] = "<in an hour>".to_string();
+        }
+        tags
+    }
+
+    #[test]
+    fn factory_builds_every_kind_of_event_with_the_same_optional_tags() {
+        let keys = keys(2);
+        let mention = mention(&keys[0], "hi", &[]);
+        let factory = configured_factory();
+
+        let reply = fixed_expiration(tag_set(factory.reply(&mention, "hello"), &keys[1]));
+        assert_eq!(
+            reply,
+            vec![
+                strings(&["e", &mention.id.to_hex(), "", "root"]),
+                strings(&["p", &keys[0].public_key().to_hex()]),
+                strings(&["t", "sixdegrees"]),
+                strings(&["client", CLIENT_TAG_NAME]),
+                strings(&["expiration", "<in an hour>"]),
+            ]
+        );
+
+        let note = fixed_expiration(tag_set(factory.note("hello"), &keys[1]));
+        assert_eq!(
+            note,
+            vec![
+                strings(&["t", "sixdegrees"]),
+                strings(&["client", CLIENT_TAG_NAME]),
+                strings(&["expiration", "<in an hour>"]),
+            ]
+        );
+
+        // Not read as a note, so without hashtags but with an alt tag
+        let builder = factory.replaceable(
+            Kind::from(30000),
+            "",
+            vec![Tag::identifier("list")],
+            "A list",
+        );
+        let replaceable = fixed_expiration(tag_set(builder, &keys[1]));
+        assert_eq!(
+            replaceable,
+            vec![
+                strings(&["d", "list"]),
+                strings(&["alt", "A list"]),
+                strings(&["client", CLIENT_TAG_NAME]),
+                strings(&["expiration", "<in an hour>"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn default_factory_adds_no_optional_tags() {
+        let keys = keys(2);
+        let mention = mention(&keys[0], "hi", &[]);
+        let factory = EventFactory::default();
+        assert_eq!(tag_set(factory.reply(&mention, "hello"), &keys[1]).len(), 2);
+        assert!(tag_set(factory.note("hello"), &keys[1]).is_empty());
+        let builder = factory.replaceable(Kind::from(30000), "", vec![], "A list");
+        assert_eq!(
+            tag_set(builder, &keys[1]),
+            vec![strings(&["alt", "A list"])]
+        );
+    }
+
+    #[test]
+    fn malformed_p_tags_are_counted_and_skipped() {
+        let keys = keys(3);
+        let [a, b] = [1, 2].map(|x| keys[x].public_key());
+        let tags = [
+            vec!["p".to_string(), a.to_hex()],
+            vec!["p".to_string(), "not a key".to_string()],
+            vec![
+                "p".to_string(),
+                b.to_hex(),
+                "wss://relay.b".to_string(),
+                "bee".to_string(),
+            ],
+            vec![
+                "p".to_string(),
+                "".to_string(),
+                "".to_string(),
+                "nobody".to_string(),
+            ],
+            vec!["p".to_string(), a.to_hex()[1..].to_string()],
+            vec!["t".to_string(), "not a p tag".to_string()],
+        ]
+        .map(|x| Tag::parse(&x).unwrap());
+        let event = EventBuilder::new(Kind::ContactList, "", tags)
+            .to_event(&keys[0])
+            .unwrap();
+
+        let (entries, malformed) = contact_list_entries(&event);
+        assert_eq!(malformed, 3);
+        assert_eq!(
+            entries,
+            vec![
+                (a, PetnameInfo::default()),
+                (
+                    b,
+                    PetnameInfo {
+                        relay_hint: Some("wss://relay.b".to_string()),
+                        petname: Some("bee".to_string()),
+                    }
+                ),
+            ]
+        );
+
+        // Other tests fetch contact lists too, so the total only grows by at least as many
+        let before = malformed_p_tags();
+        let lists = contact_lists_from_events(vec![event]);
+        assert_eq!(lists[&keys[0].public_key()].0, vec![a, b]);
+        assert!(malformed_p_tags() >= before + 3);
+    }
+
+    #[tokio::test]
+    async fn follows_of_every_p_tag_shape_are_kept_with_their_petnames() {
+        let keys = keys(6);
+        let users = keys.iter().map(|x| x.public_key()).collect::<Vec<_>>();
+        let p = |user: usize, rest: &[&str]| {
+            let mut tag = vec!["p".to_string(), users[user].to_hex()];
+            tag.extend(rest.iter().map(|x| x.to_string()));
+            Tag::parse(&tag).unwrap()
+        };
+        // As written by different clients: bare, with an empty relay, with a relay, with an empty
+        // relay and a petname, and with both
+        let tags = [
+            p(1, &[]),
+            p(2, &[""]),
+            p(3, &["wss://relay.damus.io", ""]),
+            p(4, &["", "alice"]),
+            p(5, &["wss://nos.lol", "bob"]),
+        ];
+        let event = EventBuilder::new(Kind::ContactList, "", tags)
+            .to_event(&keys[0])
+            .unwrap();
+        let relay = FakeRelay::start(vec![event]).await;
+        let client = client(&[&relay]).await;
+
+        let following =
+            get_following_multiple_users_with_timestamp_and_timeout(vec![users[0]], &client, None)
+                .await
+                .unwrap();
+        assert_eq!(following[&users[0]].0, users[1..]);
+
+        let mut entries =
+            get_contact_list_entries_multiple_users_with_timeout(vec![users[0]], &client, None)
+                .await
+                .unwrap();
+        let (entries, time) = entries.remove(&users[0]).unwrap();
+        let info = |relay_hint: Option<&str>, petname: Option<&str>| PetnameInfo {
+            relay_hint: relay_hint.map(|x| x.to_string()),
+            petname: petname.map(|x| x.to_string()),
+        };
+        assert_eq!(
+            entries.iter().map(|(_, x)| x.clone()).collect::<Vec<_>>(),
+            [
+                info(None, None),
+                info(None, None),
+                info(Some("wss://relay.damus.io"), None),
+                info(None, Some("alice")),
+                info(Some("wss://nos.lol"), Some("bob")),
+            ]
+        );
+
+        let mut network = Network::new();
+        network.update_contact_list(users[0], entries.iter().map(|(x, _)| x), &time);
+        network.update_petnames(users[0], entries);
+        assert_eq!(network.given_petname(&users[0], &users[4]), Some("alice"));
+        assert_eq!(network.given_petname(&users[0], &users[3]), None);
+        // Someone else sees the petname 0 gave
+        assert_eq!(network.petname(&users[1], &users[5]), Some("bob"));
+    }
+
+    #[test]
+    fn reply_tags_keep_only_a_well_formed_thread() {
+        let keys = keys(2);
+        let other = keys[1].public_key().to_hex();
+        let [root, reply, another] = ["aa", "bb", "cc"].map(|x| x.repeat(32));
+        let tag = |x: &[&str]| Tag::parse(x).unwrap();
+        let e = |id: &str, marker: &str| tag(&["e", id, "wss://relay.one", marker]);
+        let as_strings =
+            |tags: Vec<Tag>| tags.iter().map(|x| x.as_vec().to_vec()).collect::<Vec<_>>();
+        let reply_tags = |tags: Vec<Tag>| {
+            let mention = EventBuilder::text_note("", tags)
+                .to_event(&keys[0])
+                .unwrap();
+            let tags = as_strings(map_event_tags_to_reply(&mention));
+            (mention, tags)
+        };
+        let p_author = |mention: &Event| vec!["p".to_string(), mention.pubkey.to_hex()];
+        let e_mention = |mention: &Event, marker: &str| {
+            ["e", &mention.id.to_hex(), "", marker]
+                .map(|x| x.to_string())
+                .to_vec()
+        };
+        // The mention is the root
+        let as_root = |tags: Vec<Tag>| {
+            let (mention, tags) = reply_tags(tags);
+            assert_eq!(tags, [e_mention(&mention, "root"), p_author(&mention)]);
+        };
+
+        // A well formed thread, with its p tags
+        let (mention, tags) = reply_tags(vec![
+            e(&root, "root"),
+            e(&reply, "reply"),
+            tag(&["p", &other]),
+        ]);
+        assert_eq!(
+            tags,
+            [
+                e_mention(&mention, "reply"),
+                p_author(&mention),
+                as_strings(vec![e(&root, "root")])[0].clone(),
+                vec!["p".to_string(), other.clone()],
+            ]
+        );
+        // The same root twice, in different case
+        let (_, tags) = reply_tags(vec![e(&root, "root"), e(&root.to_uppercase(), "root")]);
+        assert_eq!(tags.iter().filter(|x| x[0] == "e").count(), 2);
+
+        as_root(vec![]);
+        // Malformed root ids
+        as_root(vec![e(&root[1..], "root")]);
+        as_root(vec![e(&format!("{}zz", &root[2..]), "root")]);
+        // Contradictory markers
+        as_root(vec![e(&root, "root"), e(&another, "root")]);
+        as_root(vec![e(&reply, "reply")]);
+    }
+
+    #[test]
+    fn bolt11_amounts_are_read_with_their_multiplier() {
+        for (invoice, amount) in [
+            ("lnbc1pvjluez", None),
+            ("lnbc2500u1pvjluez", Some(250_000_000)),
+            ("LNBC20M1PVJLUEZ", Some(2_000_000_000)),
+            ("lightning:lnbc10n1pvjluez", Some(1_000)),
+            ("lntb1500n1pvjluez", Some(150_000)),
+            ("lnbc1p1pvjluez", None),
+            ("lnbc10p1pvjluez", Some(1)),
+            ("lnbc2x1pvjluez", None),
+            ("lnbc99999999999999999m1pvjluez", None),
+            ("bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq", None),
+            ("nothing", None),
+        ] {
+            assert_eq!(bolt11_amount_msat(invoice), amount, "{invoice}");
+        }
+    }
+
+    #[test]
+    fn zap_receipts_give_the_sender_of_the_request_and_the_amount() {
+        let keys = keys(3);
+        let (sender, recipient) = (keys[0].public_key(), keys[1].public_key());
+        let tag = |x: &[&str]| Tag::parse(x).unwrap();
+        let request = |amount: Option<&str>| {
+            let tags = amount.map(|x| tag(&["amount", x]));
+            EventBuilder::new(Kind::ZapRequest, "", tags)
+                .to_event(&keys[0])
+                .unwrap()
+                .as_json()
+        };
+        // Made by the wallet of the recipient, keys[2]
+        let receipt = |tags: Vec<Tag>| {
+            EventBuilder::new(Kind::ZapReceipt, "", tags)
+                .to_event(&keys[2])
+                .unwrap()
+        };
+        let p = tag(&["p", &recipient.to_hex()]);
+        let zap = |amount_msat| {
+            Some(Zap {
+                sender,
+                recipient,
+                amount_msat,
+            })
+        };
+
+        // The invoice amount wins over the requested one
+        let full = receipt(vec![
+            p.clone(),
+            tag(&["bolt11", "lnbc210n1pvjluez"]),
+            tag(&["description", &request(Some("5000"))]),
+        ]);
+        assert_eq!(zap_from_receipt(&full), zap(21_000));
+        let without_invoice = receipt(vec![
+            p.clone(),
+            tag(&["description", &request(Some("5000"))]),
+        ]);
+        assert_eq!(zap_from_receipt(&without_invoice), zap(5_000));
+
+        for broken in [
+            // No amount anywhere
+            vec![p.clone(), tag(&["description", &request(None)])],
+            // No request, or not an event
+            vec![p.clone(), tag(&["bolt11", "lnbc210n1pvjluez"])],
+            vec![
+                p.clone(),
+                tag(&["bolt11", "lnbc210n1pvjluez"]),
+                tag(&["description", "{\"amount\": 1}"]),
+            ],
+            // No recipient
+            vec![
+                tag(&["bolt11", "lnbc210n1pvjluez"]),
+                tag(&["description", &request(None)]),
+            ],
+            vec![
+                tag(&["p", "npub"]),
+                tag(&["bolt11", "lnbc210n1pvjluez"]),
+                tag(&["description", &request(None)]),
+            ],
+        ] {
+            assert_eq!(
+                zap_from_receipt(&receipt(broken.clone())),
+                None,
+                "{broken:?}"
+            );
+        }
+    }
 }
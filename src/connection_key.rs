@@ -0,0 +1,96 @@
+/// Where the bot's secret key comes from, so it doesn't have to be given in the command line
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use nostr_sdk::prelude::*;
+
+pub const CONNECTION_KEY_ENV: &str = "SIX_DEGREES_NSEC";
+
+#[derive(Debug)]
+pub enum ConnectionKeyError {
+    Missing,
+    FileRead(PathBuf, std::io::Error),
+    /// Invalid key, with where it came from
+    Invalid(&'static str, nostr_sdk::key::Error),
+}
+
+impl Display for ConnectionKeyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionKeyError::Missing => write!(
+                f,
+                "No connection key: give it with --connection-key, the {CONNECTION_KEY_ENV} environment variable or --connection-key-file"
+            ),
+            ConnectionKeyError::FileRead(path, error) => {
+                write!(f, "Connection key file {}: {}", path.display(), error)
+            }
+            ConnectionKeyError::Invalid(source, error) => {
+                write!(f, "Invalid connection key in {source}: {error}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConnectionKeyError {}
+
+/// Find the connection key, from the command line, then the environment, then the key file
+///
+/// `new` in the command line generates a key, which is saved to the key file if it doesn't exist yet
+pub fn load_connection_keys(
+    cli: Option<&str>,
+    key_file: Option<&Path>,
+) -> Result<Keys, ConnectionKeyError> {
+    match cli {
+        Some("new") => {
+            let keys = Keys::generate();
+            eprintln!(
+                "generated key: {} {}",
+                keys.secret_key().unwrap().to_bech32().unwrap(),
+                keys.public_key().to_bech32().unwrap()
+            );
+            if let Some(path) = key_file {
+                save_new_key(&keys, path);
+            }
+            return Ok(keys);
+        }
+        Some(key) => {
+            return Keys::parse(key)
+                .map_err(|err| ConnectionKeyError::Invalid("--connection-key", err))
+        }
+        None => (),
+    }
+    if let Ok(key) = std::env::var(CONNECTION_KEY_ENV) {
+        return Keys::parse(key.trim())
+            .map_err(|err| ConnectionKeyError::Invalid(CONNECTION_KEY_ENV, err));
+    }
+    match key_file {
+        Some(path) => {
+            let key = fs::read_to_string(path)
+                .map_err(|err| ConnectionKeyError::FileRead(path.to_path_buf(), err))?;
+            Keys::parse(key.trim())
+                .map_err(|err| ConnectionKeyError::Invalid("--connection-key-file", err))
+        }
+        None => Err(ConnectionKeyError::Missing),
+    }
+}
+
+/// Write the key only readable by the owner, never replacing an existing file
+fn save_new_key(keys: &Keys, path: &Path) {
+    if path.exists() {
+        eprintln!("Generated key not saved: {} already exists", path.display());
+        return;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let res = options.open(path).and_then(|mut file| {
+        use std::io::Write;
+        writeln!(file, "{}", keys.secret_key().unwrap().to_bech32().unwrap())
+    });
+    match res {
+        Ok(()) => eprintln!("Generated key saved to {}", path.display()),
+        Err(err) => eprintln!("Generated key not saved to {}: {}", path.display(), err),
+    }
+}
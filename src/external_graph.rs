@@ -0,0 +1,346 @@
+/// Contact lists from a follow graph indexer, e.g. a local copy of the nostr.band API, for when
+/// relays don't have them
+///
+/// Only the contact lists of the targets of a search are asked for, unless configured to also
+/// fill in the hops. Answers using them are flagged, since no relay vouched for these lists
+///
+/// The indexer answers `GET <base_url>/contact_list/<hex pubkey>` with
+/// `{"follows": ["<hex pubkey>", ...], "created_at": <unix seconds>}`, or a 404 when it doesn't
+/// have the user
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use nostr_sdk::prelude::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExternalGraphConfig {
+    /// Like https://indexer.example/api, where contact_list/<hex pubkey> is appended
+    pub base_url: String,
+    /// Sent as a bearer token
+    #[serde(default)]
+    pub api_key: Option<String>,
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Also ask for the users between the targets whose contact list no relay had
+    #[serde(default)]
+    pub use_for_hops: bool,
+}
+
+pub fn default_timeout_secs() -> u64 {
+    5
+}
+
+#[derive(Debug)]
+pub enum ExternalGraphError {
+    /// The base url isn't an http or https url with a host
+    InvalidUrl(String),
+    Http(reqwest::Error),
+    Timeout,
+    /// The indexer answered with a status other than 200 or 404
+    Status(u16),
+    Malformed(String),
+}
+
+impl Display for ExternalGraphError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ExternalGraphError::InvalidUrl(url) => write!(f, "Invalid external graph url {url}"),
+            ExternalGraphError::Http(err) => write!(f, "External graph connection error: {err}"),
+            ExternalGraphError::Timeout => write!(f, "External graph timed out"),
+            ExternalGraphError::Status(status) => {
+                write!(f, "External graph answered with status {status}")
+            }
+            ExternalGraphError::Malformed(reason) => {
+                write!(f, "Malformed external graph answer: {reason}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ExternalGraphError {}
+
+impl From<reqwest::Error> for ExternalGraphError {
+    fn from(value: reqwest::Error) -> Self {
+        if value.is_timeout() {
+            Self::Timeout
+        } else {
+            Self::Http(value)
+        }
+    }
+}
+
+pub type ContactListFuture<'a> = Pin<
+    Box<
+        dyn Future<Output = Result<Option<(Vec<PublicKey>, Timestamp)>, ExternalGraphError>>
+            + Send
+            + 'a,
+    >,
+>;
+
+/// Where contact lists come from when relays don't have them
+pub trait ExternalGraphSource: Send + Sync {
+    /// Who `user` follows and when the list was made, None when the source doesn't have it
+    fn contact_list(&self, user: PublicKey) -> ContactListFuture<'_>;
+}
+
+/// A source and where it's used in searches
+#[derive(Clone)]
+pub struct ExternalGraph {
+    pub source: Arc<dyn ExternalGraphSource>,
+    pub use_for_hops: bool,
+}
+
+impl fmt::Debug for ExternalGraph {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExternalGraph")
+            .field("use_for_hops", &self.use_for_hops)
+            .finish()
+    }
+}
+
+impl ExternalGraph {
+    pub fn from_config(config: &ExternalGraphConfig) -> Result<ExternalGraph, ExternalGraphError> {
+        Ok(ExternalGraph {
+            source: Arc::new(HttpGraphSource::new(config)?),
+            use_for_hops: config.use_for_hops,
+        })
+    }
+}
+
+/// Largest answer read, a contact list of a few thousand follows is far below it
+const MAX_RESPONSE_BYTES: usize = 4 * 1024 * 1024;
+
+/// Indexer reached over http or https
+#[derive(Debug, Clone)]
+pub struct HttpGraphSource {
+    client: reqwest::Client,
+    /// The base url, without a trailing slash
+    base_url: String,
+    api_key: Option<String>,
+}
+
+impl HttpGraphSource {
+    pub fn new(config: &ExternalGraphConfig) -> Result<HttpGraphSource, ExternalGraphError> {
+        let invalid = || ExternalGraphError::InvalidUrl(config.base_url.clone());
+        let url = Url::parse(&config.base_url).map_err(|_| invalid())?;
+        if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() {
+            return Err(invalid());
+        }
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .build()
+            .map_err(ExternalGraphError::Http)?;
+        Ok(HttpGraphSource {
+            client,
+            base_url: config.base_url.trim_end_matches('/').to_string(),
+            api_key: config.api_key.clone(),
+        })
+    }
+
+    /// Status and body of the answer, up to MAX_RESPONSE_BYTES
+    async fn get(&self, url: &str) -> Result<(u16, Vec<u8>), ExternalGraphError> {
+        let mut request = self
+            .client
+            .get(url)
+            .header(reqwest::header::ACCEPT, "application/json");
+        if let Some(key) = &self.api_key {
+            request = request.bearer_auth(key);
+        }
+        let mut response = request.send().await?;
+        let status = response.status().as_u16();
+        let mut body = vec![];
+        while let Some(chunk) = response.chunk().await? {
+            if body.len() + chunk.len() > MAX_RESPONSE_BYTES {
+                return Err(ExternalGraphError::Malformed(format!(
+                    "answer over {MAX_RESPONSE_BYTES} bytes"
+                )));
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok((status, body))
+    }
+}
+
+impl ExternalGraphSource for HttpGraphSource {
+    fn contact_list(&self, user: PublicKey) -> ContactListFuture<'_> {
+        Box::pin(async move {
+            let url = format!("{}/contact_list/{}", self.base_url, user.to_hex());
+            let (status, body) = self.get(&url).await?;
+            match status {
+                200 => parse_contact_list(&body).map(Some),
+                404 => Ok(None),
+                status => Err(ExternalGraphError::Status(status)),
+            }
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ContactListAnswer {
+    follows: Vec<String>,
+    created_at: u64,
+}
+
+/// The follows and time of an indexer answer. A single invalid pubkey rejects all of it
+fn parse_contact_list(body: &[u8]) -> Result<(Vec<PublicKey>, Timestamp), ExternalGraphError> {
+    let answer: ContactListAnswer = serde_json::from_slice(body)
+        .map_err(|err| ExternalGraphError::Malformed(err.to_string()))?;
+    let follows = answer
+        .follows
+        .iter()
+        .map(PublicKey::from_hex)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| ExternalGraphError::Malformed(err.to_string()))?;
+    Ok((follows, Timestamp::from(answer.created_at)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use std::sync::Mutex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Indexer answering every request with `response` after `delay`, keeping the requests
+    async fn indexer(response: String, delay: Duration) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}/api/", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let seen = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let (response, seen) = (response.clone(), seen.clone());
+                tokio::spawn(async move {
+                    let mut request = vec![0u8; 4096];
+                    let read = stream.read(&mut request).await.unwrap_or(0);
+                    seen.lock()
+                        .unwrap()
+                        .push(String::from_utf8_lossy(&request[..read]).to_string());
+                    tokio::time::sleep(delay).await;
+                    _ = stream.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        (base_url, requests)
+    }
+
+    fn answer(status: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    fn source(base_url: &str) -> HttpGraphSource {
+        HttpGraphSource::new(&ExternalGraphConfig {
+            base_url: base_url.to_string(),
+            api_key: Some("secret".to_string()),
+            timeout_secs: 1,
+            use_for_hops: false,
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn contact_list_is_read_from_the_indexer() {
+        let users = pubkeys(3);
+        let body = format!(
+            r#"{{"follows": ["{}", "{}"], "created_at": 1700000000}}"#,
+            users[1].to_hex(),
+            users[2].to_hex()
+        );
+        let (base_url, requests) = indexer(answer("200 OK", &body), Duration::ZERO).await;
+        let (follows, created_at) = source(&base_url)
+            .contact_list(users[0])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(follows, users[1..]);
+        assert_eq!(created_at, Timestamp::from(1700000000));
+
+        let request = requests.lock().unwrap()[0].to_ascii_lowercase();
+        assert!(request.starts_with(&format!("get /api/contact_list/{} ", users[0].to_hex())));
+        assert!(request.contains("authorization: bearer secret"));
+
+        // Also in chunks
+        let chunked = format!(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n{:x}\r\n{}\r\n{:x}\r\n{}\r\n0\r\n\r\n",
+            10,
+            &body[..10],
+            body.len() - 10,
+            &body[10..]
+        );
+        let (base_url, _) = indexer(chunked, Duration::ZERO).await;
+        let answer = source(&base_url).contact_list(users[0]).await.unwrap();
+        assert_eq!(answer.map(|x| x.0), Some(users[1..].to_vec()));
+    }
+
+    #[tokio::test]
+    async fn unknown_users_failures_and_malformed_answers_are_told_apart() {
+        let user = pubkeys(1)[0];
+        for (response, expected) in [
+            (answer("404 Not Found", ""), "none"),
+            (answer("500 Internal Server Error", "{}"), "status 500"),
+            (answer("200 OK", "not json"), "malformed"),
+            (answer("200 OK", r#"{"follows": []}"#), "malformed"),
+            (
+                answer("200 OK", r#"{"follows": ["npub"], "created_at": 1}"#),
+                "malformed",
+            ),
+            (
+                "HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n{".to_string(),
+                "http",
+            ),
+            ("garbage\r\n\r\n".to_string(), "http"),
+        ] {
+            let (base_url, _) = indexer(response.clone(), Duration::ZERO).await;
+            let result = source(&base_url).contact_list(user).await;
+            let kind = match result {
+                Ok(None) => "none",
+                Err(ExternalGraphError::Status(500)) => "status 500",
+                Err(ExternalGraphError::Malformed(_)) => "malformed",
+                Err(ExternalGraphError::Http(_)) => "http",
+                _ => "other",
+            };
+            assert_eq!(kind, expected, "{response}");
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_indexer_times_out() {
+        let (base_url, _) = indexer(answer("404 Not Found", ""), Duration::from_secs(5)).await;
+        let result = source(&base_url).contact_list(pubkeys(1)[0]).await;
+        assert!(
+            matches!(result, Err(ExternalGraphError::Timeout)),
+            "{result:?}"
+        );
+    }
+
+    #[test]
+    fn base_url_must_be_http_or_https_with_a_host() {
+        assert!(HttpGraphSource::new(&ExternalGraphConfig {
+            base_url: "https://indexer.example/api".to_string(),
+            api_key: None,
+            timeout_secs: 5,
+            use_for_hops: false,
+        })
+        .is_ok());
+        for base_url in ["ftp://indexer.example", "not a url", "unix:/run/indexer"] {
+            let result = HttpGraphSource::new(&ExternalGraphConfig {
+                base_url: base_url.to_string(),
+                api_key: None,
+                timeout_secs: 5,
+                use_for_hops: false,
+            });
+            assert!(
+                matches!(result, Err(ExternalGraphError::InvalidUrl(_))),
+                "{base_url}"
+            );
+        }
+    }
+}
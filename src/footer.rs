@@ -0,0 +1,125 @@
+/// Optional footer appended to replies, disclosing the work done for a request
+use std::time::Duration;
+
+/// Footers longer than this are truncated
+pub const MAX_FOOTER_LEN: usize = 80;
+
+pub fn default_template() -> String {
+    "req #{request} • {elapsed} • {profiles} profiles scanned".to_string()
+}
+
+/// Usage of a single answered request, given by the listener
+#[derive(Debug, Clone)]
+pub struct RequestUsage {
+    pub number: u64,
+    pub elapsed: Duration,
+    pub template: String,
+}
+
+/// 1204 -> "1,204"
+pub fn group_thousands(n: u64) -> String {
+    let digits = n.to_string();
+    let mut grouped = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(c);
+    }
+    grouped
+}
+
+/// Exact below 10 thousand, then 12.3k, 1.2M...
+pub fn humanize_count(n: u64) -> String {
+    if n < 10_000 {
+        group_thousands(n)
+    } else if n < 1_000_000 {
+        format!("{:.1}k", n as f64 / 1_000.0)
+    } else {
+        format!("{:.1}M", n as f64 / 1_000_000.0)
+    }
+}
+
+/// 38s, 2m10s, 1h5m
+pub fn humanize_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs < 60 {
+        format!("{secs}s")
+    } else if secs < 3600 {
+        format!("{}m{}s", secs / 60, secs % 60)
+    } else {
+        format!("{}h{}m", secs / 3600, (secs % 3600) / 60)
+    }
+}
+
+/// Fill the template placeholders `{request}`, `{elapsed}` and `{profiles}`,
+/// cutting the result at MAX_FOOTER_LEN characters
+pub fn format_footer(usage: &RequestUsage, profiles_scanned: usize) -> String {
+    let footer = usage
+        .template
+        .replace("{request}", &group_thousands(usage.number))
+        .replace("{elapsed}", &humanize_duration(usage.elapsed))
+        .replace("{profiles}", &humanize_count(profiles_scanned as u64));
+    if footer.chars().count() <= MAX_FOOTER_LEN {
+        return footer;
+    }
+    let mut cut: String = footer.chars().take(MAX_FOOTER_LEN - 1).collect();
+    cut.push('…');
+    cut
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(template: &str) -> RequestUsage {
+        RequestUsage {
+            number: 1204,
+            elapsed: Duration::from_secs(38),
+            template: template.to_string(),
+        }
+    }
+
+    #[test]
+    fn counts_are_grouped_then_shortened() {
+        assert_eq!(humanize_count(0), "0");
+        assert_eq!(humanize_count(999), "999");
+        assert_eq!(humanize_count(1_891), "1,891");
+        assert_eq!(humanize_count(9_999), "9,999");
+        assert_eq!(humanize_count(12_345), "12.3k");
+        assert_eq!(humanize_count(999_949), "999.9k");
+        assert_eq!(humanize_count(1_200_000), "1.2M");
+        assert_eq!(group_thousands(1_234_567), "1,234,567");
+    }
+
+    #[test]
+    fn durations_use_the_two_largest_units() {
+        assert_eq!(humanize_duration(Duration::from_millis(900)), "0s");
+        assert_eq!(humanize_duration(Duration::from_secs(38)), "38s");
+        assert_eq!(humanize_duration(Duration::from_secs(60)), "1m0s");
+        assert_eq!(humanize_duration(Duration::from_secs(130)), "2m10s");
+        assert_eq!(humanize_duration(Duration::from_secs(3900)), "1h5m");
+    }
+
+    #[test]
+    fn footer_fills_the_template() {
+        assert_eq!(
+            format_footer(&usage(&default_template()), 1891),
+            "req #1,204 • 38s • 1,891 profiles scanned"
+        );
+        assert_eq!(
+            format_footer(&usage("{profiles} perfis, pedido {request}"), 12_345),
+            "12.3k perfis, pedido 1,204"
+        );
+    }
+
+    #[test]
+    fn footer_is_cut_at_the_cap() {
+        let footer = format_footer(&usage(&"•".repeat(200)), 0);
+        assert_eq!(footer.chars().count(), MAX_FOOTER_LEN);
+        assert!(footer.ends_with('…'));
+
+        let footer = format_footer(&usage(&"x".repeat(MAX_FOOTER_LEN)), 0);
+        assert_eq!(footer, "x".repeat(MAX_FOOTER_LEN));
+    }
+}
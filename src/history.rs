@@ -0,0 +1,243 @@
+/// Log of the searches answered while listening, one JSON record per line, so a past answer can
+/// be searched again with --replay-history and compared
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::sep_degrees::{Degree, SearchOptions, SearchStats, SepDegreeError};
+
+use nostr_sdk::prelude::*;
+
+/// How a search ended
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Outcome {
+    pub degree: Option<Degree>,
+    pub path: Vec<PublicKey>,
+    pub error: Option<String>,
+    pub profiles_scanned: usize,
+    pub low_confidence: bool,
+}
+
+impl Outcome {
+    pub fn new(
+        result: &Result<(Degree, Vec<PublicKey>), SepDegreeError>,
+        stats: &SearchStats,
+    ) -> Outcome {
+        let (degree, path, error) = match result {
+            Ok((degree, path)) => (Some(*degree), path.clone(), None),
+            Err(err) => (None, vec![], Some(err.to_string())),
+        };
+        Outcome {
+            degree,
+            path,
+            error,
+            profiles_scanned: stats.profiles_scanned,
+            low_confidence: stats.low_confidence,
+        }
+    }
+}
+
+/// Everything needed to run a search again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryRecord {
+    /// The mention that asked for the search
+    pub request: EventId,
+    pub at: Timestamp,
+    pub from: PublicKey,
+    pub to: PublicKey,
+    pub options: SearchOptions,
+    /// Relays the client had when searching
+    pub relays: Vec<Url>,
+    /// Newest cache generation written before the search, if the network was saved
+    pub cache_saved_at: Option<Timestamp>,
+    pub outcome: Outcome,
+}
+
+#[derive(Debug)]
+pub struct History {
+    path: PathBuf,
+    /// Where the network is saved, to tell which generation a search started from
+    cache_path: Option<PathBuf>,
+}
+
+impl History {
+    pub fn new(path: PathBuf, cache_path: Option<PathBuf>) -> History {
+        History { path, cache_path }
+    }
+
+    /// When the cache was last written
+    pub fn cache_saved_at(&self) -> Option<Timestamp> {
+        let modified = fs::metadata(self.cache_path.as_ref()?)
+            .ok()?
+            .modified()
+            .ok()?;
+        let secs = modified
+            .duration_since(std::time::UNIX_EPOCH)
+            .ok()?
+            .as_secs();
+        Some(Timestamp::from(secs))
+    }
+
+    pub fn append(&self, record: &HistoryRecord) {
+        let res = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| writeln!(file, "{}", serde_json::to_string(record).unwrap()));
+        if let Err(err) = res {
+            eprintln!("History {} write error: {}", self.path.display(), err);
+        }
+    }
+
+    /// The record of the request, the last one if it was answered more than once
+    pub fn find(&self, request: &EventId) -> Result<Option<HistoryRecord>, std::io::Error> {
+        let text = fs::read_to_string(&self.path)?;
+        Ok(text
+            .lines()
+            .filter_map(|line| serde_json::from_str::<HistoryRecord>(line).ok())
+            .rfind(|x| x.request == *request))
+    }
+}
+
+/// The outcome of a record next to the one of searching it again
+pub struct ReplayComparison<'a> {
+    pub original: &'a Outcome,
+    pub replay: &'a Outcome,
+}
+
+impl Display for ReplayComparison<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let degree = |x: &Outcome| x.degree.map_or("none".to_string(), |x| x.to_string());
+        let error = |x: &Outcome| x.error.clone().unwrap_or_else(|| "none".to_string());
+        let rows = [
+            ("degree", degree(self.original), degree(self.replay)),
+            ("error", error(self.original), error(self.replay)),
+            (
+                "profiles",
+                self.original.profiles_scanned.to_string(),
+                self.replay.profiles_scanned.to_string(),
+            ),
+            (
+                "low confidence",
+                self.original.low_confidence.to_string(),
+                self.replay.low_confidence.to_string(),
+            ),
+        ];
+        writeln!(f, "{:<16}{:<66}replay", "", "original")?;
+        for (name, original, replay) in rows {
+            let mark = if original == replay { " " } else { "*" };
+            writeln!(f, "{mark}{name:<15}{original:<66}{replay}")?;
+        }
+        let hops = self.original.path.len().max(self.replay.path.len());
+        for hop in 0..hops {
+            let npub = |x: &Outcome| {
+                x.path
+                    .get(hop)
+                    .map_or(String::new(), |x| x.to_bech32().unwrap())
+            };
+            let (original, replay) = (npub(self.original), npub(self.replay));
+            let mark = if original == replay { " " } else { "*" };
+            writeln!(
+                f,
+                "{mark}{:<15}{original:<66}{replay}",
+                format!("hop {hop}")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Network;
+    use crate::search_service::{SearchService, SeparationRequest};
+    use crate::test_utils::*;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    async fn service(relay: &FakeRelay) -> SearchService {
+        SearchService::new(
+            Arc::new(client(&[relay]).await),
+            Arc::new(Mutex::new(Network::new())),
+            SearchOptions::default(),
+            None,
+        )
+    }
+
+    #[tokio::test]
+    async fn replayed_record_is_compared_with_its_outcome() {
+        // 0 - 1 - 2 - 3 are a chain of mutuals when answered, and 0 - 4 - 3 when replayed
+        let keys = keys(5);
+        let users = keys.iter().map(|x| x.public_key()).collect::<Vec<_>>();
+        let answered =
+            FakeRelay::start(contact_lists(&keys, &[&[1], &[0, 2], &[1, 3], &[2]])).await;
+        let path = temp_path("history.jsonl");
+        let answering = service(&answered)
+            .await
+            .with_history(History::new(path.clone(), None));
+        let request = EventId::all_zeros();
+        let other = EventId::from_slice(&[1; 32]).unwrap();
+        for (id, to) in [(other, users[1]), (request, users[3])] {
+            answering
+                .separation(SeparationRequest {
+                    from: users[0],
+                    to,
+                    options: None,
+                    request_id: Some(id),
+                })
+                .await;
+        }
+
+        let record = History::new(path, None).find(&request).unwrap().unwrap();
+        assert_eq!((record.from, record.to), (users[0], users[3]));
+        assert_eq!(record.outcome.degree, Some(Degree(3)));
+        assert_eq!(record.relays, std::slice::from_ref(&answered.url));
+
+        let replayed =
+            FakeRelay::start(contact_lists(&keys, &[&[4], &[], &[], &[4], &[0, 3]])).await;
+        let response = service(&replayed)
+            .await
+            .separation(SeparationRequest {
+                from: record.from,
+                to: record.to,
+                options: Some(record.options),
+                request_id: None,
+            })
+            .await;
+        let replay = Outcome::new(&response.result, &response.stats);
+        let comparison = ReplayComparison {
+            original: &record.outcome,
+            replay: &replay,
+        }
+        .to_string();
+        let row = |name: &str| {
+            comparison
+                .lines()
+                .find(|x| x[1..].starts_with(name))
+                .unwrap()
+                .to_string()
+        };
+        let columns = |line: String| {
+            line.split_whitespace()
+                .skip(1)
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>()
+        };
+        let npub = |user: usize| users[user].to_bech32().unwrap();
+
+        // Rows that changed are marked
+        assert!(row("degree").starts_with('*'));
+        assert_eq!(columns(row("degree")), ["3", "2"]);
+        assert!(row("error").starts_with(' '));
+        assert_eq!(
+            row("hop 0"),
+            format!(" {:<15}{:<66}{}", "hop 0", npub(0), npub(0))
+        );
+        assert!(row("hop 1").starts_with('*'));
+        assert_eq!(columns(row("hop 1")), ["1", &npub(1), &npub(4)]);
+        assert_eq!(columns(row("hop 3")), ["3", &npub(3)]);
+    }
+}
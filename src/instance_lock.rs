@@ -0,0 +1,252 @@
+/// Lock file that keeps two listeners from sharing the same config and state
+///
+/// On unix the file is held with flock, so it's released even if the process dies. Elsewhere the
+/// file existing is the lock, and stale ones must be taken over with force
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum LockError {
+    Io(io::Error),
+    /// Another instance holds the lock. Has its PID, if it could be read
+    Held(PathBuf, Option<u32>),
+}
+
+impl Display for LockError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LockError::Io(error) => write!(f, "Lock file error: {}", error),
+            LockError::Held(path, Some(pid)) => write!(
+                f,
+                "Another instance (PID {pid}) is using this config, see {}",
+                path.display()
+            ),
+            LockError::Held(path, None) => write!(
+                f,
+                "Another instance is using this config, see {}. Use --force-takeover if none is running",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LockError {}
+
+impl From<io::Error> for LockError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+/// Held for as long as the instance runs. The lock file is removed on drop
+#[derive(Debug)]
+pub struct InstanceLock {
+    _file: File,
+    path: PathBuf,
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        _ = fs::remove_file(&self.path);
+    }
+}
+
+/// The lock file of a state file is `<path>.lock`
+pub fn lock_path(state_path: &Path) -> PathBuf {
+    let mut name = state_path.as_os_str().to_owned();
+    name.push(".lock");
+    PathBuf::from(name)
+}
+
+/// Lock the state file for this instance
+///
+/// A lock left by a process that isn't running anymore is recovered. With `force_takeover`, the
+/// lock is also taken from a holder whose liveness can't be told, but never from a running one
+pub fn acquire(state_path: &Path, force_takeover: bool) -> Result<InstanceLock, LockError> {
+    let path = lock_path(state_path);
+    match try_lock(&path) {
+        Ok(lock) => Ok(lock),
+        Err(LockError::Held(_, pid)) => {
+            let alive = pid.and_then(is_running);
+            if alive == Some(true) || (alive.is_none() && !force_takeover) {
+                return Err(LockError::Held(path, pid));
+            }
+            eprintln!(
+                "Taking over the lock {} from PID {}, which isn't running",
+                path.display(),
+                pid.map_or("unknown".to_string(), |x| x.to_string())
+            );
+            // A new file, so a lock held on the old one doesn't matter
+            fs::remove_file(&path)?;
+            try_lock(&path)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut text = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut text).ok()?;
+    text.trim().parse().ok()
+}
+
+fn write_pid(file: &mut File) -> io::Result<()> {
+    file.set_len(0)?;
+    file.rewind()?;
+    write!(file, "{}", std::process::id())?;
+    file.sync_all()
+}
+
+#[cfg(unix)]
+fn try_lock(path: &Path) -> Result<InstanceLock, LockError> {
+    use std::os::fd::AsRawFd;
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::WouldBlock {
+            return Err(LockError::Held(path.to_path_buf(), read_pid(&mut file)));
+        }
+        return Err(LockError::Io(err));
+    }
+    // Not locked, so whoever wrote a PID here is gone
+    if let Some(pid) = read_pid(&mut file) {
+        eprintln!("Recovered stale lock {} of PID {pid}", path.display());
+    }
+    write_pid(&mut file)?;
+    Ok(InstanceLock {
+        _file: file,
+        path: path.to_path_buf(),
+    })
+}
+
+#[cfg(not(unix))]
+fn try_lock(path: &Path) -> Result<InstanceLock, LockError> {
+    match OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(path)
+    {
+        Ok(mut file) => {
+            write_pid(&mut file)?;
+            Ok(InstanceLock {
+                _file: file,
+                path: path.to_path_buf(),
+            })
+        }
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+            let pid = File::open(path).ok().and_then(|mut x| read_pid(&mut x));
+            Err(LockError::Held(path.to_path_buf(), pid))
+        }
+        Err(err) => Err(LockError::Io(err)),
+    }
+}
+
+/// Whether the process is running, None when it can't be told
+#[cfg(unix)]
+fn is_running(pid: u32) -> Option<bool> {
+    let pid = libc::pid_t::try_from(pid).ok()?;
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return Some(true);
+    }
+    match io::Error::last_os_error().raw_os_error() {
+        Some(libc::ESRCH) => Some(false),
+        // Exists, but belongs to another user
+        Some(libc::EPERM) => Some(true),
+        _ => None,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> Option<bool> {
+    None
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use std::os::fd::AsRawFd;
+
+    /// PID of a process that already exited
+    fn dead_pid() -> u32 {
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        child.id()
+    }
+
+    /// Lock file held by "another instance" with `pid`, while the returned file is open
+    fn held_by(state_path: &Path, pid: Option<u32>) -> File {
+        let file = File::create(lock_path(state_path)).unwrap();
+        assert_eq!(
+            unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) },
+            0
+        );
+        if let Some(pid) = pid {
+            write!(&file, "{pid}").unwrap();
+        }
+        file
+    }
+
+    fn lock_pid(state_path: &Path) -> Option<u32> {
+        read_pid(&mut File::open(lock_path(state_path)).unwrap())
+    }
+
+    #[test]
+    fn lock_is_acquired_and_removed_on_drop() {
+        let state = temp_path("acquire.toml");
+        let lock = acquire(&state, false).unwrap();
+        assert_eq!(lock_pid(&state), Some(std::process::id()));
+        drop(lock);
+        assert!(!lock_path(&state).exists());
+    }
+
+    #[test]
+    fn second_instance_fails_with_the_holder_pid() {
+        let state = temp_path("contention.toml");
+        let _lock = acquire(&state, false).unwrap();
+        for force in [false, true] {
+            match acquire(&state, force) {
+                Err(LockError::Held(path, pid)) => {
+                    assert_eq!((path, pid), (lock_path(&state), Some(std::process::id())))
+                }
+                other => panic!("expected the lock to be held, got {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn stale_lock_of_a_dead_process_is_recovered() {
+        let state = temp_path("stale.toml");
+        // Left behind unlocked, like after a crash
+        fs::write(lock_path(&state), dead_pid().to_string()).unwrap();
+        let _lock = acquire(&state, false).unwrap();
+        assert_eq!(lock_pid(&state), Some(std::process::id()));
+    }
+
+    #[test]
+    fn held_lock_is_taken_over_only_from_a_dead_or_unknown_holder() {
+        let state = temp_path("takeover.toml");
+        let _held = held_by(&state, Some(dead_pid()));
+        let _lock = acquire(&state, false).unwrap();
+        assert_eq!(lock_pid(&state), Some(std::process::id()));
+
+        let state = temp_path("takeover_unknown.toml");
+        let _held = held_by(&state, None);
+        assert!(matches!(
+            acquire(&state, false),
+            Err(LockError::Held(_, None))
+        ));
+        let _lock = acquire(&state, true).unwrap();
+        assert_eq!(lock_pid(&state), Some(std::process::id()));
+    }
+}
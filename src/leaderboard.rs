@@ -0,0 +1,217 @@
+/// Degrees of separation among the members of a list
+use itertools::Itertools;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::search_service::{SearchService, SeparationRequest};
+use crate::sep_degrees::{Degree, SearchOptions};
+
+use nostr_sdk::prelude::*;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MemberScore {
+    pub npub: String,
+    /// Members this one was found connected to
+    pub connected: usize,
+    pub average_degree: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Leaderboard {
+    pub list: String,
+    pub members: usize,
+    pub pairs_found: usize,
+    pub pairs_not_found: usize,
+    /// Pairs left out because the budget ran out
+    pub pairs_skipped: usize,
+    pub average_degree: Option<f64>,
+    pub most_connected: Option<MemberScore>,
+    pub most_isolated: Option<MemberScore>,
+}
+
+/// Find the separation of every pair of members, sharing the network so each search reuses the
+/// contact lists fetched by the previous ones
+///
+/// Pairs are no longer searched once `budget` is exceeded
+pub async fn compute(
+    list: &str,
+    members: &[PublicKey],
+    service: &SearchService,
+    budget: Duration,
+) -> Leaderboard {
+    let start = Instant::now();
+    let mut degrees: HashMap<(usize, usize), Degree> = HashMap::new();
+    let mut pairs_not_found = 0;
+    let mut pairs_skipped = 0;
+
+    for (i, j) in (0..members.len()).tuple_combinations() {
+        if start.elapsed() > budget {
+            pairs_skipped += 1;
+            continue;
+        }
+        // A single slow pair can't take the time of all the others
+        let left = budget.saturating_sub(start.elapsed());
+        let options = service.options();
+        let request = SeparationRequest {
+            from: members[i],
+            to: members[j],
+            options: Some(SearchOptions {
+                budget: Some(options.budget.map_or(left, |x| x.min(left))),
+                ..options
+            }),
+            request_id: None,
+        };
+        match service.separation(request).await.result {
+            Ok((degree, _)) => _ = degrees.insert((i, j), degree),
+            Err(err) => {
+                eprintln!(
+                    "Leaderboard: {} and {}: {}",
+                    members[i].to_bech32().unwrap(),
+                    members[j].to_bech32().unwrap(),
+                    err
+                );
+                pairs_not_found += 1;
+            }
+        }
+    }
+
+    let scores = (0..members.len())
+        .map(|member| {
+            let member_degrees = degrees
+                .iter()
+                .filter(|((i, j), _)| *i == member || *j == member)
+                .map(|(_, degree)| *degree)
+                .collect_vec();
+            MemberScore {
+                npub: members[member].to_bech32().unwrap(),
+                connected: member_degrees.len(),
+                average_degree: average(&member_degrees),
+            }
+        })
+        .collect_vec();
+
+    // Most connected: found connected to more members, then closer on average
+    let most_connected = scores
+        .iter()
+        .filter(|x| x.connected > 0)
+        .min_by(|x, y| {
+            y.connected.cmp(&x.connected).then(
+                x.average_degree
+                    .partial_cmp(&y.average_degree)
+                    .unwrap_or(std::cmp::Ordering::Equal),
+            )
+        })
+        .cloned();
+    // Most isolated: found connected to less members, then farther on average
+    let most_isolated = scores
+        .iter()
+        .min_by(|x, y| {
+            x.connected.cmp(&y.connected).then(
+                y.average_degree
+                    .partial_cmp(&x.average_degree)
+                    .unwrap_or(std::cmp::Ordering::Equal),
+            )
+        })
+        .cloned();
+
+    Leaderboard {
+        list: list.to_string(),
+        members: members.len(),
+        pairs_found: degrees.len(),
+        pairs_not_found,
+        pairs_skipped,
+        average_degree: average(&degrees.values().copied().collect_vec()),
+        most_connected,
+        most_isolated,
+    }
+}
+
+fn average(degrees: &[Degree]) -> Option<f64> {
+    if degrees.is_empty() {
+        return None;
+    }
+    Some(degrees.iter().map(|x| x.0 as f64).sum::<f64>() / degrees.len() as f64)
+}
+
+impl Leaderboard {
+    pub fn render_text(&self) -> String {
+        let describe = |score: &Option<MemberScore>| match score {
+            Some(s) => match s.average_degree {
+                Some(avg) => format!(
+                    "nostr:{} (average degree {:.2} to {} members)",
+                    s.npub, avg, s.connected
+                ),
+                None => format!("nostr:{} (no connection found)", s.npub),
+            },
+            None => "None".to_string(),
+        };
+        let mut text = format!(
+            "Degrees of separation in {} ({} members)\n\n",
+            self.list, self.members
+        );
+        text += &match self.average_degree {
+            Some(avg) => format!("Average degree: {avg:.2}\n"),
+            None => "Average degree: no connection found\n".to_string(),
+        };
+        text += &format!("Most connected: {}\n", describe(&self.most_connected));
+        text += &format!("Most isolated: {}\n", describe(&self.most_isolated));
+        text += &format!(
+            "\nPairs: {} connected, {} not found",
+            self.pairs_found, self.pairs_not_found
+        );
+        if self.pairs_skipped > 0 {
+            text += &format!(", {} skipped by the time budget", self.pairs_skipped);
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Network;
+    use crate::test_utils::*;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    #[tokio::test]
+    async fn leaderboard_of_a_chain_and_an_isolated_member() {
+        // Members 0 - 1 - 2 - 3 are a chain of mutuals, 4 only follows 5, who has no contact list
+        let keys = keys(6);
+        let users = keys.iter().map(|x| x.public_key()).collect_vec();
+        let events = contact_lists(&keys, &[&[1], &[0, 2], &[1, 3], &[2], &[5]]);
+        let relay = FakeRelay::start(events).await;
+        let service = SearchService::new(
+            Arc::new(client(&[&relay]).await),
+            Arc::new(Mutex::new(Network::new())),
+            SearchOptions::default(),
+            None,
+        );
+
+        let board = compute("fixture", &users[..5], &service, Duration::from_secs(60)).await;
+        assert_eq!(board.members, 5);
+        assert_eq!(
+            (
+                board.pairs_found,
+                board.pairs_not_found,
+                board.pairs_skipped
+            ),
+            (6, 4, 0)
+        );
+        // 1 + 2 + 3 + 1 + 2 + 1 over 6 pairs
+        assert!((board.average_degree.unwrap() - 10.0 / 6.0).abs() < 1e-9);
+
+        let most_connected = board.most_connected.unwrap();
+        assert_eq!(most_connected.npub, users[1].to_bech32().unwrap());
+        assert_eq!(most_connected.connected, 3);
+        assert!((most_connected.average_degree.unwrap() - 4.0 / 3.0).abs() < 1e-9);
+
+        let most_isolated = board.most_isolated.unwrap();
+        assert_eq!(most_isolated.npub, users[4].to_bech32().unwrap());
+        assert_eq!(
+            (most_isolated.connected, most_isolated.average_degree),
+            (0, None)
+        );
+    }
+}
@@ -9,16 +9,29 @@ use std::borrow::Borrow;
 use std::collections::HashSet;
 use std::fs;
 use std::future::Future;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::join;
 use tokio::sync::Mutex;
 use tokio::time::interval;
 
+use crate::alerts::{AlertClass, AlertConfig, Alerter};
 use crate::client_utils::*;
-use crate::network::Network;
+use crate::external_graph::{ExternalGraph, ExternalGraphConfig};
+use crate::footer::{self, RequestUsage};
+use crate::history::History;
+use crate::network::filters::CrawlFilters;
+use crate::network::persist::{self, CacheMode};
+use crate::network::{Network, PathMode, PruneOptions};
+use crate::outbox::{Outbox, OutboxPace};
+use crate::privacy;
+use crate::quiet_hours::{Clock, QuietHours};
+use crate::search_service::SearchService;
+use crate::sep_degrees::{self, Degree, OfflineAnswers, SearchOptions, SearchOverrides};
+use crate::supervisor::{Supervisor, TaskContext, TaskSettings};
 use crate::user::User;
+use crate::watchdog::{self, WatchdogConfig};
 
 use nostr_sdk::prelude::*;
 
@@ -27,31 +40,427 @@ use std::env;
 #[derive(Debug, Serialize, Deserialize)]
 struct Responded(HashSet<EventId>);
 
+/// Mention whose handler panicked. It counts as responded, so it isn't retried until requeued
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct FailedMention {
+    id: EventId,
+    at: Timestamp,
+    /// The panic message
+    error: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Config {
     responded: Responded,
     wait_time_secs: u64,
+    /// Minutes between saves of the network, 0 disables it
+    #[serde(default = "default_autosave_interval_mins")]
+    autosave_interval_mins: u64,
+    #[serde(default = "default_cache_path")]
+    cache_path: String,
+    /// How many older copies of the cache are kept
+    #[serde(default = "default_cache_generations")]
+    cache_generations: usize,
+    /// readonly to load the cache without ever writing it, e.g. when another instance crawls and
+    /// saves it. Searches then answer from the cache first
+    #[serde(default)]
+    cache_mode: CacheMode,
+    /// In readonly cache mode, search relays when the cache has no path
+    #[serde(default = "default_readonly_relay_fallback")]
+    readonly_relay_fallback: bool,
+    /// bincode, bincode_zstd or json. Told from the cache path extension when missing
+    #[serde(default)]
+    cache_format: Option<persist::CacheFormat>,
+    /// Append a line with usage metrics to replies
+    #[serde(default)]
+    usage_footer: bool,
+    #[serde(default = "footer::default_template")]
+    footer_template: String,
+    /// Number of answered requests, kept across restarts
+    #[serde(default)]
+    request_count: u64,
+    /// Log the mention contents, redacted and truncated. Otherwise only event ids and results are logged
+    #[serde(default)]
+    debug_store_content: bool,
+    /// Timeout of contact list queries, 0 uses the library default
+    #[serde(default = "default_timeout_secs")]
+    contact_list_timeout_secs: u64,
+    /// Timeout of metadata queries, 0 uses the library default
+    #[serde(default = "default_timeout_secs")]
+    metadata_timeout_secs: u64,
+    /// Replies add a caveat when some contact list of the path was returned by fewer relays. 0 disables it
+    #[serde(default)]
+    min_corroborating_relays: usize,
+    /// Read the users to search from the content first (content_first) or from the p tags (tags_first)
+    #[serde(default)]
+    target_precedence: TargetPrecedence,
+    /// Cached contact lists older than this are fetched again, 0 keeps them forever
+    #[serde(default = "default_contact_list_ttl_secs")]
+    contact_list_ttl_secs: u64,
+    /// Most time a search takes, split among its contact list fetches. 0 doesn't limit it
+    #[serde(default)]
+    search_budget_secs: u64,
+    /// Seconds between removals of the users that only take memory, 0 disables them. Contact
+    /// lists older than contact_list_ttl_secs are also dropped then
+    #[serde(default = "default_prune_interval_secs")]
+    prune_interval_secs: u64,
+    /// Keep the distances from this user through follows ready, for the users within 3 hops
+    #[serde(default)]
+    root_npub: Option<String>,
+    /// Accounts "score" mentions are scored against. The 1000 most followed users of the network
+    /// when empty
+    #[serde(default)]
+    score_reference_npubs: Vec<String>,
+    /// Replies not sent yet, kept for the next run
+    #[serde(default = "default_outbox_path")]
+    outbox_path: String,
+    /// Seconds between replies, at least 1, with a random jitter of up to half of it
+    #[serde(default = "default_reply_interval_secs")]
+    reply_interval_secs: u64,
+    /// Replies no relay accepted are tried this many times before giving up
+    #[serde(default = "default_reply_max_attempts")]
+    reply_max_attempts: u32,
+    /// Direct message the admin when errors keep happening
+    #[serde(default)]
+    alerts: Option<AlertConfig>,
+    /// Every search answered is appended here, see --replay-history. Unset disables it
+    #[serde(default)]
+    history_path: Option<String>,
+    /// Seconds between checks of the relay connections, 0 disables them
+    #[serde(default = "default_watchdog_interval_secs")]
+    watchdog_interval_secs: u64,
+    /// Relays that haven't answered for this many seconds are reconnected
+    #[serde(default = "default_relay_idle_secs")]
+    relay_idle_secs: u64,
+    /// When every relay is dead for this many seconds, all of them are connected again
+    #[serde(default = "default_client_reset_secs")]
+    client_reset_secs: u64,
+    /// Don't find paths through users muted by the first user of a search
+    #[serde(default)]
+    avoid_muted: bool,
+    /// Fetch metadata along with contact lists while searching, using more bandwidth
+    #[serde(default)]
+    prefetch_metadata: bool,
+    /// Among paths as short, prefer the ones not going through this many of the top bridges of
+    /// the network. 0 doesn't look for them
+    #[serde(default)]
+    avoid_hubs: usize,
+    /// Don't find paths through users following more than this many. 0 goes through all of them
+    #[serde(default)]
+    search_max_follows: usize,
+    /// Don't find paths through users whose profile has neither a name nor a NIP-05 address
+    #[serde(default)]
+    search_require_name: bool,
+    /// Don't find paths through users whose name matches this regex
+    #[serde(default)]
+    search_name_blocklist: Option<String>,
+    /// Most hops searched, up to MAX_DEPTH_CEILING. #maxdepth in a mention can only lower it
+    #[serde(default = "default_max_depth")]
+    max_depth: u32,
+    /// Seconds a found path answers the same pair of users again without searching, 0 disables it
+    #[serde(default = "default_result_cache_secs")]
+    result_cache_secs: u64,
+    /// Like "23:00-08:00". Alerts wait for the end of these hours, replies still go out
+    #[serde(default)]
+    quiet_hours: Option<String>,
+    /// UTC offset of the quiet hours, like "-03:00". The local time of the machine when unset
+    #[serde(default)]
+    quiet_hours_utc_offset: Option<String>,
+    /// Time zone of the quiet hours, like "America/Sao_Paulo", following its DST changes.
+    /// Instead of the UTC offset
+    #[serde(default)]
+    quiet_hours_time_zone: Option<String>,
+    /// Indexer asked for the contact lists no relay has. Unset only uses relays
+    #[serde(default)]
+    external_graph: Option<ExternalGraphConfig>,
+    /// Reply to mentions whose handler panicked. {id} is replaced by the note id of the mention
+    #[serde(default = "default_internal_error_reply")]
+    internal_error_reply: String,
+    /// Mentions whose handler panicked, see --requeue-mention
+    #[serde(default)]
+    failed: Vec<FailedMention>,
+}
+
+fn default_autosave_interval_mins() -> u64 {
+    60
+}
+
+fn default_cache_path() -> String {
+    "cache.bin".to_string()
+}
+
+fn default_readonly_relay_fallback() -> bool {
+    true
+}
+
+fn default_cache_generations() -> usize {
+    3
+}
+
+fn default_outbox_path() -> String {
+    "outbox.json".to_string()
+}
+
+fn default_reply_interval_secs() -> u64 {
+    5
+}
+
+fn default_reply_max_attempts() -> u32 {
+    3
+}
+
+fn default_watchdog_interval_secs() -> u64 {
+    5 * 60
+}
+
+fn default_relay_idle_secs() -> u64 {
+    15 * 60
+}
+
+fn default_client_reset_secs() -> u64 {
+    30 * 60
+}
+
+fn default_max_depth() -> u32 {
+    sep_degrees::DEFAULT_MAX_DEPTH.0
+}
+
+fn default_result_cache_secs() -> u64 {
+    10 * 60
+}
+
+fn default_internal_error_reply() -> String {
+    "Sorry, I ran into an internal error answering this. The admin was told about it ({id})"
+        .to_string()
+}
+
+impl Config {
+    /// Settings of the background tasks. Nothing is pruned or autosaved with a read-only cache
+    fn task_settings(&self) -> TaskSettings {
+        let read_only = self.cache_mode == CacheMode::ReadOnly;
+        let every = |secs: u64| (secs > 0).then(|| Duration::from_secs(secs));
+        TaskSettings {
+            outbox: OutboxPace {
+                interval: Duration::from_secs(self.reply_interval_secs.max(1)),
+                max_attempts: self.reply_max_attempts,
+            },
+            watchdog: every(self.watchdog_interval_secs).map(|interval| WatchdogConfig {
+                interval,
+                idle_after: Duration::from_secs(self.relay_idle_secs),
+                reset_after: Duration::from_secs(self.client_reset_secs),
+            }),
+            prune_interval: every(self.prune_interval_secs).filter(|_| !read_only),
+            contact_list_ttl: sep_degrees::contact_list_ttl_from_secs(self.contact_list_ttl_secs),
+            autosave_interval: every(self.autosave_interval_mins * 60).filter(|_| !read_only),
+        }
+    }
+
+    /// Take the settings of the background tasks that can change while running from `other`,
+    /// the config file read again
+    fn reload_task_fields(&mut self, other: &Config) {
+        self.reply_interval_secs = other.reply_interval_secs;
+        self.reply_max_attempts = other.reply_max_attempts;
+        self.watchdog_interval_secs = other.watchdog_interval_secs;
+        self.relay_idle_secs = other.relay_idle_secs;
+        self.client_reset_secs = other.client_reset_secs;
+        self.prune_interval_secs = other.prune_interval_secs;
+        self.autosave_interval_mins = other.autosave_interval_mins;
+    }
+}
+
+fn default_timeout_secs() -> u64 {
+    DEFAULT_QUERY_TIMEOUT_SECS
+}
+
+fn default_contact_list_ttl_secs() -> u64 {
+    sep_degrees::DEFAULT_CONTACT_LIST_TTL_SECS
+}
+
+fn default_prune_interval_secs() -> u64 {
+    6 * 60 * 60
+}
+
+/// Text of a panic payload, which is a &str or a String when made by panic!
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown panic".to_string(),
+        },
+    }
+}
+
+/// Record a mention whose handler panicked as failed and responded, so it isn't retried in a
+/// crash loop, and tell who asked that it went wrong
+async fn fail_mention(
+    config: &Mutex<Config>,
+    config_path: &str,
+    mention: &Event,
+    error: String,
+    factory: &EventFactory,
+    client: &Client,
+    outbox: &Outbox,
+) {
+    let id = mention.id.to_bech32().unwrap();
+    eprintln!("Handler of {id} panicked: {error}");
+    let reply = {
+        let mut config_lock = config.lock().await;
+        config_lock.responded.0.insert(mention.id);
+        config_lock.failed.push(FailedMention {
+            id: mention.id,
+            at: Timestamp::now(),
+            error,
+        });
+        fs::write(
+            config_path,
+            toml::to_string::<Config>(&config_lock).unwrap(),
+        )
+        .unwrap();
+        config_lock.internal_error_reply.replace("{id}", &id)
+    };
+    match factory
+        .sign(
+            client,
+            factory.reply(mention, &reply),
+            outbox.pow_required(),
+        )
+        .await
+    {
+        Ok(reply) => outbox.push(reply).await,
+        Err(err) => eprintln!("Reply error: {err}"),
+    };
+}
+
+/// Answer the mentions again, forgetting they were responded or failed
+fn requeue_mentions(config: &mut Config, requeue: &[EventId]) {
+    for id in requeue {
+        if config.responded.0.remove(id) {
+            println!("Requeued {}", id.to_bech32().unwrap());
+        } else {
+            eprintln!(
+                "{} wasn't responded, nothing to requeue",
+                id.to_bech32().unwrap()
+            );
+        }
+    }
+    config.failed.retain(|x| !requeue.contains(&x.id));
+}
+
+/// Snapshot of the network and its mutation count, or None when it didn't change since the save
+/// at `last_saved`
+fn autosave_snapshot(
+    network: &Network,
+    last_saved: u64,
+) -> Option<(u64, persist::NetworkSnapshot)> {
+    let mutations = network.mutation_count();
+    (mutations != last_saved).then(|| (mutations, network.to_snapshot()))
+}
+
+/// Periodically save the network to disk, skipping when it didn't change since the last save
+async fn autosave_network(
+    network: Arc<Mutex<Network>>,
+    cache_path: String,
+    generations: usize,
+    format: persist::CacheFormat,
+    alerter: Arc<Alerter>,
+    mut context: TaskContext,
+) {
+    let mut last_saved = network.lock().await.mutation_count();
+    while context.sleep(|x| x.autosave_interval).await {
+        let Some((mutations, snapshot)) = autosave_snapshot(&*network.lock().await, last_saved)
+        else {
+            continue;
+        };
+
+        // Write outside of the lock, so searches aren't blocked by disk IO
+        let path = cache_path.clone();
+        let res = tokio::task::spawn_blocking(move || {
+            persist::save_rotating(&snapshot, Path::new(&path), generations, format)
+        })
+        .await;
+        match res {
+            Ok(Ok(())) => {
+                last_saved = mutations;
+                println!("Network saved to {cache_path}");
+                alerter.success(AlertClass::StateUnwritable).await;
+            }
+            Ok(Err(err)) => {
+                eprintln!("Autosave error: {}", err);
+                alerter
+                    .error(AlertClass::StateUnwritable, format!("autosave: {err}"))
+                    .await;
+            }
+            Err(err) => eprintln!("JoinError: {}", err),
+        }
+    }
+}
+
+/// Periodically remove from the network the users that only take memory, see Network::prune
+async fn prune_network(network: Arc<Mutex<Network>>, mut context: TaskContext) {
+    while context.sleep(|x| x.prune_interval).await {
+        let ttl = context.settings().contact_list_ttl;
+        // Wait for the searches in progress, which hold node indices
+        let _allow_del_lock = {
+            let lock = network.lock().await;
+            lock.get_delete_lock()
+        }
+        .write_owned()
+        .await;
+        let options = PruneOptions {
+            stale_before: ttl.map(|x| Timestamp::now() - x),
+        };
+        let report = network.lock().await.prune(options);
+        println!("Pruned the network: {report}");
+    }
+}
+
+/// Answer of a mention as it's logged, without the users it's about
+pub trait LoggedAnswer: std::fmt::Debug {
+    /// What was answered and how it went, like "separation: not found"
+    fn outcome(&self) -> String;
+
+    /// Degrees of separation found, if the answer is a path
+    fn degree(&self) -> Option<Degree>;
 }
 
 /// Listen for mentions to the key configured in user
 ///
 /// action: Processing of the collected event
 ///
-/// second_action: Action with the result of action, e.g. send a reply
+/// second_action: Action with the result of action, e.g. queue a reply in the outbox. It's given the
+/// request usage when the usage footer is enabled
+///
+/// overrides: Replace the search options of the config file given to action
+///
+/// external_graph: Replaces the one of the config file
+///
+/// requeue: Mentions answered again, e.g. after fixing what made their handler panic
+///
+/// factory: Builds the reply sent when a handler panics
+#[allow(clippy::too_many_arguments)]
 pub async fn listen_mention<T1, T2, S, F>(
     client: &Arc<Client>,
     user: User,
+    network: Arc<Mutex<Network>>,
     config_path: &str,
-    action: impl Fn(Event, S) -> T1 + Clone + Send + 'static,
+    overrides: SearchOverrides,
+    external_graph: Option<ExternalGraphConfig>,
+    requeue: &[EventId],
+    factory: EventFactory,
+    action: impl Fn(Event, S, Arc<SearchService>) -> T1 + Clone + Send + 'static,
     action_args: S,
-    second_action: impl Fn(Event, T2, Arc<Client>) -> F + Clone + Send + 'static,
+    second_action: impl Fn(Event, T2, Option<RequestUsage>, Arc<Client>, Arc<Outbox>) -> F
+        + Clone
+        + Send
+        + 'static,
 ) where
     T1: Future<Output = T2> + Send + 'static,
-    T2: std::fmt::Debug + Send + Sync + 'static,
+    T2: LoggedAnswer + Send + Sync + 'static,
     F: Future<Output = ()> + Send + 'static,
     S: Clone + Send + Sync + 'static,
 {
-    let config = match fs::read_to_string(config_path) {
+    let mut config = match fs::read_to_string(config_path) {
         Ok(config_text) => match toml::from_str::<Config>(&config_text) {
             Ok(ok) => ok,
             Err(err) => {
@@ -64,18 +473,227 @@ pub async fn listen_mention<T1, T2, S, F>(
             let config = Config {
                 responded: Responded(HashSet::new()),
                 wait_time_secs: 100,
+                autosave_interval_mins: default_autosave_interval_mins(),
+                cache_path: default_cache_path(),
+                cache_generations: default_cache_generations(),
+                cache_mode: CacheMode::default(),
+                readonly_relay_fallback: default_readonly_relay_fallback(),
+                cache_format: None,
+                usage_footer: false,
+                footer_template: footer::default_template(),
+                request_count: 0,
+                debug_store_content: false,
+                contact_list_timeout_secs: default_timeout_secs(),
+                metadata_timeout_secs: default_timeout_secs(),
+                min_corroborating_relays: 0,
+                target_precedence: TargetPrecedence::default(),
+                contact_list_ttl_secs: default_contact_list_ttl_secs(),
+                search_budget_secs: 0,
+                prune_interval_secs: default_prune_interval_secs(),
+                root_npub: None,
+                score_reference_npubs: vec![],
+                outbox_path: default_outbox_path(),
+                reply_interval_secs: default_reply_interval_secs(),
+                reply_max_attempts: default_reply_max_attempts(),
+                alerts: None,
+                history_path: None,
+                watchdog_interval_secs: default_watchdog_interval_secs(),
+                relay_idle_secs: default_relay_idle_secs(),
+                client_reset_secs: default_client_reset_secs(),
+                avoid_muted: false,
+                prefetch_metadata: false,
+                avoid_hubs: 0,
+                search_max_follows: 0,
+                search_require_name: false,
+                search_name_blocklist: None,
+                max_depth: default_max_depth(),
+                result_cache_secs: default_result_cache_secs(),
+                quiet_hours: None,
+                quiet_hours_utc_offset: None,
+                quiet_hours_time_zone: None,
+                external_graph: None,
+                internal_error_reply: default_internal_error_reply(),
+                failed: vec![],
             };
             fs::write(config_path, toml::to_string(&config).unwrap()).unwrap();
             config
         }
     };
+
+    if !requeue.is_empty() {
+        requeue_mentions(&mut config, requeue);
+        fs::write(config_path, toml::to_string(&config).unwrap()).unwrap();
+    }
+
+    let cache_path = Path::new(&config.cache_path);
+    if let Some((loaded, generation)) = persist::load_newest(cache_path, config.cache_generations) {
+        println!(
+            "Loaded network from {}",
+            persist::generation_path(cache_path, generation).display()
+        );
+        network.lock().await.merge(loaded);
+    }
+    if let Some(root) = &config.root_npub {
+        match PublicKey::parse(root) {
+            Ok(root) => network.lock().await.set_root(Some(root)),
+            Err(err) => eprintln!("Invalid root_npub {root}: {err}"),
+        }
+    }
+    match CrawlFilters::new(
+        config.search_max_follows,
+        config.search_require_name,
+        config.search_name_blocklist.as_deref(),
+    ) {
+        Ok(filters) => network.lock().await.set_search_filters(filters),
+        Err(err) => {
+            eprintln!("Invalid search_name_blocklist: {err}");
+            return;
+        }
+    }
+    if config.max_depth > sep_degrees::MAX_DEPTH_CEILING.0 {
+        eprintln!(
+            "Invalid max_depth {}: it can't be over {}",
+            config.max_depth,
+            sep_degrees::MAX_DEPTH_CEILING
+        );
+        return;
+    }
+    let alerter = match &config.alerts {
+        Some(alerts) => match Alerter::new(alerts, client.clone()) {
+            Ok(alerter) => alerter,
+            Err(err) => {
+                eprintln!("Invalid admin_npub {}: {err}", alerts.admin_npub);
+                return;
+            }
+        },
+        None => Alerter::disabled(),
+    };
+    let alerter = match &config.quiet_hours {
+        Some(quiet_hours) => {
+            let quiet = quiet_hours.parse::<QuietHours>().and_then(|hours| {
+                Ok((
+                    hours,
+                    Clock::from_config(
+                        config.quiet_hours_utc_offset.as_deref(),
+                        config.quiet_hours_time_zone.as_deref(),
+                    )?,
+                ))
+            });
+            match quiet {
+                Ok((hours, clock)) => alerter.with_quiet_hours(hours, clock),
+                Err(err) => {
+                    eprintln!("Config file error: {err}");
+                    return;
+                }
+            }
+        }
+        None => alerter,
+    };
+    let alerter = Arc::new(alerter);
+    // Every task runs, the ones disabled in the config wait until they're enabled
+    let mut supervisor = Supervisor::new(config.task_settings());
+    if config.quiet_hours.is_some() {
+        supervisor.spawn("deferred alerts", |context| {
+            alerter.clone().run_deferred(context)
+        });
+    }
+    let outbox = Arc::new(Outbox::load(PathBuf::from(&config.outbox_path)));
+    supervisor.spawn("outbox", |context| {
+        outbox.clone().run(client.clone(), alerter.clone(), context)
+    });
+    supervisor.spawn("watchdog", |context| watchdog::run(client.clone(), context));
+    let read_only = config.cache_mode == CacheMode::ReadOnly;
+    println!("Cache {} mode: {}", config.cache_path, config.cache_mode);
+    supervisor.spawn("prune", |context| prune_network(network.clone(), context));
+    supervisor.spawn("autosave", |context| {
+        autosave_network(
+            network.clone(),
+            config.cache_path.clone(),
+            config.cache_generations,
+            config
+                .cache_format
+                .unwrap_or_else(|| persist::CacheFormat::from_path(cache_path)),
+            alerter.clone(),
+            context,
+        )
+    });
+
+    let options = overrides.apply(SearchOptions {
+        timeouts: QueryTimeouts::from_secs(
+            config.contact_list_timeout_secs,
+            config.metadata_timeout_secs,
+        ),
+        min_corroborating_relays: config.min_corroborating_relays,
+        target_precedence: config.target_precedence,
+        contact_list_ttl: sep_degrees::contact_list_ttl_from_secs(config.contact_list_ttl_secs),
+        budget: sep_degrees::search_budget_from_secs(config.search_budget_secs),
+        avoid_muted: config.avoid_muted,
+        prefetch_metadata: config.prefetch_metadata,
+        offline: match (read_only, config.readonly_relay_fallback) {
+            (false, _) => OfflineAnswers::Off,
+            (true, true) => OfflineAnswers::First,
+            (true, false) => OfflineAnswers::Only,
+        },
+        max_depth: Some(Degree(config.max_depth.max(1))),
+        path_mode: PathMode::Mutual,
+        avoid_hubs: config.avoid_hubs,
+    });
+    let mut service = SearchService::new(
+        client.clone(),
+        network,
+        options,
+        (config.result_cache_secs > 0).then(|| Duration::from_secs(config.result_cache_secs)),
+    );
+    let external_graph = match external_graph.as_ref().or(config.external_graph.as_ref()) {
+        Some(external) => match ExternalGraph::from_config(external) {
+            Ok(ok) => Some(ok),
+            Err(err) => {
+                eprintln!("{err}");
+                supervisor.shutdown().await;
+                return;
+            }
+        },
+        None => None,
+    };
+    service = service.with_external_graph(external_graph);
+    let score_reference = config
+        .score_reference_npubs
+        .iter()
+        .filter_map(|npub| match PublicKey::parse(npub) {
+            Ok(user) => Some(user),
+            Err(err) => {
+                eprintln!("Invalid score_reference_npubs entry {npub}: {err}");
+                None
+            }
+        })
+        .collect();
+    service = service.with_score_reference(score_reference);
+    if let Some(path) = &config.history_path {
+        let cache_path =
+            (config.autosave_interval_mins > 0 || read_only).then(|| cache_path.to_path_buf());
+        service = service.with_history(History::new(PathBuf::from(path), cache_path));
+    }
+    let service = Arc::new(service);
     let config = Arc::new(Mutex::new(config));
     let wait_time = config.lock().await.wait_time_secs;
     let mut delay = interval(Duration::from_secs(wait_time));
     loop {
         delay.tick().await;
+        // Intervals of the background tasks edited in the config file apply from now
+        match fs::read_to_string(config_path)
+            .map_err(|x| x.to_string())
+            .and_then(|text| toml::from_str::<Config>(&text).map_err(|x| x.to_string()))
+        {
+            Ok(file) => {
+                let mut config_lock = config.lock().await;
+                config_lock.reload_task_fields(&file);
+                supervisor.update(config_lock.task_settings());
+            }
+            Err(err) => eprintln!("Config file reload error: {err}"),
+        }
         // Listen for mentions
         println!("Looking for new mentions");
+        relay_health().log(true);
         let mentions = {
             let config_lock = config.lock().await;
             match listen_mentions(&client, user.public_key(), None).await {
@@ -84,6 +702,7 @@ pub async fn listen_mention<T1, T2, S, F>(
                     let val;
                     loop {
                         eprintln!("Listen mentions error: {}", err);
+                        alerter.error(AlertClass::RelaysDown, &err).await;
                         let res = listen_mentions(&client, user.public_key(), None).await;
                         if let Ok(ok) = res {
                             val = ok;
@@ -98,32 +717,74 @@ pub async fn listen_mention<T1, T2, S, F>(
             .filter(|event| !config_lock.responded.0.contains(&event.id))
             .collect_vec()
         };
+        alerter.success(AlertClass::RelaysDown).await;
 
         let mut tasks = vec![];
+        // The mention of each task, answered by the listener if the task panics
+        let mut handled = vec![];
         for mention in mentions {
+            #[allow(clippy::too_many_arguments)]
             async fn block<T1, T2, S, F>(
                 client: Arc<Client>,
                 config: Arc<Mutex<Config>>,
                 mention: Event,
                 config_path: String,
-                action: impl Fn(Event, S) -> T1,
+                action: impl Fn(Event, S, Arc<SearchService>) -> T1,
                 action_args: S,
-                second_action: impl Fn(Event, T2, Arc<Client>) -> F + Clone + Send + 'static,
+                service: Arc<SearchService>,
+                second_action: impl Fn(Event, T2, Option<RequestUsage>, Arc<Client>, Arc<Outbox>) -> F
+                    + Clone
+                    + Send
+                    + 'static,
+                outbox: Arc<Outbox>,
             ) where
                 T1: Future<Output = T2> + Send,
-                T2: std::fmt::Debug + Send,
+                T2: LoggedAnswer + Send,
                 F: Future + Send + 'static,
             {
                 let mention_id = mention.id;
 
                 println!("Read {}", mention_id.to_bech32().unwrap());
-                let mut ret = action(mention.clone(), action_args).await;
+                if config.lock().await.debug_store_content {
+                    println!(
+                        "Content of {}: {}",
+                        mention_id.to_bech32().unwrap(),
+                        privacy::loggable_content(&mention.content)
+                    );
+                }
+                let start = Instant::now();
+                let ret = action(mention.clone(), action_args, service).await;
+                let degree = ret
+                    .degree()
+                    .map(|x| format!(", degree {}", x.0))
+                    .unwrap_or_default();
                 println!(
-                    "Produced answer: {:?} to {}",
-                    ret,
-                    mention_id.to_bech32().unwrap()
+                    "Produced answer to {}: {}{degree}",
+                    mention_id.to_bech32().unwrap(),
+                    ret.outcome()
                 );
-                second_action(mention, ret, client).await;
+                if config.lock().await.debug_store_content {
+                    println!(
+                        "Answer to {}: {}",
+                        mention_id.to_bech32().unwrap(),
+                        privacy::loggable_content(&format!("{ret:?}"))
+                    );
+                }
+
+                let usage = {
+                    let mut config_lock = config.lock().await;
+                    config_lock.request_count += 1;
+                    if config_lock.usage_footer {
+                        Some(RequestUsage {
+                            number: config_lock.request_count,
+                            elapsed: start.elapsed(),
+                            template: config_lock.footer_template.clone(),
+                        })
+                    } else {
+                        None
+                    }
+                };
+                second_action(mention, ret, usage, client, outbox).await;
 
                 let mut config_lock = config.lock().await;
                 config_lock.responded.0.insert(mention_id);
@@ -134,6 +795,7 @@ pub async fn listen_mention<T1, T2, S, F>(
                 .unwrap();
             }
 
+            handled.push(mention.clone());
             tasks.push(tokio::task::spawn(block(
                 client.clone(),
                 config.clone(),
@@ -141,18 +803,149 @@ pub async fn listen_mention<T1, T2, S, F>(
                 config_path.to_string(),
                 action.clone(),
                 action_args.clone(),
+                service.clone(),
                 second_action.clone(),
+                outbox.clone(),
             )));
         }
         let tasks_len = tasks.len();
         if tasks_len == 0 {
             println!("No new mention found. Waiting {} seconds", wait_time);
         }
-        for val in join_all(tasks).await {
+        let mut panicked = false;
+        for (mention, val) in handled.into_iter().zip(join_all(tasks).await) {
             match val {
                 Ok(()) => (),
-                Err(err) => eprintln!("JoinError: {}", err),
+                Err(err) => {
+                    eprintln!("JoinError: {}", err);
+                    alerter.error(AlertClass::HandlerPanic, &err).await;
+                    panicked = true;
+                    if err.is_panic() {
+                        let error = panic_message(err.into_panic());
+                        fail_mention(
+                            &config,
+                            config_path,
+                            &mention,
+                            error,
+                            &factory,
+                            client,
+                            &outbox,
+                        )
+                        .await;
+                    }
+                }
             }
         }
+        if tasks_len > 0 && !panicked {
+            alerter.success(AlertClass::HandlerPanic).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn autosave_skips_an_unchanged_network() {
+        let users = pubkeys(3);
+        let mut network = network(&users, &[(0, &[1])]);
+        let (saved, _) = autosave_snapshot(&network, 0).unwrap();
+        assert!(autosave_snapshot(&network, saved).is_none());
+
+        network.update_contact_list(users[0], &[users[1], users[2]], &Timestamp::from(2000));
+        let (mutations, snapshot) = autosave_snapshot(&network, saved).unwrap();
+        assert_eq!(Network::from_snapshot(snapshot).unwrap().edge_count(), 2);
+        assert!(autosave_snapshot(&network, mutations).is_none());
+    }
+
+    #[test]
+    fn request_count_survives_a_restart() {
+        let mut config: Config = toml::from_str("responded = []\nwait_time_secs = 10\n").unwrap();
+        assert_eq!(config.request_count, 0);
+        config.request_count += 1204;
+        let config: Config = toml::from_str(&toml::to_string(&config).unwrap()).unwrap();
+        assert_eq!(config.request_count, 1204);
+    }
+
+    #[tokio::test]
+    async fn panic_messages_are_read_from_the_payload() {
+        let join = |task: fn()| async move {
+            panic_message(
+                tokio::spawn(async move { task() })
+                    .await
+                    .unwrap_err()
+                    .into_panic(),
+            )
+        };
+        assert_eq!(join(|| panic!("static message")).await, "static message");
+        assert_eq!(
+            join(|| panic!("formatted {}", "message")).await,
+            "formatted message"
+        );
+        assert_eq!(join(|| std::panic::panic_any(42)).await, "unknown panic");
+    }
+
+    #[tokio::test]
+    async fn mention_whose_handler_panicked_is_answered_recorded_and_requeued() {
+        let users = keys(2);
+        let mention = EventBuilder::text_note("nostr:npub... how far?", [])
+            .to_event(&users[1])
+            .unwrap();
+        let relay = FakeRelay::start(vec![]).await;
+        let client = client(&[&relay]).await;
+        let config_path = temp_path("listen-failed.toml");
+        let outbox_path = temp_path("listen-failed-outbox.json");
+        let outbox = Outbox::load(outbox_path.clone());
+        let config: Config = toml::from_str("responded = []\nwait_time_secs = 10\n").unwrap();
+        let config = Mutex::new(config);
+
+        fail_mention(
+            &config,
+            config_path.to_str().unwrap(),
+            &mention,
+            "index out of bounds".to_string(),
+            &EventFactory::default(),
+            &client,
+            &outbox,
+        )
+        .await;
+
+        // Recorded as failed and responded, also in the saved config
+        let saved: Config = toml::from_str(&fs::read_to_string(&config_path).unwrap()).unwrap();
+        for config in [&*config.lock().await, &saved] {
+            assert!(config.responded.0.contains(&mention.id));
+            assert_eq!(config.failed.len(), 1);
+            assert_eq!(config.failed[0].id, mention.id);
+            assert_eq!(config.failed[0].error, "index out of bounds");
+        }
+
+        // The reply waits in the outbox, telling the id of the mention
+        let queued: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&outbox_path).unwrap()).unwrap();
+        let reply: Event = serde_json::from_value(queued[0]["event"].clone()).unwrap();
+        assert!(reply.content.contains(&mention.id.to_bech32().unwrap()));
+        assert!(reply.event_ids().any(|x| *x == mention.id));
+
+        let mut config = saved;
+        requeue_mentions(&mut config, &[mention.id]);
+        assert!(!config.responded.0.contains(&mention.id));
+        assert!(config.failed.is_empty());
+    }
+
+    #[test]
+    fn read_only_cache_is_never_pruned_or_autosaved() {
+        let text = "responded = []\nwait_time_secs = 10\nautosave_interval_mins = 5\n";
+        let config: Config = toml::from_str(text).unwrap();
+        let settings = config.task_settings();
+        assert!(settings.prune_interval.is_some());
+        assert_eq!(settings.autosave_interval, Some(Duration::from_secs(300)));
+
+        let config: Config = toml::from_str(&format!("{text}cache_mode = \"readonly\"\n")).unwrap();
+        assert_eq!(config.cache_mode, CacheMode::ReadOnly);
+        let settings = config.task_settings();
+        assert_eq!(settings.prune_interval, None);
+        assert_eq!(settings.autosave_interval, None);
     }
 }
@@ -1,22 +1,54 @@
-use clap::ArgGroup;
-use clap::ValueHint;
-#[allow(unused)]
-use clap::{arg, command, value_parser, Arg, ArgAction, Command};
-use network::follow::FollowNetwork;
-use sep_degrees::from_pubkeys;
+use clap::ArgMatches;
+use external_graph::{ExternalGraph, ExternalGraphConfig};
+use history::{History, Outcome, ReplayComparison};
+use itertools::Itertools;
+use network::diff::{ContactListPresence, DiffDisplay};
+use network::export::{DotOptions, EdgeLabels, ExportFormat};
+use network::follow::{
+    Checkpoint, FollowNetwork, FollowPackInfo, FollowPackSource, LevelIdx, RankConfig,
+};
+use pow::PowLimits;
+use search_service::{SearchService, SeparationRequest};
+use sep_degrees::{SearchOptions, SearchOverrides};
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
 
+mod alerts;
+mod cli;
 mod client_utils;
+mod connection_key;
+mod external_graph;
+mod footer;
+mod history;
+mod instance_lock;
+mod leaderboard;
 mod listen;
 mod map_intersect;
+mod mention_command;
+mod mention_options;
 mod network;
+mod nip05;
+mod outbox;
+mod pow;
+mod privacy;
+mod quiet_hours;
+mod search_service;
+mod self_test;
 mod sep_degrees;
+mod supervisor;
+mod target_lists;
+#[cfg(test)]
+mod test_utils;
 mod user;
+mod watchdog;
 
 use client_utils::*;
-use network::Network;
+use connection_key::ConnectionKeyError;
+use network::{Network, PathMode};
 use user::User;
 
 use nostr_sdk::prelude::*;
@@ -26,105 +58,332 @@ use std::env;
 async fn start_connection(
     con_keys: Keys,
     my_pubkey: PublicKey,
-) -> (
-    Arc<nostr_sdk::Client>,
-    User,
-    Arc<tokio::sync::Mutex<Network>>,
-) {
-    let client = Arc::new(build_client(&con_keys).await);
+    relays: &[Url],
+    proxy: Option<SocketAddr>,
+    auth_relays: &[Url],
+    sources: LocalSources<'_>,
+) -> Result<
+    (
+        Arc<nostr_sdk::Client>,
+        User,
+        Arc<tokio::sync::Mutex<Network>>,
+    ),
+    BuildClientError,
+> {
+    let client = Arc::new(build_client(&con_keys, relays, proxy, auth_relays).await?);
     let user = User::new(my_pubkey, &client)
         .await
         .expect("User creation error");
-    let network = Arc::new(Mutex::new(Network::new()));
-    (client, user, network)
+    let mut network = Network::new();
+    if let Some(path) = sources.db {
+        attach_db(&mut network, path);
+    }
+    if let Some(path) = sources.import_events {
+        import_events(&mut network, path);
+    }
+    let network = Arc::new(Mutex::new(network));
+    Ok((client, user, network))
+}
+
+/// Where the network is filled from before asking relays
+#[derive(Debug, Clone, Copy, Default)]
+struct LocalSources<'a> {
+    /// See --db
+    db: Option<&'a Path>,
+    /// See --import-events
+    import_events: Option<&'a Path>,
+}
+
+fn import_events(network: &mut Network, path: &Path) {
+    let res = std::fs::File::open(path)
+        .and_then(|file| network.import_events(std::io::BufReader::new(file)));
+    match res {
+        Ok(report) => eprintln!("Imported {}: {}", path.display(), report),
+        Err(err) => {
+            eprintln!("Import of {} failed: {}", path.display(), err);
+            std::process::exit(2);
+        }
+    }
+}
+
+/// Load the sqlite database, to be kept up to date while running. See --db
+#[cfg(feature = "sqlite")]
+fn attach_db(network: &mut Network, path: &Path) {
+    match network::db::ContactListDb::open(path).and_then(|db| network.attach_db(db)) {
+        Ok(()) => eprintln!("Loaded database {}", path.display()),
+        Err(err) => {
+            eprintln!("Database {} error: {}", path.display(), err);
+            std::process::exit(2);
+        }
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn attach_db(_network: &mut Network, _path: &Path) {
+    unreachable!("--db is refused without the sqlite feature")
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     env::set_var("RUST_BACKTRACE", "0");
 
-    let matches = command!()
-        .arg(
-            Arg::new("print rank")
-                .long("print-rank")
-                .action(ArgAction::SetTrue)
-                .help("Pretty print recommendations rank, based on --user-key value")
-                .requires("user key"),
-        )
-        .arg(
-            Arg::new("connection key")
-                .long("connection-key")
-                .help("Set connection authentication key. Required unless an nsec is provided otherwise")
-                .required_unless_present_any(["print rank", "run old"]),
-        )
-        .arg(
-            Arg::new("user key")
-                .long("user-key")
-                .help("User Nostr npub or nsec"),
-        )
-        .arg(
-            Arg::new("run old")
-                .long("run-old")
-                .action(ArgAction::SetTrue)
-                .hide(true),
-        )
-        .arg(
-            Arg::new("separation degrees")
-                .long("sep-degree")
-                .help("Find degree of separation between two users. Also outputs which users make the shortest path, if found")
-                .value_name("npub")
-                .num_args(2),
-        )
-        .arg(
-            Arg::new("listen mentions")
-                .long("listen-mentions")
-                .help("Listen for events that mention the client pubkey")
-                .value_name("config path")
-                .value_hint(ValueHint::FilePath)
-                .num_args(1)
-        )
-        .group(
-            ArgGroup::new("Mutually exclusive")
-                .args(["run old", "print rank", "separation degrees", "listen mentions"])
-                .multiple(false),
+    let matches = cli::command().get_matches();
+
+    // Modes that only read the cache, so they don't connect
+    if let Some(as_of) = matches.get_one::<Timestamp>("as of") {
+        return separation_as_of(&matches, *as_of);
+    }
+
+    if let Some(mut paths) = matches.get_many::<String>("convert cache") {
+        convert_cache(paths.next().unwrap(), paths.next().unwrap());
+        return Ok(());
+    }
+
+    if let Some(path) = matches.get_one::<String>("check cache") {
+        check_cache(path, matches.get_one::<bool>("repair") == Some(&true));
+        return Ok(());
+    }
+
+    if let Some(path) = matches.get_one::<String>("export") {
+        export(&matches, path);
+        return Ok(());
+    }
+
+    if matches.get_one::<bool>("components") == Some(&true) {
+        components(&matches);
+        return Ok(());
+    }
+
+    let sources = LocalSources {
+        db: matches.get_one::<String>("db").map(Path::new),
+        import_events: matches.get_one::<String>("import events").map(Path::new),
+    };
+    if sources.db.is_some() && cfg!(not(feature = "sqlite")) {
+        eprintln!("--db needs the sqlite feature, build with --features sqlite");
+        std::process::exit(2);
+    }
+
+    // Held until the listener exits
+    let _instance_lock = match matches.get_one::<String>("listen mentions") {
+        Some(config_path) => match instance_lock::acquire(
+            Path::new(config_path),
+            matches.get_one::<bool>("force takeover") == Some(&true),
+        ) {
+            Ok(lock) => Some(lock),
+            Err(err) => {
+                eprintln!("{err}");
+                std::process::exit(2);
+            }
+        },
+        None => None,
+    };
+
+    let relays_config = matches
+        .get_one::<String>("relays config")
+        .map(|x| x.as_str());
+    let mut relays = load_relays(
+        relays_config,
+        matches.get_one::<bool>("no default relays") != Some(&true),
+    );
+    let proxy = matches
+        .get_one::<SocketAddr>("proxy")
+        .copied()
+        .or(load_proxy(relays_config));
+    let auth_relays = load_auth_relays(relays_config);
+    if let Some(extra) = matches.get_many::<Url>("relay") {
+        relays.extend(extra.cloned());
+    }
+    if relays.is_empty() {
+        eprintln!("No relays to connect to: give them with --relay or --relays-config");
+        std::process::exit(2);
+    }
+    let timeout_args = (
+        matches.get_one::<u64>("timeout").copied(),
+        matches.get_one::<u64>("metadata timeout").copied(),
+    );
+    let timeouts = QueryTimeouts::from_secs(
+        timeout_args.0.unwrap_or(DEFAULT_QUERY_TIMEOUT_SECS),
+        timeout_args.1.unwrap_or(DEFAULT_QUERY_TIMEOUT_SECS),
+    );
+    // Only the options given in the command line override the listen config file
+    let overrides = SearchOverrides {
+        timeouts: (timeout_args != (None, None)).then_some(timeouts),
+        min_corroborating_relays: matches
+            .get_one::<usize>("min corroborating relays")
+            .copied(),
+        contact_list_ttl: matches
+            .get_one::<u64>("contact list ttl")
+            .map(|x| sep_degrees::contact_list_ttl_from_secs(*x)),
+        budget: matches
+            .get_one::<u64>("search budget")
+            .map(|x| sep_degrees::search_budget_from_secs(*x)),
+        avoid_muted: (matches.get_one::<bool>("avoid muted") == Some(&true)).then_some(true),
+        prefetch_metadata: (matches.get_one::<bool>("prefetch metadata") == Some(&true))
+            .then_some(true),
+        avoid_hubs: matches.get_one::<usize>("avoid hubs").copied(),
+        max_depth: matches
+            .get_one::<u32>("max depth")
+            .map(|x| sep_degrees::Degree(*x)),
+        path_mode: matches
+            .get_one::<String>("path mode")
+            .map(|x| match x.as_str() {
+                "directed" => PathMode::Directed,
+                "either" => PathMode::Either,
+                _ => PathMode::Mutual,
+            }),
+    };
+    let external_graph =
+        matches
+            .get_one::<String>("external graph url")
+            .map(|url| ExternalGraphConfig {
+                base_url: url.clone(),
+                api_key: matches.get_one::<String>("external graph api key").cloned(),
+                timeout_secs: *matches.get_one::<u64>("external graph timeout").unwrap(),
+                use_for_hops: matches.get_one::<bool>("external graph hops") == Some(&true),
+            });
+    let options = overrides.apply(SearchOptions {
+        timeouts,
+        contact_list_ttl: sep_degrees::contact_list_ttl_from_secs(
+            sep_degrees::DEFAULT_CONTACT_LIST_TTL_SECS,
+        ),
+        ..Default::default()
+    });
+
+    let factory = EventFactory {
+        expiration: matches
+            .get_one::<u64>("event expiration")
+            .map(|x| Duration::from_secs(*x)),
+        client_tag: matches.get_one::<bool>("client tag") == Some(&true),
+        hashtags: matches
+            .get_many::<String>("hashtag")
+            .map(|x| x.cloned().collect())
+            .unwrap_or_default(),
+        pow: PowLimits {
+            max_difficulty: *matches.get_one::<u8>("pow max difficulty").unwrap(),
+            budget: Duration::from_secs(*matches.get_one::<u64>("pow budget").unwrap()),
+        },
+    };
+
+    let print_relay_stats = || {
+        if matches.get_one::<bool>("relay stats") == Some(&true) {
+            eprint!("{}", relay_health().stats_table());
+        }
+    };
+
+    let connection_keys = || {
+        connection_key::load_connection_keys(
+            matches
+                .get_one::<String>("connection key")
+                .map(|x| x.as_str()),
+            matches
+                .get_one::<String>("connection key file")
+                .map(Path::new),
         )
-        .get_matches();
+    };
 
     if matches.get_one::<bool>("print rank") == Some(&true) {
         print_rank(
-            matches.get_one::<String>("user key").unwrap(),
-            matches
-                .get_one::<String>("connection key")
-                .map(|x| x.as_str())
-                .or(Some("put the bot nsec here"))
-                .unwrap(),
+            rank_args(&matches, &factory),
+            connection_keys,
+            &relays,
+            proxy,
+            &auth_relays,
+            timeouts,
+            sources,
         )
-        .await
-        .unwrap();
+        .await?;
+        print_relay_stats();
         return Ok(());
     }
 
-    let my_keys = match matches
-        .get_one::<String>("connection key")
-        .map(|x| x.as_str())
-    {
-        Some("new") => {
-            let keys = Keys::generate();
-            eprintln!(
-                "generated key: {} {}",
-                keys.secret_key().unwrap().to_bech32().unwrap(),
-                keys.public_key().to_bech32().unwrap()
-            );
-            keys
+    // Read before connecting, so a bad config fails fast
+    let self_test_config = match matches.get_one::<String>("self test") {
+        Some(path) => match self_test::load_config(path) {
+            Ok(config) => Some(config),
+            Err(err) => {
+                eprintln!("{err}");
+                std::process::exit(2);
+            }
+        },
+        None => None,
+    };
+
+    let my_keys = match connection_keys() {
+        Ok(keys) => keys,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(2);
         }
-        Some(s) => Keys::parse(s).unwrap(),
-        None => Err(nostr_sdk::key::Error::InvalidSecretKey).unwrap(),
     };
     let my_pubkey = my_keys.public_key();
-    let (client, user, network) = start_connection(my_keys, my_pubkey).await;
+    let (client, user, network) =
+        start_connection(my_keys, my_pubkey, &relays, proxy, &auth_relays, sources).await?;
+    let external = match external_graph.as_ref().map(ExternalGraph::from_config) {
+        Some(Ok(ok)) => Some(ok),
+        Some(Err(err)) => {
+            eprintln!("{err}");
+            std::process::exit(2);
+        }
+        None => None,
+    };
+    // One shot commands search each pair once, so they don't cache
+    let service = Arc::new(
+        SearchService::new(client.clone(), network.clone(), options, None)
+            .with_external_graph(external),
+    );
 
     if let Some(vals) = matches.get_many::<String>("separation degrees") {
-        sep_degrees::main(vals.map(|x| x.as_str()), &client, &network).await;
+        sep_degrees::main(
+            vals.map(|x| x.as_str()),
+            &service,
+            matches.get_one::<bool>("json") == Some(&true),
+            matches.get_one::<bool>("explain not found") == Some(&true),
+        )
+        .await;
+        print_relay_stats();
+        return Ok(());
+    }
+
+    if let Some(config) = &self_test_config {
+        let report = self_test::run(config, &service).await;
+        println!("{report}");
+        print_relay_stats();
+        if !report.passed() {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Some(list_name) = matches.get_one::<String>("leaderboard") {
+        leaderboard(&matches, list_name, &service, &client, &factory).await?;
+        print_relay_stats();
+        return Ok(());
+    }
+
+    if let Some(mut vals) = matches.get_many::<String>("community distance") {
+        let (user, list_name) = (vals.next().unwrap(), vals.next().unwrap());
+        community_distance(&matches, user, list_name, &service).await?;
+        print_relay_stats();
+        return Ok(());
+    }
+
+    if let Some(vals) = matches.get_many::<String>("common") {
+        common(vals.map(|x| x.as_str()), &client, &network, options).await?;
+        print_relay_stats();
+        return Ok(());
+    }
+
+    if let Some(path) = matches.get_one::<String>("replay history") {
+        let id = matches.get_one::<EventId>("request").unwrap();
+        replay_history(path, id, &client, &service).await?;
+        print_relay_stats();
+        return Ok(());
+    }
+
+    if let Some(followed) = matches.get_one::<String>("followers") {
+        let pages = *matches.get_one::<usize>("followers pages").unwrap();
+        followers(followed, pages, &client, &network, timeouts).await?;
+        print_relay_stats();
         return Ok(());
     }
 
@@ -132,76 +391,26 @@ async fn main() -> Result<()> {
         assert!(Path::new(config_path).is_file());
         let client_clone = client.clone();
 
-        async fn second_action(
-            event: Event,
-            result: Result<(u32, Vec<PublicKey>), sep_degrees::SepDegreeError>,
-            client: Arc<Client>,
-        ) {
-            let message = match result {
-                Ok((_, mut path)) => {
-                    let mut saudation = "Found Connection:\n\n".to_string();
-                    let last = path.pop().unwrap();
-                    for pubkey in path.iter() {
-                        saudation +=
-                            &format!("nostr:{} is mutual with\n", pubkey.to_bech32().unwrap());
-                    }
-                    if path.is_empty() {
-                        saudation += &format!(
-                            "nostr:{} is the sole one in this chain",
-                            last.to_bech32().unwrap()
-                        );
-                    } else {
-                        saudation += &format!("nostr:{}", last.to_bech32().unwrap());
-                    }
-                    saudation
-                }
-                Err(err) => match err {
-                    sep_degrees::SepDegreeError::TooFewArguments => {
-                        "Too few public keys in request. Use: mention me and then another 2 users!"
-                            .to_string()
-                    }
-                    sep_degrees::SepDegreeError::TooMuchArguments => {
-                        "Too much public keys in request. Use: mention me and then another 2 users!"
-                            .to_string()
-                    }
-                    sep_degrees::SepDegreeError::NostrClientError(_error) => {
-                        "Nostr client internal error".to_string()
-                    }
-                    sep_degrees::SepDegreeError::NotFound => {
-                        "Connection between users not found".to_string()
-                    }
-                    sep_degrees::SepDegreeError::MissingContactList(public_key) => format!(
-                        "Missing contact list of nostr:{}",
-                        public_key.to_bech32().unwrap()
-                    ),
-                },
-            };
-            match reply_to_text(&client, &event, &message).await {
-                Ok(ok) => println!("Sent event {}", ok.id()),
-                Err(err) => eprintln!("Reply error: {err}"),
-            };
-        }
-
+        let requeue = matches
+            .get_many::<EventId>("requeue mention")
+            .unwrap_or_default()
+            .copied()
+            .collect_vec();
+        let bot_pubkey = user.public_key();
         listen::listen_mention(
             &client,
             user,
+            network.clone(),
             config_path,
-            |x, y| {
-                let (client, network) = y;
-                let argnum = 3;
-                async move {
-                    let vals = find_pubkeys_in_message(&x.content);
-                    if vals.len() > argnum {
-                        return Err(sep_degrees::SepDegreeError::TooMuchArguments);
-                    } else if vals.len() < argnum {
-                        return Err(sep_degrees::SepDegreeError::TooFewArguments);
-                    }
-                    let (i, j) = (1, 2);
-                    from_pubkeys(vals[i], vals[j], &client, &network).await
-                }
+            overrides,
+            external_graph,
+            &requeue,
+            factory.clone(),
+            mention_command::answer,
+            bot_pubkey,
+            move |event, answer, usage, client, outbox| {
+                mention_command::reply(event, answer, usage, client, outbox, factory.clone())
             },
-            (client.clone(), network),
-            second_action,
         )
         .await;
         return Ok(());
@@ -211,7 +420,589 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-async fn print_rank(key: &str, nsec: &str) -> Result<()> {
+/// Answer --sep-degree offline, from the newest cache generation taken by --as-of
+fn separation_as_of(matches: &ArgMatches, as_of: Timestamp) -> Result<()> {
+    let vals = matches
+        .get_many::<String>("separation degrees")
+        .unwrap()
+        .map(PublicKey::parse)
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let (snapshot, generation) = match network::persist::generation_as_of(
+        Path::new(matches.get_one::<String>("cache").unwrap()),
+        as_of,
+    ) {
+        Ok(ok) => ok,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(2);
+        }
+    };
+    println!(
+        "As of the cache generation {} taken at {}",
+        generation,
+        snapshot.created_at().to_human_datetime()
+    );
+    let network = Network::from_snapshot(snapshot)?;
+    match network.shortest_path(&vals[0], &vals[1], PathMode::Mutual) {
+        Some(path) => {
+            println!("degrees: {}", sep_degrees::Degree::of_path(&path));
+            let path = path
+                .into_iter()
+                .map(|x| x.to_bech32().unwrap())
+                .collect_vec();
+            println!("{:?}", path);
+        }
+        None => println!("Connection between users not found in this generation"),
+    }
+    Ok(())
+}
+
+/// Write the cache `from` in the format of the extension of `to`, see --convert-cache
+fn convert_cache(from: &str, to: &str) {
+    match network::persist::convert(Path::new(from), Path::new(to)) {
+        Ok(format) => eprintln!("Cache {from} written to {to} as {format:?}"),
+        Err(err) => {
+            eprintln!("Cache {from} conversion error: {err}");
+            std::process::exit(2);
+        }
+    }
+}
+
+/// Print what's wrong with the cache in `path`, see --check-cache. Exits with 1 when it can't be
+/// loaded, unless repaired
+fn check_cache(path: &str, repair: bool) {
+    match network::check::check_cache(Path::new(path), repair) {
+        Ok(check) => {
+            print!("{check}");
+            if repair && !check.is_empty() {
+                println!(
+                    "Repaired, the old cache is in {}",
+                    network::check::backup_path(Path::new(path)).display()
+                );
+            } else if check.is_fatal() {
+                std::process::exit(1);
+            }
+        }
+        Err(err) => {
+            eprintln!("Cache {path} load error: {err}");
+            std::process::exit(2);
+        }
+    }
+}
+
+/// Write the network in the cache to `path`, around the --ego user if given, see --export
+fn export(matches: &ArgMatches, path: &str) {
+    let cache = Path::new(matches.get_one::<String>("cache").unwrap());
+    let ego = matches
+        .get_one::<String>("ego")
+        .map(|x| match PublicKey::parse(x) {
+            Ok(pubkey) => (pubkey, *matches.get_one::<usize>("radius").unwrap()),
+            Err(err) => {
+                eprintln!("Invalid --ego {x}: {err}");
+                std::process::exit(2);
+            }
+        });
+    if let Err(err) = export_cache(cache, Path::new(path), ego) {
+        eprintln!("{err}");
+        std::process::exit(2);
+    }
+}
+
+/// Print the sizes of the parts of the network in the cache, see --components
+fn components(matches: &ArgMatches) {
+    let cache = Path::new(matches.get_one::<String>("cache").unwrap());
+    let network = match network::persist::NetworkSnapshot::read_from(cache)
+        .and_then(Network::from_snapshot)
+    {
+        Ok(network) => network,
+        Err(err) => {
+            eprintln!("Cache {} load error: {}", cache.display(), err);
+            std::process::exit(2);
+        }
+    };
+    print!(
+        "{}",
+        network.connected_components(matches.get_one::<bool>("mutual only") == Some(&true))
+    );
+}
+
+/// Print the leaderboard of the list `list_name`, and publish it with --publish
+async fn leaderboard(
+    matches: &ArgMatches,
+    list_name: &str,
+    service: &SearchService,
+    client: &Client,
+    factory: &EventFactory,
+) -> Result<()> {
+    let members = target_lists::load_target_list(
+        matches.get_one::<String>("lists config").unwrap(),
+        list_name,
+    )?;
+    let budget = Duration::from_secs(*matches.get_one::<u64>("leaderboard budget").unwrap());
+    let board = leaderboard::compute(list_name, &members, service, budget).await;
+    let text = board.render_text();
+    if matches.get_one::<bool>("json") == Some(&true) {
+        println!("{}", serde_json::to_string_pretty(&board)?);
+    } else {
+        println!("{text}");
+    }
+    if matches.get_one::<bool>("publish") == Some(&true) {
+        let output = client.send_event_builder(factory.note(&text)).await?;
+        println!("Published {}", output.id().to_bech32()?);
+    }
+    Ok(())
+}
+
+/// Print the member of the list `list_name` closest to `user`, and the path to them
+async fn community_distance(
+    matches: &ArgMatches,
+    user: &str,
+    list_name: &str,
+    service: &SearchService,
+) -> Result<()> {
+    let user = PublicKey::parse(user)?;
+    let members = target_lists::load_target_list(
+        matches.get_one::<String>("lists config").unwrap(),
+        list_name,
+    )?;
+    match service.closest_member(user, &members).await {
+        Ok((degree, path)) => {
+            println!("closest member: {}", path.last().unwrap().to_bech32()?);
+            println!("degrees: {degree}");
+            let path = path
+                .into_iter()
+                .map(|x| x.to_bech32().unwrap())
+                .collect_vec();
+            println!("{:?}", path);
+        }
+        Err(err) => println!("{err}"),
+    }
+    Ok(())
+}
+
+/// Print the accounts the two users both follow and both are mutuals with
+async fn common(
+    vals: impl IntoIterator<Item = &str>,
+    client: &Client,
+    network: &Mutex<Network>,
+    options: SearchOptions,
+) -> Result<()> {
+    let (a, b) = vals
+        .into_iter()
+        .map(PublicKey::parse)
+        .collect::<std::result::Result<Vec<_>, _>>()?
+        .into_iter()
+        .collect_tuple()
+        .unwrap();
+    let common = sep_degrees::common_connections(client, network, a, b, options).await?;
+    for (title, users) in [("Follows", &common.follows), ("Mutuals", &common.mutuals)] {
+        println!("{title} in common: {}", users.len());
+        for (user, name) in users {
+            println!(
+                "{} | {}",
+                name.as_deref().unwrap_or("None"),
+                user.to_bech32()?
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Search again the request `id` of the history in `path`, with its options and relays, and
+/// print how the answers compare
+async fn replay_history(
+    path: &str,
+    id: &EventId,
+    client: &Client,
+    service: &SearchService,
+) -> Result<()> {
+    let Some(record) = History::new(path.into(), None).find(id)? else {
+        eprintln!("Request {} not in {path}", id.to_bech32()?);
+        std::process::exit(1);
+    };
+    println!(
+        "Replaying {} from {}, answered at {}",
+        id.to_bech32()?,
+        path,
+        record.at.to_human_datetime()
+    );
+    if let Some(saved_at) = record.cache_saved_at {
+        println!("Cache then saved at {}", saved_at.to_human_datetime());
+    }
+    for url in &record.relays {
+        println!("relay: {url}");
+        if client.add_relay(url.clone()).await? {
+            client.connect_relay(url.clone()).await?;
+        }
+    }
+    let response = service
+        .separation(SeparationRequest {
+            from: record.from,
+            to: record.to,
+            options: Some(record.options),
+            request_id: None,
+        })
+        .await;
+    let replay = Outcome::new(&response.result, &response.stats);
+    print!(
+        "{}",
+        ReplayComparison {
+            original: &record.outcome,
+            replay: &replay,
+        }
+    );
+    Ok(())
+}
+
+/// Print the users that follow `followed`, with their names, from at most `pages` pages
+async fn followers(
+    followed: &str,
+    pages: usize,
+    client: &Arc<Client>,
+    network: &Arc<Mutex<Network>>,
+    timeouts: QueryTimeouts,
+) -> Result<()> {
+    let followed = User::new(PublicKey::parse(followed)?, client).await?;
+    let mut user_network =
+        FollowNetwork::new(followed, client.clone(), network.clone(), timeouts).await;
+    user_network.add_followers_level(pages).await?;
+    user_network.add_metadata(LevelIdx(1)).await?;
+    let net_lock = network.lock().await;
+    let followers = user_network.level(LevelIdx(1)).unwrap();
+    for follower in followers
+        .iter()
+        .sorted_by_cached_key(|x| net_lock.display_name(x).to_lowercase())
+    {
+        println!(
+            "{} | {}",
+            net_lock.display_name(follower),
+            follower.to_bech32()?
+        );
+    }
+    println!("followers: {}", followers.len());
+    Ok(())
+}
+
+/// Most followed users printed with --stats
+const STATS_HUBS: usize = 10;
+
+/// Follow pack to publish after ranking
+struct PackArgs {
+    info: FollowPackInfo,
+    size: usize,
+    /// Use the users of this level instead of the best ranked
+    level: Option<LevelIdx>,
+    /// Don't ask for confirmation
+    yes: bool,
+    factory: EventFactory,
+}
+
+/// Follow set to publish after ranking
+struct ListArgs {
+    /// The d tag, the name of the set
+    identifier: String,
+    /// How many of the best ranked users go in it
+    size: usize,
+    /// Don't ask for confirmation
+    yes: bool,
+    factory: EventFactory,
+}
+
+/// Where to write the network after ranking
+struct DotArgs {
+    path: String,
+    /// Only users up to this distance of the user
+    max_distance: Option<LevelIdx>,
+    edge_labels: EdgeLabels,
+}
+
+/// Drop the excluded users found by the last level, before their follows are fetched
+async fn remove_excluded(user_network: &mut FollowNetwork, excluded: &[PublicKey]) {
+    for user in excluded {
+        if user_network.remove_user(user).await {
+            eprintln!("Excluded {}", user.to_bech32().unwrap());
+        }
+    }
+}
+
+/// Network to compare with after ranking
+struct DiffArgs {
+    old: Network,
+    /// Also compare the users followed by the user
+    follows: bool,
+}
+
+/// Write the network saved in the cache to `path`, see --export. With ego, only the users within
+/// the radius of it
+fn export_cache(
+    cache: &Path,
+    path: &Path,
+    ego: Option<(PublicKey, usize)>,
+) -> std::result::Result<(), String> {
+    let format = ExportFormat::from_path(path).ok_or_else(|| {
+        format!(
+            "Unknown export format of {}, use .csv or .jsonl",
+            path.display()
+        )
+    })?;
+    let network = network::persist::NetworkSnapshot::read_from(cache)
+        .and_then(Network::from_snapshot)
+        .map_err(|err| format!("Cache {} load error: {}", cache.display(), err))?;
+    let network = match ego {
+        Some((center, radius)) => {
+            if !network.contains_user(&center) {
+                return Err(format!(
+                    "{} isn't in the cache",
+                    center.to_bech32().unwrap()
+                ));
+            }
+            network.ego_subgraph(&center, radius, false)
+        }
+        None => network,
+    };
+
+    let write = |path: &Path, export: &dyn Fn(&mut dyn std::io::Write) -> std::io::Result<()>| {
+        let res = std::fs::File::create(path).and_then(|file| {
+            let mut writer = std::io::BufWriter::new(file);
+            export(&mut writer)?;
+            std::io::Write::flush(&mut writer)
+        });
+        match res {
+            Ok(()) => {
+                eprintln!("Network written to {}", path.display());
+                Ok(())
+            }
+            Err(err) => Err(format!("Couldn't write {}: {}", path.display(), err)),
+        }
+    };
+    match format {
+        ExportFormat::Csv => {
+            write(path, &|w| network.export_edges_csv(w))?;
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            let nodes = path.with_file_name(format!("{stem}_nodes.csv"));
+            write(&nodes, &|w| network.export_nodes_csv(w))
+        }
+        ExportFormat::Jsonl => write(path, &|w| network.export_jsonl(w)),
+    }
+}
+
+/// Publish the follow set of `source`, replacing the one with the same name. Returns its id and
+/// the relays that accepted it
+async fn publish_follow_set(
+    client: &Client,
+    user_network: &FollowNetwork,
+    source: &FollowPackSource<'_>,
+    list: &ListArgs,
+) -> Result<(EventId, Vec<String>)> {
+    let builder = user_network.to_follow_set(source, &list.identifier, &list.factory)?;
+    let output = client.send_event_builder(builder).await?;
+    let relays = output
+        .success
+        .iter()
+        .map(|x| x.to_string())
+        .sorted()
+        .collect();
+    Ok((output.val, relays))
+}
+
+/// Ask in the terminal, anything other than y or yes is a no
+fn confirm(question: &str) -> bool {
+    eprint!("{question} [y/N] ");
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// What to rank and what to do with the ranking, see --print-rank
+struct RankArgs<'a> {
+    /// npub or nsec of the user ranked for
+    key: &'a str,
+    pack: Option<PackArgs>,
+    list: Option<ListArgs>,
+    dot: Option<DotArgs>,
+    checkpoint: Option<Checkpoint>,
+    diff: Option<DiffArgs>,
+    /// Left out of the ranking and the network
+    excluded: Vec<PublicKey>,
+    rank_config: RankConfig,
+    /// Also rank by the interactions of these many last days
+    interactions_days: Option<u64>,
+    /// Also rank by the zaps of these many last days
+    zaps_days: Option<u64>,
+    /// Also print the connector score, estimated from this many users
+    connector_sample: Option<usize>,
+    /// The level ranked
+    depth: LevelIdx,
+    store_petnames: bool,
+    /// Also print the follows stats of the network
+    stats: bool,
+}
+
+/// Options of --print-rank. Exits when a file they name can't be read
+fn rank_args<'a>(matches: &'a ArgMatches, factory: &EventFactory) -> RankArgs<'a> {
+    // Read before connecting, so a bad cache fails fast
+    let diff = matches.get_one::<String>("diff").map(|path| {
+        match network::persist::NetworkSnapshot::read_from(Path::new(path))
+            .and_then(Network::from_snapshot)
+        {
+            Ok(old) => DiffArgs {
+                old,
+                follows: matches.get_one::<bool>("diff follows") == Some(&true),
+            },
+            Err(err) => {
+                eprintln!("Cache {path} load error: {err}");
+                std::process::exit(2);
+            }
+        }
+    });
+    let excluded = matches
+        .get_many::<String>("exclude user")
+        .unwrap_or_default()
+        .map(|x| match PublicKey::parse(x) {
+            Ok(pubkey) => pubkey,
+            Err(err) => {
+                eprintln!("Invalid --exclude-user {x}: {err}");
+                std::process::exit(2);
+            }
+        })
+        .collect_vec();
+    let mut rank_config = match matches.get_one::<String>("rank config") {
+        Some(path) => match RankConfig::load(Path::new(path)) {
+            Ok(config) => config,
+            Err(err) => {
+                eprintln!("{err}");
+                std::process::exit(2);
+            }
+        },
+        None => RankConfig::default(),
+    };
+    // Only the flags given override the config file
+    for (name, weight) in [
+        ("mutual weight", &mut rank_config.mutual_weight),
+        (
+            "popularity dampening",
+            &mut rank_config.popularity_dampening,
+        ),
+        ("recency weight", &mut rank_config.recency_weight),
+        ("recency half life", &mut rank_config.recency_half_life_days),
+        ("similarity weight", &mut rank_config.similarity_weight),
+        ("community weight", &mut rank_config.community_weight),
+        ("interaction weight", &mut rank_config.interaction_weight),
+        ("zap weight", &mut rank_config.zap_weight),
+    ] {
+        if let Some(x) = matches.get_one::<f64>(name) {
+            *weight = *x;
+        }
+    }
+    if let Some(min) = matches.get_one::<i32>("min rank") {
+        rank_config.min_rank = Some(*min);
+    }
+    if let Some(max) = matches.get_one::<usize>("max results") {
+        rank_config.max_results = Some(*max);
+    }
+    if let Some(max) = matches.get_one::<usize>("max level size") {
+        rank_config.max_level_size = Some(*max);
+    }
+    if let Some(max) = matches.get_one::<usize>("max follows") {
+        rank_config.max_follows = *max;
+    }
+    if matches.get_one::<bool>("require name") == Some(&true) {
+        rank_config.require_name = true;
+    }
+    if let Some(pattern) = matches.get_one::<String>("name blocklist") {
+        rank_config.name_blocklist = Some(pattern.clone());
+    }
+    if let Err(err) = rank_config.filters() {
+        eprintln!("{err}");
+        std::process::exit(2);
+    }
+    if matches.get_one::<bool>("include followed") == Some(&true) {
+        rank_config.include_followed = true;
+    }
+    if let Some(path) = matches.get_one::<String>("exclusion file") {
+        if let Err(err) = rank_config.load_exclusions(Path::new(path)) {
+            eprintln!("{err}");
+            std::process::exit(2);
+        }
+    }
+    RankArgs {
+        key: matches.get_one::<String>("user key").unwrap(),
+        pack: (matches.get_one::<bool>("publish follow pack") == Some(&true)).then(|| PackArgs {
+            info: FollowPackInfo {
+                identifier: matches.get_one::<String>("pack id").unwrap().clone(),
+                title: matches.get_one::<String>("pack title").unwrap().clone(),
+                description: matches.get_one::<String>("pack description").cloned(),
+            },
+            size: *matches.get_one::<usize>("pack size").unwrap(),
+            level: matches
+                .get_one::<usize>("pack level")
+                .copied()
+                .map(LevelIdx),
+            yes: matches.get_one::<bool>("yes") == Some(&true),
+            factory: factory.clone(),
+        }),
+        list: matches
+            .get_one::<String>("publish list")
+            .map(|name| ListArgs {
+                identifier: name.clone(),
+                size: *matches.get_one::<usize>("pack size").unwrap(),
+                yes: matches.get_one::<bool>("yes") == Some(&true),
+                factory: factory.clone(),
+            }),
+        dot: matches.get_one::<String>("export dot").map(|path| DotArgs {
+            path: path.clone(),
+            max_distance: matches
+                .get_one::<usize>("dot distance")
+                .copied()
+                .map(LevelIdx),
+            edge_labels: EdgeLabels::from_name(matches.get_one::<String>("edge labels").unwrap())
+                .unwrap(),
+        }),
+        checkpoint: matches
+            .get_one::<String>("rank cache")
+            .map(|path| Checkpoint {
+                path: path.into(),
+                every_chunks: *matches.get_one::<usize>("checkpoint chunks").unwrap(),
+            }),
+        diff,
+        excluded,
+        rank_config,
+        interactions_days: matches.get_one::<u64>("interactions days").copied(),
+        zaps_days: matches.get_one::<u64>("zaps days").copied(),
+        connector_sample: matches.get_one::<usize>("connector sample").copied(),
+        depth: LevelIdx(*matches.get_one::<usize>("depth").unwrap()),
+        store_petnames: matches.get_one::<bool>("no petnames") != Some(&true),
+        stats: matches.get_one::<bool>("stats") == Some(&true),
+    }
+}
+
+async fn print_rank(
+    args: RankArgs<'_>,
+    connection_keys: impl FnOnce() -> std::result::Result<Keys, ConnectionKeyError>,
+    relays: &[Url],
+    proxy: Option<SocketAddr>,
+    auth_relays: &[Url],
+    timeouts: QueryTimeouts,
+    sources: LocalSources<'_>,
+) -> Result<()> {
+    let RankArgs {
+        key,
+        pack,
+        list,
+        dot,
+        checkpoint,
+        diff,
+        excluded,
+        rank_config,
+        interactions_days,
+        zaps_days,
+        connector_sample,
+        depth,
+        store_petnames,
+        stats,
+    } = args;
     // It's ok if my_keys doesn't match my_pubkey, because the 1st is used in the client and the 2nd is used in
     // the program's logic. Events will only be signed with the bot key but they aren't here so it doesn't matter
     let (my_keys, my_pubkey) = match Keys::parse(key) {
@@ -220,23 +1011,74 @@ async fn print_rank(key: &str, nsec: &str) -> Result<()> {
             (ok, pubkey)
         }
         Err(_err) => (
-            Keys::parse(nsec).expect("Give a valid nsec with --connection-key argument"),
+            connection_keys()?,
             PublicKey::parse(key).expect("Key parse error"),
         ),
     };
 
-    let (client, user, network) = start_connection(my_keys, my_pubkey).await;
-    let mut user_network = FollowNetwork::new(user.clone(), client.clone(), network.clone()).await;
+    let (client, user, network) =
+        start_connection(my_keys, my_pubkey, relays, proxy, auth_relays, sources).await?;
+    if let Some(checkpoint) = &checkpoint {
+        if checkpoint.path.is_file() {
+            match network::persist::NetworkSnapshot::read_from(&checkpoint.path)
+                .and_then(Network::from_snapshot)
+            {
+                Ok(loaded) => {
+                    eprintln!("Resuming from {}", checkpoint.path.display());
+                    network.lock().await.merge(loaded);
+                }
+                Err(err) => eprintln!("Cache {} load error: {}", checkpoint.path.display(), err),
+            }
+        }
+    }
+    let mut user_network =
+        FollowNetwork::new(user.clone(), client.clone(), network.clone(), timeouts).await;
+    user_network.set_checkpoint(checkpoint);
+    user_network.set_max_level_size(rank_config.max_level_size);
+    user_network.set_filters(rank_config.filters()?);
+    network.lock().await.set_store_petnames(store_petnames);
 
+    // Up to the level ranked, and one more for the contact lists of its users, to find mutuals
+    for level in 1..=depth.0 {
+        user_network.add_level().await?;
+        remove_excluded(&mut user_network, &excluded).await;
+        user_network.add_metadata(LevelIdx(level)).await?;
+    }
     user_network.add_level().await?;
-    user_network.add_metadata(1).await?;
-    user_network.add_level().await?;
-    user_network.add_metadata(2).await?;
-    user_network.add_level().await?;
+    remove_excluded(&mut user_network, &excluded).await;
+    if !user_network.failed().is_empty() {
+        let fetched = user_network.retry_failed().await;
+        eprintln!("Retried failed contact lists: {fetched} fetched");
+    }
+    user_network.add_mute_list().await?;
+    if let Some(days) = interactions_days {
+        let since = Timestamp::now() - Duration::from_secs(days * 24 * 60 * 60);
+        user_network
+            .add_interactions(depth, &[Kind::TextNote, Kind::Reaction], Some(since))
+            .await?;
+    }
+    if let Some(days) = zaps_days {
+        let since = Timestamp::now() - Duration::from_secs(days * 24 * 60 * 60);
+        user_network.add_zaps(depth, Some(since)).await?;
+    }
 
-    let res = user_network.generate_user_ranks().await?;
+    let res = user_network
+        .generate_user_ranks_at(depth, &rank_config)
+        .await?;
+    let center = user.public_key();
+    let connectors: HashMap<PublicKey, f64> = match connector_sample {
+        Some(sample) => network
+            .lock()
+            .await
+            .top_bridges(sample, network::bridges::HUB_SEED)
+            .into_iter()
+            .collect(),
+        None => HashMap::new(),
+    };
     for (pubkey, rank, reasons) in res.iter().rev() {
         let net_lock = network.lock().await;
+        // A name someone gave them, when they didn't give themselves one
+        let petname = || net_lock.petname(&center, pubkey);
         println!(
             "{} | {} | rank: {}",
             match net_lock.get_pubkey_metadata(pubkey) {
@@ -244,10 +1086,10 @@ async fn print_rank(key: &str, nsec: &str) -> Result<()> {
                     Some(n) => n,
                     None => match &m.display_name {
                         Some(dn) => dn,
-                        None => "None",
+                        None => petname().unwrap_or("None"),
                     },
                 },
-                None => "None",
+                None => petname().unwrap_or("None"),
             },
             pubkey.to_bech32()?,
             rank
@@ -267,11 +1109,163 @@ async fn print_rank(key: &str, nsec: &str) -> Result<()> {
                         );
                     }
                 }
+                network::follow::RankReasons::SimilarFollows(similarity) => {
+                    println!("- follows similarity: {:.0}%", similarity * 100.0);
+                }
+                network::follow::RankReasons::TightCommunity(coefficient) => {
+                    println!("- mutuals of each other: {:.0}%", coefficient * 100.0);
+                }
+                network::follow::RankReasons::InteractsWithYourFollows(count) => {
+                    println!("- replies and reactions to your follows: {count}");
+                }
+                network::follow::RankReasons::ZappedByYourNetwork(sats, count) => {
+                    println!("- zapped by your follows: {sats} sats in {count} zaps");
+                }
+                network::follow::RankReasons::FollowedByCount(count) => {
+                    println!(
+                        "- followed by {} in the crawled network",
+                        footer::group_thousands(*count as u64)
+                    );
+                }
+                network::follow::RankReasons::RecentConnections(vec) => {
+                    for (pubkey2, updated) in vec {
+                        let days = Timestamp::now().as_u64().saturating_sub(updated.as_u64())
+                            / (24 * 60 * 60);
+                        println!(
+                            "- {:?} last updated a contact list following them {days} days ago",
+                            match net_lock.get_pubkey_metadata(pubkey2) {
+                                Some((m, _)) => m.name.clone(),
+                                None => None,
+                            },
+                        );
+                    }
+                }
             }
         }
+        if connector_sample.is_some() {
+            let score = connectors.get(pubkey).copied().unwrap_or_default();
+            println!("- connector score: {score:.4}");
+        }
+    }
+
+    if let Some(diff) = diff {
+        let net_lock = network.lock().await;
+        let mut users = vec![my_pubkey];
+        if diff.follows {
+            users.extend(
+                user_network
+                    .users_within(LevelIdx(1))
+                    .into_iter()
+                    .filter(|x| *x != my_pubkey)
+                    .sorted(),
+            );
+        }
+        println!();
+        for user in users {
+            let changes = Network::diff(&diff.old, &net_lock, &user);
+            // Only the follows that changed, to keep it short
+            if user != my_pubkey
+                && changes.presence == ContactListPresence::Both
+                && changes.is_empty()
+            {
+                continue;
+            }
+            let display = DiffDisplay {
+                diff: &changes,
+                old: &diff.old,
+                new: &net_lock,
+            };
+            print!("{display}");
+        }
+    }
+
+    relay_health().log(false);
+
+    if let Some(dot) = dot {
+        let options = DotOptions {
+            users: dot.max_distance.map(|x| user_network.users_within(x)),
+            edge_labels: dot.edge_labels,
+        };
+        let net_lock = network.lock().await;
+        let res = std::fs::File::create(&dot.path).and_then(|file| {
+            let mut writer = std::io::BufWriter::new(file);
+            net_lock.export_dot(&mut writer, &options)?;
+            std::io::Write::flush(&mut writer)
+        });
+        drop(net_lock);
+        match res {
+            Ok(()) => eprintln!("Network written to {}", dot.path),
+            Err(err) => eprintln!("Couldn't write {}: {}", dot.path, err),
+        }
     }
 
-    //println!("{:#.4?}", user_network);
+    if let Some(pack) = pack {
+        let source = match pack.level {
+            Some(level) => FollowPackSource::Level(level),
+            None => FollowPackSource::TopRanks(&res, pack.size),
+        };
+        let builder = user_network.to_follow_pack(&source, &pack.info, &pack.factory)?;
+        let entries = user_network.follow_pack_users(&source)?.len();
+        let question = format!(
+            "Publish follow pack \"{}\" with {} users?",
+            pack.info.title, entries
+        );
+        if pack.yes || confirm(&question) {
+            let output = client.send_event_builder(builder).await?;
+            println!("Published follow pack {}", output.id().to_bech32()?);
+        } else {
+            println!("Follow pack not published");
+        }
+    }
+
+    if let Some(list) = list {
+        let source = FollowPackSource::TopRanks(&res, list.size);
+        let entries = user_network.follow_pack_users(&source)?.len();
+        let question = format!(
+            "Publish follow set \"{}\" with {} users?",
+            list.identifier, entries
+        );
+        if list.yes || confirm(&question) {
+            let (id, relays) = publish_follow_set(&client, &user_network, &source, &list).await?;
+            println!(
+                "Published follow set {}",
+                Nip19Event::new(id, relays).to_bech32()?
+            );
+        } else {
+            println!("Follow set not published");
+        }
+    }
+
+    let net_lock = network.lock().await;
+    eprintln!(
+        "Network: {} users, {} follows, {} with metadata",
+        net_lock.node_count(),
+        net_lock.edge_count(),
+        net_lock.metadata_count()
+    );
+    if user_network.skipped().total() > 0 {
+        eprintln!("Not crawled through: {}", user_network.skipped());
+    }
+    for (level, size) in user_network.truncated_levels() {
+        eprintln!(
+            "Level {level} truncated from {} to {} users",
+            footer::humanize_count(*size as u64),
+            footer::humanize_count(user_network.level(*level).map_or(0, |x| x.len()) as u64)
+        );
+    }
+    for coverage in user_network.coverage().iter().filter(|x| x.is_poor()) {
+        eprintln!(
+            "Warning: level {} is missing the follows of {} of {} users, whose contact lists couldn't be fetched ({:.0}% coverage)",
+            coverage.level,
+            footer::humanize_count(coverage.failed as u64),
+            footer::humanize_count(coverage.users as u64),
+            coverage.share() * 100.0
+        );
+    }
+    if stats {
+        println!();
+        print!("{}", net_lock.degree_stats(STATS_HUBS));
+    }
 
     Ok(())
 }
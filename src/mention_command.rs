@@ -0,0 +1,930 @@
+/// What a mention asks the bot for, told by a word in it like "score", otherwise a separation
+/// search between the users it mentions
+///
+/// The words are checked in the order of the variants, so a mention with two of them does what
+/// the first one asks. Each command is answered by answer and the answer is turned into the text
+/// of the reply by reply
+use itertools::Itertools;
+use std::sync::Arc;
+
+use crate::client_utils::{
+    find_pubkeys_in_message_with_offsets, find_targets_in_mention, is_recommend_request,
+    is_score_request, is_status_request, is_unfollows_request, EventFactory, MentionTargets,
+    DEFAULT_RELAYS,
+};
+use crate::footer::{self, RequestUsage};
+use crate::listen::LoggedAnswer;
+use crate::mention_options::MentionOptions;
+use crate::network::diff::ContactListDelta;
+use crate::network::follow::{RankReasons, RecommendationError};
+use crate::network::names::NameError;
+use crate::network::progress::ProgressEvent;
+use crate::network::reach::ROOT_MAX_DEPTH;
+use crate::network::{self, Hop};
+use crate::nip05;
+use crate::outbox::Outbox;
+use crate::search_service::{ScoreResponse, SearchService, SeparationRequest};
+use crate::sep_degrees::{self, SearchStats};
+
+use nostr_sdk::prelude::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MentionCommand {
+    /// Who a user unfollowed, see sep_degrees::unfollows
+    Unfollows,
+    /// Connectivity score of a user, see SearchService::score
+    Score,
+    /// Users a user may want to follow, see SearchService::recommend
+    Recommend,
+    /// How far along the running recommendation of a user is, see
+    /// SearchService::recommend_progress
+    Status,
+    /// Degrees of separation between two users
+    Separation,
+}
+
+impl MentionCommand {
+    pub fn parse(content: &str) -> MentionCommand {
+        if is_unfollows_request(content) {
+            MentionCommand::Unfollows
+        } else if is_score_request(content) {
+            MentionCommand::Score
+        } else if is_recommend_request(content) {
+            MentionCommand::Recommend
+        } else if is_status_request(content) {
+            MentionCommand::Status
+        } else {
+            MentionCommand::Separation
+        }
+    }
+}
+
+/// Answer of a mention asking how far apart two users are
+#[derive(Debug)]
+pub struct MentionAnswer {
+    pub result: Result<(sep_degrees::Degree, Vec<PublicKey>), sep_degrees::SepDegreeError>,
+    pub stats: SearchStats,
+    /// The users searched
+    pub found: MentionTargets,
+    /// How many accounts both follow
+    pub common_follows: Option<usize>,
+    /// Other paths as short, with #allpaths
+    pub other_paths: Vec<Vec<PublicKey>>,
+    /// How each user of the path is joined to the next
+    pub hops: Vec<Option<Hop>>,
+    /// How far each user searched is from the root
+    pub from_root: Option<FromRoot>,
+}
+
+impl MentionAnswer {
+    /// Answer of a mention whose search didn't run, because of err
+    fn failed(err: sep_degrees::SepDegreeError, found: MentionTargets) -> MentionAnswer {
+        MentionAnswer {
+            result: Err(err),
+            stats: SearchStats::default(),
+            found,
+            common_follows: None,
+            other_paths: vec![],
+            hops: vec![],
+            from_root: None,
+        }
+    }
+}
+
+/// The root user set with root_npub, and how many follows away from it each user searched is.
+/// None for the users farther than ROOT_MAX_DEPTH
+pub type FromRoot = (PublicKey, Vec<Option<usize>>);
+
+/// Most other paths listed with #allpaths
+const ALL_PATHS_LIMIT: usize = 5;
+
+/// Answer of a mention asking who its author, or another user, unfollowed
+pub type UnfollowsAnswer = (
+    PublicKey,
+    Result<ContactListDelta, sep_degrees::SepDegreeError>,
+);
+
+/// Answer of a mention asking for the connectivity score of its author, or another user
+pub type ScoreAnswer = (
+    PublicKey,
+    Result<ScoreResponse, sep_degrees::SepDegreeError>,
+);
+
+/// Answer of a mention asking for users for its author, or another user, to follow
+pub type RecommendAnswer = (
+    PublicKey,
+    Result<Vec<(PublicKey, i32, Vec<RankReasons>)>, RecommendationError>,
+);
+
+/// Answer of a mention asking how far along the recommendation for its author, or another
+/// user, is, with how many recommendations are running
+pub type StatusAnswer = (PublicKey, Option<ProgressEvent>, usize);
+
+/// Most characters of the reason given for each recommended user
+const RECOMMEND_REASON_CHARS: usize = 80;
+
+#[derive(Debug)]
+pub enum MentionReply {
+    Separation(Box<MentionAnswer>),
+    Unfollows(UnfollowsAnswer),
+    Score(ScoreAnswer),
+    Recommend(RecommendAnswer),
+    Status(StatusAnswer),
+}
+
+impl LoggedAnswer for (MentionOptions, MentionReply) {
+    fn outcome(&self) -> String {
+        let (command, error) = match &self.1 {
+            MentionReply::Separation(answer) => {
+                ("separation", answer.result.as_ref().err().map(|x| x.kind()))
+            }
+            MentionReply::Unfollows((_, result)) => {
+                ("unfollows", result.as_ref().err().map(|x| x.kind()))
+            }
+            MentionReply::Score((_, result)) => ("score", result.as_ref().err().map(|x| x.kind())),
+            MentionReply::Recommend((_, result)) => {
+                ("recommend", result.as_ref().err().map(|_| "failed"))
+            }
+            MentionReply::Status(_) => ("status", None),
+        };
+        format!("{command}: {}", error.unwrap_or("ok"))
+    }
+
+    fn degree(&self) -> Option<sep_degrees::Degree> {
+        match &self.1 {
+            MentionReply::Separation(answer) => answer.result.as_ref().ok().map(|x| x.0),
+            _ => None,
+        }
+    }
+}
+
+/// Reply to a separation search and how many profiles it scanned
+fn separation_message(event: &Event, answer: MentionAnswer) -> (String, usize) {
+    let MentionAnswer {
+        result,
+        stats,
+        found,
+        common_follows,
+        other_paths,
+        hops,
+        from_root,
+    } = answer;
+    let mut message = match result {
+        Ok((_, mut path)) => {
+            let mut saudation = "Found Connection:\n\n".to_string();
+            let last = path.pop().unwrap();
+            for (i, pubkey) in path.iter().enumerate() {
+                let link = match hops.get(i).copied().flatten() {
+                    Some(Hop::Mutual) => "is mutual with",
+                    Some(Hop::Follows) => "follows",
+                    Some(Hop::FollowedBy) => "is followed by",
+                    None => "is connected to",
+                };
+                saudation += &format!("nostr:{} {link}\n", pubkey.to_bech32().unwrap());
+            }
+            if path.is_empty() {
+                saudation += &format!(
+                    "nostr:{} is the sole one in this chain",
+                    last.to_bech32().unwrap()
+                );
+            } else {
+                saudation += &format!("nostr:{}", last.to_bech32().unwrap());
+            }
+            if let (true, Some(relays)) = (stats.low_confidence, stats.corroborating_relays)
+            {
+                saudation += &format!(
+                    "\n\nLow confidence: part of this path was confirmed by only {relays} relays"
+                );
+            }
+            if stats.external_in_path {
+                saudation +=
+                    "\n\nPart of this path comes from an indexer, not from relays";
+            }
+            if let Some(common) = common_follows.filter(|x| *x > 0) {
+                saudation += &format!("\n\nBoth follow {common} accounts in common");
+            }
+            if !other_paths.is_empty() {
+                saudation += "\n\nOther paths as short:";
+                for other in &other_paths {
+                    saudation += "\n";
+                    saudation += &other
+                        .iter()
+                        .map(|x| format!("nostr:{}", x.to_bech32().unwrap()))
+                        .collect::<Vec<_>>()
+                        .join(" > ");
+                }
+            }
+            saudation
+        }
+        Err(err) => match err {
+            sep_degrees::SepDegreeError::TooFewArguments => {
+                "Too few public keys in request. Use: mention me and then another 2 users!"
+                    .to_string()
+            }
+            sep_degrees::SepDegreeError::TooMuchArguments => {
+                "Too much public keys in request. Use: mention me and then another 2 users!"
+                    .to_string()
+            }
+            sep_degrees::SepDegreeError::NostrClientError(_error) => {
+                "Nostr client internal error".to_string()
+            }
+            sep_degrees::SepDegreeError::NotFound(frontier) => {
+                eprintln!("Not found: {}", frontier.explain());
+                if frontier.disconnected {
+                    "Connection between users not found: these users are in disconnected parts of the known graph".to_string()
+                } else if let sep_degrees::StopReason::DepthLimit(hops) = frontier.stop {
+                    format!("Connection between users not found: no connection within {hops} hops")
+                } else if frontier.stop == sep_degrees::StopReason::Exhausted {
+                    "Connection between users not found: one of them has no more mutuals to go through".to_string()
+                } else {
+                    "Connection between users not found".to_string()
+                }
+            }
+            sep_degrees::SepDegreeError::Name(err) => match err {
+                NameError::TooShort(name) => format!(
+                    "\"{name}\" is too short to look up. Mention the users with their npubs instead!"
+                ),
+                NameError::NotFound(name) => format!(
+                    "I don't know anyone named \"{name}\". Mention the users with their npubs instead!"
+                ),
+                NameError::Ambiguous(name, users) => format!(
+                    "More than one user is named \"{name}\", like {}\n\nMention the one you mean with their npub!",
+                    users
+                        .iter()
+                        .map(|x| format!("nostr:{}", x.to_bech32().unwrap()))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            },
+            sep_degrees::SepDegreeError::EmptyContactList(public_key) => {
+                if public_key == event.author() {
+                    "Your contact list follows nobody, so you have no mutuals to find a path through. Follow someone and ask me again!".to_string()
+                } else {
+                    format!("nostr:{} follows nobody", public_key.to_bech32().unwrap())
+                }
+            }
+            sep_degrees::SepDegreeError::MissingContactList(public_key, relays) => {
+                let relays = relays
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                if public_key == event.author() {
+                    format!(
+                        "I couldn't find your contact list in any of the relays I checked: {}\n\nPublish it to a popular relay, like {}, and ask me again!",
+                        relays,
+                        DEFAULT_RELAYS[0]
+                    )
+                } else {
+                    format!(
+                        "Missing contact list of nostr:{}\n\nRelays checked: {}",
+                        public_key.to_bech32().unwrap(),
+                        relays
+                    )
+                }
+            }
+            sep_degrees::SepDegreeError::Nip05ResolutionFailed(identifier) => format!(
+                "I couldn't find who {identifier} is, its domain didn't tell me. Mention the user with their npub instead!"
+            ),
+            sep_degrees::SepDegreeError::VerificationFailed(_) => {
+                "Connection between users not found: every path I found went through someone who unfollowed".to_string()
+            }
+        },
+    };
+    if let Some((root, distances)) = from_root {
+        message += &format!("\n\nFollows away from nostr:{}:", root.to_bech32().unwrap());
+        for (user, distance) in found.targets.iter().zip(distances) {
+            let distance = match distance {
+                Some(distance) => distance.to_string(),
+                None => format!("more than {ROOT_MAX_DEPTH}"),
+            };
+            message += &format!("\nnostr:{} {distance}", user.to_bech32().unwrap());
+        }
+    }
+    if found.from_tags {
+        let targets = found
+            .targets
+            .iter()
+            .map(|x| format!("nostr:{}", x.to_bech32().unwrap()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        message = format!("Users taken from the tags of your note: {targets}\n\n{message}");
+    } else if found.from_names {
+        let targets = found
+            .targets
+            .iter()
+            .map(|x| format!("nostr:{}", x.to_bech32().unwrap()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        message = format!("Users found by name: {targets}\n\n{message}");
+    }
+    (message, stats.profiles_scanned)
+}
+
+fn score_message(event: &Event, (user, result): ScoreAnswer) -> String {
+    let who = if user == event.author() {
+        "you".to_string()
+    } else {
+        format!("nostr:{}", user.to_bech32().unwrap())
+    };
+    let response = match result {
+        Ok(response) => response,
+        Err(sep_degrees::SepDegreeError::MissingContactList(_, relays)) => {
+            let relays = relays
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            return format!(
+                "I couldn't find the contact list of {who} in any of the relays I checked: {relays}"
+            );
+        }
+        Err(sep_degrees::SepDegreeError::EmptyContactList(_)) => {
+            return format!("The contact list of {who} is empty, so no one is reached through it");
+        }
+        Err(sep_degrees::SepDegreeError::TooMuchArguments) => {
+            return "Too much public keys in request. Use: mention me, score and the user, or nobody to score yourself!".to_string();
+        }
+        Err(_) => return "Nostr client internal error".to_string(),
+    };
+    let mut message = format!(
+        "Six degrees score of {who}: {:.0}%\n\nReaches {}",
+        response.score.percent(),
+        response.score
+    );
+    if let Some(percentile) = response.percentile {
+        message +=
+            &format!("\n\nBetter connected than {percentile:.0}% of the users I scored this week");
+    }
+    if response.cached {
+        message += "\n\nScored earlier this week";
+    }
+    message
+}
+
+fn recommend_message(event: &Event, (user, result): RecommendAnswer) -> String {
+    let who = if user == event.author() {
+        "you".to_string()
+    } else {
+        format!("nostr:{}", user.to_bech32().unwrap())
+    };
+    let ranks = match result {
+        Ok(ranks) => ranks,
+        Err(RecommendationError::TooManyUsers) => {
+            return "Too much public keys in request. Use: mention me, recommend and the user, or nobody to get recommendations yourself!".to_string();
+        }
+        Err(RecommendationError::TimedOut) => {
+            return format!(
+                "Finding recommendations for {who} took too long, so I gave up. Sorry!"
+            );
+        }
+        Err(RecommendationError::NotEnoughLevels) => {
+            return format!(
+                "I couldn't find enough follows of follows of {who} to recommend anyone"
+            );
+        }
+        Err(err) => {
+            eprintln!("Recommendation error: {err}");
+            return "Nostr client internal error".to_string();
+        }
+    };
+    if ranks.is_empty() {
+        return format!("I found no one to recommend to {who}");
+    }
+    let mut message = format!("Recommended for {who}:");
+    for (pubkey, _, reasons) in &ranks {
+        message += &format!("\n\nnostr:{}", pubkey.to_bech32().unwrap());
+        if let Some(reason) = recommend_reason(reasons) {
+            message += "\n";
+            message += &reason
+                .chars()
+                .take(RECOMMEND_REASON_CHARS)
+                .collect::<String>();
+        }
+    }
+    message
+}
+
+fn status_message(event: &Event, (user, progress, running): StatusAnswer) -> String {
+    let who = if user == event.author() {
+        "you".to_string()
+    } else {
+        format!("nostr:{}", user.to_bech32().unwrap())
+    };
+    let mut message = match progress {
+        Some(progress) => format!("Finding recommendations for {who}: {progress}"),
+        None => format!("I'm not finding recommendations for {who} right now"),
+    };
+    if running > 0 {
+        message += &format!("\n\n{running} recommendations running");
+    }
+    message
+}
+
+/// The reason a user was ranked high, in a line
+fn recommend_reason(reasons: &[RankReasons]) -> Option<String> {
+    reasons.iter().find_map(|reason| match reason {
+        RankReasons::MutualConnections(mutuals) if !mutuals.is_empty() => {
+            Some(format!("Mutuals with {} of your follows", mutuals.len()))
+        }
+        RankReasons::SimilarFollows(similarity) => Some(format!(
+            "Follows {:.0}% the same as you",
+            similarity * 100.0
+        )),
+        _ => None,
+    })
+}
+
+fn unfollows_message(event: &Event, (user, result): UnfollowsAnswer) -> String {
+    let who = if user == event.author() {
+        "you".to_string()
+    } else {
+        format!("nostr:{}", user.to_bech32().unwrap())
+    };
+    let delta = match result {
+        Ok(delta) => delta,
+        Err(sep_degrees::SepDegreeError::MissingContactList(_, relays)) => {
+            let relays = relays
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            return format!(
+                "I couldn't find the contact list of {who} in any of the relays I checked: {relays}"
+            );
+        }
+        Err(sep_degrees::SepDegreeError::TooMuchArguments) => {
+            return "Too much public keys in request. Use: mention me, unfollows and the user, or nobody to check yourself!".to_string();
+        }
+        Err(_) => return "Nostr client internal error".to_string(),
+    };
+    let Some(since) = delta.since else {
+        return format!(
+            "This is the first time I see the contact list of {who}, so there's nothing to compare it with yet. Ask me again later!"
+        );
+    };
+    if delta.is_empty() {
+        return format!(
+            "No follows changed for {who} since I last saw the contact list, made at {}",
+            since.to_human_datetime()
+        );
+    }
+    let list = |users: &[PublicKey]| {
+        users
+            .iter()
+            .map(|x| format!("nostr:{}", x.to_bech32().unwrap()))
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    let mut message = format!(
+        "Since the contact list I last saw, made at {}:",
+        since.to_human_datetime()
+    );
+    if !delta.removed.is_empty() {
+        message += &format!("\n\nUnfollowed:\n{}", list(&delta.removed));
+    }
+    if !delta.added.is_empty() {
+        message += &format!("\n\nFollowed:\n{}", list(&delta.added));
+    }
+    message
+}
+
+/// Send the reply to a mention, with the options it was given and the usage footer
+pub async fn reply(
+    event: Event,
+    (options, reply): (MentionOptions, MentionReply),
+    usage: Option<RequestUsage>,
+    client: Arc<Client>,
+    outbox: Arc<Outbox>,
+    factory: EventFactory,
+) {
+    let (mut message, profiles_scanned) = match reply {
+        MentionReply::Separation(answer) => separation_message(&event, *answer),
+        MentionReply::Unfollows(answer) => (unfollows_message(&event, answer), 1),
+        MentionReply::Score(answer) => (score_message(&event, answer), 1),
+        MentionReply::Recommend(answer) => (recommend_message(&event, answer), 1),
+        MentionReply::Status(answer) => (status_message(&event, answer), 1),
+    };
+    if !options.is_empty() {
+        message += &format!("\n\nOptions: {options}");
+    }
+    if let (Some(usage), false) = (usage, options.quiet) {
+        message += "\n\n";
+        message += &footer::format_footer(&usage, profiles_scanned);
+    }
+    match factory
+        .sign(
+            &client,
+            factory.reply(&event, &message),
+            outbox.pow_required(),
+        )
+        .await
+    {
+        Ok(reply) => outbox.push(reply).await,
+        Err(err) => eprintln!("Reply error: {err}"),
+    };
+}
+
+/// The users referenced in `content` and the ones `resolved` from the NIP-05 identifiers at each
+/// offset of it, in the order they're written, as the first one is searched from. Without `bot`
+fn targets_in_order(
+    content: &str,
+    resolved: impl IntoIterator<Item = (usize, PublicKey)>,
+    bot: &PublicKey,
+) -> Vec<PublicKey> {
+    find_pubkeys_in_message_with_offsets(content)
+        .into_iter()
+        .chain(resolved)
+        .sorted_by_key(|(offset, _)| *offset)
+        .map(|(_, user)| user)
+        .filter(|x| x != bot)
+        .unique()
+        .collect()
+}
+
+/// Find what a mention asks for and answer it, with the options given in it
+pub async fn answer(
+    x: Event,
+    bot: PublicKey,
+    service: Arc<SearchService>,
+) -> (MentionOptions, MentionReply) {
+    let argnum = 2;
+    let options = MentionOptions::parse(&x, service.options().max_depth());
+    let reply = async {
+        let network = service.network();
+        let command = MentionCommand::parse(&x.content);
+        if command == MentionCommand::Unfollows {
+            // Their own contact list when no one else is mentioned
+            let found = find_targets_in_mention(&x, &bot, 1, service.options().target_precedence);
+            let user = match found.targets[..] {
+                [] => x.author(),
+                [user] => user,
+                _ => {
+                    return MentionReply::Unfollows((
+                        x.author(),
+                        Err(sep_degrees::SepDegreeError::TooMuchArguments),
+                    ))
+                }
+            };
+            let delta =
+                sep_degrees::unfollows(service.client(), network, user, service.options()).await;
+            return MentionReply::Unfollows((user, delta));
+        }
+        if command == MentionCommand::Score {
+            // Their own score when no one else is mentioned
+            let found = find_targets_in_mention(&x, &bot, 1, service.options().target_precedence);
+            let user = match found.targets[..] {
+                [] => x.author(),
+                [user] => user,
+                _ => {
+                    return MentionReply::Score((
+                        x.author(),
+                        Err(sep_degrees::SepDegreeError::TooMuchArguments),
+                    ))
+                }
+            };
+            return MentionReply::Score((user, service.score(user).await));
+        }
+        if command == MentionCommand::Recommend {
+            // Recommendations for themselves when no one else is mentioned
+            let found = find_targets_in_mention(&x, &bot, 1, service.options().target_precedence);
+            let user = match found.targets[..] {
+                [] => x.author(),
+                [user] => user,
+                _ => {
+                    return MentionReply::Recommend((
+                        x.author(),
+                        Err(RecommendationError::TooManyUsers),
+                    ))
+                }
+            };
+            return MentionReply::Recommend((user, service.recommend(user).await));
+        }
+        if command == MentionCommand::Status {
+            // Their own recommendation when no one else is mentioned
+            let found = find_targets_in_mention(&x, &bot, 1, service.options().target_precedence);
+            let user = found.targets.first().copied().unwrap_or(x.author());
+            return MentionReply::Status((
+                user,
+                service.recommend_progress(&user),
+                service.running_recommendations(),
+            ));
+        }
+        let mut found =
+            find_targets_in_mention(&x, &bot, argnum, service.options().target_precedence);
+        // NIP-05 identifiers, like jack@cash.app, when the npubs aren't enough
+        let identifiers = nip05::nip05_identifiers(&x.content);
+        if found.targets.len() < argnum && !identifiers.is_empty() {
+            let (offsets, identifiers): (Vec<_>, Vec<_>) = identifiers.into_iter().unzip();
+            match service.resolve_nip05(&identifiers).await {
+                Ok(users) => {
+                    // The targets are the users of the content here, as the tags had too few
+                    let resolved = offsets.into_iter().zip(users);
+                    found.targets = targets_in_order(&x.content, resolved, &bot);
+                }
+                Err(err) => {
+                    return MentionReply::Separation(Box::new(MentionAnswer::failed(err, found)))
+                }
+            }
+        }
+        // Without any npub, look the names up in the network
+        let names = network::names::names_in_text(&x.content, argnum);
+        if found.targets.is_empty() && names.len() == argnum {
+            match network.lock().await.resolve_names(&names) {
+                Ok(targets) => {
+                    found.targets = targets;
+                    found.from_names = true;
+                }
+                Err(err) => {
+                    return MentionReply::Separation(Box::new(MentionAnswer::failed(
+                        sep_degrees::SepDegreeError::Name(err),
+                        found,
+                    )))
+                }
+            }
+        }
+        let vals = &found.targets;
+        if vals.len() > argnum {
+            return MentionReply::Separation(Box::new(MentionAnswer::failed(
+                sep_degrees::SepDegreeError::TooMuchArguments,
+                found,
+            )));
+        } else if vals.len() < argnum {
+            return MentionReply::Separation(Box::new(MentionAnswer::failed(
+                sep_degrees::SepDegreeError::TooFewArguments,
+                found,
+            )));
+        }
+        let response = service
+            .separation(SeparationRequest {
+                from: vals[0],
+                to: vals[1],
+                options: options.search_options(service.options()),
+                request_id: Some(x.id),
+            })
+            .await;
+        // The search loaded both contact lists
+        let mode = options.path_mode.unwrap_or(service.options().path_mode);
+        let (common_follows, other_paths, hops) = match &response.result {
+            Ok((_, path)) => {
+                let net_lock = network.lock().await;
+                let other_paths = if options.all_paths {
+                    net_lock
+                        .shortest_paths(&vals[0], &vals[1], mode, ALL_PATHS_LIMIT + 1)
+                        .into_iter()
+                        .filter(|x| x.len() == path.len() && x != path)
+                        .take(ALL_PATHS_LIMIT)
+                        .collect_vec()
+                } else {
+                    vec![]
+                };
+                let common = net_lock.common_follows(&vals[0], &vals[1]).len();
+                let hops = path
+                    .iter()
+                    .tuple_windows()
+                    .map(|(a, b)| net_lock.hop(a, b))
+                    .collect_vec();
+                (Some(common), other_paths, hops)
+            }
+            Err(_) => (None, vec![], vec![]),
+        };
+        // Kept up to date by the contact lists the search loaded
+        let from_root = {
+            let net_lock = network.lock().await;
+            net_lock.root().map(|root| {
+                let distances = vals.iter().map(|x| net_lock.distance_from_root(x));
+                (root, distances.collect_vec())
+            })
+        };
+        MentionReply::Separation(Box::new(MentionAnswer {
+            result: response.result,
+            stats: response.stats,
+            found,
+            common_follows,
+            other_paths,
+            hops,
+            from_root,
+        }))
+    }
+    .await;
+    (options, reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    fn mention(author: &Keys) -> Event {
+        EventBuilder::text_note("", []).to_event(author).unwrap()
+    }
+
+    fn error_answer(err: sep_degrees::SepDegreeError) -> MentionAnswer {
+        let found = MentionTargets {
+            targets: vec![],
+            from_tags: false,
+            from_names: false,
+        };
+        MentionAnswer::failed(err, found)
+    }
+
+    #[test]
+    fn missing_contact_list_of_the_author_asks_to_publish_it() {
+        let keys = keys(2);
+        let relays = vec![
+            Url::parse("wss://relay.one").unwrap(),
+            Url::parse("wss://relay.two").unwrap(),
+        ];
+        let event = mention(&keys[0]);
+
+        let (own, _) = separation_message(
+            &event,
+            error_answer(sep_degrees::SepDegreeError::MissingContactList(
+                keys[0].public_key(),
+                relays.clone(),
+            )),
+        );
+        assert!(own.starts_with(
+            "I couldn't find your contact list in any of the relays I checked: wss://relay.one/, wss://relay.two/"
+        ));
+        assert!(own.contains(&format!(
+            "Publish it to a popular relay, like {}",
+            DEFAULT_RELAYS[0]
+        )));
+
+        let (other, _) = separation_message(
+            &event,
+            error_answer(sep_degrees::SepDegreeError::MissingContactList(
+                keys[1].public_key(),
+                relays,
+            )),
+        );
+        assert_eq!(
+            other,
+            format!(
+                "Missing contact list of nostr:{}\n\nRelays checked: wss://relay.one/, wss://relay.two/",
+                keys[1].public_key().to_bech32().unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn low_confidence_path_gets_a_caveat() {
+        let users = pubkeys(2);
+        let event = mention(&keys(1)[0]);
+        let found = MentionTargets {
+            targets: users.clone(),
+            from_tags: false,
+            from_names: false,
+        };
+        let caveat = |low_confidence| {
+            let stats = SearchStats {
+                corroborating_relays: Some(1),
+                low_confidence,
+                ..Default::default()
+            };
+            let answer = MentionAnswer {
+                result: Ok((sep_degrees::Degree(1), users.clone())),
+                stats,
+                found: found.clone(),
+                common_follows: None,
+                other_paths: vec![],
+                hops: vec![Some(Hop::Mutual)],
+                from_root: None,
+            };
+            let (message, _) = separation_message(&event, answer);
+            message.contains("Low confidence: part of this path was confirmed by only 1 relays")
+        };
+        assert!(caveat(true));
+        assert!(!caveat(false));
+    }
+
+    #[test]
+    fn targets_taken_from_the_tags_are_told() {
+        let users = pubkeys(2);
+        let event = mention(&keys(1)[0]);
+        let mut answer = error_answer(sep_degrees::SepDegreeError::TooFewArguments);
+        answer.found = MentionTargets {
+            targets: users.clone(),
+            from_tags: true,
+            from_names: false,
+        };
+        let (message, _) = separation_message(&event, answer);
+        assert!(message.starts_with(&format!(
+            "Users taken from the tags of your note: nostr:{}, nostr:{}\n\n",
+            users[0].to_bech32().unwrap(),
+            users[1].to_bech32().unwrap()
+        )));
+    }
+
+    #[test]
+    fn distances_from_the_root_are_told() {
+        let users = pubkeys(3);
+        let event = mention(&keys(1)[0]);
+        let mut answer = error_answer(sep_degrees::SepDegreeError::TooFewArguments);
+        answer.found.targets = vec![users[1], users[2]];
+        answer.from_root = Some((users[0], vec![Some(2), None]));
+        let (message, _) = separation_message(&event, answer);
+        assert!(message.ends_with(&format!(
+            "\n\nFollows away from nostr:{}:\nnostr:{} 2\nnostr:{} more than 3",
+            users[0].to_bech32().unwrap(),
+            users[1].to_bech32().unwrap(),
+            users[2].to_bech32().unwrap()
+        )));
+    }
+
+    #[test]
+    fn logged_outcome_names_no_user() {
+        let users = pubkeys(3);
+        let mut answer = error_answer(sep_degrees::SepDegreeError::EmptyContactList(users[0]));
+        let logged = (
+            MentionOptions::default(),
+            MentionReply::Separation(Box::new(answer)),
+        );
+        assert_eq!(logged.outcome(), "separation: empty contact list");
+        assert_eq!(logged.degree(), None);
+
+        answer = error_answer(sep_degrees::SepDegreeError::TooFewArguments);
+        answer.result = Ok((sep_degrees::Degree(2), users.clone()));
+        let logged = (
+            MentionOptions::default(),
+            MentionReply::Separation(Box::new(answer)),
+        );
+        assert_eq!(logged.outcome(), "separation: ok");
+        assert_eq!(logged.degree(), Some(sep_degrees::Degree(2)));
+    }
+
+    #[test]
+    fn ambiguous_name_lists_the_candidates() {
+        let keys = keys(3);
+        let candidates = vec![keys[1].public_key(), keys[2].public_key()];
+        let (message, _) = separation_message(
+            &mention(&keys[0]),
+            error_answer(sep_degrees::SepDegreeError::Name(NameError::Ambiguous(
+                "bob".to_string(),
+                candidates.clone(),
+            ))),
+        );
+        assert!(message.starts_with(&format!(
+            "More than one user is named \"bob\", like nostr:{}, nostr:{}",
+            candidates[0].to_bech32().unwrap(),
+            candidates[1].to_bech32().unwrap()
+        )));
+    }
+
+    #[test]
+    fn empty_contact_list_is_told_as_following_nobody() {
+        let keys = keys(2);
+        let event = mention(&keys[0]);
+        let message = |user: PublicKey| {
+            separation_message(
+                &event,
+                error_answer(sep_degrees::SepDegreeError::EmptyContactList(user)),
+            )
+            .0
+        };
+        assert!(message(keys[0].public_key()).starts_with("Your contact list follows nobody"));
+        assert!(message(keys[1].public_key()).starts_with(&format!(
+            "nostr:{} follows nobody",
+            keys[1].public_key().to_bech32().unwrap()
+        )));
+    }
+
+    #[test]
+    fn nip05_users_keep_their_place_among_the_npubs() {
+        let [bot, a, jack, fiatjaf] = pubkeys(4)[..] else {
+            unreachable!()
+        };
+        let uri = |user: &PublicKey| format!("nostr:{}", user.to_bech32().unwrap());
+        let targets = |content: &str, resolved: &[(&str, PublicKey)]| {
+            let identifiers = nip05::nip05_identifiers(content);
+            let resolved = resolved.iter().map(|(identifier, user)| {
+                let (offset, _) = identifiers.iter().find(|(_, x)| x == identifier).unwrap();
+                (*offset, *user)
+            });
+            targets_in_order(content, resolved, &bot)
+        };
+
+        let content = format!("{} how far is jack@cash.app from {}?", uri(&bot), uri(&a));
+        assert_eq!(targets(&content, &[("jack@cash.app", jack)]), [jack, a]);
+        let content = format!("{} how far is {} from Jack@Cash.app?", uri(&bot), uri(&a));
+        assert_eq!(targets(&content, &[("jack@cash.app", jack)]), [a, jack]);
+        let content = format!("{} jack@cash.app fiatjaf@fiatjaf.com", uri(&bot));
+        assert_eq!(
+            targets(
+                &content,
+                &[("jack@cash.app", jack), ("fiatjaf@fiatjaf.com", fiatjaf)]
+            ),
+            [jack, fiatjaf]
+        );
+        // The same user by both, and the bot by its NIP-05, count once and not at all
+        let content = format!("{} jack@cash.app {} bot@six.degrees", uri(&a), uri(&jack));
+        assert_eq!(
+            targets(
+                &content,
+                &[("jack@cash.app", jack), ("bot@six.degrees", bot)]
+            ),
+            [a, jack]
+        );
+    }
+}
@@ -0,0 +1,175 @@
+/// Options of a search given with hashtags in the mention asking for it, like #directed, #oneway
+/// or #maxdepth4
+///
+/// Hashtags are read from the t tags of the mention and then from its content, in order. When two
+/// of them conflict, like #directed and #mutual or two #maxdepth, the last one wins. Hashtags that
+/// aren't options are ignored, most of them aren't meant for the bot
+use std::fmt::{self, Display, Formatter};
+
+use crate::client_utils::mention_words;
+use crate::network::PathMode;
+use crate::sep_degrees::{Degree, SearchOptions};
+use nostr_sdk::prelude::*;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MentionOptions {
+    /// #directed goes through follows instead of mutuals, #oneway through follows in either
+    /// direction, #mutual back. None keeps the configured
+    pub path_mode: Option<PathMode>,
+    /// #maxdepthN, already clamped to the configured most hops
+    pub max_depth: Option<Degree>,
+    /// #allpaths also lists other paths of the same length in the network
+    pub all_paths: bool,
+    /// #quiet replies without the footer
+    pub quiet: bool,
+}
+
+impl MentionOptions {
+    /// Options in the hashtags of `event`. #maxdepth is kept between 1 and `ceiling`
+    pub fn parse(event: &Event, ceiling: Degree) -> MentionOptions {
+        let from_tags = event
+            .tags
+            .iter()
+            .filter_map(|tag| match tag.as_standardized() {
+                Some(TagStandard::Hashtag(hashtag)) => Some(hashtag.to_lowercase()),
+                _ => None,
+            });
+        let from_content = mention_words(&event.content)
+            .filter_map(|x| x.strip_prefix('#'))
+            .map(|x| x.to_lowercase());
+
+        let mut options = MentionOptions::default();
+        for hashtag in from_tags.chain(from_content) {
+            match hashtag.as_str() {
+                "directed" => options.path_mode = Some(PathMode::Directed),
+                "oneway" => options.path_mode = Some(PathMode::Either),
+                "mutual" => options.path_mode = Some(PathMode::Mutual),
+                "allpaths" => options.all_paths = true,
+                "quiet" => options.quiet = true,
+                other => {
+                    let depth = other
+                        .strip_prefix("maxdepth")
+                        .filter(|x| !x.is_empty() && x.chars().all(|c| c.is_ascii_digit()));
+                    if let Some(depth) = depth {
+                        // Too many digits for a number are as far as it goes too
+                        let depth = depth.parse::<u32>().unwrap_or(u32::MAX);
+                        options.max_depth = Some(Degree(depth.clamp(1, ceiling.0.max(1))));
+                    }
+                }
+            }
+        }
+        options
+    }
+
+    /// Whether no option was given
+    pub fn is_empty(&self) -> bool {
+        *self == MentionOptions::default()
+    }
+
+    /// `options` changed by the ones of the mention. None when the search options stay as they are
+    pub fn search_options(&self, options: SearchOptions) -> Option<SearchOptions> {
+        if self.path_mode.is_none() && self.max_depth.is_none() {
+            return None;
+        }
+        Some(SearchOptions {
+            path_mode: self.path_mode.unwrap_or(options.path_mode),
+            max_depth: self.max_depth.or(options.max_depth),
+            ..options
+        })
+    }
+}
+
+/// Like "directed, max depth 4", as echoed in replies
+impl Display for MentionOptions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let mut names = vec![];
+        match self.path_mode {
+            Some(PathMode::Directed) => names.push("directed".to_string()),
+            Some(PathMode::Either) => names.push("one-way".to_string()),
+            Some(PathMode::Mutual) => names.push("mutual".to_string()),
+            None => (),
+        }
+        if let Some(depth) = self.max_depth {
+            names.push(format!("max depth {depth}"));
+        }
+        if self.all_paths {
+            names.push("all paths".to_string());
+        }
+        if self.quiet {
+            names.push("quiet".to_string());
+        }
+        write!(f, "{}", names.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    fn options(content: &str, hashtags: &[&str]) -> MentionOptions {
+        let tags = hashtags.iter().map(|x| Tag::hashtag(*x));
+        let event = EventBuilder::text_note(content, tags)
+            .to_event(&keys(1)[0])
+            .unwrap();
+        MentionOptions::parse(&event, Degree(6))
+    }
+
+    #[test]
+    fn hashtags_of_the_content_and_the_tags_are_options() {
+        let all = options(
+            "nostr:npub1x nostr:npub1y #Directed, #maxdepth4 #allpaths! #QUIET",
+            &[],
+        );
+        assert_eq!(
+            all,
+            MentionOptions {
+                path_mode: Some(PathMode::Directed),
+                max_depth: Some(Degree(4)),
+                all_paths: true,
+                quiet: true,
+            }
+        );
+        assert_eq!(all.to_string(), "directed, max depth 4, all paths, quiet");
+        assert_eq!(
+            options("", &["oneway", "maxdepth2"]).to_string(),
+            "one-way, max depth 2"
+        );
+        // Other hashtags and words aren't options
+        assert!(options("directed #nostr #maxdepth #maxdepthfour #depth3", &["zap"]).is_empty());
+    }
+
+    #[test]
+    fn last_of_conflicting_hashtags_wins_and_depth_is_clamped() {
+        // Tags come before the content
+        let mode = options("#mutual", &["directed"]);
+        assert_eq!(mode.path_mode, Some(PathMode::Mutual));
+        assert_eq!(
+            options("#oneway #directed", &[]).path_mode,
+            Some(PathMode::Directed)
+        );
+        assert_eq!(
+            options("#maxdepth2 #maxdepth5", &[]).max_depth,
+            Some(Degree(5))
+        );
+
+        assert_eq!(options("#maxdepth0", &[]).max_depth, Some(Degree(1)));
+        assert_eq!(options("#maxdepth9", &[]).max_depth, Some(Degree(6)));
+        assert_eq!(
+            options("#maxdepth99999999999999999999", &[]).max_depth,
+            Some(Degree(6))
+        );
+    }
+
+    #[test]
+    fn search_options_change_only_with_a_mode_or_depth() {
+        let base = SearchOptions::default();
+        assert_eq!(options("#allpaths #quiet", &[]).search_options(base), None);
+        let changed = options("#directed", &[]).search_options(base).unwrap();
+        assert_eq!(changed.path_mode, PathMode::Directed);
+        assert_eq!(changed.max_depth, base.max_depth);
+        let changed = options("#maxdepth3", &[]).search_options(base).unwrap();
+        assert_eq!(changed.path_mode, base.path_mode);
+        assert_eq!(changed.max_depth, Some(Degree(3)));
+    }
+}
@@ -1,17 +1,140 @@
 /// Defines a network of users
 use itertools::Itertools;
 use petgraph::visit::EdgeRef;
-use petgraph::Direction;
-use std::collections::{HashMap, HashSet};
+use petgraph::{Directed, Direction};
+use serde::{Deserialize, Serialize};
+use std::collections::{hash_map::Entry, HashMap, HashSet, VecDeque};
+use std::fmt::{self, Display, Formatter};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::RwLock;
 
+use crate::network::bridges::HubsCache;
+#[cfg(feature = "sqlite")]
+use crate::network::db::ContactListDb;
+use crate::network::filters::CrawlFilters;
+use crate::network::mutuals::MutualProjection;
+use crate::network::persist::{NetworkSnapshot, PersistError, SNAPSHOT_VERSION};
+use crate::network::reach::RootDistances;
 use nostr_sdk::prelude::*;
-use petgraph::graph::{DiGraph, EdgeIndex, NodeIndex};
+use petgraph::graph::{DiGraph, EdgeIndex, Edges, NodeIndex};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum EdgeKind {
     Following,
+    /// Public entry of a mute list, never followed by searches
+    Muted,
+}
+
+/// What joins each user of a path to the next
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PathMode {
+    /// Following each other
+    #[default]
+    Mutual,
+    /// A follow, from the first user of the path to the last
+    Directed,
+    /// A follow in either direction
+    Either,
+}
+
+/// How a user of a path is joined to the next, see Network::hop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hop {
+    Mutual,
+    Follows,
+    FollowedBy,
+}
+
+/// Iterator over the users followed by an user, see Network::get_user_contacts
+pub enum ContactsIter<'a> {
+    Empty,
+    Edges {
+        graph: &'a DiGraph<PublicKey, EdgeKind>,
+        edges: Edges<'a, EdgeKind, Directed>,
+    },
+}
+
+impl<'a> Iterator for ContactsIter<'a> {
+    type Item = &'a PublicKey;
+
+    #[inline]
+    fn next(&mut self) -> Option<&'a PublicKey> {
+        match self {
+            ContactsIter::Empty => None,
+            ContactsIter::Edges { graph, edges } => loop {
+                let edge = edges.next()?;
+                if edge.weight() == &EdgeKind::Following {
+                    return Some(
+                        graph
+                            .node_weight(edge.target())
+                            .expect("Node without weight?!"),
+                    );
+                }
+            },
+        }
+    }
+}
+
+/// What a contact list says of a follow besides the public key
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PetnameInfo {
+    /// Relay where the follow can be found
+    pub relay_hint: Option<String>,
+    /// Name the user gave to the follow
+    pub petname: Option<String>,
+}
+
+/// Zaps from a user to another, see Network::add_zap
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ZapTotal {
+    pub amount_msat: u64,
+    pub count: u32,
+}
+
+/// Result of Network::update_contact_list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContactListUpdate {
+    Applied,
+    /// The network already had a contact list as new, which was kept
+    IgnoredOlder,
+}
+
+/// What the network knows of the contact list of a user, see Network::contact_list_state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContactListState {
+    /// With the creation time of the contact list
+    Known(Timestamp),
+    /// The user published a contact list that follows nobody
+    Empty(Timestamp),
+    /// No contact list of the user was found
+    Unknown,
+}
+
+/// What Network::prune removes besides the users without follows or metadata
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PruneOptions {
+    /// Forget the contact lists fetched before this, so their users can be removed too
+    pub stale_before: Option<Timestamp>,
+}
+
+/// How much Network::prune removed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PruneReport {
+    pub users: usize,
+    pub follows: usize,
+    pub stale_contact_lists: usize,
+}
+
+impl Display for PruneReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} users, {} follows, {} stale contact lists",
+            self.users, self.follows, self.stale_contact_lists
+        )
+    }
 }
 
 /// Graph that tracks association between users (follows, etc.)
@@ -22,8 +145,28 @@ pub struct Network {
     users_metadata: HashMap<PublicKey, Option<(Metadata, Timestamp)>>,
     added_out_edges_since: HashMap<PublicKey, Timestamp>,
     contact_list_creation: HashMap<PublicKey, Timestamp>,
+    mute_list_creation: HashMap<PublicKey, Timestamp>,
+    /// Relay hints and petnames, by (user, follow)
+    petnames: HashMap<(PublicKey, PublicKey), PetnameInfo>,
+    /// Whether update_petnames keeps them, as they take memory for every follow that has one
+    store_petnames: bool,
+    /// Replies and reactions, by (author, user replied or reacted to)
+    interactions: HashMap<(PublicKey, PublicKey), u32>,
+    /// Zaps, by (sender, recipient)
+    zaps: HashMap<(PublicKey, PublicKey), ZapTotal>,
     all_users: HashSet<PublicKey>,
     delete_lock: Arc<RwLock<()>>,
+    mutations: u64,
+    root_distances: Option<RootDistances>,
+    /// Bridges ranked by the last call to hubs
+    pub(super) hubs: Option<HubsCache>,
+    /// Pairs of users following each other, updated with the follows
+    pub(super) mutuals: MutualProjection,
+    /// Users separation searches don't go through, see set_search_filters
+    search_filters: CrawlFilters,
+    /// Where fetched contact lists and metadata are also written to
+    #[cfg(feature = "sqlite")]
+    db: Option<ContactListDb>,
 }
 
 impl Network {
@@ -34,11 +177,28 @@ impl Network {
             users_metadata: HashMap::new(),
             added_out_edges_since: HashMap::new(),
             contact_list_creation: HashMap::new(),
+            mute_list_creation: HashMap::new(),
+            petnames: HashMap::new(),
+            store_petnames: true,
+            interactions: HashMap::new(),
+            zaps: HashMap::new(),
             all_users: HashSet::new(),
             delete_lock: Arc::new(RwLock::new(())),
+            mutations: 0,
+            root_distances: None,
+            hubs: None,
+            mutuals: MutualProjection::default(),
+            search_filters: CrawlFilters::default(),
+            #[cfg(feature = "sqlite")]
+            db: None,
         }
     }
 
+    /// Counter increased on every change to the network, used to tell if it changed since a given point
+    pub fn mutation_count(&self) -> u64 {
+        self.mutations
+    }
+
     /// Usage:
     ///
     /// Call read_owned to prevent deletions in the underlying network until the guard is dropped
@@ -59,6 +219,7 @@ impl Network {
         let val = self.graph.add_node(user);
         self.graph_indices.insert(user, val);
         self.all_users.insert(user);
+        self.mutations += 1;
 
         (val, true)
     }
@@ -74,13 +235,23 @@ impl Network {
     }
 
     /// Update contact list of user, removing old follows and adding new ones
+    ///
+    /// The contact list counts as fresh from now on, even if it was the same or older
     pub fn update_contact_list<'a>(
         &mut self,
         user: PublicKey,
         contacts: impl IntoIterator<Item = &'a PublicKey>,
         timestamp: &Timestamp,
-    ) {
+    ) -> ContactListUpdate {
+        let contacts = contacts.into_iter().collect_vec();
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &mut self.db {
+            if let Err(err) = db.store_contact_list(&user, contacts.iter().copied(), *timestamp) {
+                eprintln!("Database error storing the contact list of {user}: {err}");
+            }
+        }
         let (node_user, added) = self.add_user(user);
+        self.mark_contact_list_fetched(user);
         if !added {
             if timestamp
                 > self
@@ -90,32 +261,43 @@ impl Network {
                     .unwrap()
             {
                 self.remove_contact_list(user);
-                self.contact_list_creation.insert(user, *timestamp);
             } else {
-                return;
+                return ContactListUpdate::IgnoredOlder;
             }
         }
+        self.contact_list_creation.insert(user, *timestamp);
+        self.mutations += 1;
         for follow in contacts {
             self.add_follow(user, *follow);
         }
+        ContactListUpdate::Applied
     }
 
-    /// Add contact list of user, but doesn't replace an old one
-    pub fn add_contact_list<'a>(
-        &mut self,
-        user: PublicKey,
-        contacts: impl IntoIterator<Item = &'a PublicKey>,
-        timestamp: &Timestamp,
-    ) {
-        let (node_user, added) = self.add_user(user);
-        if !added {
-            if let Some(_time) = self.contact_list_creation.get(&user) {
-                return;
+    fn mark_contact_list_fetched(&mut self, user: PublicKey) {
+        self.set_contact_list_fetched_at(user, Timestamp::now());
+    }
+
+    /// Set when the contact list was fetched, which tells if it's fresh
+    pub fn set_contact_list_fetched_at(&mut self, user: PublicKey, fetched_at: Timestamp) {
+        self.mutations += 1;
+        self.added_out_edges_since.insert(user, fetched_at);
+    }
+
+    /// Creation time of the contact list in the network
+    pub fn contact_list_created_at(&self, user: &PublicKey) -> Option<Timestamp> {
+        self.contact_list_creation.get(user).copied()
+    }
+
+    /// Whether the contact list of user is known, and was fetched less than `ttl` ago.
+    /// Without ttl, known contact lists never get stale
+    pub fn is_contact_list_fresh(&self, user: &PublicKey, ttl: Option<Duration>) -> bool {
+        match (self.added_out_edges_since.get(user), ttl) {
+            (_, None) => self.does_user_follow(user),
+            (None, Some(_)) => false,
+            (Some(fetched), Some(ttl)) => {
+                Timestamp::now().as_u64().saturating_sub(fetched.as_u64()) <= ttl.as_secs()
             }
         }
-        for follow in contacts {
-            self.add_follow(user, *follow);
-        }
     }
 
     pub fn remove_contact_list(&mut self, user: PublicKey) {
@@ -128,10 +310,19 @@ impl Network {
             .graph
             .edges_directed(node_user, Direction::Outgoing)
             .filter(|x| x.weight() == &EdgeKind::Following)
-            .map(|x| x.id())
+            .map(|x| (x.id(), self.graph[x.target()]))
             .collect_vec();
-        for follow in follows {
-            self.graph.remove_edge(follow);
+        // Indices of edges change on removal, so remove the last ones first
+        for (follow, _) in follows.iter().sorted_by_key(|(x, _)| *x).rev() {
+            self.graph.remove_edge(*follow);
+            self.mutations += 1;
+        }
+        for (_, follow) in &follows {
+            self.mutuals.unlink(&user, follow);
+        }
+        if let Some(mut distances) = self.root_distances.take() {
+            distances.follows_removed(self, &user, follows.iter().map(|(_, x)| x));
+            self.root_distances = Some(distances);
         }
     }
 
@@ -155,10 +346,12 @@ impl Network {
         }
     }
 
-    pub fn add_follow_nodes(&mut self, user_node: NodeIndex, follow_node: NodeIndex) -> EdgeIndex {
+    /// Both nodes must be in the graph, so only used with the ones just added
+    fn add_follow_nodes(&mut self, user_node: NodeIndex, follow_node: NodeIndex) -> EdgeIndex {
         match self.get_following_edge_nodes(user_node, follow_node) {
             Some(s) => s.id(),
             None => {
+                self.mutations += 1;
                 self.added_out_edges_since.insert(
                     *self
                         .graph
@@ -166,25 +359,191 @@ impl Network {
                         .expect("Node not in graph"),
                     Timestamp::now(),
                 );
-                self.graph
-                    .update_edge(user_node, follow_node, EdgeKind::Following)
+                // Not update_edge, which would turn a mute edge into a follow
+                let edge = self
+                    .graph
+                    .add_edge(user_node, follow_node, EdgeKind::Following);
+                if self.is_following_nodes(follow_node, user_node) {
+                    self.mutuals
+                        .link(self.graph[user_node], self.graph[follow_node]);
+                }
+                if let Some(mut distances) = self.root_distances.take() {
+                    distances.follow_added(self, &self.graph[user_node], &self.graph[follow_node]);
+                    self.root_distances = Some(distances);
+                }
+                edge
             }
         }
     }
 
+    /// Replace the mute list of user, unless the network has one as new
+    pub fn update_mute_list<'a>(
+        &mut self,
+        user: PublicKey,
+        muted: impl IntoIterator<Item = &'a PublicKey>,
+        timestamp: &Timestamp,
+    ) -> ContactListUpdate {
+        if self.mute_list_creation.get(&user) >= Some(timestamp) {
+            return ContactListUpdate::IgnoredOlder;
+        }
+        let node_user = self.add_user(user).0;
+        let old = self
+            .graph
+            .edges_directed(node_user, Direction::Outgoing)
+            .filter(|x| x.weight() == &EdgeKind::Muted)
+            .map(|x| x.id())
+            .collect_vec();
+        // Indices of edges change on removal, so remove the last ones first
+        for edge in old.into_iter().sorted().rev() {
+            self.graph.remove_edge(edge);
+        }
+        for muted in muted.into_iter().unique() {
+            let node_muted = self.add_user(*muted).0;
+            self.graph.add_edge(node_user, node_muted, EdgeKind::Muted);
+        }
+        self.mute_list_creation.insert(user, *timestamp);
+        self.mutations += 1;
+        ContactListUpdate::Applied
+    }
+
+    /// Keep the relay hints and petnames of contact lists, on by default. Turning it off drops
+    /// the ones kept
+    pub fn set_store_petnames(&mut self, store: bool) {
+        self.store_petnames = store;
+        if !store {
+            self.petnames.clear();
+        }
+    }
+
+    /// Skip the users caught by `filters` in separation searches. None are skipped by default
+    pub fn set_search_filters(&mut self, filters: CrawlFilters) {
+        self.search_filters = filters;
+    }
+
+    pub fn search_filters(&self) -> &CrawlFilters {
+        &self.search_filters
+    }
+
+    /// Replace the relay hints and petnames user gave to their follows. Empty ones aren't kept
+    pub fn update_petnames(&mut self, user: PublicKey, entries: Vec<(PublicKey, PetnameInfo)>) {
+        if !self.store_petnames {
+            return;
+        }
+        self.petnames.retain(|(x, _), _| *x != user);
+        self.petnames.extend(
+            entries
+                .into_iter()
+                .filter(|(_, info)| *info != PetnameInfo::default())
+                .map(|(follow, info)| ((user, follow), info)),
+        );
+        self.mutations += 1;
+    }
+
+    /// The petname `by` gave to user, or else any other petname user was given
+    pub fn petname(&self, by: &PublicKey, user: &PublicKey) -> Option<&str> {
+        self.petnames
+            .get(&(*by, *user))
+            .and_then(|x| x.petname.as_deref())
+            .or_else(|| {
+                self.petnames
+                    .iter()
+                    .filter(|((_, x), _)| x == user)
+                    .find_map(|(_, info)| info.petname.as_deref())
+            })
+    }
+
+    /// The petname `by` gave to user in their contact list, without looking at other users
+    pub fn given_petname(&self, by: &PublicKey, user: &PublicKey) -> Option<&str> {
+        self.petnames
+            .get(&(*by, *user))
+            .and_then(|x| x.petname.as_deref())
+    }
+
+    /// Count `count` more interactions of `by` with `user`
+    pub fn add_interactions(&mut self, by: PublicKey, user: PublicKey, count: u32) {
+        if count == 0 || by == user {
+            return;
+        }
+        *self.interactions.entry((by, user)).or_default() += count;
+        self.mutations += 1;
+    }
+
+    /// Every (by, user, count) of add_interactions
+    pub fn iter_interactions(&self) -> impl Iterator<Item = (PublicKey, PublicKey, u32)> + '_ {
+        self.interactions
+            .iter()
+            .map(|((by, user), count)| (*by, *user, *count))
+    }
+
+    /// Count a zap of `amount_msat` from `sender` to `recipient`
+    pub fn add_zap(&mut self, sender: PublicKey, recipient: PublicKey, amount_msat: u64) {
+        if sender == recipient {
+            return;
+        }
+        let total = self.zaps.entry((sender, recipient)).or_default();
+        total.amount_msat = total.amount_msat.saturating_add(amount_msat);
+        total.count += 1;
+        self.mutations += 1;
+    }
+
+    /// Every (sender, recipient, total) of add_zap
+    pub fn iter_zaps(&self) -> impl Iterator<Item = (PublicKey, PublicKey, ZapTotal)> + '_ {
+        self.zaps
+            .iter()
+            .map(|((sender, recipient), total)| (*sender, *recipient, *total))
+    }
+
+    /// Whether the mute list of user in the network has other
+    pub fn is_muted(&self, user: &PublicKey, other: &PublicKey) -> bool {
+        match (self.pubkey_to_node(user), self.pubkey_to_node(other)) {
+            (Some(user), Some(other)) => self
+                .graph
+                .edges_connecting(user, other)
+                .any(|x| x.weight() == &EdgeKind::Muted),
+            _ => false,
+        }
+    }
+
+    /// Users in the mute list of user, empty when it isn't known
+    pub fn get_user_muted(&self, user: &PublicKey) -> Vec<PublicKey> {
+        match self.graph_indices.get(user) {
+            Some(node) => self
+                .graph
+                .edges_directed(*node, Direction::Outgoing)
+                .filter(|x| x.weight() == &EdgeKind::Muted)
+                .map(|x| self.graph[x.target()])
+                .collect_vec(),
+            None => vec![],
+        }
+    }
+
     pub fn user_follow_last_update(&self, user: &PublicKey) -> Option<Timestamp> {
         self.added_out_edges_since.get(user).copied()
     }
 
+    /// Whether the contact list of user was fetched, even if it follows nobody. See
+    /// contact_list_state to tell those apart
     pub fn does_user_follow(&self, user: &PublicKey) -> bool {
         self.added_out_edges_since.get(user).is_some()
     }
 
-    pub fn are_users_mutuals(&self, user: &PublicKey, other: &PublicKey) -> bool {
-        let node_user = self.pubkey_to_node(user).unwrap();
-        let node_other = self.pubkey_to_node(other).unwrap();
-        self.is_following_nodes(node_user, node_other)
-            && self.is_following_nodes(node_other, node_user)
+    /// Whether the contact list of user is known, and if it follows anyone
+    pub fn contact_list_state(&self, user: &PublicKey) -> ContactListState {
+        match self.contact_list_creation.get(user) {
+            None => ContactListState::Unknown,
+            Some(time) if self.get_user_contacts(user).next().is_none() => {
+                ContactListState::Empty(*time)
+            }
+            Some(time) => ContactListState::Known(*time),
+        }
+    }
+
+    /// Whether the users follow each other. None when one of them isn't in the network
+    pub fn are_users_mutuals(&self, user: &PublicKey, other: &PublicKey) -> Option<bool> {
+        if !self.contains_user(user) || !self.contains_user(other) {
+            return None;
+        }
+        Some(self.mutuals.contains(user, other))
     }
 
     pub fn add_user_metadata(
@@ -193,6 +552,9 @@ impl Network {
         metadata: Metadata,
         timestamp: Timestamp,
     ) -> Option<(Metadata, Timestamp)> {
+        self.mutations += 1;
+        #[cfg(feature = "sqlite")]
+        self.store_metadata_in_db(&user, &metadata, timestamp);
         self.users_metadata
             .insert(user, Some((metadata, timestamp)))
             .flatten()
@@ -202,55 +564,493 @@ impl Network {
         &mut self,
         metadata_iter: impl IntoIterator<Item = (PublicKey, Option<(Metadata, Timestamp)>)>,
     ) {
+        self.mutations += 1;
+        #[cfg(feature = "sqlite")]
+        let metadata_iter = metadata_iter.into_iter().collect_vec();
+        #[cfg(feature = "sqlite")]
+        for (user, metadata) in &metadata_iter {
+            if let Some((metadata, timestamp)) = metadata {
+                self.store_metadata_in_db(user, metadata, *timestamp);
+            }
+        }
         self.users_metadata.extend(metadata_iter)
     }
 
+    #[cfg(feature = "sqlite")]
+    fn store_metadata_in_db(&self, user: &PublicKey, metadata: &Metadata, timestamp: Timestamp) {
+        if let Some(db) = &self.db {
+            if let Err(err) = db.store_metadata(user, metadata, timestamp) {
+                eprintln!("Database error storing the metadata of {user}: {err}");
+            }
+        }
+    }
+
+    /// Load what the database has that's newer than in memory, then write to it what's fetched
+    /// from now on
+    #[cfg(feature = "sqlite")]
+    pub fn attach_db(&mut self, db: ContactListDb) -> rusqlite::Result<()> {
+        self.db = None;
+        for (author, (follows, created_at, fetched_at)) in db.contact_lists()? {
+            let fetched_before = self.added_out_edges_since.get(&author).copied();
+            self.update_contact_list(author, &follows, &created_at);
+            // Only as fresh as when it was last fetched
+            let fetched = fetched_before.map_or(fetched_at, |x| x.max(fetched_at));
+            self.added_out_edges_since.insert(author, fetched);
+        }
+        for (pubkey, metadata, created_at) in db.metadata()? {
+            let newer = match self.get_pubkey_metadata(&pubkey) {
+                Some((_, time)) => created_at > *time,
+                None => true,
+            };
+            if newer {
+                self.users_metadata
+                    .insert(pubkey, Some((metadata, created_at)));
+            }
+        }
+        self.mutations += 1;
+        self.db = Some(db);
+        Ok(())
+    }
+
+    #[cfg(feature = "sqlite")]
+    pub fn detach_db(&mut self) -> Option<ContactListDb> {
+        self.db.take()
+    }
+
     /// Mark an user as explicitly having no metadata associated
     pub fn add_user_no_metadata(&mut self, user: PublicKey) -> Option<(Metadata, Timestamp)> {
+        self.mutations += 1;
         self.users_metadata.insert(user, None).flatten()
     }
 
     pub fn get_user_mutuals(&self, user: &PublicKey) -> Vec<NodeIndex> {
-        let user_node = match self.graph_indices.get(user) {
-            Some(s) => s,
-            None => return vec![],
+        self.mutuals
+            .mutuals(user)
+            .map(|x| self.graph_indices[x])
+            .collect_vec()
+    }
+
+    /// Users known to follow `user`, out of the contact lists in the graph
+    pub fn get_user_followers(&self, user: &PublicKey) -> Vec<PublicKey> {
+        match self.graph_indices.get(user) {
+            Some(node) => self
+                .graph
+                .edges_directed(*node, Direction::Incoming)
+                .filter(|x| x.weight() == &EdgeKind::Following)
+                .map(|x| self.graph[x.source()])
+                .collect_vec(),
+            None => vec![],
+        }
+    }
+
+    /// Users both `a` and `b` follow, sorted
+    pub fn common_follows(&self, a: &PublicKey, b: &PublicKey) -> Vec<PublicKey> {
+        let follows_b: HashSet<&PublicKey> = self.get_user_contacts(b).collect();
+        self.get_user_contacts(a)
+            .filter(|x| follows_b.contains(x))
+            .copied()
+            .sorted()
+            .collect_vec()
+    }
+
+    /// Users mutual with both `a` and `b`, sorted
+    pub fn common_mutuals(&self, a: &PublicKey, b: &PublicKey) -> Vec<PublicKey> {
+        let mutuals_b: HashSet<NodeIndex> = self.get_user_mutuals(b).into_iter().collect();
+        self.get_user_mutuals(a)
+            .into_iter()
+            .filter(|x| mutuals_b.contains(x))
+            .map(|x| self.graph[x])
+            .sorted()
+            .collect_vec()
+    }
+
+    /// Jaccard index of the users `a` and `b` follow: the follows in common over the follows of
+    /// either. None when either follows nobody known
+    pub fn follow_similarity(&self, a: &PublicKey, b: &PublicKey) -> Option<f64> {
+        let follows_a: HashSet<&PublicKey> = self.get_user_contacts(a).collect();
+        let follows_b: HashSet<&PublicKey> = self.get_user_contacts(b).collect();
+        if follows_a.is_empty() || follows_b.is_empty() {
+            return None;
+        }
+        let common = follows_a.intersection(&follows_b).count();
+        Some(common as f64 / (follows_a.len() + follows_b.len() - common) as f64)
+    }
+
+    /// Add the users, follows and metadata of `other`, e.g. read from a cache
+    ///
+    /// Of the contact lists and metadata in both, the newest is kept. Fetch times are also the
+    /// newest of both
+    pub fn merge(&mut self, other: Network) {
+        for user in other.graph.node_weights() {
+            self.add_user(*user);
+        }
+
+        for user in &other.all_users {
+            let follows = other.get_user_contacts(user).copied().collect_vec();
+            let fetched_before = self.added_out_edges_since.get(user).copied();
+            match other.contact_list_creation.get(user) {
+                Some(created_at) => _ = self.update_contact_list(*user, &follows, created_at),
+                // Follows added without a contact list, only known to exist
+                None => {
+                    for follow in &follows {
+                        self.add_follow(*user, *follow);
+                    }
+                }
+            }
+            let fetched = match (fetched_before, other.added_out_edges_since.get(user)) {
+                (Some(x), Some(y)) => Some(x.max(*y)),
+                (x, y) => x.or(y.copied()),
+            };
+            match fetched {
+                Some(fetched) => self.set_contact_list_fetched_at(*user, fetched),
+                None => _ = self.added_out_edges_since.remove(user),
+            }
+        }
+
+        for ((by, user), count) in &other.interactions {
+            self.add_interactions(*by, *user, *count);
+        }
+        for (key, total) in &other.zaps {
+            let current = self.zaps.entry(*key).or_default();
+            current.amount_msat = current.amount_msat.saturating_add(total.amount_msat);
+            current.count += total.count;
+        }
+
+        for (user, created_at) in &other.mute_list_creation {
+            _ = self.update_mute_list(*user, &other.get_user_muted(user), created_at);
+        }
+
+        for (user, metadata) in other.users_metadata {
+            match (metadata, self.users_metadata.get(&user)) {
+                (Some((_, time)), Some(Some((_, current)))) if time <= *current => (),
+                (Some((metadata, time)), _) => _ = self.add_user_metadata(user, metadata, time),
+                (None, None) => _ = self.users_metadata.insert(user, None),
+                (None, Some(_)) => (),
+            }
+        }
+    }
+
+    /// Take the user out of the network, with their follows both ways and their metadata, e.g. for
+    /// a deletion request. Returns whether the user was known
+    ///
+    /// Node indices change, so hold the delete lock for writing while removing
+    pub fn remove_user(&mut self, user: &PublicKey) -> bool {
+        let node = self.graph_indices.remove(user);
+        let metadata = self.users_metadata.remove(user).is_some();
+        self.added_out_edges_since.remove(user);
+        self.contact_list_creation.remove(user);
+        self.mute_list_creation.remove(user);
+        self.petnames.retain(|(x, y), _| x != user && y != user);
+        self.interactions.retain(|(x, y), _| x != user && y != user);
+        self.zaps.retain(|(x, y), _| x != user && y != user);
+        self.mutuals.remove_user(user);
+        let Some(node) = node else {
+            return metadata;
         };
-        let outgoing: HashSet<NodeIndex> = self
+        self.graph.remove_node(node);
+        // The last node takes the index of the removed one
+        if let Some(moved) = self.graph.node_weight(node) {
+            self.graph_indices.insert(*moved, node);
+        }
+        self.all_users.remove(user);
+        self.mutations += 1;
+        if let Some(root) = self.root() {
+            self.set_root(Some(root));
+        }
+        true
+    }
+
+    /// Remove the users that follow and are followed by no one, and don't have metadata or a
+    /// known contact list, like the border users added by searches
+    ///
+    /// Node indices change, so hold the delete lock for writing while pruning
+    pub fn prune(&mut self, options: PruneOptions) -> PruneReport {
+        let users_before = self.graph.node_count();
+        let follows_before = self.graph.edge_count();
+        // Recomputed once at the end, rather than on every removal
+        let root = self.root_distances.take().map(|x| x.root());
+
+        let mut stale_contact_lists = 0;
+        if let Some(cutoff) = options.stale_before {
+            let stale = self
+                .added_out_edges_since
+                .iter()
+                .filter(|(_, fetched)| **fetched < cutoff)
+                .map(|(user, _)| *user)
+                .collect_vec();
+            for user in stale {
+                self.remove_contact_list(user);
+                self.added_out_edges_since.remove(&user);
+                self.contact_list_creation.remove(&user);
+                stale_contact_lists += 1;
+            }
+        }
+
+        let removed: HashSet<NodeIndex> = self
             .graph
-            .edges_directed(*user_node, Direction::Outgoing)
-            .filter(|x| x.weight() == &EdgeKind::Following)
-            .map(|x| x.target())
+            .node_indices()
+            .filter(|node| {
+                let user = &self.graph[*node];
+                self.graph.neighbors_undirected(*node).next().is_none()
+                    && self.get_pubkey_metadata(user).is_none()
+                    && !self.does_user_follow(user)
+            })
             .collect();
-
-        let ingoing: HashSet<NodeIndex> = self
+        for node in &removed {
+            self.users_metadata.remove(&self.graph[*node]);
+        }
+        // Removing a node moves the last one to its index, so every index is looked up again
+        self.graph.retain_nodes(|_, node| !removed.contains(&node));
+        self.graph_indices = self
             .graph
-            .edges_directed(*user_node, Direction::Incoming)
-            .filter(|x| x.weight() == &EdgeKind::Following)
-            .map(|x| x.source())
+            .node_indices()
+            .map(|node| (self.graph[node], node))
             .collect();
+        self.all_users = self.graph_indices.keys().copied().collect();
+
+        self.set_root(root);
+        let report = PruneReport {
+            users: users_before - self.graph.node_count(),
+            follows: follows_before - self.graph.edge_count(),
+            stale_contact_lists,
+        };
+        if report != PruneReport::default() {
+            self.mutations += 1;
+        }
+        report
+    }
+
+    /// Track the distances from `root` through follows, up to ROOT_MAX_DEPTH, see distance_from_root
+    pub fn set_root(&mut self, root: Option<PublicKey>) {
+        self.root_distances =
+            root.map(|x| RootDistances::new(x, crate::network::reach::ROOT_MAX_DEPTH, self));
+    }
+
+    pub fn root(&self) -> Option<PublicKey> {
+        self.root_distances.as_ref().map(|x| x.root())
+    }
 
-        ingoing.intersection(&outgoing).map(|x| *x).collect_vec()
+    /// Distance from the root set with set_root, without searching. None when there's no root,
+    /// or the user isn't within ROOT_MAX_DEPTH of it
+    pub fn distance_from_root(&self, user: &PublicKey) -> Option<usize> {
+        self.root_distances.as_ref()?.distance(user)
     }
 
-    pub fn get_user_contacts<'a>(
-        &'a self,
-        user: &PublicKey,
-    ) -> Box<dyn Iterator<Item = &'a PublicKey> + 'a> {
-        let user_node = match self.graph_indices.get(user) {
-            Some(s) => s,
-            // Since it has no items, it cannot access anything with lifetime 'a
-            None => return Box::new(std::iter::empty()),
+    /// Shortest chain of follows from one user to the other, using only the graph in memory,
+    /// joined as `mode` says
+    ///
+    /// The path includes both users. None when either isn't in the network
+    pub fn shortest_path(
+        &self,
+        from: &PublicKey,
+        to: &PublicKey,
+        mode: PathMode,
+    ) -> Option<Vec<PublicKey>> {
+        let start = *self.graph_indices.get(from)?;
+        let end = *self.graph_indices.get(to)?;
+        let mut parents: HashMap<NodeIndex, NodeIndex> = HashMap::new();
+        parents.insert(start, start);
+        let mut queue = VecDeque::from([start]);
+        while let Some(node) = queue.pop_front() {
+            if node == end {
+                let mut path = vec![self.graph[end]];
+                let mut current = end;
+                while current != start {
+                    current = parents[&current];
+                    path.push(self.graph[current]);
+                }
+                path.reverse();
+                return Some(path);
+            }
+            for follow in self.path_steps(node, mode) {
+                if let Entry::Vacant(e) = parents.entry(follow) {
+                    e.insert(node);
+                    queue.push_back(follow);
+                }
+            }
+        }
+        None
+    }
+
+    /// Up to `limit` of the shortest paths from `from` to `to`, see shortest_path
+    pub fn shortest_paths(
+        &self,
+        from: &PublicKey,
+        to: &PublicKey,
+        mode: PathMode,
+        limit: usize,
+    ) -> Vec<Vec<PublicKey>> {
+        let (Some(start), Some(end)) = (self.graph_indices.get(from), self.graph_indices.get(to))
+        else {
+            return vec![];
         };
-        Box::new(
-            self.graph
-                .edges_directed(*user_node, Direction::Outgoing)
-                .filter(|x| x.weight() == &EdgeKind::Following)
-                .map(|x| {
+        // Each node reached with its distance and all the nodes one step closer leading to it
+        let mut parents: HashMap<NodeIndex, (usize, Vec<NodeIndex>)> =
+            HashMap::from([(*start, (0, vec![]))]);
+        let mut queue = VecDeque::from([*start]);
+        while let Some(node) = queue.pop_front() {
+            let distance = parents[&node].0;
+            if parents.get(end).is_some_and(|(x, _)| distance >= *x) {
+                break;
+            }
+            for follow in self.path_steps(node, mode) {
+                match parents.entry(follow) {
+                    Entry::Vacant(e) => {
+                        e.insert((distance + 1, vec![node]));
+                        queue.push_back(follow);
+                    }
+                    Entry::Occupied(mut e) if e.get().0 == distance + 1 => e.get_mut().1.push(node),
+                    Entry::Occupied(_) => (),
+                }
+            }
+        }
+        if !parents.contains_key(end) {
+            return vec![];
+        }
+
+        let mut paths = vec![];
+        let mut partial = vec![vec![*end]];
+        while let Some(path) = partial.pop() {
+            if paths.len() >= limit {
+                break;
+            }
+            let last = *path.last().unwrap();
+            if last == *start {
+                paths.push(path.iter().rev().map(|x| self.graph[*x]).collect_vec());
+                continue;
+            }
+            for parent in &parents[&last].1 {
+                let mut path = path.clone();
+                path.push(*parent);
+                partial.push(path);
+            }
+        }
+        paths
+    }
+
+    /// Nodes a path goes to next from `node`
+    fn path_steps(&self, node: NodeIndex, mode: PathMode) -> Vec<NodeIndex> {
+        let follows = self
+            .graph
+            .edges_directed(node, Direction::Outgoing)
+            .filter(|x| x.weight() == &EdgeKind::Following)
+            .map(|x| x.target());
+        match mode {
+            PathMode::Mutual => self.get_user_mutuals(&self.graph[node]),
+            PathMode::Directed => follows.collect_vec(),
+            PathMode::Either => follows
+                .chain(
                     self.graph
-                        .node_weight(x.target())
-                        .expect("Node without weight?!")
-                }),
-        )
+                        .edges_directed(node, Direction::Incoming)
+                        .filter(|x| x.weight() == &EdgeKind::Following)
+                        .map(|x| x.source()),
+                )
+                .unique()
+                .collect_vec(),
+        }
+    }
+
+    /// How `user` is joined to `next` in a path, the strongest way when there are two. None when
+    /// neither follows the other, or one isn't in the network
+    pub fn hop(&self, user: &PublicKey, next: &PublicKey) -> Option<Hop> {
+        let follows = |a: &PublicKey, b: &PublicKey| self.get_user_contacts(a).any(|x| x == b);
+        match (follows(user, next), follows(next, user)) {
+            (true, true) => Some(Hop::Mutual),
+            (true, false) => Some(Hop::Follows),
+            (false, true) => Some(Hop::FollowedBy),
+            (false, false) => None,
+        }
+    }
+
+    /// The users at most `radius` follows away from center, in either direction, as a network of
+    /// their own with the edges between them, their metadata and when their contact lists were
+    /// fetched. With mutual_only, a follow is only taken when it's followed back
+    ///
+    /// Empty when center isn't in the network
+    pub fn ego_subgraph(&self, center: &PublicKey, radius: usize, mutual_only: bool) -> Network {
+        let mut ego = Network::new();
+        let Some(start) = self.pubkey_to_node(center) else {
+            return ego;
+        };
+        let mut distances: HashMap<NodeIndex, usize> = HashMap::from([(start, 0)]);
+        let mut queue = VecDeque::from([start]);
+        while let Some(node) = queue.pop_front() {
+            let distance = distances[&node];
+            if distance >= radius {
+                continue;
+            }
+            let next = if mutual_only {
+                self.get_user_mutuals(&self.graph[node])
+            } else {
+                self.graph
+                    .edges_directed(node, Direction::Outgoing)
+                    .filter(|x| x.weight() == &EdgeKind::Following)
+                    .map(|x| x.target())
+                    .chain(
+                        self.graph
+                            .edges_directed(node, Direction::Incoming)
+                            .filter(|x| x.weight() == &EdgeKind::Following)
+                            .map(|x| x.source()),
+                    )
+                    .collect_vec()
+            };
+            for other in next {
+                if let Entry::Vacant(e) = distances.entry(other) {
+                    e.insert(distance + 1);
+                    queue.push_back(other);
+                }
+            }
+        }
+
+        // Added in the order of this graph, so the ego network lists them the same way
+        let included = self
+            .graph
+            .node_indices()
+            .filter(|x| distances.contains_key(x))
+            .collect_vec();
+        for node in &included {
+            let user = self.graph[*node];
+            ego.add_user(user);
+            if let Some(metadata) = self.users_metadata.get(&user) {
+                ego.users_metadata.insert(user, metadata.clone());
+            }
+            if let Some(fetched) = self.added_out_edges_since.get(&user) {
+                ego.added_out_edges_since.insert(user, *fetched);
+            }
+            if let Some(created) = self.contact_list_creation.get(&user) {
+                ego.contact_list_creation.insert(user, *created);
+            }
+            if let Some(created) = self.mute_list_creation.get(&user) {
+                ego.mute_list_creation.insert(user, *created);
+            }
+        }
+        for edge in self.graph.edge_references() {
+            if distances.contains_key(&edge.source()) && distances.contains_key(&edge.target()) {
+                let source = ego.graph_indices[&self.graph[edge.source()]];
+                let target = ego.graph_indices[&self.graph[edge.target()]];
+                ego.graph.add_edge(source, target, *edge.weight());
+            }
+        }
+        ego.mutuals = MutualProjection::from_follows(ego.iter_follows());
+        let petnames = self
+            .petnames
+            .iter()
+            .filter(|((x, y), _)| ego.contains_user(x) && ego.contains_user(y))
+            .map(|(k, v)| (*k, v.clone()))
+            .collect_vec();
+        ego.petnames.extend(petnames);
+        ego.mutations = 0;
+        ego
+    }
+
+    pub fn get_user_contacts(&self, user: &PublicKey) -> ContactsIter<'_> {
+        match self.graph_indices.get(user) {
+            Some(user_node) => ContactsIter::Edges {
+                graph: &self.graph,
+                edges: self.graph.edges_directed(*user_node, Direction::Outgoing),
+            },
+            None => ContactsIter::Empty,
+        }
     }
 
     pub fn node_to_pubkey(&self, node: NodeIndex) -> Option<PublicKey> {
@@ -261,6 +1061,52 @@ impl Network {
         self.graph_indices.get(pubkey).map(|x| *x)
     }
 
+    /// Every user in the network, in the order they were added
+    pub fn users(&self) -> impl Iterator<Item = PublicKey> + '_ {
+        self.graph.node_weights().copied()
+    }
+
+    /// Every edge in the network, as (source, target, kind)
+    pub fn edges(&self) -> impl Iterator<Item = (PublicKey, PublicKey, EdgeKind)> + '_ {
+        self.graph
+            .edge_references()
+            .map(|x| (self.graph[x.source()], self.graph[x.target()], *x.weight()))
+    }
+
+    /// Every user in the network, without copying them
+    pub fn iter_users(&self) -> impl Iterator<Item = &PublicKey> {
+        self.graph.node_weights()
+    }
+
+    /// Every follow in the network, as (user, follow). Mute list entries aren't included
+    pub fn iter_follows(&self) -> impl Iterator<Item = (PublicKey, PublicKey)> + '_ {
+        self.graph
+            .edge_references()
+            .filter(|x| x.weight() == &EdgeKind::Following)
+            .map(|x| (self.graph[x.source()], self.graph[x.target()]))
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.graph.node_count()
+    }
+
+    /// Follows in the network, the edges of iter_follows
+    pub fn edge_count(&self) -> usize {
+        self.iter_follows().count()
+    }
+
+    /// Users with known metadata, not counting the ones known to have none
+    pub fn metadata_count(&self) -> usize {
+        self.users_metadata.values().filter(|x| x.is_some()).count()
+    }
+
+    /// Every user with known metadata
+    pub fn users_metadata(&self) -> impl Iterator<Item = (&PublicKey, &Metadata)> {
+        self.users_metadata
+            .iter()
+            .filter_map(|(user, meta)| Some((user, &meta.as_ref()?.0)))
+    }
+
     pub fn get_pubkey_metadata(&self, pubkey: &PublicKey) -> Option<&(Metadata, Timestamp)> {
         match self.users_metadata.get(pubkey) {
             Some(Some(s)) => Some(s),
@@ -268,4 +1114,376 @@ impl Network {
             None => None,
         }
     }
+
+    /// Copy the network into a serializable form
+    pub fn to_snapshot(&self) -> NetworkSnapshot {
+        let users = self.graph.node_weights().copied().collect_vec();
+        let follows = self
+            .graph
+            .edge_references()
+            .filter(|x| x.weight() == &EdgeKind::Following)
+            .map(|x| (x.source().index() as u32, x.target().index() as u32))
+            .collect_vec();
+        let users_metadata = self
+            .users_metadata
+            .iter()
+            .map(|(pubkey, meta)| (*pubkey, meta.as_ref().map(|(m, t)| (m.as_json(), *t))))
+            .collect_vec();
+
+        NetworkSnapshot {
+            version: SNAPSHOT_VERSION,
+            created_at: Timestamp::now(),
+            users,
+            follows,
+            users_metadata,
+            added_out_edges_since: self
+                .added_out_edges_since
+                .iter()
+                .map(|(k, v)| (*k, *v))
+                .collect_vec(),
+            contact_list_creation: self
+                .contact_list_creation
+                .iter()
+                .map(|(k, v)| (*k, *v))
+                .collect_vec(),
+        }
+    }
+
+    pub fn from_snapshot(snapshot: NetworkSnapshot) -> Result<Network, PersistError> {
+        let mut network = Network::new();
+        let nodes = snapshot
+            .users
+            .into_iter()
+            .map(|user| network.add_user(user).0)
+            .collect_vec();
+        for (user, follow) in snapshot.follows {
+            match (nodes.get(user as usize), nodes.get(follow as usize)) {
+                (Some(user), Some(follow)) => {
+                    network
+                        .graph
+                        .update_edge(*user, *follow, EdgeKind::Following);
+                }
+                _ => return Err(PersistError::Corrupted("follow to unknown user")),
+            }
+        }
+        network.mutuals = MutualProjection::from_follows(network.iter_follows());
+        for (pubkey, meta) in snapshot.users_metadata {
+            let meta = match meta {
+                Some((json, time)) => match Metadata::from_json(json) {
+                    Ok(m) => Some((m, time)),
+                    Err(_err) => return Err(PersistError::Corrupted("invalid metadata")),
+                },
+                None => None,
+            };
+            network.users_metadata.insert(pubkey, meta);
+        }
+        network
+            .added_out_edges_since
+            .extend(snapshot.added_out_edges_since);
+        network
+            .contact_list_creation
+            .extend(snapshot.contact_list_creation);
+        network.mutations = 0;
+
+        Ok(network)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn newer_contact_list_with_the_same_follows_is_a_mutation() {
+        let users = pubkeys(2);
+        let mut network = network(&users, &[(0, &[1])]);
+        let saved = network.mutation_count();
+        network.update_contact_list(users[0], &[users[1]], &Timestamp::from(2000));
+        assert_ne!(network.mutation_count(), saved);
+        assert_eq!(
+            network.contact_list_created_at(&users[0]),
+            Some(Timestamp::from(2000))
+        );
+    }
+
+    #[test]
+    fn reading_is_not_a_mutation() {
+        let users = pubkeys(3);
+        let network = network(&users, &[(0, &[1]), (1, &[0, 2])]);
+        let saved = network.mutation_count();
+        network.shortest_path(&users[0], &users[2], PathMode::Either);
+        network.get_user_contacts(&users[1]).count();
+        network.to_snapshot();
+        assert_eq!(network.mutation_count(), saved);
+    }
+
+    fn contacts(network: &Network, user: &PublicKey) -> Vec<PublicKey> {
+        network.get_user_contacts(user).copied().sorted().collect()
+    }
+
+    fn contacts_of(users: &[PublicKey], indices: &[usize]) -> Vec<PublicKey> {
+        indices.iter().map(|x| users[*x]).sorted().collect()
+    }
+
+    #[test]
+    fn merge_keeps_the_newest_of_each_user() {
+        let users = pubkeys(6);
+        let name = |network: &Network, user| {
+            network
+                .get_pubkey_metadata(&users[user])
+                .map(|(x, time)| (x.name.clone().unwrap(), time.as_u64()))
+        };
+        // 0 is newer in the live network, 1 in the merged one, 2 and 5 only in the merged one
+        let mut live = network(&users, &[(0, &[1, 2]), (1, &[0])]);
+        live.update_contact_list(users[0], &[users[1], users[3]], &Timestamp::from(3000));
+        live.add_user_metadata(users[0], Metadata::new().name("new"), Timestamp::from(3000));
+        live.add_user_metadata(users[1], Metadata::new().name("old"), Timestamp::from(1000));
+        live.set_contact_list_fetched_at(users[0], Timestamp::from(5000));
+        live.set_contact_list_fetched_at(users[1], Timestamp::from(1000));
+
+        let mut other = network(&users, &[(0, &[4]), (2, &[0, 5])]);
+        other.update_contact_list(users[1], &[users[2], users[4]], &Timestamp::from(2000));
+        other.add_user_metadata(users[0], Metadata::new().name("old"), Timestamp::from(1000));
+        other.add_user_metadata(users[1], Metadata::new().name("new"), Timestamp::from(2000));
+        other.set_contact_list_fetched_at(users[0], Timestamp::from(4000));
+        other.set_contact_list_fetched_at(users[1], Timestamp::from(6000));
+
+        live.merge(other);
+        assert_eq!(contacts(&live, &users[0]), contacts_of(&users, &[1, 3]));
+        assert_eq!(contacts(&live, &users[1]), contacts_of(&users, &[2, 4]));
+        assert_eq!(contacts(&live, &users[2]), contacts_of(&users, &[0, 5]));
+        assert_eq!(
+            (
+                live.contact_list_created_at(&users[0]),
+                live.contact_list_created_at(&users[1])
+            ),
+            (Some(Timestamp::from(3000)), Some(Timestamp::from(2000)))
+        );
+        assert_eq!(name(&live, 0), Some(("new".to_string(), 3000)));
+        assert_eq!(name(&live, 1), Some(("new".to_string(), 2000)));
+        assert_eq!(
+            (
+                live.added_out_edges_since[&users[0]],
+                live.added_out_edges_since[&users[1]]
+            ),
+            (Timestamp::from(5000), Timestamp::from(6000))
+        );
+
+        assert_indices_consistent(&live);
+        assert_eq!(live.graph.node_count(), 6);
+    }
+
+    /// Every user is at the node the indices say, and only the users in the graph are known
+    fn assert_indices_consistent(network: &Network) {
+        assert_eq!(network.graph_indices.len(), network.graph.node_count());
+        for (user, node) in &network.graph_indices {
+            assert_eq!(network.graph[*node], *user);
+        }
+        assert_eq!(
+            network.all_users,
+            network.graph_indices.keys().copied().collect()
+        );
+    }
+
+    #[test]
+    fn prune_removes_the_isolated_users_and_rebuilds_the_indices() {
+        // 0 - 1 - 2 are a chain of mutuals, 3 and 5 were only added by a search, 4 only has
+        // metadata and 6 has an empty contact list
+        let users = pubkeys(7);
+        let mut network = network(&users, &[(0, &[1]), (1, &[0, 2]), (2, &[1]), (6, &[])]);
+        network.add_user(users[3]);
+        network.add_user(users[4]);
+        network.add_user(users[5]);
+        network.add_user_metadata(
+            users[4],
+            Metadata::new().name("four"),
+            Timestamp::from(1000),
+        );
+        network.set_root(Some(users[0]));
+        let saved = network.mutation_count();
+
+        let report = network.prune(PruneOptions::default());
+        assert_eq!(
+            report,
+            PruneReport {
+                users: 2,
+                follows: 0,
+                stale_contact_lists: 0
+            }
+        );
+        assert_ne!(network.mutation_count(), saved);
+        assert!(!network.contains_user(&users[3]) && !network.contains_user(&users[5]));
+        assert!(network.contains_user(&users[4]) && network.contains_user(&users[6]));
+        assert_indices_consistent(&network);
+        assert_eq!(contacts(&network, &users[1]), contacts_of(&users, &[0, 2]));
+        assert_eq!(
+            network.shortest_path(&users[0], &users[2], PathMode::Mutual),
+            Some(vec![users[0], users[1], users[2]])
+        );
+        assert_eq!(network.distance_from_root(&users[2]), Some(2));
+
+        // Nothing left to prune
+        let saved = network.mutation_count();
+        assert_eq!(
+            network.prune(PruneOptions::default()),
+            PruneReport::default()
+        );
+        assert_eq!(network.mutation_count(), saved);
+    }
+
+    #[test]
+    fn prune_forgets_stale_contact_lists() {
+        // 0 follows 1 and was fetched long ago, 2 follows 3 and was fetched recently
+        let users = pubkeys(4);
+        let mut network = network(&users, &[(0, &[1]), (2, &[3])]);
+        network.set_contact_list_fetched_at(users[0], Timestamp::from(1000));
+        network.set_contact_list_fetched_at(users[2], Timestamp::from(3000));
+
+        let report = network.prune(PruneOptions {
+            stale_before: Some(Timestamp::from(2000)),
+        });
+        assert_eq!(
+            report,
+            PruneReport {
+                users: 2,
+                follows: 1,
+                stale_contact_lists: 1
+            }
+        );
+        assert!(!network.contains_user(&users[0]) && !network.contains_user(&users[1]));
+        assert_eq!(network.contact_list_created_at(&users[0]), None);
+        assert_eq!(contacts(&network, &users[2]), contacts_of(&users, &[3]));
+        assert_indices_consistent(&network);
+    }
+
+    #[test]
+    fn removing_a_user_from_the_middle_keeps_every_other_user_reachable() {
+        let users = pubkeys(6);
+        let mut network = network(
+            &users,
+            &[(0, &[1, 2]), (1, &[0, 2, 5]), (2, &[1]), (5, &[0, 2])],
+        );
+        network.add_user(users[3]);
+        network.add_user_metadata(users[2], Metadata::new().name("two"), Timestamp::from(1000));
+        // The last node is the one moved to the removed index
+        let last = network.graph[NodeIndex::new(network.graph.node_count() - 1)];
+
+        assert!(network.remove_user(&users[2]));
+        assert!(!network.contains_user(&users[2]));
+        assert_eq!(network.pubkey_to_node(&users[2]), None);
+        assert_eq!(network.get_pubkey_metadata(&users[2]), None);
+        assert_eq!(network.user_follow_last_update(&users[2]), None);
+        assert_indices_consistent(&network);
+        for user in [0, 1, 3, 5] {
+            let node = network.pubkey_to_node(&users[user]).unwrap();
+            assert_eq!(network.node_to_pubkey(node), Some(users[user]));
+        }
+        assert!(network.pubkey_to_node(&last).is_some());
+        // Follows both ways are gone, the others are kept
+        assert_eq!(contacts(&network, &users[0]), contacts_of(&users, &[1]));
+        assert_eq!(contacts(&network, &users[1]), contacts_of(&users, &[0, 5]));
+        assert_eq!(contacts(&network, &users[5]), contacts_of(&users, &[0]));
+
+        // Unknown users aren't removed twice
+        assert!(!network.remove_user(&users[2]));
+    }
+
+    #[test]
+    fn contact_lists_applied_in_any_order_converge_to_the_newest() {
+        let users = pubkeys(5);
+        let lists: [(&[usize], u64); 3] = [(&[1, 2], 1000), (&[2, 3], 2000), (&[4], 3000)];
+        for order in lists.iter().permutations(lists.len()) {
+            let mut network = Network::new();
+            let mut newest = 0;
+            for (contacts, time) in order {
+                let update = network.update_contact_list(
+                    users[0],
+                    &contacts_of(&users, contacts),
+                    &Timestamp::from(*time),
+                );
+                let expected = if *time > newest {
+                    ContactListUpdate::Applied
+                } else {
+                    ContactListUpdate::IgnoredOlder
+                };
+                assert_eq!(update, expected);
+                newest = newest.max(*time);
+            }
+            assert_eq!(contacts(&network, &users[0]), contacts_of(&users, &[4]));
+            assert_eq!(
+                network.contact_list_created_at(&users[0]),
+                Some(Timestamp::from(3000))
+            );
+        }
+
+        // The same creation time keeps what the network has
+        let mut network = network(&users, &[(0, &[1])]);
+        assert_eq!(
+            network.update_contact_list(users[0], &[users[2]], &Timestamp::from(1000)),
+            ContactListUpdate::IgnoredOlder
+        );
+        assert_eq!(contacts(&network, &users[0]), contacts_of(&users, &[1]));
+    }
+
+    #[test]
+    fn users_not_in_the_network_are_told_apart_from_unlinked_ones() {
+        let users = pubkeys(4);
+        let network = network(&users, &[(0, &[1]), (1, &[0]), (2, &[])]);
+        assert_eq!(network.are_users_mutuals(&users[0], &users[1]), Some(true));
+        assert_eq!(network.are_users_mutuals(&users[0], &users[2]), Some(false));
+        assert_eq!(network.are_users_mutuals(&users[0], &users[3]), None);
+        assert_eq!(network.are_users_mutuals(&users[3], &users[0]), None);
+        assert_eq!(network.hop(&users[3], &users[0]), None);
+        assert_eq!(network.get_user_contacts(&users[3]).count(), 0);
+        assert_eq!(
+            network.shortest_path(&users[0], &users[3], PathMode::Either),
+            None
+        );
+    }
+
+    #[test]
+    fn follow_similarity_is_the_jaccard_index_of_the_follows() {
+        let users = pubkeys(8);
+        let network = network(
+            &users,
+            &[
+                (0, &[2, 3, 4]),
+                (1, &[3, 4, 5, 6]),
+                (2, &[2, 3, 4]),
+                (3, &[7]),
+                (4, &[]),
+            ],
+        );
+        // 3 and 4 in common, of 2 to 6
+        assert_eq!(network.follow_similarity(&users[0], &users[1]), Some(0.4));
+        assert_eq!(
+            network.follow_similarity(&users[0], &users[1]),
+            network.follow_similarity(&users[1], &users[0])
+        );
+        assert_eq!(network.follow_similarity(&users[0], &users[2]), Some(1.0));
+        assert_eq!(network.follow_similarity(&users[0], &users[3]), Some(0.0));
+        // Following nobody, or not in the network
+        assert_eq!(network.follow_similarity(&users[0], &users[4]), None);
+        assert_eq!(network.follow_similarity(&users[5], &users[0]), None);
+    }
+
+    #[test]
+    fn interactions_add_up_by_direction() {
+        let users = pubkeys(3);
+        let mut network = network(&users, &[]);
+        network.add_interactions(users[0], users[1], 2);
+        network.add_interactions(users[0], users[1], 3);
+        network.add_interactions(users[1], users[0], 1);
+        // Nothing counted, or with oneself, isn't kept
+        let saved = network.mutation_count();
+        network.add_interactions(users[0], users[2], 0);
+        network.add_interactions(users[2], users[2], 4);
+        assert_eq!(network.mutation_count(), saved);
+
+        let interactions = network.iter_interactions().sorted().collect_vec();
+        let mut expected = vec![(users[0], users[1], 5), (users[1], users[0], 1)];
+        expected.sort();
+        assert_eq!(interactions, expected);
+    }
 }
@@ -0,0 +1,130 @@
+/// Users most paths between others go through, the connectors searches keep finding
+///
+/// Exact betweenness centrality takes a search from every user, too slow for networks this big,
+/// so it's estimated from the searches of a sample of users. The sample is picked from a seed,
+/// so the same network and seed always give the same scores
+use itertools::Itertools;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::network::*;
+use nostr_sdk::prelude::*;
+
+/// Users searched from by Network::hubs
+pub const HUB_SAMPLE: usize = 32;
+/// Seed of the sample of Network::hubs
+pub const HUB_SEED: u64 = 6;
+
+/// Bridges ranked by Network::hubs, with the network size they were ranked at
+#[derive(Debug, Clone, Default)]
+pub(crate) struct HubsCache {
+    users: usize,
+    ranked: Vec<PublicKey>,
+}
+
+impl Network {
+    /// Estimated betweenness centrality of the users through mutuals, from the shortest paths
+    /// that start in `sample` users picked by `seed`. Scores go from 0 to 1, the share of the
+    /// shortest paths between other users going through the user. Highest first, without the
+    /// users no sampled path goes through
+    pub fn top_bridges(&self, sample: usize, seed: u64) -> Vec<(PublicKey, f64)> {
+        let users = self.users().collect_vec();
+        let n = users.len();
+        if n < 3 || sample == 0 {
+            return vec![];
+        }
+        let index: HashMap<PublicKey, usize> =
+            users.iter().enumerate().map(|(i, x)| (*x, i)).collect();
+        let mutuals = users
+            .iter()
+            .map(|user| {
+                self.mutuals_of(user)
+                    .filter_map(|x| index.get(x).copied())
+                    .collect_vec()
+            })
+            .collect_vec();
+
+        let sources = users
+            .iter()
+            .enumerate()
+            .sorted_by_key(|(_, user)| (sample_key(user, seed), **user))
+            .take(sample)
+            .map(|(i, _)| i)
+            .collect_vec();
+
+        // Brandes, from each source only
+        let mut scores = vec![0.0; n];
+        for source in &sources {
+            let mut order = vec![];
+            let mut parents: Vec<Vec<usize>> = vec![vec![]; n];
+            let mut paths = vec![0.0_f64; n];
+            let mut distance: Vec<Option<usize>> = vec![None; n];
+            paths[*source] = 1.0;
+            distance[*source] = Some(0);
+            let mut queue = VecDeque::from([*source]);
+            while let Some(node) = queue.pop_front() {
+                order.push(node);
+                let next_distance = distance[node].unwrap() + 1;
+                for mutual in &mutuals[node] {
+                    if distance[*mutual].is_none() {
+                        distance[*mutual] = Some(next_distance);
+                        queue.push_back(*mutual);
+                    }
+                    if distance[*mutual] == Some(next_distance) {
+                        paths[*mutual] += paths[node];
+                        parents[*mutual].push(node);
+                    }
+                }
+            }
+            let mut dependency = vec![0.0; n];
+            for node in order.into_iter().rev() {
+                for parent in &parents[node] {
+                    dependency[*parent] += paths[*parent] / paths[node] * (1.0 + dependency[node]);
+                }
+                if node != *source {
+                    scores[node] += dependency[node];
+                }
+            }
+        }
+
+        // Scaled up to every source, over the ordered pairs of other users
+        let scale = n as f64 / sources.len() as f64 / ((n - 1) * (n - 2)) as f64;
+        users
+            .into_iter()
+            .zip(scores)
+            .filter(|(_, score)| *score > 0.0)
+            .map(|(user, score)| (user, score * scale))
+            .sorted_by(|(a, x), (b, y)| y.total_cmp(x).then(a.cmp(b)))
+            .collect()
+    }
+
+    /// The `count` top bridges, see top_bridges. They're ranked again only once the network grew a
+    /// tenth since the last time, as ranking takes a while in big networks
+    pub fn hubs(&mut self, count: usize) -> HashSet<PublicKey> {
+        let users = self.node_count();
+        let stale = match &self.hubs {
+            Some(cache) => users > cache.users + cache.users / 10,
+            None => true,
+        };
+        if stale {
+            eprintln!("Ranking the bridges of {users} users");
+            let ranked = self
+                .top_bridges(HUB_SAMPLE, HUB_SEED)
+                .into_iter()
+                .map(|(user, _)| user)
+                .collect();
+            self.hubs = Some(HubsCache { users, ranked });
+        }
+        let cache = self.hubs.as_ref().unwrap();
+        cache.ranked.iter().take(count).copied().collect()
+    }
+}
+
+/// Order of `user` in the sample of `seed`, mixing the start of the key with splitmix64
+fn sample_key(user: &PublicKey, seed: u64) -> u64 {
+    let bytes = user.to_bytes();
+    let mut x = seed ^ u64::from_le_bytes(bytes[..8].try_into().unwrap());
+    x = x.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
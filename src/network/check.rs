@@ -0,0 +1,281 @@
+/// Checking a saved network for entries that don't agree with each other, e.g. after a crash or
+/// an upgrade, and dropping them
+use itertools::Itertools;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::network::persist::{self, CacheFormat, NetworkSnapshot, PersistError};
+use nostr_sdk::prelude::*;
+
+/// Times this far ahead of the clock are still taken as a difference between clocks
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(10 * 60);
+
+/// Inconsistent entries found in a snapshot, counted by kind
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheCheck {
+    /// Users listed more than once. Their follows go to the first one
+    pub duplicate_users: usize,
+    /// Follows from or to a user that isn't in the snapshot. Loading fails with them
+    pub dangling_follows: usize,
+    /// The same follow listed more than once
+    pub duplicate_follows: usize,
+    /// Metadata that doesn't parse. Loading fails with it
+    pub invalid_metadata: usize,
+    /// Metadata, fetch times or contact list times of users that aren't in the snapshot
+    pub unknown_users: usize,
+    /// Times after the clock, which would keep newer contact lists and metadata from replacing
+    /// them
+    pub future_timestamps: usize,
+}
+
+impl CacheCheck {
+    pub fn is_empty(&self) -> bool {
+        *self == CacheCheck::default()
+    }
+
+    /// Whether the snapshot can't be loaded as it is
+    pub fn is_fatal(&self) -> bool {
+        self.dangling_follows > 0 || self.invalid_metadata > 0
+    }
+}
+
+impl Display for CacheCheck {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "No problems found");
+        }
+        let lines = [
+            (self.duplicate_users, "duplicate users", false),
+            (self.dangling_follows, "follows of unknown users", true),
+            (self.duplicate_follows, "duplicate follows", false),
+            (self.invalid_metadata, "invalid metadata", true),
+            (
+                self.unknown_users,
+                "entries of users not in the network",
+                false,
+            ),
+            (self.future_timestamps, "timestamps in the future", false),
+        ];
+        for (count, name, fatal) in lines {
+            if count > 0 {
+                let fatal = if fatal { " (fatal)" } else { "" };
+                writeln!(f, "{count} {name}{fatal}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl NetworkSnapshot {
+    /// The snapshot without its inconsistent entries, and what was dropped. Times after `now`,
+    /// allowing for MAX_CLOCK_SKEW, are in the future. The creation time of the snapshot is set
+    /// to `now` instead
+    pub fn repair(self, now: Timestamp) -> (NetworkSnapshot, CacheCheck) {
+        let mut check = CacheCheck::default();
+        let latest = now + MAX_CLOCK_SKEW;
+        let future_timestamps = Cell::new(0);
+        let in_future = |time: &Timestamp| {
+            let future = *time > latest;
+            future_timestamps.set(future_timestamps.get() + future as usize);
+            future
+        };
+
+        let created_at = if in_future(&self.created_at) {
+            now
+        } else {
+            self.created_at
+        };
+
+        // Index in the repaired users of each index of the snapshot
+        let mut new_index: HashMap<PublicKey, u32> = HashMap::new();
+        let mut remap = Vec::with_capacity(self.users.len());
+        let mut users = vec![];
+        for user in self.users {
+            let index = *new_index.entry(user).or_insert_with(|| {
+                users.push(user);
+                (users.len() - 1) as u32
+            });
+            remap.push(index);
+        }
+        check.duplicate_users = remap.len() - users.len();
+
+        let mut seen = HashSet::new();
+        let mut follows = vec![];
+        for (user, follow) in self.follows {
+            match (remap.get(user as usize), remap.get(follow as usize)) {
+                (Some(user), Some(follow)) if seen.insert((*user, *follow)) => {
+                    follows.push((*user, *follow))
+                }
+                (Some(_), Some(_)) => check.duplicate_follows += 1,
+                _ => check.dangling_follows += 1,
+            }
+        }
+
+        // Entries of users that aren't in the snapshot
+        let unknown_users = Cell::new(0);
+        let known = |user: &PublicKey| {
+            let known = new_index.contains_key(user);
+            unknown_users.set(unknown_users.get() + !known as usize);
+            known
+        };
+        let users_metadata = self
+            .users_metadata
+            .into_iter()
+            .filter(|(user, meta)| {
+                if !known(user) {
+                    return false;
+                }
+                match meta {
+                    Some((json, _)) if Metadata::from_json(json).is_err() => {
+                        check.invalid_metadata += 1;
+                        false
+                    }
+                    Some((_, time)) => !in_future(time),
+                    None => true,
+                }
+            })
+            .collect_vec();
+        let times = |entries: Vec<(PublicKey, Timestamp)>| {
+            entries
+                .into_iter()
+                .filter(|(user, time)| known(user) && !in_future(time))
+                .collect_vec()
+        };
+        let added_out_edges_since = times(self.added_out_edges_since);
+        let contact_list_creation = times(self.contact_list_creation);
+        check.unknown_users = unknown_users.get();
+        check.future_timestamps = future_timestamps.get();
+
+        let snapshot = NetworkSnapshot {
+            version: persist::SNAPSHOT_VERSION,
+            created_at,
+            users,
+            follows,
+            users_metadata,
+            added_out_edges_since,
+            contact_list_creation,
+        };
+        (snapshot, check)
+    }
+}
+
+/// Where check_cache keeps the cache as it was
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".corrupt");
+    PathBuf::from(name)
+}
+
+/// Check the cache at `path` and, with `repair`, write it again without the inconsistent entries,
+/// copying the old one to backup_path first
+pub fn check_cache(path: &Path, repair: bool) -> Result<CacheCheck, PersistError> {
+    let (repaired, check) = NetworkSnapshot::read_from(path)?.repair(Timestamp::now());
+    if repair && !check.is_empty() {
+        fs::copy(path, backup_path(path))?;
+        persist::save_rotating(&repaired, path, 0, CacheFormat::from_path(path))?;
+    }
+    Ok(check)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Network;
+    use crate::test_utils::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    /// Snapshot of 0 -> 1 -> 2 with every kind of inconsistency, once each except the future
+    /// timestamps: the creation time, a metadata and a fetch time
+    fn corrupt_snapshot(users: &[PublicKey]) -> NetworkSnapshot {
+        let future = Timestamp::from(NOW + 3600);
+        let past = Timestamp::from(NOW - 3600);
+        let metadata = Metadata::new().name("alice").as_json();
+        NetworkSnapshot {
+            version: persist::SNAPSHOT_VERSION,
+            created_at: future,
+            // 0 listed twice
+            users: vec![users[0], users[1], users[2], users[0]],
+            follows: vec![(0, 1), (1, 2), (0, 1), (3, 1), (1, 9)],
+            users_metadata: vec![
+                (users[0], Some((metadata.clone(), past))),
+                (users[1], Some(("{not json".to_string(), past))),
+                (users[2], Some((metadata.clone(), future))),
+                (users[3], Some((metadata, past))),
+            ],
+            added_out_edges_since: vec![(users[0], past), (users[1], future)],
+            contact_list_creation: vec![(users[0], past), (users[1], past)],
+        }
+    }
+
+    #[test]
+    fn repair_drops_each_kind_of_inconsistent_entry() {
+        let users = pubkeys(4);
+        let (repaired, check) = corrupt_snapshot(&users).repair(Timestamp::from(NOW));
+        assert_eq!(
+            check,
+            CacheCheck {
+                duplicate_users: 1,
+                dangling_follows: 1,
+                // Both (0, 1) and the (3, 1) of the duplicate of 0
+                duplicate_follows: 2,
+                invalid_metadata: 1,
+                unknown_users: 1,
+                future_timestamps: 3,
+            }
+        );
+        assert!(check.is_fatal());
+        assert_eq!(repaired.created_at(), Timestamp::from(NOW));
+
+        // What's left loads, and is consistent
+        let network = Network::from_snapshot(repaired).unwrap();
+        assert_eq!(network.edge_count(), 2);
+        assert!(network.get_pubkey_metadata(&users[0]).is_some());
+        assert!(network.get_pubkey_metadata(&users[2]).is_none());
+        let (_, check) = network.to_snapshot().repair(Timestamp::now());
+        assert!(check.is_empty(), "{check}");
+    }
+
+    #[test]
+    fn skewed_clocks_arent_in_the_future_and_only_some_problems_are_fatal() {
+        let users = pubkeys(2);
+        let mut snapshot = network(&users, &[(0, &[1])]).to_snapshot();
+        let skewed = Timestamp::now() + MAX_CLOCK_SKEW / 2;
+        snapshot.added_out_edges_since = vec![(users[0], skewed)];
+        snapshot.users.push(users[1]);
+        let (_, check) = snapshot.repair(Timestamp::now());
+        assert_eq!(check.future_timestamps, 0);
+        assert_eq!(check.duplicate_users, 1);
+        assert!(!check.is_fatal());
+        assert_eq!(check.to_string(), "1 duplicate users\n");
+    }
+
+    #[test]
+    fn check_cache_repairs_only_when_asked_keeping_a_backup() {
+        let users = pubkeys(4);
+        let path = temp_path("check-cache.json");
+        corrupt_snapshot(&users)
+            .write_to(&path, CacheFormat::Json)
+            .unwrap();
+        let original = fs::read(&path).unwrap();
+
+        let check = check_cache(&path, false).unwrap();
+        assert!(check.is_fatal());
+        assert_eq!(fs::read(&path).unwrap(), original);
+        assert!(!backup_path(&path).exists());
+
+        assert_eq!(check_cache(&path, true).unwrap(), check);
+        assert_eq!(fs::read(backup_path(&path)).unwrap(), original);
+        assert!(check_cache(&path, false).unwrap().is_empty());
+        let network = Network::from_snapshot(NetworkSnapshot::read_from(&path).unwrap()).unwrap();
+        assert_eq!(network.edge_count(), 2);
+
+        // A file that isn't a snapshot can't be checked
+        fs::write(&path, b"\x00\x01garbage").unwrap();
+        assert!(check_cache(&path, true).is_err());
+    }
+}
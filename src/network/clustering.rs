@@ -0,0 +1,98 @@
+/// How tight-knit the circles of mutuals of users are
+use petgraph::graph::NodeIndex;
+use std::collections::{HashMap, HashSet};
+
+use crate::network::*;
+use nostr_sdk::prelude::*;
+
+impl Network {
+    /// For each of `users`, the share of the pairs of its mutuals that are mutuals themselves,
+    /// closing a triangle. None when the user isn't in the network or has fewer than 2 mutuals
+    ///
+    /// The mutuals found are shared among the users, which makes it much faster for users in the
+    /// same circles
+    pub fn clustering_coefficients<'a>(
+        &self,
+        users: impl IntoIterator<Item = &'a PublicKey>,
+    ) -> HashMap<PublicKey, Option<f64>> {
+        let mut mutuals = HashMap::new();
+        users
+            .into_iter()
+            .map(|user| {
+                (
+                    *user,
+                    self.clustering_coefficient_cached(user, &mut mutuals),
+                )
+            })
+            .collect()
+    }
+
+    fn clustering_coefficient_cached(
+        &self,
+        user: &PublicKey,
+        mutuals: &mut HashMap<NodeIndex, HashSet<NodeIndex>>,
+    ) -> Option<f64> {
+        let node = self.pubkey_to_node(user)?;
+        let neighbors = self.cached_mutuals(node, mutuals).clone();
+        let degree = neighbors.len();
+        if degree < 2 {
+            return None;
+        }
+        // Each triangle is seen from both of its other ends
+        let mut links = 0;
+        for neighbor in &neighbors {
+            let theirs = self.cached_mutuals(*neighbor, mutuals);
+            let (small, big) = if theirs.len() < neighbors.len() {
+                (theirs, &neighbors)
+            } else {
+                (&neighbors, theirs)
+            };
+            links += small.iter().filter(|x| big.contains(x)).count();
+        }
+        Some(links as f64 / (degree * (degree - 1)) as f64)
+    }
+
+    fn cached_mutuals<'a>(
+        &self,
+        node: NodeIndex,
+        mutuals: &'a mut HashMap<NodeIndex, HashSet<NodeIndex>>,
+    ) -> &'a HashSet<NodeIndex> {
+        mutuals.entry(node).or_insert_with(|| {
+            let user = self.node_to_pubkey(node).expect("Node without weight?!");
+            self.get_user_mutuals(&user).into_iter().collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::*;
+
+    #[test]
+    fn clustering_coefficient_is_the_share_of_mutuals_that_are_mutuals() {
+        let users = pubkeys(7);
+        // 0 is mutuals with 1, 2 and 3, of which only 1 and 2 are mutuals. 4 only follows 0
+        let network = network(
+            &users,
+            &[
+                (0, &[1, 2, 3]),
+                (1, &[0, 2]),
+                (2, &[0, 1]),
+                (3, &[0]),
+                (4, &[0]),
+                (5, &[6]),
+                (6, &[5]),
+            ],
+        );
+        let outside = pubkeys(8)[7];
+        let coefficients =
+            network.clustering_coefficients(&[users[0], users[1], users[3], users[5], outside]);
+        assert_eq!(coefficients[&users[0]], Some(1.0 / 3.0));
+        assert_eq!(coefficients[&users[1]], Some(1.0));
+        // Fewer than 2 mutuals, or not in the network
+        assert_eq!(coefficients[&users[3]], None);
+        assert_eq!(coefficients[&users[5]], None);
+        assert_eq!(coefficients[&outside], None);
+        assert_eq!(coefficients.len(), 5);
+    }
+}
@@ -0,0 +1,92 @@
+/// Parts of the network not connected to each other, to tell a search that couldn't find a path
+/// apart from one that stopped too early
+use itertools::Itertools;
+use petgraph::unionfind::UnionFind;
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+
+use crate::network::*;
+use nostr_sdk::prelude::*;
+
+/// Connected components of the network, from the biggest
+#[derive(Debug, Clone, Default)]
+pub struct Components {
+    /// Users in each component, indexed by component id
+    pub sizes: Vec<usize>,
+    component_of: HashMap<PublicKey, usize>,
+}
+
+impl Components {
+    /// Id of the component of `user`, None when it isn't in the network
+    pub fn component(&self, user: &PublicKey) -> Option<usize> {
+        self.component_of.get(user).copied()
+    }
+
+    /// Whether `a` and `b` are in the same component. Users not in the network aren't in any
+    pub fn connected(&self, a: &PublicKey, b: &PublicKey) -> bool {
+        match (self.component(a), self.component(b)) {
+            (Some(a), Some(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Display for Components {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let isolated = self.sizes.iter().filter(|x| **x == 1).count();
+        writeln!(
+            f,
+            "{} users in {} components, {} of them single users",
+            self.component_of.len(),
+            self.sizes.len(),
+            isolated
+        )?;
+        for (id, size) in self.sizes.iter().enumerate().take_while(|(_, x)| **x > 1) {
+            writeln!(f, "{id}: {size} users")?;
+        }
+        Ok(())
+    }
+}
+
+impl Network {
+    /// Components of the follow graph, with follows as undirected edges. With `mutual_only`, only
+    /// mutuals connect users, as in searches. Mute list entries never do
+    pub fn connected_components(&self, mutual_only: bool) -> Components {
+        let indices: HashMap<&PublicKey, usize> = self
+            .iter_users()
+            .enumerate()
+            .map(|(i, user)| (user, i))
+            .collect();
+        let mut sets = UnionFind::<usize>::new(indices.len());
+        for (user, follow) in self.iter_follows() {
+            if mutual_only && self.are_users_mutuals(&follow, &user) != Some(true) {
+                continue;
+            }
+            sets.union(indices[&user], indices[&follow]);
+        }
+
+        let labels = sets.into_labeling();
+        let mut sizes: HashMap<usize, usize> = HashMap::new();
+        for label in &labels {
+            *sizes.entry(*label).or_default() += 1;
+        }
+        // Ids from the biggest component, ties broken by label so they don't change between runs
+        let ids: HashMap<usize, usize> = sizes
+            .iter()
+            .sorted_by_key(|(label, size)| (std::cmp::Reverse(**size), **label))
+            .enumerate()
+            .map(|(id, (label, _))| (*label, id))
+            .collect();
+        let mut component_sizes = vec![0; ids.len()];
+        for (label, size) in &sizes {
+            component_sizes[ids[label]] = *size;
+        }
+        Components {
+            sizes: component_sizes,
+            component_of: indices
+                .into_iter()
+                .map(|(user, i)| (*user, ids[&labels[i]]))
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,163 @@
+/// SQLite store of contact lists and metadata, shared by every run that uses the same file
+///
+/// Attached to a Network, it's loaded into memory once and then written to as contact lists and
+/// metadata arrive, so later runs start from what earlier ones fetched
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+
+use nostr_sdk::prelude::*;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS contact_lists (
+    author TEXT PRIMARY KEY,
+    created_at INTEGER NOT NULL,
+    fetched_at INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS follows (
+    author TEXT NOT NULL,
+    follow TEXT NOT NULL,
+    PRIMARY KEY (author, follow)
+);
+CREATE TABLE IF NOT EXISTS metadata (
+    pubkey TEXT PRIMARY KEY,
+    json TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+";
+
+/// Contact list as stored: follows, creation time and when it was last fetched
+pub type StoredContactList = (Vec<PublicKey>, Timestamp, Timestamp);
+
+#[derive(Debug)]
+pub struct ContactListDb {
+    conn: Connection,
+}
+
+impl ContactListDb {
+    pub fn open(path: &Path) -> rusqlite::Result<ContactListDb> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(ContactListDb { conn })
+    }
+
+    pub fn contact_lists(&self) -> rusqlite::Result<Vec<(PublicKey, StoredContactList)>> {
+        let mut lists = self
+            .conn
+            .prepare("SELECT author, created_at, fetched_at FROM contact_lists")?
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, u64>(1)?,
+                    row.get::<_, u64>(2)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?
+            .into_iter()
+            .filter_map(|(author, created_at, fetched_at)| {
+                Some((
+                    PublicKey::from_hex(&author).ok()?,
+                    (
+                        vec![],
+                        Timestamp::from(created_at),
+                        Timestamp::from(fetched_at),
+                    ),
+                ))
+            })
+            .collect::<std::collections::HashMap<_, _>>();
+
+        let mut statement = self.conn.prepare("SELECT author, follow FROM follows")?;
+        let follows = statement.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+        for follow in follows {
+            let (author, follow) = follow?;
+            let (Ok(author), Ok(follow)) =
+                (PublicKey::from_hex(&author), PublicKey::from_hex(&follow))
+            else {
+                continue;
+            };
+            if let Some((list, _, _)) = lists.get_mut(&author) {
+                list.push(follow);
+            }
+        }
+        Ok(lists.into_iter().collect())
+    }
+
+    pub fn metadata(&self) -> rusqlite::Result<Vec<(PublicKey, Metadata, Timestamp)>> {
+        let rows = self
+            .conn
+            .prepare("SELECT pubkey, json, created_at FROM metadata")?
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, u64>(2)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(rows
+            .into_iter()
+            .filter_map(|(pubkey, json, created_at)| {
+                Some((
+                    PublicKey::from_hex(&pubkey).ok()?,
+                    Metadata::from_json(json).ok()?,
+                    Timestamp::from(created_at),
+                ))
+            })
+            .collect())
+    }
+
+    /// Store the contact list, unless the stored one is as new. It counts as fetched now either way
+    pub fn store_contact_list<'a>(
+        &mut self,
+        author: &PublicKey,
+        follows: impl IntoIterator<Item = &'a PublicKey>,
+        created_at: Timestamp,
+    ) -> rusqlite::Result<()> {
+        let author = author.to_hex();
+        let now = Timestamp::now().as_u64();
+        let tx = self.conn.transaction()?;
+        let stored: Option<u64> = tx
+            .query_row(
+                "SELECT created_at FROM contact_lists WHERE author = ?1",
+                [&author],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if stored.is_some_and(|x| x >= created_at.as_u64()) {
+            tx.execute(
+                "UPDATE contact_lists SET fetched_at = ?2 WHERE author = ?1",
+                params![author, now],
+            )?;
+        } else {
+            tx.execute(
+                "INSERT OR REPLACE INTO contact_lists (author, created_at, fetched_at) VALUES (?1, ?2, ?3)",
+                params![author, created_at.as_u64(), now],
+            )?;
+            tx.execute("DELETE FROM follows WHERE author = ?1", [&author])?;
+            let mut insert =
+                tx.prepare("INSERT OR IGNORE INTO follows (author, follow) VALUES (?1, ?2)")?;
+            for follow in follows {
+                insert.execute(params![author, follow.to_hex()])?;
+            }
+            drop(insert);
+        }
+        tx.commit()
+    }
+
+    /// Store the metadata, unless the stored one is as new
+    pub fn store_metadata(
+        &self,
+        pubkey: &PublicKey,
+        metadata: &Metadata,
+        created_at: Timestamp,
+    ) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "INSERT INTO metadata (pubkey, json, created_at) VALUES (?1, ?2, ?3)
+            ON CONFLICT(pubkey) DO UPDATE SET json = excluded.json, created_at = excluded.created_at
+            WHERE excluded.created_at > metadata.created_at",
+            params![pubkey.to_hex(), metadata.as_json(), created_at.as_u64()],
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+/// What changed in the contact lists between two networks, e.g. two saved crawls
+use itertools::Itertools;
+use std::collections::HashSet;
+use std::fmt::{self, Display, Formatter};
+
+use crate::network::{ContactListState, ContactListUpdate, Network};
+use nostr_sdk::prelude::*;
+
+/// Which of the networks know the contact list of the user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContactListPresence {
+    Both,
+    OnlyOld,
+    OnlyNew,
+    Neither,
+}
+
+/// Follows of an user added and removed from the old network to the new one
+///
+/// Only filled when both networks know the contact list, otherwise what changed can't be told
+#[derive(Debug, Clone)]
+pub struct FollowsDiff {
+    pub user: PublicKey,
+    pub presence: ContactListPresence,
+    pub added: Vec<PublicKey>,
+    pub removed: Vec<PublicKey>,
+}
+
+impl FollowsDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Follows added and removed by a contact list, see Network::apply_contact_list_tracked
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ContactListDelta {
+    /// Creation time of the contact list compared with. None when the network had none, and
+    /// then nothing is added or removed
+    pub since: Option<Timestamp>,
+    pub added: Vec<PublicKey>,
+    pub removed: Vec<PublicKey>,
+}
+
+impl ContactListDelta {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+impl Network {
+    /// Like update_contact_list, returning the follows that changed from the contact list the
+    /// network had, sorted by public key. Empty when the network had no contact list of the user
+    /// or had a newer one
+    pub fn apply_contact_list_tracked<'a>(
+        &mut self,
+        user: PublicKey,
+        contacts: impl IntoIterator<Item = &'a PublicKey>,
+        timestamp: &Timestamp,
+    ) -> ContactListDelta {
+        let since = match self.contact_list_state(&user) {
+            ContactListState::Known(time) | ContactListState::Empty(time) => time,
+            ContactListState::Unknown => {
+                self.update_contact_list(user, contacts, timestamp);
+                return ContactListDelta::default();
+            }
+        };
+        let old_follows: HashSet<PublicKey> = self.get_user_contacts(&user).copied().collect();
+        if self.update_contact_list(user, contacts, timestamp) == ContactListUpdate::IgnoredOlder {
+            return ContactListDelta {
+                since: Some(since),
+                ..Default::default()
+            };
+        }
+        let new_follows: HashSet<PublicKey> = self.get_user_contacts(&user).copied().collect();
+        ContactListDelta {
+            since: Some(since),
+            added: new_follows
+                .difference(&old_follows)
+                .copied()
+                .sorted()
+                .collect_vec(),
+            removed: old_follows
+                .difference(&new_follows)
+                .copied()
+                .sorted()
+                .collect_vec(),
+        }
+    }
+
+    /// Follows of `user` that changed from `old` to `new`, sorted by public key
+    pub fn diff(old: &Network, new: &Network, user: &PublicKey) -> FollowsDiff {
+        let presence = match (old.does_user_follow(user), new.does_user_follow(user)) {
+            (true, true) => ContactListPresence::Both,
+            (true, false) => ContactListPresence::OnlyOld,
+            (false, true) => ContactListPresence::OnlyNew,
+            (false, false) => ContactListPresence::Neither,
+        };
+        let (added, removed) = if presence == ContactListPresence::Both {
+            let old_follows: HashSet<&PublicKey> = old.get_user_contacts(user).collect();
+            let new_follows: HashSet<&PublicKey> = new.get_user_contacts(user).collect();
+            (
+                new_follows
+                    .difference(&old_follows)
+                    .map(|x| **x)
+                    .sorted()
+                    .collect_vec(),
+                old_follows
+                    .difference(&new_follows)
+                    .map(|x| **x)
+                    .sorted()
+                    .collect_vec(),
+            )
+        } else {
+            (vec![], vec![])
+        };
+        FollowsDiff {
+            user: *user,
+            presence,
+            added,
+            removed,
+        }
+    }
+
+    /// Name from the metadata, or the npub
+    pub fn display_name(&self, user: &PublicKey) -> String {
+        self.name(user).unwrap_or_else(|| user.to_bech32().unwrap())
+    }
+
+    /// Name or else display name from the metadata
+    pub fn name(&self, user: &PublicKey) -> Option<String> {
+        self.get_pubkey_metadata(user)
+            .and_then(|(m, _)| m.name.clone().or_else(|| m.display_name.clone()))
+    }
+}
+
+/// Renders the diff with the names known in either network, preferring the new one
+pub struct DiffDisplay<'a> {
+    pub diff: &'a FollowsDiff,
+    pub old: &'a Network,
+    pub new: &'a Network,
+}
+
+impl DiffDisplay<'_> {
+    fn name(&self, user: &PublicKey) -> String {
+        if self.new.get_pubkey_metadata(user).is_some() {
+            self.new.display_name(user)
+        } else {
+            self.old.display_name(user)
+        }
+    }
+}
+
+impl Display for DiffDisplay<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let user = self.diff.user.to_bech32().unwrap();
+        write!(f, "{} | {}: ", self.name(&self.diff.user), user)?;
+        match self.diff.presence {
+            ContactListPresence::OnlyOld => {
+                return writeln!(f, "contact list only in the old cache")
+            }
+            ContactListPresence::OnlyNew => {
+                return writeln!(f, "contact list not in the old cache")
+            }
+            ContactListPresence::Neither => return writeln!(f, "contact list unknown"),
+            ContactListPresence::Both if self.diff.is_empty() => return writeln!(f, "no changes"),
+            ContactListPresence::Both => writeln!(
+                f,
+                "{} followed, {} unfollowed",
+                self.diff.added.len(),
+                self.diff.removed.len()
+            )?,
+        }
+        for added in &self.diff.added {
+            writeln!(f, "+ {} | {}", self.name(added), added.to_bech32().unwrap())?;
+        }
+        for removed in &self.diff.removed {
+            writeln!(
+                f,
+                "- {} | {}",
+                self.name(removed),
+                removed.to_bech32().unwrap()
+            )?;
+        }
+        Ok(())
+    }
+}
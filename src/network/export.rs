@@ -0,0 +1,294 @@
+/// Write the network in formats other tools read
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::network::*;
+use nostr_sdk::prelude::*;
+
+/// Text shown on the edges of a DOT export
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EdgeLabels {
+    #[default]
+    None,
+    /// The petname the follower gave to the followed user, when it gave one
+    Petnames,
+}
+
+impl EdgeLabels {
+    pub fn from_name(name: &str) -> Option<EdgeLabels> {
+        match name {
+            "none" => Some(EdgeLabels::None),
+            "petnames" => Some(EdgeLabels::Petnames),
+            _ => None,
+        }
+    }
+}
+
+/// What goes in a DOT export
+#[derive(Debug, Clone, Default)]
+pub struct DotOptions {
+    /// Only these users and the edges between them. The full graph is usually too big for Graphviz
+    pub users: Option<HashSet<PublicKey>>,
+    pub edge_labels: EdgeLabels,
+}
+
+impl EdgeKind {
+    fn name(&self) -> &'static str {
+        match self {
+            EdgeKind::Following => "following",
+            EdgeKind::Muted => "muted",
+        }
+    }
+
+    fn dot_color(&self) -> &'static str {
+        match self {
+            EdgeKind::Following => "gray40",
+            EdgeKind::Muted => "red3",
+        }
+    }
+}
+
+/// Metadata name of the user, or the start of the npub
+fn user_label(network: &Network, pubkey: &PublicKey) -> String {
+    let name = network
+        .get_pubkey_metadata(pubkey)
+        .and_then(|(m, _)| m.name.clone().or(m.display_name.clone()))
+        .filter(|x| !x.trim().is_empty());
+    match name {
+        Some(name) => name,
+        None => {
+            let npub = pubkey.to_bech32().unwrap();
+            format!("{}…", &npub[..16])
+        }
+    }
+}
+
+/// Format of an export, told from the file extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// Edges in the file, nodes in `<name>_nodes.csv` beside it
+    Csv,
+    /// One node per line, with its outgoing follows
+    Jsonl,
+}
+
+impl ExportFormat {
+    pub fn from_path(path: &Path) -> Option<ExportFormat> {
+        match path.extension()?.to_str()?.to_lowercase().as_str() {
+            "csv" => Some(ExportFormat::Csv),
+            "jsonl" => Some(ExportFormat::Jsonl),
+            _ => None,
+        }
+    }
+}
+
+/// Quote the field if it has anything that would break the row
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+fn dot_escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\r', "")
+}
+
+impl Network {
+    /// Write the graph as a Graphviz digraph, with nodes named by their hex pubkey
+    pub fn export_dot(
+        &self,
+        writer: &mut (impl Write + ?Sized),
+        options: &DotOptions,
+    ) -> io::Result<()> {
+        let included =
+            |pubkey: &PublicKey| options.users.as_ref().is_none_or(|x| x.contains(pubkey));
+
+        writeln!(writer, "digraph network {{")?;
+        for user in self.users().filter(included) {
+            writeln!(
+                writer,
+                "  \"{}\" [label=\"{}\"];",
+                user,
+                dot_escape(&user_label(self, &user))
+            )?;
+        }
+        for (source, target, kind) in self.edges() {
+            if !included(&source) || !included(&target) {
+                continue;
+            }
+            let label = match (options.edge_labels, kind) {
+                (EdgeLabels::Petnames, EdgeKind::Following) => self.given_petname(&source, &target),
+                _ => None,
+            };
+            match label {
+                Some(label) => writeln!(
+                    writer,
+                    "  \"{}\" -> \"{}\" [color=\"{}\", label=\"{}\"];",
+                    source,
+                    target,
+                    kind.dot_color(),
+                    dot_escape(label)
+                )?,
+                None => writeln!(
+                    writer,
+                    "  \"{}\" -> \"{}\" [color=\"{}\"];",
+                    source,
+                    target,
+                    kind.dot_color()
+                )?,
+            }
+        }
+        writeln!(writer, "}}")
+    }
+
+    /// Write one `source_npub,target_npub,edge_kind` row per edge, with a header
+    pub fn export_edges_csv(&self, writer: &mut (impl Write + ?Sized)) -> io::Result<()> {
+        writeln!(writer, "source_npub,target_npub,edge_kind")?;
+        for (source, target, kind) in self.edges() {
+            writeln!(
+                writer,
+                "{},{},{}",
+                source.to_bech32().unwrap(),
+                target.to_bech32().unwrap(),
+                kind.name()
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Write one `npub,name,nip05,about` row per user, empty where there's no metadata
+    pub fn export_nodes_csv(&self, writer: &mut (impl Write + ?Sized)) -> io::Result<()> {
+        writeln!(writer, "npub,name,nip05,about")?;
+        for user in self.users() {
+            let metadata = self.get_pubkey_metadata(&user).map(|(m, _)| m);
+            let field = |f: fn(&Metadata) -> &Option<String>| {
+                csv_field(metadata.and_then(|m| f(m).as_deref()).unwrap_or(""))
+            };
+            writeln!(
+                writer,
+                "{},{},{},{}",
+                user.to_bech32().unwrap(),
+                field(|m| &m.name),
+                field(|m| &m.nip05),
+                field(|m| &m.about)
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Write one JSON object per line for each user, with its metadata and the users it follows
+    pub fn export_jsonl(&self, writer: &mut (impl Write + ?Sized)) -> io::Result<()> {
+        let mut follows: HashMap<PublicKey, Vec<String>> = HashMap::new();
+        for (source, target, kind) in self.edges() {
+            if kind == EdgeKind::Following {
+                follows
+                    .entry(source)
+                    .or_default()
+                    .push(target.to_bech32().unwrap());
+            }
+        }
+        for user in self.users() {
+            let metadata = self.get_pubkey_metadata(&user).map(|(m, _)| m);
+            let line = serde_json::json!({
+                "npub": user.to_bech32().unwrap(),
+                "name": metadata.and_then(|m| m.name.clone()),
+                "nip05": metadata.and_then(|m| m.nip05.clone()),
+                "about": metadata.and_then(|m| m.about.clone()),
+                "follows": follows.remove(&user).unwrap_or_default(),
+            });
+            writeln!(writer, "{line}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    fn dot(network: &Network, options: &DotOptions) -> String {
+        let mut out = vec![];
+        network.export_dot(&mut out, options).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn petnamed(users: &[PublicKey], store: bool) -> Network {
+        let mut network = network(users, &[(0, &[1, 2]), (1, &[2])]);
+        network.set_store_petnames(store);
+        let info = |petname: &str| PetnameInfo {
+            relay_hint: None,
+            petname: Some(petname.to_string()),
+        };
+        network.update_petnames(
+            users[0],
+            vec![
+                (users[1], info("the \"real\" one")),
+                (users[2], info("back\\slash\r\nnew line")),
+            ],
+        );
+        network
+    }
+
+    #[test]
+    fn dot_edges_are_labeled_with_escaped_petnames() {
+        let users = pubkeys(3);
+        let network = petnamed(&users, true);
+        let options = DotOptions {
+            users: None,
+            edge_labels: EdgeLabels::Petnames,
+        };
+        let dot = dot(&network, &options);
+        assert!(dot.contains(&format!(
+            "\"{}\" -> \"{}\" [color=\"gray40\", label=\"the \\\"real\\\" one\"];",
+            users[0], users[1]
+        )));
+        assert!(dot.contains(&format!(
+            "\"{}\" -> \"{}\" [color=\"gray40\", label=\"back\\\\slash\\nnew line\"];",
+            users[0], users[2]
+        )));
+        // Without a petname given, the edge has no label
+        assert!(dot.contains(&format!(
+            "\"{}\" -> \"{}\" [color=\"gray40\"];",
+            users[1], users[2]
+        )));
+
+        // Only the edges between the users asked for
+        let options = DotOptions {
+            users: Some(HashSet::from([users[0], users[2]])),
+            edge_labels: EdgeLabels::Petnames,
+        };
+        let dot = self::dot(&network, &options);
+        assert_eq!(dot.matches(" -> ").count(), 1);
+        assert!(!dot.contains("real"));
+    }
+
+    #[test]
+    fn dot_edges_arent_labeled_unless_asked_or_without_stored_petnames() {
+        let users = pubkeys(3);
+        let unlabeled = dot(&petnamed(&users, true), &DotOptions::default());
+        let unstored = dot(
+            &petnamed(&users, false),
+            &DotOptions {
+                users: None,
+                edge_labels: EdgeLabels::Petnames,
+            },
+        );
+        for dot in [unlabeled, unstored] {
+            assert_eq!(dot.matches(" -> ").count(), 3);
+            assert!(!dot.contains("label=\"the"));
+            assert!(!dot.contains("slash"));
+        }
+        assert_eq!(
+            EdgeLabels::from_name("petnames"),
+            Some(EdgeLabels::Petnames)
+        );
+        assert_eq!(EdgeLabels::from_name("names"), None);
+    }
+}
@@ -0,0 +1,113 @@
+/// Heuristics telling probable spam and bot accounts apart, so crawls and searches can skip them
+///
+/// They're opinionated, so each one can be turned off on its own. Rankings skip accounts following
+/// too many by default, searches skip nothing by default, as a path through an account following
+/// everyone is still a path
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+
+use crate::network::*;
+use nostr_sdk::prelude::*;
+
+/// Accounts following more are skipped by rankings unless configured otherwise
+pub const DEFAULT_RANK_MAX_FOLLOWS: usize = 5000;
+
+#[derive(Debug, Clone, Default)]
+pub struct CrawlFilters {
+    /// Skip users following more than this many. 0 doesn't skip any
+    pub max_follows: usize,
+    /// Skip users whose metadata has neither a name nor a NIP-05 address. Users whose metadata
+    /// wasn't fetched aren't skipped
+    pub require_name: bool,
+    /// Skip users whose name or display name matches
+    pub name_blocklist: Option<Regex>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SkipReason {
+    TooManyFollows,
+    NoName,
+    BlockedName,
+}
+
+impl Display for SkipReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SkipReason::TooManyFollows => write!(f, "following too many"),
+            SkipReason::NoName => write!(f, "without a name"),
+            SkipReason::BlockedName => write!(f, "with a blocked name"),
+        }
+    }
+}
+
+/// Users skipped by each reason
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SkipCounts(BTreeMap<SkipReason, usize>);
+
+impl SkipCounts {
+    pub fn add(&mut self, reason: SkipReason) {
+        *self.0.entry(reason).or_default() += 1;
+    }
+
+    pub fn total(&self) -> usize {
+        self.0.values().sum()
+    }
+}
+
+/// Like "3 following too many, 1 with a blocked name"
+impl Display for SkipCounts {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let counts = self
+            .0
+            .iter()
+            .map(|(reason, count)| format!("{count} {reason}"))
+            .collect::<Vec<_>>();
+        write!(f, "{}", counts.join(", "))
+    }
+}
+
+impl CrawlFilters {
+    /// Filters with `name_blocklist` compiled, None or an empty pattern not blocking any name
+    pub fn new(
+        max_follows: usize,
+        require_name: bool,
+        name_blocklist: Option<&str>,
+    ) -> Result<CrawlFilters, regex::Error> {
+        Ok(CrawlFilters {
+            max_follows,
+            require_name,
+            name_blocklist: name_blocklist
+                .filter(|x| !x.is_empty())
+                .map(Regex::new)
+                .transpose()?,
+        })
+    }
+
+    /// Why `user` should be skipped, with what the network knows of them
+    pub fn check(&self, network: &Network, user: &PublicKey) -> Option<SkipReason> {
+        if self.max_follows > 0 && network.get_user_contacts(user).count() > self.max_follows {
+            return Some(SkipReason::TooManyFollows);
+        }
+        let (metadata, _) = network.get_pubkey_metadata(user)?;
+        let names = [&metadata.name, &metadata.display_name];
+        if self.require_name
+            && names
+                .iter()
+                .all(|x| x.as_ref().is_none_or(|x| x.is_empty()))
+            && metadata.nip05.as_ref().is_none_or(|x| x.is_empty())
+        {
+            return Some(SkipReason::NoName);
+        }
+        if let Some(blocklist) = &self.name_blocklist {
+            if names
+                .iter()
+                .flat_map(|x| x.as_ref())
+                .any(|x| blocklist.is_match(x))
+            {
+                return Some(SkipReason::BlockedName);
+            }
+        }
+        None
+    }
+}
@@ -1,13 +1,16 @@
 /// Network that is centered in a particular user, tracking user follows
-use async_utility::futures_util::future::try_join_all;
 use itertools::Itertools;
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
-use std::usize;
 use tokio::sync::Mutex;
 
 use crate::client_utils::*;
+use crate::network::filters::{CrawlFilters, SkipCounts, DEFAULT_RANK_MAX_FOLLOWS};
+use crate::network::progress::{ProgressCallback, ProgressEvent};
 use crate::network::*;
 use nostr_sdk::prelude::*;
 
@@ -15,9 +18,67 @@ use std::fmt::{self, Display, Formatter};
 
 pub struct FollowNetwork {
     net: Arc<Mutex<Network>>,
-    users_distances: HashMap<PublicKey, usize>,
+    users_distances: HashMap<PublicKey, LevelIdx>,
     levels: Vec<HashSet<PublicKey>>,
     client: Arc<Client>,
+    timeouts: QueryTimeouts,
+    checkpoint: Option<Checkpoint>,
+    /// Most users add_level keeps in a level, see set_max_level_size
+    max_level_size: Option<usize>,
+    /// Levels add_level cut down to max_level_size, with how many users they had
+    truncated: Vec<(LevelIdx, usize)>,
+    /// Users add_level doesn't crawl through and generate_user_ranks doesn't rank
+    filters: CrawlFilters,
+    /// Users of the levels add_level didn't crawl through, by reason
+    skipped: SkipCounts,
+    /// Contact lists in the network fetched longer ago are fetched again by add_level. None uses
+    /// any known contact list
+    contact_list_ttl: Option<Duration>,
+    /// Users whose contact lists add_level couldn't fetch, see retry_failed
+    failed: HashSet<PublicKey>,
+    coverage: Vec<LevelCoverage>,
+    /// Told of the steps of add_level and add_metadata, which are logged when None
+    progress: Option<ProgressCallback>,
+}
+
+/// Times a chunk of contact lists is asked for again when the relays fail, before add_level goes
+/// on without it
+pub const CHUNK_RETRIES: u32 = 2;
+/// Wait before the first retry of a chunk, doubled for each one after
+const CHUNK_RETRY_WAIT: Duration = Duration::from_secs(2);
+/// Levels with a smaller share of the contact lists fetched are warned about
+pub const POOR_COVERAGE: f64 = 0.9;
+
+/// How many users of the level before `level` add_level got the contact lists of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LevelCoverage {
+    pub level: LevelIdx,
+    /// Users of the level before
+    pub users: usize,
+    /// Users whose contact lists couldn't be fetched, see FollowNetwork::retry_failed
+    pub failed: usize,
+}
+
+impl LevelCoverage {
+    /// Share of the contact lists fetched or already known, from 0 to 1
+    pub fn share(&self) -> f64 {
+        if self.users == 0 {
+            return 1.0;
+        }
+        1.0 - self.failed as f64 / self.users as f64
+    }
+
+    pub fn is_poor(&self) -> bool {
+        self.share() < POOR_COVERAGE
+    }
+}
+
+/// Where long crawls save the network while running, so they can resume after a crash
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    pub path: PathBuf,
+    /// Save after this many chunks
+    pub every_chunks: usize,
 }
 
 impl fmt::Debug for FollowNetwork {
@@ -84,6 +145,11 @@ impl From<nostr_sdk::client::Error> for GetMetadataError {
 pub enum RecommendationError {
     NotEnoughLevels,
     InternalGraphError(i32),
+    /// More than one user to recommend for
+    TooManyUsers,
+    /// The crawl took too long and was given up
+    TimedOut,
+    Client(String),
 }
 
 impl Display for RecommendationError {
@@ -91,15 +157,223 @@ impl Display for RecommendationError {
         match self {
             RecommendationError::NotEnoughLevels => write!(f, "Not Enough Levels"),
             RecommendationError::InternalGraphError(x) => write!(f, "Internal Graph Error {x}"),
+            RecommendationError::TooManyUsers => write!(f, "Too Many Users"),
+            RecommendationError::TimedOut => write!(f, "Timed Out"),
+            RecommendationError::Client(err) => write!(f, "Client Error {err}"),
         }
     }
 }
 
 impl std::error::Error for RecommendationError {}
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// Index of a level of a FollowNetwork, the follows away from the center user, who is level 0
+///
+/// Not a Degree: following isn't being mutuals, so there's no conversion between them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct LevelIdx(pub usize);
+
+impl LevelIdx {
+    pub const CENTER: LevelIdx = LevelIdx(0);
+}
+
+impl Display for LevelIdx {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum RankReasons {
+    /// Users of the level before the ranked one who are mutuals with the user, sorted. Left out
+    /// when there are none
     MutualConnections(Vec<PublicKey>),
+    /// Jaccard index of the follows of the user and the center user, see
+    /// Network::follow_similarity
+    SimilarFollows(f64),
+    /// Clustering coefficient of the user, see Network::clustering_coefficients
+    TightCommunity(f64),
+    /// Replies and reactions of the user to the users the center user follows, see
+    /// FollowNetwork::add_interactions
+    InteractsWithYourFollows(u32),
+    /// Sats and count of the zaps to the user from the users the center user follows, see
+    /// FollowNetwork::add_zaps
+    ZappedByYourNetwork(u64, u32),
+    /// Followers of the user in the crawled network, which dampen the rank of the mutuals, see
+    /// RankConfig::popularity_dampening
+    FollowedByCount(usize),
+    /// Mutuals whose contact list was last updated within RankConfig::recency_half_life_days,
+    /// newest first. Contact lists are replaced whole, so it's when the list following the user
+    /// was published, not when the follow was added to it
+    RecentConnections(Vec<(PublicKey, Timestamp)>),
+}
+
+/// Rank given for each mutual the user has among the follows of the center user
+pub const DEFAULT_MUTUAL_WEIGHT: f64 = 10.0;
+
+/// Rank given for following exactly the same users as the center user, scaled down by how few
+/// follows are in common
+pub const DEFAULT_SIMILARITY_WEIGHT: f64 = 50.0;
+
+/// How much the followers of a user dampen the rank of their mutuals, so accounts everyone
+/// follows don't crowd out the closer ones
+pub const DEFAULT_POPULARITY_DAMPENING: f64 = 1.0;
+
+/// How much more a mutual counts when their contact list was just updated, see
+/// RankConfig::recency_weight
+pub const DEFAULT_RECENCY_WEIGHT: f64 = 1.0;
+
+/// Days after which an update of the contact list of a mutual counts half as much
+pub const DEFAULT_RECENCY_HALF_LIFE_DAYS: f64 = 30.0;
+
+/// Rank given for having every mutual be mutuals with each other, scaled down by how few are
+pub const DEFAULT_COMMUNITY_WEIGHT: f64 = 20.0;
+
+/// Rank given for each doubling of the interactions with the users the center user follows
+pub const DEFAULT_INTERACTION_WEIGHT: f64 = 5.0;
+
+/// Rank given for each doubling of the sats zapped to the user by the users the center user
+/// follows
+pub const DEFAULT_ZAP_WEIGHT: f64 = 3.0;
+
+/// How generate_user_ranks weighs each of the RankReasons, and which ranks it returns
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RankConfig {
+    /// See RankReasons::MutualConnections
+    pub mutual_weight: f64,
+    /// See RankReasons::SimilarFollows
+    pub similarity_weight: f64,
+    /// See RankReasons::TightCommunity
+    pub community_weight: f64,
+    /// See RankReasons::InteractsWithYourFollows
+    pub interaction_weight: f64,
+    /// See RankReasons::ZappedByYourNetwork
+    pub zap_weight: f64,
+    /// The rank of the mutuals is divided by 1 + this times the base 10 logarithm of the
+    /// followers of the user in the crawled network. 0 doesn't dampen it
+    pub popularity_dampening: f64,
+    /// Each mutual counts as 1 + this, halved every recency_half_life_days since their contact
+    /// list was last updated. 0 counts them all the same
+    pub recency_weight: f64,
+    /// See recency_weight
+    pub recency_half_life_days: f64,
+    /// Users ranked lower are left out
+    pub min_rank: Option<i32>,
+    /// Only this many of the best ranked users are returned
+    pub max_results: Option<usize>,
+    /// Most users crawled in each level, see FollowNetwork::set_max_level_size
+    pub max_level_size: Option<usize>,
+    /// Skip users following more than this many, see CrawlFilters. 0 doesn't skip any
+    pub max_follows: usize,
+    /// Skip users without a name nor a NIP-05 address, see CrawlFilters
+    pub require_name: bool,
+    /// Skip users whose name matches this regex, see CrawlFilters
+    pub name_blocklist: Option<String>,
+    /// Also rank the users the center user already follows, who the crawl may find in level 2
+    /// when they were followed after it started
+    pub include_followed: bool,
+    /// Users never ranked, see load_exclusions
+    #[serde(skip)]
+    pub excluded: HashSet<PublicKey>,
+}
+
+impl Default for RankConfig {
+    fn default() -> Self {
+        RankConfig {
+            mutual_weight: DEFAULT_MUTUAL_WEIGHT,
+            similarity_weight: DEFAULT_SIMILARITY_WEIGHT,
+            community_weight: DEFAULT_COMMUNITY_WEIGHT,
+            interaction_weight: DEFAULT_INTERACTION_WEIGHT,
+            zap_weight: DEFAULT_ZAP_WEIGHT,
+            popularity_dampening: DEFAULT_POPULARITY_DAMPENING,
+            recency_weight: DEFAULT_RECENCY_WEIGHT,
+            recency_half_life_days: DEFAULT_RECENCY_HALF_LIFE_DAYS,
+            min_rank: None,
+            max_results: None,
+            max_level_size: None,
+            max_follows: DEFAULT_RANK_MAX_FOLLOWS,
+            require_name: false,
+            name_blocklist: None,
+            include_followed: false,
+            excluded: HashSet::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RankConfigError {
+    Read(std::io::Error),
+    Parse(toml::de::Error),
+    InvalidPubkey(String),
+    NameBlocklist(regex::Error),
+}
+
+impl Display for RankConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RankConfigError::Read(err) => write!(f, "Rank config read error: {err}"),
+            RankConfigError::Parse(err) => write!(f, "Rank config parse error: {err}"),
+            RankConfigError::InvalidPubkey(key) => write!(f, "Invalid public key {key}"),
+            RankConfigError::NameBlocklist(err) => write!(f, "Invalid name blocklist: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for RankConfigError {}
+
+impl RankConfig {
+    /// Read from a TOML file. Missing fields keep their defaults
+    pub fn load(path: &Path) -> Result<RankConfig, RankConfigError> {
+        let text = fs::read_to_string(path).map_err(RankConfigError::Read)?;
+        toml::from_str(&text).map_err(RankConfigError::Parse)
+    }
+
+    /// The filters of the crawl, see FollowNetwork::set_filters
+    pub fn filters(&self) -> Result<CrawlFilters, RankConfigError> {
+        CrawlFilters::new(
+            self.max_follows,
+            self.require_name,
+            self.name_blocklist.as_deref(),
+        )
+        .map_err(RankConfigError::NameBlocklist)
+    }
+
+    /// Add to the excluded users the ones in a file with a public key per line. Empty lines and
+    /// lines starting with # are skipped
+    pub fn load_exclusions(&mut self, path: &Path) -> Result<(), RankConfigError> {
+        let text = fs::read_to_string(path).map_err(RankConfigError::Read)?;
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let user = PublicKey::parse(line)
+                .map_err(|_| RankConfigError::InvalidPubkey(line.to_string()))?;
+            self.excluded.insert(user);
+        }
+        Ok(())
+    }
+}
+
+/// Kind of follow pack events, lists of users curated to be followed together
+pub const FOLLOW_PACK_KIND: u16 = 39089;
+/// Most users put in a follow pack
+pub const MAX_FOLLOW_PACK_ENTRIES: usize = 150;
+
+/// Users a follow pack is made of
+pub enum FollowPackSource<'a> {
+    /// Every user at this distance of the center user
+    Level(LevelIdx),
+    /// The best ranked users, out of generate_user_ranks results
+    TopRanks(&'a [(PublicKey, i32, Vec<RankReasons>)], usize),
+}
+
+/// Tags of a follow pack
+#[derive(Debug, Clone)]
+pub struct FollowPackInfo {
+    /// The d tag, publishing again with the same one replaces the pack
+    pub identifier: String,
+    pub title: String,
+    pub description: Option<String>,
 }
 
 impl FollowNetwork {
@@ -107,6 +381,7 @@ impl FollowNetwork {
         user: crate::user::User,
         client: Arc<Client>,
         net: Arc<Mutex<Network>>,
+        timeouts: QueryTimeouts,
     ) -> FollowNetwork {
         let user_pubkey = user.public_key();
         {
@@ -121,116 +396,554 @@ impl FollowNetwork {
         level_zero.insert(user_pubkey);
 
         let mut users_distances = HashMap::new();
-        users_distances.insert(user_pubkey, 0);
+        users_distances.insert(user_pubkey, LevelIdx::CENTER);
 
         FollowNetwork {
             net,
             users_distances,
             levels: vec![level_zero.clone()],
             client,
+            timeouts,
+            checkpoint: None,
+            max_level_size: None,
+            truncated: vec![],
+            filters: CrawlFilters::default(),
+            skipped: SkipCounts::default(),
+            contact_list_ttl: None,
+            failed: HashSet::new(),
+            coverage: vec![],
+            progress: None,
+        }
+    }
+
+    /// A FollowNetwork crawling with the level size and filters of `config`, with the levels up to
+    /// `depth` already told by the contact lists in `net` not older than `contact_list_ttl`, e.g.
+    /// from a cache or an earlier crawl, see add_cached_levels. add_level goes on from the last of
+    /// them
+    pub async fn from_network(
+        user: crate::user::User,
+        client: Arc<Client>,
+        net: Arc<Mutex<Network>>,
+        timeouts: QueryTimeouts,
+        config: &RankConfig,
+        contact_list_ttl: Option<Duration>,
+        depth: LevelIdx,
+    ) -> Result<FollowNetwork, RankConfigError> {
+        let mut network = FollowNetwork::new(user, client, net, timeouts).await;
+        network.set_max_level_size(config.max_level_size);
+        network.set_filters(config.filters()?);
+        network.set_contact_list_ttl(contact_list_ttl);
+        network.add_cached_levels(depth).await;
+        Ok(network)
+    }
+
+    /// Fetch again the contact lists fetched longer than `ttl` ago, see
+    /// Network::is_contact_list_fresh. None, the default, never does
+    pub fn set_contact_list_ttl(&mut self, ttl: Option<Duration>) {
+        self.contact_list_ttl = ttl;
+    }
+
+    /// Keep in each level added by add_level only the `max` users followed by the most users of
+    /// the level before, so crawls of users following many don't take hours. None keeps them all
+    pub fn set_max_level_size(&mut self, max: Option<usize>) {
+        self.max_level_size = max;
+    }
+
+    /// Skip the users caught by `filters`, none by default. add_level doesn't add their follows to
+    /// the next level, and generate_user_ranks leaves them out
+    pub fn set_filters(&mut self, filters: CrawlFilters) {
+        self.filters = filters;
+    }
+
+    /// Send the steps of add_level and add_metadata to `progress` instead of logging them
+    pub fn set_progress(&mut self, progress: Option<ProgressCallback>) {
+        self.progress = progress;
+    }
+
+    fn report(&mut self, event: ProgressEvent) {
+        match &mut self.progress {
+            Some(progress) => progress(event),
+            None => event.log(),
+        }
+    }
+
+    /// Users add_level didn't crawl through, see set_filters
+    pub fn skipped(&self) -> &SkipCounts {
+        &self.skipped
+    }
+
+    /// Levels cut down by set_max_level_size, with how many users they had before
+    pub fn truncated_levels(&self) -> &[(LevelIdx, usize)] {
+        &self.truncated
+    }
+
+    /// Save the network every few chunks of add_level and add_metadata. Users already in the
+    /// network are skipped by them, so crawls resume from a loaded checkpoint
+    pub fn set_checkpoint(&mut self, checkpoint: Option<Checkpoint>) {
+        self.checkpoint = checkpoint;
+    }
+
+    async fn save_checkpoint(&self, chunk: usize, total: usize) {
+        let Some(checkpoint) = &self.checkpoint else {
+            return;
+        };
+        if !chunk.is_multiple_of(checkpoint.every_chunks.max(1)) || chunk == total {
+            return;
+        }
+        let snapshot = self.net.lock().await.to_snapshot();
+        // No older generations, only replacing the file at once
+        match persist::save_rotating(
+            &snapshot,
+            &checkpoint.path,
+            0,
+            persist::CacheFormat::from_path(&checkpoint.path),
+        ) {
+            Ok(()) => eprintln!(
+                "Checkpoint saved to {} at {chunk}/{total}",
+                checkpoint.path.display()
+            ),
+            Err(err) => eprintln!("Checkpoint {} error: {}", checkpoint.path.display(), err),
+        }
+    }
+
+    /// Save the network at the end of a crawl step
+    async fn save_final_checkpoint(&self) {
+        if let Some(checkpoint) = &self.checkpoint {
+            let snapshot = self.net.lock().await.to_snapshot();
+            if let Err(err) = persist::save_rotating(
+                &snapshot,
+                &checkpoint.path,
+                0,
+                persist::CacheFormat::from_path(&checkpoint.path),
+            ) {
+                eprintln!("Checkpoint {} error: {}", checkpoint.path.display(), err);
+            }
         }
     }
 
     pub async fn add_level(&mut self) -> Result<&mut Self> {
         let top_level = self.levels.last().unwrap();
-        let current_level = self.levels.len();
-        let mut users_following = HashMap::new();
+        let current_level = LevelIdx(self.levels.len());
 
         let chunk_size = 2000;
 
-        // Logging
-        eprintln!("add_level: Getting next level on network");
-        let size = top_level.len().div_ceil(chunk_size);
+        // Users whose contact list is already in the network and fresh, e.g. from a checkpoint
+        let missing = {
+            let net_lock = self.net.lock().await;
+            top_level
+                .iter()
+                .filter(|x| !net_lock.is_contact_list_fresh(x, self.contact_list_ttl))
+                .copied()
+                .collect_vec()
+        };
+
+        let top_level_len = top_level.len();
+        let skipped = top_level_len - missing.len();
+        let size = missing.len().div_ceil(chunk_size);
         let mut current = 0;
-        eprintln!("{current}/{size}");
+        self.report(ProgressEvent::LevelStarted {
+            level: current_level,
+            chunks: size,
+            skipped,
+        });
+
+        let mut failed = 0;
+        for chunk in missing.chunks(chunk_size) {
+            if !self.fetch_contact_lists(chunk).await {
+                failed += chunk.len();
+            }
+
+            current += 1;
+            self.report(ProgressEvent::ChunkDone {
+                level: current_level,
+                done: current,
+                total: size,
+            });
+            self.save_checkpoint(current, size).await;
+        }
 
-        let pubkey_chunks = top_level.iter().chunks(chunk_size);
+        self.coverage.push(LevelCoverage {
+            level: current_level,
+            users: top_level_len,
+            failed,
+        });
+        let users = self.push_next_level().await;
+        self.save_final_checkpoint().await;
+
+        self.report(ProgressEvent::LevelFinished {
+            level: current_level,
+            users,
+        });
+        Ok(self)
+    }
 
-        for chunk in pubkey_chunks.into_iter() {
-            let batch = chunk.into_iter().map(|x| *x);
-            let client = &self.client;
-            let followings = get_following_multiple_users_with_timestamp_and_timeout(
-                batch,
-                client,
-                Some(Duration::from_secs(20)),
+    /// Fetch the contact lists of `users` into the network, trying CHUNK_RETRIES more times with a
+    /// growing wait when the relays fail. When they still do, the users are kept in `failed` for
+    /// retry_failed, and false is returned
+    async fn fetch_contact_lists(&mut self, users: &[PublicKey]) -> bool {
+        let mut attempt = 0;
+        let followings = loop {
+            // As a string, so the error isn't held across the wait
+            let result = get_contact_list_entries_multiple_users_with_timeout(
+                users.iter().copied(),
+                &self.client,
+                self.timeouts.contact_lists,
             )
-            .await?;
+            .await
+            .map_err(|err| err.to_string());
+            match result {
+                Ok(followings) => break followings,
+                Err(err) if attempt < CHUNK_RETRIES => {
+                    attempt += 1;
+                    let wait = CHUNK_RETRY_WAIT * 2u32.pow(attempt - 1);
+                    eprintln!(
+                        "Contact lists of {} users error: {err}. Retrying in {}s",
+                        users.len(),
+                        wait.as_secs()
+                    );
+                    tokio::time::sleep(wait).await;
+                }
+                Err(err) => {
+                    eprintln!(
+                        "Contact lists of {} users error: {err}. Going on without them",
+                        users.len()
+                    );
+                    self.failed.extend(users);
+                    return false;
+                }
+            }
+        };
 
-            users_following.extend(followings);
+        let mut net_lock = self.net.lock().await;
+        // Add to graph and node map, replacing stale contact lists
+        for (user, (entries, time)) in followings {
+            let update = net_lock.update_contact_list(user, entries.iter().map(|(x, _)| x), &time);
+            if update == ContactListUpdate::Applied {
+                net_lock.update_petnames(user, entries);
+            }
+        }
+        for user in users {
+            self.failed.remove(user);
+        }
+        true
+    }
 
-            // Logging
-            current += 1;
-            eprintln!("{current}/{size}");
+    /// Fetch again the contact lists add_level couldn't. The new follows of the users fetched are
+    /// added to the level after theirs, when there's one, but not further. Returns how many
+    /// users were fetched
+    pub async fn retry_failed(&mut self) -> usize {
+        let chunk_size = 2000;
+        let failed = self.failed.iter().copied().collect_vec();
+        let mut fetched = vec![];
+        for chunk in failed.chunks(chunk_size) {
+            if self.fetch_contact_lists(chunk).await {
+                fetched.extend_from_slice(chunk);
+            }
         }
 
+        let net_lock = self.net.lock().await;
+        for user in &fetched {
+            let Some(level) = self.users_distances.get(user).copied() else {
+                continue;
+            };
+            let next = LevelIdx(level.0 + 1);
+            if let Some(coverage) = self.coverage.iter_mut().find(|x| x.level == next) {
+                coverage.failed = coverage.failed.saturating_sub(1);
+            }
+            if next.0 >= self.levels.len() || self.filters.check(&net_lock, user).is_some() {
+                continue;
+            }
+            for follow in net_lock.get_user_contacts(user) {
+                if !self.users_distances.contains_key(follow) {
+                    self.levels[next.0].insert(*follow);
+                    self.users_distances.insert(*follow, next);
+                }
+            }
+        }
+        fetched.len()
+    }
+
+    /// Users whose contact lists add_level couldn't fetch, see retry_failed
+    pub fn failed(&self) -> &HashSet<PublicKey> {
+        &self.failed
+    }
+
+    /// How many contact lists add_level fetched for each level it added
+    pub fn coverage(&self) -> &[LevelCoverage] {
+        &self.coverage
+    }
+
+    /// Add as the next level the follows of the top level known by the network, returning how
+    /// many users it has
+    async fn push_next_level(&mut self) -> usize {
+        let current_level = LevelIdx(self.levels.len());
+
         // Add to new users in next_level and to weighs
         let mut next_level = HashSet::new();
-        for (_, (followings, _)) in &users_following {
-            // Make sure to add newly found users
-            let follow_iter = followings
-                .iter()
-                .filter(|x| !self.levels.iter().any(|y| y.contains(x)));
-            next_level.extend(follow_iter.clone());
-            for following in follow_iter {
-                self.users_distances.insert(*following, current_level);
+        let mut followed_by: HashMap<PublicKey, usize> = HashMap::new();
+        {
+            let net_lock = self.net.lock().await;
+            let top_level = self.levels.last().unwrap();
+            for user in top_level {
+                if let Some(reason) = self.filters.check(&net_lock, user) {
+                    self.skipped.add(reason);
+                    continue;
+                }
+                // Make sure to add newly found users
+                let follow_iter = net_lock
+                    .get_user_contacts(user)
+                    .filter(|x| !self.levels.iter().any(|y| y.contains(x)));
+                for follow in follow_iter {
+                    next_level.insert(*follow);
+                    *followed_by.entry(*follow).or_default() += 1;
+                }
             }
         }
-        self.levels.push(next_level.clone());
+        if let Some(max) = self.max_level_size.filter(|max| next_level.len() > *max) {
+            self.report(ProgressEvent::LevelTruncated {
+                level: current_level,
+                from: next_level.len(),
+                to: max,
+            });
+            self.truncated.push((current_level, next_level.len()));
+            next_level = followed_by
+                .into_iter()
+                .sorted_by_key(|(user, count)| (std::cmp::Reverse(*count), *user))
+                .take(max)
+                .map(|(user, _)| user)
+                .collect();
+        }
+        for following in &next_level {
+            self.users_distances.insert(*following, current_level);
+        }
+        let users = next_level.len();
+        self.levels.push(next_level);
+        users
+    }
 
-        {
+    /// Add the levels up to `depth` whose users can be told from the contact lists already in the
+    /// network, without asking the relays. Stops at the first level with a user of the level
+    /// before whose contact list is missing or stale, as it would miss their follows. Returns how
+    /// many levels were added
+    pub async fn add_cached_levels(&mut self, depth: LevelIdx) -> usize {
+        let mut added = 0;
+        while self.levels.len() <= depth.0 {
+            let cached = {
+                let net_lock = self.net.lock().await;
+                self.levels
+                    .last()
+                    .unwrap()
+                    .iter()
+                    .all(|x| net_lock.is_contact_list_fresh(x, self.contact_list_ttl))
+            };
+            if !cached {
+                break;
+            }
+            self.push_next_level().await;
+            added += 1;
+        }
+        if added > 0 {
+            eprintln!("add_cached_levels: {added} levels from the network");
+        }
+        added
+    }
+
+    /// Add as the next level the users that follow the ones of the top level, crawling in reverse
+    ///
+    /// Followers are asked in pages of FOLLOWERS_PAGE_SIZE, at most `max_pages` per user
+    pub async fn add_followers_level(&mut self, max_pages: usize) -> Result<&mut Self> {
+        let top_level = self.levels.last().unwrap().iter().copied().collect_vec();
+        let current_level = LevelIdx(self.levels.len());
+
+        eprintln!(
+            "add_followers_level: Getting followers of {} users",
+            top_level.len()
+        );
+        let mut next_level = HashSet::new();
+        for user in top_level {
+            let followers = get_followers_of_user_batched(
+                user,
+                &self.client,
+                self.timeouts.contact_lists,
+                max_pages,
+            )
+            .await?;
             let mut net_lock = self.net.lock().await;
-            // Add to graph and node map
-            for (user, (followings, _)) in &users_following {
-                let node_user = net_lock.pubkey_to_node(user).unwrap().clone();
-                for following in followings {
-                    match net_lock.pubkey_to_node(following) {
-                        Some(node_mutual) => {
-                            net_lock.add_follow_nodes(node_user, node_mutual);
-
-                            // Não precisa atualizar o map dos índices
-                        }
-                        None => {
-                            let node_mutual = net_lock.add_user(*following).0;
-                            net_lock.add_follow_nodes(node_user, node_mutual);
-                        }
-                    };
+            for (follower, (follows, time)) in &followers {
+                net_lock.update_contact_list(*follower, follows, time);
+            }
+            next_level.extend(
+                net_lock
+                    .get_user_followers(&user)
+                    .into_iter()
+                    .filter(|x| !self.levels.iter().any(|y| y.contains(x))),
+            );
+        }
+        for follower in &next_level {
+            self.users_distances.insert(*follower, current_level);
+        }
+        self.levels.push(next_level);
+
+        eprintln!("add_followers_level: Finished");
+        Ok(self)
+    }
+
+    /// Count the events of `kinds` made by the users of `level` since `since` that reply or react to
+    /// others, see get_interactions_multiple_users_with_timeout. Counts add up, so calling it again
+    /// for the same users counts their events twice
+    pub async fn add_interactions(
+        &mut self,
+        level: LevelIdx,
+        kinds: &[Kind],
+        since: Option<Timestamp>,
+    ) -> Result<&mut Self> {
+        // Users post many more events than contact lists, so the chunks are smaller
+        let chunk_size = 500;
+
+        let Some(users) = self.level(level) else {
+            eprintln!("add_interactions: No level {level}");
+            return Ok(self);
+        };
+        let users = users.iter().copied().collect_vec();
+
+        // Logging
+        eprintln!("add_interactions: Getting interactions of level {level}");
+        let size = users.len().div_ceil(chunk_size);
+        let mut current = 0;
+        eprintln!("{current}/{size}");
+
+        for chunk in users.chunks(chunk_size) {
+            let interactions = get_interactions_multiple_users_with_timeout(
+                chunk.iter().copied(),
+                &self.client,
+                kinds,
+                since,
+                self.timeouts.contact_lists,
+            )
+            .await?;
+            {
+                let mut net_lock = self.net.lock().await;
+                for ((by, user), count) in interactions {
+                    net_lock.add_interactions(by, user, count);
                 }
             }
+
+            // Logging
+            current += 1;
+            eprintln!("{current}/{size}");
         }
 
-        eprintln!("add_level_mutual: Finished");
+        eprintln!("add_interactions: Finished");
         Ok(self)
     }
 
-    pub async fn add_metadata(&mut self, level: usize) -> Result<(), GetMetadataError> {
-        let chunk_size = 2000;
+    /// Fetch the zaps to the users of `level` since `since`, see get_zaps_to_users_with_timeout.
+    /// Zaps add up, so calling it again for the same users counts them twice
+    pub async fn add_zaps(
+        &mut self,
+        level: LevelIdx,
+        since: Option<Timestamp>,
+    ) -> Result<&mut Self> {
+        // Popular users get many zaps, so the chunks are smaller
+        let chunk_size = 200;
+
+        let Some(users) = self.level(level) else {
+            eprintln!("add_zaps: No level {level}");
+            return Ok(self);
+        };
+        let users = users.iter().copied().collect_vec();
 
         // Logging
-        eprintln!("add_metadata: Getting metadata");
+        eprintln!("add_zaps: Getting zaps to level {level}");
+        let size = users.len().div_ceil(chunk_size);
+        let mut current = 0;
+        eprintln!("{current}/{size}");
+
+        for chunk in users.chunks(chunk_size) {
+            let zaps = get_zaps_to_users_with_timeout(
+                chunk.iter().copied(),
+                &self.client,
+                since,
+                self.timeouts.contact_lists,
+            )
+            .await?;
+            {
+                let mut net_lock = self.net.lock().await;
+                for zap in zaps {
+                    net_lock.add_zap(zap.sender, zap.recipient, zap.amount_msat);
+                }
+            }
+
+            // Logging
+            current += 1;
+            eprintln!("{current}/{size}");
+        }
 
-        match self.levels.get(level) {
+        eprintln!("add_zaps: Finished");
+        Ok(self)
+    }
+
+    /// Fetch the mute list of the center user, whose muted users aren't recommended
+    pub async fn add_mute_list(&mut self) -> Result<&mut Self> {
+        let center = self.center();
+        let mut mute_lists = get_mute_lists_multiple_users_with_timeout(
+            [center],
+            &self.client,
+            self.timeouts.contact_lists,
+        )
+        .await?;
+        if let Some((muted, time)) = mute_lists.remove(&center) {
+            eprintln!("add_mute_list: {} users muted", muted.len());
+            self.net
+                .lock()
+                .await
+                .update_mute_list(center, &muted, &time);
+        }
+        Ok(self)
+    }
+
+    pub async fn add_metadata(&mut self, level: LevelIdx) -> Result<(), GetMetadataError> {
+        let chunk_size = 2000;
+
+        match self.level(level) {
             Some(lvl) => {
-                // Logging
-                let size = lvl.len().div_ceil(chunk_size);
+                // Users whose metadata is already in the network, e.g. from a checkpoint
+                let missing = {
+                    let net_lock = self.net.lock().await;
+                    lvl.iter()
+                        .filter(|x| net_lock.get_pubkey_metadata(x).is_none())
+                        .copied()
+                        .collect_vec()
+                };
+                let skipped = lvl.len() - missing.len();
+                let size = missing.len().div_ceil(chunk_size);
                 let mut current = 0;
-                eprintln!("{current}/{size}");
+                self.report(ProgressEvent::MetadataStarted {
+                    level,
+                    chunks: size,
+                    skipped,
+                });
 
-                let pubkey_chunks = lvl.iter().chunks(chunk_size);
-                for chunk in pubkey_chunks.into_iter() {
-                    let batch: Vec<PublicKey> = chunk.into_iter().map(|x| *x).collect();
+                for batch in missing.chunks(chunk_size) {
                     let metadata = get_metadata_users_with_timeout(
-                        &batch,
+                        batch,
                         &self.client,
-                        Some(Duration::from_secs(20)),
+                        self.timeouts.metadata,
                     )
                     .await?;
 
                     self.net.lock().await.extend_users_metadata(metadata);
 
-                    // Logging
                     current += 1;
-                    eprintln!("{current}/{size}");
+                    self.report(ProgressEvent::ChunkDone {
+                        level,
+                        done: current,
+                        total: size,
+                    });
+                    self.save_checkpoint(current, size).await;
                 }
+                self.save_final_checkpoint().await;
+                self.report(ProgressEvent::MetadataFinished { level });
 
                 Ok(())
             }
@@ -238,102 +951,109 @@ impl FollowNetwork {
         }
     }
 
-    #[deprecated]
-    pub async fn add_level_mutual(&mut self) -> Result<&mut Self> {
-        let top_level = self.levels.last().unwrap();
-        let current_level = self.levels.len();
-        let mut mutual_futures = vec![];
-
-        // NOTA: isso pode criar centenas ou milhares de threads e, desse modo, de requests
-        eprintln!("add_level_mutual: Getting next level on network");
-        for pubkey in top_level {
-            let client = self.client.clone();
-            mutual_futures.push(async move {
-                #[allow(deprecated)]
-                let x = match get_mutuals_user(*pubkey, &client).await {
-                    Ok(ok) => Ok((*pubkey, ok)),
-                    Err(err) => Err(err),
-                };
-                x
-            });
+    /// Take the user out of the levels and the network, e.g. a spam account. Returns whether the
+    /// network knew the user
+    pub async fn remove_user(&mut self, user: &PublicKey) -> bool {
+        for level in &mut self.levels {
+            level.remove(user);
         }
-        let mut mutuals_of_users = vec![];
-        let batches: Vec<Vec<_>> = mutual_futures
-            .into_iter()
-            .chunks(100)
-            .into_iter()
-            .map(|x| x.collect())
-            .collect();
-
-        let size = batches.len();
-        let mut current = 0;
-        eprintln!("{current}/{size}");
-        for batch in batches {
-            mutuals_of_users.append(&mut try_join_all(batch).await?);
-            current += 1;
-            eprintln!("{current}/{size}");
-        }
-
-        // Add to new users in next_level and to weighs
-        let mut next_level = HashSet::new();
-        for (_, mutuals) in &mutuals_of_users {
-            // Make sure to add newly found users
-            let mutual_iter = mutuals
-                .iter()
-                .filter(|x| !self.levels.iter().any(|y| y.contains(x)));
-            next_level.extend(mutual_iter.clone());
-            for mutual in mutual_iter {
-                self.users_distances.insert(*mutual, current_level);
-            }
-        }
-        self.levels.push(next_level.clone());
+        self.users_distances.remove(user);
+        self.net.lock().await.remove_user(user)
+    }
 
-        {
-            let mut net_lock = self.net.lock().await;
-            // Add to graph and node map
-            for (user, mutuals) in &mutuals_of_users {
-                let node_user = net_lock.pubkey_to_node(user).unwrap().clone();
-                for mutual in mutuals {
-                    match net_lock.pubkey_to_node(mutual) {
-                        Some(node_mutual) => {
-                            net_lock.add_follow_nodes(node_user, node_mutual);
-                            net_lock.add_follow_nodes(node_mutual, node_user);
-
-                            // Não precisa atualizar o map dos índices
-                        }
-                        None => {
-                            let node_mutual = net_lock.add_user(*mutual).0;
-                            net_lock.add_follow_nodes(node_user, node_mutual);
-                            net_lock.add_follow_nodes(node_mutual, node_user);
-                        }
-                    };
-                }
-            }
+    /// The user the network is centered in
+    fn center(&self) -> PublicKey {
+        *self.levels[0]
+            .iter()
+            .next()
+            .expect("Level 0 has the center user")
+    }
 
-            eprintln!("add_level_mutual: Getting metadata");
-            // Add new users metadata
-            let next_level: Vec<PublicKey> = next_level.drain().collect();
-            let metadata_mutuals = get_metadata_users_fake(&next_level, &self.client).await?;
-            net_lock.extend_users_metadata(metadata_mutuals.into_iter());
-        }
+    pub fn level(&self, level: LevelIdx) -> Option<&HashSet<PublicKey>> {
+        self.levels.get(level.0)
+    }
 
-        eprintln!("add_level_mutual: Finished");
-        Ok(self)
+    /// Users at most `distance` levels away from the center user
+    pub fn users_within(&self, distance: LevelIdx) -> HashSet<PublicKey> {
+        self.users_distances
+            .iter()
+            .filter(|(_, x)| **x <= distance)
+            .map(|(pubkey, _)| *pubkey)
+            .collect()
     }
 
-    /// Rank users based on their connectivity
-    /// Focuses on users in level 2, i.e. follows/mutuals of follows
+    /// Rank users based on their connectivity, weighing and keeping them as `config` says
+    /// Focuses on users in level 2, i.e. follows/mutuals of follows. See generate_user_ranks_at
     pub async fn generate_user_ranks(
         &self,
+        config: &RankConfig,
+    ) -> Result<Vec<(PublicKey, i32, Vec<RankReasons>)>, RecommendationError> {
+        self.generate_user_ranks_at(LevelIdx(2), config).await
+    }
+
+    /// Rank the users of `candidate_level` by their mutuals in the level before it, and by their
+    /// interactions with and zaps from the users the center user follows. Users the center user
+    /// follows by now aren't recommended, unless config.include_followed
+    pub async fn generate_user_ranks_at(
+        &self,
+        candidate_level: LevelIdx,
+        config: &RankConfig,
     ) -> Result<Vec<(PublicKey, i32, Vec<RankReasons>)>, RecommendationError> {
-        if self.levels.len() <= 2 {
+        if candidate_level == LevelIdx::CENTER {
             return Err(RecommendationError::NotEnoughLevels);
         }
+        let (Some(candidates), Some(connections_level)) = (
+            self.level(candidate_level),
+            self.level(LevelIdx(candidate_level.0 - 1)),
+        ) else {
+            return Err(RecommendationError::NotEnoughLevels);
+        };
+        let center = self.center();
+        // As known now, not when level 1 was crawled
+        let followed: HashSet<PublicKey> = if config.include_followed {
+            HashSet::new()
+        } else {
+            self.net
+                .lock()
+                .await
+                .get_user_contacts(&center)
+                .copied()
+                .collect()
+        };
         let mut users_ranks = HashMap::new();
-        for user in self.levels.get(2).unwrap() {
+        let now = Timestamp::now();
+        let half_life = config.recency_half_life_days.max(f64::MIN_POSITIVE);
+        let mut clustering = self.net.lock().await.clustering_coefficients(candidates);
+        let follows = self.level(LevelIdx(1)).unwrap();
+        let mut interactions: HashMap<PublicKey, u32> = HashMap::new();
+        for (by, user, count) in self.net.lock().await.iter_interactions() {
+            if follows.contains(&user) {
+                *interactions.entry(by).or_default() += count;
+            }
+        }
+        let mut zaps: HashMap<PublicKey, ZapTotal> = HashMap::new();
+        for (sender, recipient, total) in self.net.lock().await.iter_zaps() {
+            if follows.contains(&sender) {
+                let zapped = zaps.entry(recipient).or_default();
+                zapped.amount_msat += total.amount_msat;
+                zapped.count += total.count;
+            }
+        }
+        let mut skipped = SkipCounts::default();
+        for user in candidates {
+            if followed.contains(user) || config.excluded.contains(user) {
+                continue;
+            }
             let mut rank = 0;
             let mut rank_reasons = vec![];
             let net_lock = self.net.lock().await;
+            if net_lock.is_muted(&center, user) {
+                continue;
+            }
+            if let Some(reason) = self.filters.check(&net_lock, user) {
+                skipped.add(reason);
+                continue;
+            }
             let user_mutuals_nodes = net_lock.get_user_mutuals(user);
             let user_mutuals = user_mutuals_nodes
                 .iter()
@@ -343,23 +1063,491 @@ impl FollowNetwork {
             let mut mutual_reasons = vec![];
             for user_mutual in user_mutuals {
                 if let Some(user_mutual) = user_mutual {
-                    if self.levels.get(1).unwrap().contains(&user_mutual) {
-                        rank += 10;
+                    if connections_level.contains(&user_mutual) {
                         mutual_reasons.push(user_mutual);
                     }
                     // else do nothing
                 }
             }
-            rank_reasons.push(RankReasons::MutualConnections(mutual_reasons));
+            // They come out of a HashSet, in an order that changes with each run and network, so
+            // the same network wouldn't always give the same reasons
+            mutual_reasons.sort();
+            // A follow in a list updated last week says more than one in a list of years ago
+            let mut connections = 0.0;
+            let mut recent = vec![];
+            for mutual in &mutual_reasons {
+                connections += 1.0;
+                if let Some(updated) = net_lock.contact_list_created_at(mutual) {
+                    let days = now.as_u64().saturating_sub(updated.as_u64()) as f64 / 86400.0;
+                    connections += config.recency_weight * 0.5_f64.powf(days / half_life);
+                    if days <= half_life {
+                        recent.push((*mutual, updated));
+                    }
+                }
+            }
+            // A follow of a popular account says less about being close to it
+            let followers = net_lock.get_user_followers(user).len();
+            let dampening = 1.0 + config.popularity_dampening * ((followers + 1) as f64).log10();
+            rank += (connections * config.mutual_weight / dampening).round() as i32;
+            if !mutual_reasons.is_empty() {
+                rank_reasons.push(RankReasons::MutualConnections(mutual_reasons));
+            }
+            rank_reasons.push(RankReasons::FollowedByCount(followers));
+            if !recent.is_empty() {
+                recent.sort_by_key(|(mutual, updated)| (std::cmp::Reverse(*updated), *mutual));
+                rank_reasons.push(RankReasons::RecentConnections(recent));
+            }
+
+            // Following the same, often niche, users as the center user
+            if let Some(similarity) = net_lock.follow_similarity(&center, user) {
+                rank += (similarity * config.similarity_weight).round() as i32;
+                rank_reasons.push(RankReasons::SimilarFollows(similarity));
+            }
+
+            // Embedded in a circle rather than following around
+            if let Some(Some(coefficient)) = clustering.remove(user) {
+                rank += (coefficient * config.community_weight).round() as i32;
+                rank_reasons.push(RankReasons::TightCommunity(coefficient));
+            }
+
+            // Talking to the same people, not only following them
+            if let Some(count) = interactions.remove(user) {
+                rank += (((count + 1) as f64).log2() * config.interaction_weight).round() as i32;
+                rank_reasons.push(RankReasons::InteractsWithYourFollows(count));
+            }
+
+            // Paid by the users the center user follows, the strongest endorsement
+            if let Some(zapped) = zaps.remove(user) {
+                let sats = zapped.amount_msat / 1000;
+                rank += (((sats + 1) as f64).log2() * config.zap_weight).round() as i32;
+                rank_reasons.push(RankReasons::ZappedByYourNetwork(sats, zapped.count));
+            }
 
             users_ranks.insert(user, (rank, rank_reasons));
         }
 
+        if skipped.total() > 0 {
+            eprintln!("generate_user_ranks: Skipped {skipped}");
+        }
+
         let mut vec: Vec<(PublicKey, i32, Vec<RankReasons>)> = users_ranks
             .into_iter()
             .map(|(x, (y, z))| (*x, y, z))
+            .filter(|(_, y, _)| config.min_rank.is_none_or(|min| *y >= min))
             .collect();
-        vec.sort_by_cached_key(|(_, y, _)| *y);
+        // Ties are ordered by pubkey, so the same network always ranks the same
+        vec.sort_by_key(|(x, y, _)| (*y, *x));
+        // Sorted from the worst, so the best are at the end
+        if let Some(max) = config.max_results {
+            vec.drain(..vec.len().saturating_sub(max));
+        }
         return Ok(vec);
     }
+
+    /// Users of `source` that go in a follow pack, at most MAX_FOLLOW_PACK_ENTRIES of them
+    pub fn follow_pack_users(
+        &self,
+        source: &FollowPackSource,
+    ) -> Result<Vec<PublicKey>, RecommendationError> {
+        let users = match *source {
+            FollowPackSource::Level(level) => self
+                .level(level)
+                .ok_or(RecommendationError::NotEnoughLevels)?
+                .iter()
+                .copied()
+                .sorted()
+                .collect_vec(),
+            // Ranks are sorted from the worst
+            FollowPackSource::TopRanks(ranks, count) => ranks
+                .iter()
+                .rev()
+                .take(count)
+                .map(|(pubkey, _, _)| *pubkey)
+                .collect_vec(),
+        };
+        Ok(users
+            .into_iter()
+            .take(MAX_FOLLOW_PACK_ENTRIES)
+            .collect_vec())
+    }
+
+    /// Follow pack of the users in `source`, see follow_pack_users
+    pub fn to_follow_pack(
+        &self,
+        source: &FollowPackSource,
+        info: &FollowPackInfo,
+        factory: &EventFactory,
+    ) -> Result<EventBuilder, RecommendationError> {
+        let users = self.follow_pack_users(source)?;
+        let mut tags = vec![
+            Tag::identifier(info.identifier.clone()),
+            Tag::title(info.title.clone()),
+        ];
+        if let Some(description) = &info.description {
+            tags.push(Tag::description(description.clone()));
+        }
+        tags.extend(users.into_iter().map(Tag::public_key));
+        Ok(factory.replaceable(
+            Kind::from(FOLLOW_PACK_KIND),
+            "",
+            tags,
+            &format!("Follow pack: {}", info.title),
+        ))
+    }
+
+    /// NIP-51 follow set named `identifier` of the users in `source`, see follow_pack_users.
+    /// Publishing again with the same identifier replaces it
+    pub fn to_follow_set(
+        &self,
+        source: &FollowPackSource,
+        identifier: &str,
+        factory: &EventFactory,
+    ) -> Result<EventBuilder, RecommendationError> {
+        let users = self.follow_pack_users(source)?;
+        let mut tags = vec![Tag::identifier(identifier)];
+        tags.extend(users.into_iter().map(Tag::public_key));
+        Ok(factory.replaceable(
+            Kind::FollowSet,
+            "",
+            tags,
+            &format!("Follow set: {identifier}"),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    fn info(description: Option<&str>) -> FollowPackInfo {
+        FollowPackInfo {
+            identifier: "pack".to_string(),
+            title: "Friends".to_string(),
+            description: description.map(|x| x.to_string()),
+        }
+    }
+
+    fn tags(builder: EventBuilder) -> (Kind, Vec<Vec<String>>) {
+        let event = builder.to_event(&Keys::generate()).unwrap();
+        let tags = event.tags.iter().map(|x| x.as_vec().to_vec()).collect();
+        (event.kind, tags)
+    }
+
+    #[tokio::test]
+    async fn follow_pack_of_a_level_is_capped() {
+        let keys = keys(MAX_FOLLOW_PACK_ENTRIES + 11);
+        let users = keys.iter().map(|x| x.public_key()).collect_vec();
+        let follows = (1..users.len()).collect_vec();
+        let relay = FakeRelay::start(vec![]).await;
+        let network = follow_network(&relay, &keys[0], network(&users, &[(0, &follows)]), 1).await;
+
+        let builder = network
+            .to_follow_pack(
+                &FollowPackSource::Level(LevelIdx(1)),
+                &info(Some("People I follow")),
+                &EventFactory::default(),
+            )
+            .unwrap();
+        let (kind, tags) = tags(builder);
+        assert_eq!(kind, Kind::from(FOLLOW_PACK_KIND));
+        assert_eq!(
+            tags[..3],
+            [
+                vec!["d".to_string(), "pack".to_string()],
+                vec!["title".to_string(), "Friends".to_string()],
+                vec!["description".to_string(), "People I follow".to_string()],
+            ]
+        );
+        assert_eq!(
+            tags.last().unwrap(),
+            &vec!["alt".to_string(), "Follow pack: Friends".to_string()]
+        );
+        let entries = tags.iter().filter(|x| x[0] == "p").collect_vec();
+        assert_eq!(entries.len(), MAX_FOLLOW_PACK_ENTRIES);
+        // The level is sorted, so the same users are kept every time
+        let expected = users[1..].iter().sorted().take(MAX_FOLLOW_PACK_ENTRIES);
+        assert!(entries
+            .iter()
+            .zip(expected)
+            .all(|(x, y)| x[1] == y.to_hex()));
+
+        assert!(matches!(
+            network.to_follow_pack(
+                &FollowPackSource::Level(LevelIdx(2)),
+                &info(None),
+                &EventFactory::default()
+            ),
+            Err(RecommendationError::NotEnoughLevels)
+        ));
+    }
+
+    #[tokio::test]
+    async fn follow_pack_of_top_ranks_starts_from_the_best() {
+        let keys = keys(4);
+        let users = keys.iter().map(|x| x.public_key()).collect_vec();
+        let relay = FakeRelay::start(vec![]).await;
+        let network = follow_network(&relay, &keys[0], Network::new(), 0).await;
+        // Sorted from the worst
+        let ranks = [
+            (users[1], 1, vec![]),
+            (users[2], 2, vec![]),
+            (users[3], 3, vec![]),
+        ];
+
+        let builder = network
+            .to_follow_pack(
+                &FollowPackSource::TopRanks(&ranks, 2),
+                &info(None),
+                &EventFactory::default(),
+            )
+            .unwrap();
+        let (_, tags) = tags(builder);
+        assert!(!tags.iter().any(|x| x[0] == "description"));
+        let entries = tags
+            .iter()
+            .filter(|x| x[0] == "p")
+            .map(|x| x[1].clone())
+            .collect_vec();
+        assert_eq!(entries, vec![users[3].to_hex(), users[2].to_hex()]);
+    }
+
+    #[tokio::test]
+    async fn crawl_fetches_the_metadata_published_by_each_level() {
+        // 0 follows 1 and 2, and only 0 and 1 published metadata
+        let keys = keys(4);
+        let users = keys.iter().map(|x| x.public_key()).collect_vec();
+        let mut events = contact_lists(&keys, &[&[1, 2], &[3]]);
+        events.extend([
+            metadata(&keys[0], "zero", 1000),
+            metadata(&keys[1], "one", 1000),
+        ]);
+        let relay = FakeRelay::start(events).await;
+        let client = Arc::new(client(&[&relay]).await);
+        let center = crate::user::User::new(users[0], &client).await.unwrap();
+        let mut network = FollowNetwork::new(
+            center,
+            client,
+            Arc::new(Mutex::new(Network::new())),
+            QueryTimeouts::from_secs(2, 2),
+        )
+        .await;
+
+        network.add_level().await.unwrap();
+        network.add_metadata(LevelIdx(1)).await.unwrap();
+        assert_eq!(
+            network.level(LevelIdx(1)).unwrap(),
+            &HashSet::from([users[1], users[2]])
+        );
+        let net_lock = network.net.lock().await;
+        let name = |user: usize| {
+            net_lock
+                .get_pubkey_metadata(&users[user])
+                .map(|(x, _)| x.name.clone())
+        };
+        assert_eq!(name(0), Some(Some("zero".to_string())));
+        assert_eq!(name(1), Some(Some("one".to_string())));
+        // Nothing is made up for the user without metadata
+        assert_eq!(name(2), None);
+    }
+
+    #[tokio::test]
+    async fn removed_user_leaves_the_levels_and_the_network() {
+        let keys = keys(4);
+        let users = keys.iter().map(|x| x.public_key()).collect_vec();
+        let relay = FakeRelay::start(vec![]).await;
+        let net = network(&users, &[(0, &[1, 2]), (1, &[3]), (2, &[3])]);
+        let mut network = follow_network(&relay, &keys[0], net, 2).await;
+
+        assert!(network.remove_user(&users[1]).await);
+        assert_eq!(
+            network.level(LevelIdx(1)).unwrap(),
+            &HashSet::from([users[2]])
+        );
+        assert_eq!(
+            network.users_within(LevelIdx(2)),
+            HashSet::from([users[0], users[2], users[3]])
+        );
+        assert!(!network.net.lock().await.contains_user(&users[1]));
+        assert!(!network.remove_user(&users[1]).await);
+    }
+
+    #[tokio::test]
+    async fn followed_and_excluded_users_arent_ranked() {
+        let keys = keys(5);
+        let users = keys.iter().map(|x| x.public_key()).collect_vec();
+        let relay = FakeRelay::start(vec![]).await;
+        let net = network(&users, &[(0, &[1, 2]), (1, &[3, 4]), (2, &[3])]);
+        let network = follow_network(&relay, &keys[0], net, 2).await;
+        // 3 is followed after the crawl, still in level 2
+        network.net.lock().await.update_contact_list(
+            users[0],
+            &[users[1], users[2], users[3]],
+            &Timestamp::from(2000),
+        );
+
+        let ranked = |config: RankConfig| {
+            let network = &network;
+            async move {
+                let ranks = network.generate_user_ranks(&config).await.unwrap();
+                ranks
+                    .into_iter()
+                    .map(|(user, _, _)| user)
+                    .sorted()
+                    .collect_vec()
+            }
+        };
+        assert_eq!(ranked(RankConfig::default()).await, [users[4]]);
+        let config = RankConfig {
+            include_followed: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            ranked(config).await,
+            [users[3], users[4]].into_iter().sorted().collect_vec()
+        );
+
+        let path = temp_path("exclusions.txt");
+        fs::write(
+            &path,
+            format!("# Spam\n\n  {}  \n", users[4].to_bech32().unwrap()),
+        )
+        .unwrap();
+        let mut config = RankConfig {
+            include_followed: true,
+            ..Default::default()
+        };
+        config.load_exclusions(&path).unwrap();
+        assert_eq!(config.excluded, HashSet::from([users[4]]));
+        assert_eq!(ranked(config.clone()).await, [users[3]]);
+
+        fs::write(&path, format!("{}\nnot a key\n", users[3].to_hex())).unwrap();
+        assert!(matches!(
+            config.load_exclusions(&path),
+            Err(RankConfigError::InvalidPubkey(key)) if key == "not a key"
+        ));
+        assert!(matches!(
+            config.load_exclusions(&temp_path("no-exclusions.txt")),
+            Err(RankConfigError::Read(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn popular_users_rank_lower_for_the_same_mutuals() {
+        // 3 and 4 are both mutuals of 1 and 2, who 0 follows, but 3 is also followed by 20 others
+        let keys = keys(25);
+        let users = keys.iter().map(|x| x.public_key()).collect_vec();
+        let relay = FakeRelay::start(vec![]).await;
+        let fans = (5..25).map(|x| (x, &[3][..])).collect_vec();
+        let mut follows: Vec<(usize, &[usize])> = vec![
+            (0, &[1, 2]),
+            (1, &[3, 4]),
+            (2, &[3, 4]),
+            (3, &[1, 2]),
+            (4, &[1, 2]),
+        ];
+        follows.extend(fans);
+        let network = follow_network(&relay, &keys[0], network(&users, &follows), 2).await;
+
+        let ranks = |popularity_dampening| {
+            let (network, users) = (&network, &users);
+            async move {
+                let config = RankConfig {
+                    similarity_weight: 0.0,
+                    community_weight: 0.0,
+                    recency_weight: 0.0,
+                    popularity_dampening,
+                    ..Default::default()
+                };
+                let ranks = network.generate_user_ranks(&config).await.unwrap();
+                let rank = |user: PublicKey| {
+                    let (_, rank, reasons) = ranks.iter().find(|(x, _, _)| *x == user).unwrap();
+                    let followers = reasons.iter().find_map(|x| match x {
+                        RankReasons::FollowedByCount(count) => Some(*count),
+                        _ => None,
+                    });
+                    (*rank, followers.unwrap())
+                };
+                (rank(users[3]), rank(users[4]))
+            }
+        };
+        // Without dampening both count 10 per mutual
+        assert_eq!(ranks(0.0).await, ((20, 22), (20, 2)));
+        // 20 / (1 + log10(23)) and 20 / (1 + log10(3))
+        assert_eq!(
+            ranks(DEFAULT_POPULARITY_DAMPENING).await,
+            ((8, 22), (14, 2))
+        );
+    }
+
+    #[tokio::test]
+    async fn same_network_always_ranks_the_same() {
+        // 1 to 4 are all mutuals of 5 to 8, in the level after them
+        let keys = keys(9);
+        let users = keys.iter().map(|x| x.public_key()).collect_vec();
+        let follows: [(usize, &[usize]); 9] = [
+            (0, &[1, 2, 3, 4]),
+            (1, &[5, 6, 7, 8]),
+            (2, &[5, 6, 7, 8]),
+            (3, &[5, 6, 7, 8]),
+            (4, &[5, 6, 7, 8]),
+            (5, &[1, 2, 3, 4]),
+            (6, &[4, 3, 2, 1]),
+            (7, &[2, 4, 1, 3]),
+            (8, &[3, 1, 4, 2]),
+        ];
+        let mut rankings = vec![];
+        for order in [
+            follows.to_vec(),
+            follows.iter().rev().copied().collect_vec(),
+        ] {
+            let relay = FakeRelay::start(vec![]).await;
+            let network = follow_network(&relay, &keys[0], network(&users, &order), 2).await;
+            for _ in 0..2 {
+                let ranks = network
+                    .generate_user_ranks(&RankConfig::default())
+                    .await
+                    .unwrap();
+                rankings.push(format!("{ranks:?}"));
+            }
+        }
+        assert!(rankings.iter().all_equal(), "{rankings:#?}");
+        assert!(rankings[0].contains("MutualConnections"));
+    }
+
+    #[tokio::test]
+    async fn levels_from_the_network_match_a_crawl_of_the_relays() {
+        let keys = keys(7);
+        let users = keys.iter().map(|x| x.public_key()).collect_vec();
+        let follows: [&[usize]; 6] = [&[1, 2], &[3, 2], &[4], &[5], &[5, 0], &[6]];
+        let mut events = contact_lists(&keys, &follows);
+        events.push(metadata(&keys[0], "center", 1000));
+        let relay = FakeRelay::start(events).await;
+        let client = Arc::new(client(&[&relay]).await);
+        let center = crate::user::User::new(users[0], &client).await.unwrap();
+        let mut crawled = FollowNetwork::new(
+            center,
+            client,
+            Arc::new(Mutex::new(Network::new())),
+            QueryTimeouts::from_secs(2, 2),
+        )
+        .await;
+        for _ in 0..3 {
+            crawled.add_level().await.unwrap();
+        }
+        assert_eq!(crawled.level(LevelIdx(3)), Some(&HashSet::from([users[5]])));
+
+        let indexed = follows.iter().copied().enumerate().collect_vec();
+        let cached = follow_network(&relay, &keys[0], network(&users, &indexed), 3).await;
+        assert_eq!(cached.levels, crawled.levels);
+        assert_eq!(cached.users_distances, crawled.users_distances);
+
+        // Without the contact list of 4, level 3 is crawled from the relays
+        let partial = network(&users, &indexed[..4]);
+        let mut resumed = follow_network(&relay, &keys[0], partial, 3).await;
+        assert!(resumed.level(LevelIdx(3)).is_none());
+        resumed.add_level().await.unwrap();
+        assert_eq!(resumed.levels, crawled.levels);
+        assert_eq!(resumed.users_distances, crawled.users_distances);
+    }
 }
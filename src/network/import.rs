@@ -0,0 +1,98 @@
+/// Seed the network from dumps of events, e.g. a relay export, instead of asking relays
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, BufRead};
+
+use crate::client_utils::contact_list_pubkeys;
+use crate::network::*;
+use nostr_sdk::prelude::*;
+
+/// Counts of what an import did with each line
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ImportReport {
+    pub contact_lists: usize,
+    pub metadata: usize,
+    /// Events older than what the network already had
+    pub older: usize,
+    /// Events of other kinds
+    pub ignored: usize,
+    /// Lines that aren't valid events
+    pub malformed: usize,
+    /// p tags in the contact lists without a valid public key
+    pub malformed_p_tags: usize,
+}
+
+impl Display for ImportReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} contact lists, {} metadata, {} older events, {} other kinds, {} malformed lines, {} malformed p tags",
+            self.contact_lists,
+            self.metadata,
+            self.older,
+            self.ignored,
+            self.malformed,
+            self.malformed_p_tags
+        )
+    }
+}
+
+impl Network {
+    /// Read newline delimited JSON events, adding the contact lists (kind 3) and metadata
+    /// (kind 0) newer than the ones in the network
+    ///
+    /// Imported contact lists count as fetched when they were created, so a TTL gets them
+    /// fetched again once they're old
+    pub fn import_events(&mut self, reader: impl BufRead) -> io::Result<ImportReport> {
+        let mut report = ImportReport::default();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let event = match Event::from_json(&line) {
+                Ok(event) if event.verify().is_ok() => event,
+                _ => {
+                    report.malformed += 1;
+                    continue;
+                }
+            };
+            let author = event.author();
+            let created_at = event.created_at();
+            match event.kind() {
+                Kind::ContactList => {
+                    if self
+                        .contact_list_created_at(&author)
+                        .is_some_and(|x| x >= created_at)
+                    {
+                        report.older += 1;
+                        continue;
+                    }
+                    let (pubkeys, malformed) = contact_list_pubkeys(&event);
+                    report.malformed_p_tags += malformed;
+                    self.update_contact_list(author, &pubkeys, &created_at);
+                    self.set_contact_list_fetched_at(author, created_at);
+                    report.contact_lists += 1;
+                }
+                Kind::Metadata => {
+                    if self
+                        .get_pubkey_metadata(&author)
+                        .is_some_and(|(_, x)| *x >= created_at)
+                    {
+                        report.older += 1;
+                        continue;
+                    }
+                    match Metadata::from_json(event.content()) {
+                        Ok(metadata) => {
+                            self.add_user(author);
+                            self.add_user_metadata(author, metadata, created_at);
+                            report.metadata += 1;
+                        }
+                        Err(_err) => report.malformed += 1,
+                    }
+                }
+                _ => report.ignored += 1,
+            }
+        }
+        Ok(report)
+    }
+}
@@ -1,3 +1,20 @@
 pub mod base;
+pub mod bridges;
+pub mod check;
+pub mod clustering;
+pub mod components;
+#[cfg(feature = "sqlite")]
+pub mod db;
+pub mod diff;
+pub mod export;
+pub mod filters;
 pub mod follow;
+pub mod import;
+pub mod mutuals;
+pub mod names;
+pub mod persist;
+pub mod progress;
+pub mod reach;
+pub mod score;
+pub mod stats;
 pub use base::*;
@@ -0,0 +1,125 @@
+/// Mutual follows as undirected edges, kept up to date as follows change, so searches don't
+/// intersect the follows and followers of every user they go through
+use std::collections::{HashMap, HashSet};
+
+use crate::network::*;
+use nostr_sdk::prelude::*;
+
+/// Mutuals of each user. Keyed by public key, as node indices move when users are removed
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MutualProjection {
+    adjacency: HashMap<PublicKey, HashSet<PublicKey>>,
+    pairs: usize,
+}
+
+impl MutualProjection {
+    /// `a` and `b` now follow each other
+    pub fn link(&mut self, a: PublicKey, b: PublicKey) {
+        if a == b {
+            return;
+        }
+        if self.adjacency.entry(a).or_default().insert(b) {
+            self.adjacency.entry(b).or_default().insert(a);
+            self.pairs += 1;
+        }
+    }
+
+    /// `a` or `b` stopped following the other
+    pub fn unlink(&mut self, a: &PublicKey, b: &PublicKey) {
+        if self.adjacency.get_mut(a).is_some_and(|x| x.remove(b)) {
+            self.drop_empty(a);
+            if let Some(mutuals) = self.adjacency.get_mut(b) {
+                mutuals.remove(a);
+            }
+            self.drop_empty(b);
+            self.pairs -= 1;
+        }
+    }
+
+    pub fn remove_user(&mut self, user: &PublicKey) {
+        for mutual in self.adjacency.remove(user).unwrap_or_default() {
+            if let Some(mutuals) = self.adjacency.get_mut(&mutual) {
+                mutuals.remove(user);
+            }
+            self.drop_empty(&mutual);
+            self.pairs -= 1;
+        }
+    }
+
+    fn drop_empty(&mut self, user: &PublicKey) {
+        if self.adjacency.get(user).is_some_and(|x| x.is_empty()) {
+            self.adjacency.remove(user);
+        }
+    }
+
+    pub fn mutuals(&self, user: &PublicKey) -> impl Iterator<Item = &PublicKey> {
+        self.adjacency.get(user).into_iter().flatten()
+    }
+
+    pub fn contains(&self, a: &PublicKey, b: &PublicKey) -> bool {
+        self.adjacency.get(a).is_some_and(|x| x.contains(b))
+    }
+
+    /// Pairs of users following each other
+    pub fn pair_count(&self) -> usize {
+        self.pairs
+    }
+
+    /// Every pair following each other, from the follows of a network
+    pub fn from_follows(follows: impl IntoIterator<Item = (PublicKey, PublicKey)>) -> Self {
+        let follows: HashSet<(PublicKey, PublicKey)> = follows.into_iter().collect();
+        let mut projection = MutualProjection::default();
+        for (user, follow) in &follows {
+            if user < follow && follows.contains(&(*follow, *user)) {
+                projection.link(*user, *follow);
+            }
+        }
+        projection
+    }
+}
+
+impl Network {
+    /// Pairs of users following each other
+    pub fn mutual_count(&self) -> usize {
+        self.mutuals.pair_count()
+    }
+
+    /// Mutuals of `user`, in no order. Empty when the user isn't in the network
+    pub fn mutuals_of(&self, user: &PublicKey) -> impl Iterator<Item = &PublicKey> {
+        self.mutuals.mutuals(user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::*;
+    use itertools::Itertools;
+    use nostr_sdk::prelude::*;
+
+    #[test]
+    fn mutuals_follow_the_contact_lists_and_removed_users() {
+        let users = pubkeys(4);
+        let mut network = network(&users, &[(0, &[1, 2]), (1, &[0, 2]), (2, &[0]), (3, &[0])]);
+        let mutuals = |network: &crate::network::Network, user: usize| {
+            network
+                .mutuals_of(&users[user])
+                .copied()
+                .sorted()
+                .collect_vec()
+        };
+        let sorted = |x: &[usize]| x.iter().map(|x| users[*x]).sorted().collect_vec();
+        assert_eq!(network.mutual_count(), 2);
+        assert_eq!(mutuals(&network, 0), sorted(&[1, 2]));
+        assert_eq!(mutuals(&network, 3), []);
+
+        // 2 follows 1 back and unfollows 0
+        network.update_contact_list(users[2], &[users[1]], &Timestamp::from(2000));
+        assert_eq!(network.mutual_count(), 2);
+        assert_eq!(mutuals(&network, 2), sorted(&[1]));
+        assert_eq!(mutuals(&network, 0), sorted(&[1]));
+
+        network.remove_user(&users[1]);
+        assert_eq!(network.mutual_count(), 0);
+        assert!((0..4).all(|x| mutuals(&network, x).is_empty()));
+    }
+}
@@ -0,0 +1,177 @@
+/// Find users by the names in their metadata, for mentions that don't have npubs
+use itertools::Itertools;
+use regex::Regex;
+use std::fmt::{self, Display, Formatter};
+
+use crate::client_utils::mention_words;
+use crate::network::Network;
+use nostr_sdk::prelude::*;
+
+/// Shorter names match too many users to mean anyone
+pub const MIN_NAME_LEN: usize = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NameError {
+    TooShort(String),
+    NotFound(String),
+    /// More than one user has the name, with some of them
+    Ambiguous(String, Vec<PublicKey>),
+}
+
+impl Display for NameError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            NameError::TooShort(name) => write!(f, "Name \"{name}\" too short"),
+            NameError::NotFound(name) => write!(f, "No user named \"{name}\""),
+            NameError::Ambiguous(name, users) => {
+                write!(f, "{} or more users named \"{name}\"", users.len())
+            }
+        }
+    }
+}
+
+impl std::error::Error for NameError {}
+
+/// Most candidates given back for an ambiguous name
+const MAX_CANDIDATES: usize = 5;
+
+/// The names asked about in a text without npubs, e.g. "connection between fiatjaf and jb55"
+///
+/// Reads "between X and Y", or else the words left after the nostr: references and hashtags when
+/// there are exactly `count`
+pub fn names_in_text(text: &str, count: usize) -> Vec<String> {
+    let between = Regex::new(r"(?i)\bbetween\s+@?(\S+)\s+and\s+@?(\S+)").unwrap();
+    let trim = |x: &str| {
+        x.trim_matches(|c: char| !c.is_alphanumeric() && c != '_')
+            .to_string()
+    };
+    if count == 2 {
+        if let Some(caps) = between.captures(text) {
+            return vec![trim(&caps[1]), trim(&caps[2])];
+        }
+    }
+    let words = mention_words(text)
+        .filter(|x| !x.starts_with('#'))
+        .map(|x| trim(x.trim_start_matches('@')))
+        .filter(|x| !x.is_empty())
+        .collect_vec();
+    if words.len() == count {
+        words
+    } else {
+        vec![]
+    }
+}
+
+impl Network {
+    /// The only user whose name or display name is `name`, ignoring case
+    pub fn resolve_name(&self, name: &str) -> Result<PublicKey, NameError> {
+        if name.chars().count() < MIN_NAME_LEN {
+            return Err(NameError::TooShort(name.to_string()));
+        }
+        let name_lower = name.to_lowercase();
+        let matches =
+            |x: &Option<String>| x.as_ref().is_some_and(|x| x.to_lowercase() == name_lower);
+        let users = self
+            .users_metadata()
+            .filter(|(_, m)| matches(&m.name) || matches(&m.display_name))
+            .map(|(user, _)| *user)
+            .sorted()
+            .take(MAX_CANDIDATES)
+            .collect_vec();
+        match users[..] {
+            [] => Err(NameError::NotFound(name.to_string())),
+            [user] => Ok(user),
+            _ => Err(NameError::Ambiguous(name.to_string(), users)),
+        }
+    }
+
+    /// Resolve every name, failing on the first that doesn't resolve
+    pub fn resolve_names(&self, names: &[String]) -> Result<Vec<PublicKey>, NameError> {
+        names.iter().map(|x| self.resolve_name(x)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    /// Network of users with these names and display names
+    fn named(users: &[PublicKey], names: &[(&str, Option<&str>)]) -> Network {
+        let mut network = Network::new();
+        for (user, (name, display_name)) in users.iter().zip(names) {
+            let mut metadata = Metadata::new().name(*name);
+            if let Some(display_name) = display_name {
+                metadata = metadata.display_name(*display_name);
+            }
+            network.add_user_metadata(*user, metadata, Timestamp::from(1000));
+        }
+        network
+    }
+
+    #[test]
+    fn unique_name_or_display_name_resolves_ignoring_case() {
+        let users = pubkeys(3);
+        let network = named(
+            &users,
+            &[("fiatjaf", None), ("jb55", Some("Will")), ("alice", None)],
+        );
+        assert_eq!(network.resolve_name("FiatJaf"), Ok(users[0]));
+        assert_eq!(network.resolve_name("will"), Ok(users[1]));
+        assert_eq!(
+            network.resolve_names(&["jb55".to_string(), "alice".to_string()]),
+            Ok(vec![users[1], users[2]])
+        );
+    }
+
+    #[test]
+    fn ambiguous_unknown_and_short_names_dont_resolve() {
+        let users = pubkeys(MAX_CANDIDATES + 3);
+        let mut names = vec![("bob", None); MAX_CANDIDATES + 2];
+        names.push(("carol", Some("Bob")));
+        let network = named(&users, &names);
+
+        // Every user named bob, some by their display name, up to the most candidates
+        let candidates = users
+            .iter()
+            .copied()
+            .sorted()
+            .take(MAX_CANDIDATES)
+            .collect_vec();
+        assert_eq!(
+            network.resolve_name("Bob"),
+            Err(NameError::Ambiguous("Bob".to_string(), candidates))
+        );
+        assert_eq!(
+            network.resolve_name("dave"),
+            Err(NameError::NotFound("dave".to_string()))
+        );
+        assert_eq!(
+            network.resolve_name("bo"),
+            Err(NameError::TooShort("bo".to_string()))
+        );
+        // The first name that doesn't resolve is told
+        assert_eq!(
+            network.resolve_names(&["carol".to_string(), "dave".to_string()]),
+            Err(NameError::NotFound("dave".to_string()))
+        );
+    }
+
+    #[test]
+    fn names_are_read_from_the_text() {
+        assert_eq!(
+            names_in_text(
+                "nostr:npub1bot find the connection between @fiatjaf and jb55?",
+                2
+            ),
+            ["fiatjaf", "jb55"]
+        );
+        assert_eq!(
+            names_in_text("nostr:npub1bot @fiatjaf, jb55 #sixdegrees", 2),
+            ["fiatjaf", "jb55"]
+        );
+        assert_eq!(names_in_text("nostr:npub1bot fiatjaf", 1), ["fiatjaf"]);
+        // Too many words to tell which are names
+        assert!(names_in_text("nostr:npub1bot how far is fiatjaf", 1).is_empty());
+    }
+}
@@ -0,0 +1,484 @@
+/// Saving and loading the network to disk, keeping older generations of the cache file
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::network::Network;
+use nostr_sdk::prelude::*;
+
+pub const SNAPSHOT_VERSION: u32 = 2;
+
+/// zstd level of compressed caches
+pub const ZSTD_LEVEL: i32 = 9;
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// How a snapshot is written. Reading finds the format from the file contents
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheFormat {
+    #[default]
+    Bincode,
+    /// Bincode compressed with zstd, for the smallest files
+    BincodeZstd,
+    /// Readable, for debugging
+    Json,
+}
+
+impl CacheFormat {
+    /// `.json` is JSON, `.zst` is compressed, anything else is bincode
+    pub fn from_path(path: &Path) -> CacheFormat {
+        match path.extension().and_then(|x| x.to_str()) {
+            Some("json") => CacheFormat::Json,
+            Some("zst") => CacheFormat::BincodeZstd,
+            _ => CacheFormat::Bincode,
+        }
+    }
+
+    fn detect(bytes: &[u8]) -> CacheFormat {
+        if bytes.starts_with(&ZSTD_MAGIC) {
+            CacheFormat::BincodeZstd
+        } else if bytes.trim_ascii_start().starts_with(b"{") {
+            CacheFormat::Json
+        } else {
+            CacheFormat::Bincode
+        }
+    }
+}
+
+/// Whether an instance may write its cache, e.g. one answering from the cache of another
+/// instance that crawls
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheMode {
+    #[default]
+    ReadWrite,
+    /// Load the cache, but never save or prune the network
+    ReadOnly,
+}
+
+impl Display for CacheMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            CacheMode::ReadWrite => write!(f, "readwrite"),
+            CacheMode::ReadOnly => write!(f, "readonly"),
+        }
+    }
+}
+
+/// Serializable copy of a network
+///
+/// Follows are stored as pairs of indices into `users`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NetworkSnapshot {
+    pub(super) version: u32,
+    /// When the snapshot was taken
+    pub(super) created_at: Timestamp,
+    pub(super) users: Vec<PublicKey>,
+    pub(super) follows: Vec<(u32, u32)>,
+    pub(super) users_metadata: Vec<(PublicKey, Option<(String, Timestamp)>)>,
+    pub(super) added_out_edges_since: Vec<(PublicKey, Timestamp)>,
+    pub(super) contact_list_creation: Vec<(PublicKey, Timestamp)>,
+}
+
+/// Version 1 snapshot, which had no creation time
+#[derive(Debug, Deserialize)]
+struct NetworkSnapshotV1 {
+    version: u32,
+    users: Vec<PublicKey>,
+    follows: Vec<(u32, u32)>,
+    users_metadata: Vec<(PublicKey, Option<(String, Timestamp)>)>,
+    added_out_edges_since: Vec<(PublicKey, Timestamp)>,
+    contact_list_creation: Vec<(PublicKey, Timestamp)>,
+}
+
+#[derive(Debug)]
+pub enum PersistError {
+    Io(std::io::Error),
+    Encoding(bincode::Error),
+    Json(serde_json::Error),
+    UnsupportedVersion(u32),
+    Corrupted(&'static str),
+    /// No cache generation was taken at or before this time
+    NoGenerationAsOf(Timestamp),
+}
+
+impl Display for PersistError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PersistError::Io(error) => write!(f, "{}", error),
+            PersistError::Encoding(error) => write!(f, "Cache encoding error: {}", error),
+            PersistError::Json(error) => write!(f, "Cache JSON error: {}", error),
+            PersistError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported cache version {version}")
+            }
+            PersistError::Corrupted(reason) => write!(f, "Corrupted cache: {reason}"),
+            PersistError::NoGenerationAsOf(time) => write!(
+                f,
+                "No cache generation taken at or before {}",
+                time.to_human_datetime()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PersistError {}
+
+impl From<std::io::Error> for PersistError {
+    fn from(value: std::io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<bincode::Error> for PersistError {
+    fn from(value: bincode::Error) -> Self {
+        Self::Encoding(value)
+    }
+}
+
+impl From<serde_json::Error> for PersistError {
+    fn from(value: serde_json::Error) -> Self {
+        Self::Json(value)
+    }
+}
+
+/// Just the version of a JSON snapshot
+#[derive(Deserialize)]
+struct SnapshotVersion {
+    version: u32,
+}
+
+impl NetworkSnapshot {
+    pub fn write_to(&self, path: &Path, format: CacheFormat) -> Result<(), PersistError> {
+        let bytes = match format {
+            CacheFormat::Bincode => bincode::serialize(self)?,
+            CacheFormat::BincodeZstd => {
+                zstd::encode_all(bincode::serialize(self)?.as_slice(), ZSTD_LEVEL)?
+            }
+            CacheFormat::Json => serde_json::to_vec(self)?,
+        };
+        fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    /// Read a snapshot in any format. Version 1 files get the file modification time as their
+    /// creation time
+    pub fn read_from(path: &Path) -> Result<NetworkSnapshot, PersistError> {
+        let mut bytes = fs::read(path)?;
+        match CacheFormat::detect(&bytes) {
+            CacheFormat::Json => {
+                let version = serde_json::from_slice::<SnapshotVersion>(&bytes)?.version;
+                if version != SNAPSHOT_VERSION {
+                    return Err(PersistError::UnsupportedVersion(version));
+                }
+                return Ok(serde_json::from_slice(&bytes)?);
+            }
+            CacheFormat::BincodeZstd => {
+                bytes = zstd::decode_all(bytes.as_slice())
+                    .map_err(|_err| PersistError::Corrupted("invalid zstd data"))?
+            }
+            CacheFormat::Bincode => (),
+        }
+        // The version is the first field of every version
+        let version: u32 = bincode::deserialize(&bytes)?;
+        match version {
+            1 => {
+                let old: NetworkSnapshotV1 = bincode::deserialize(&bytes)?;
+                let modified = fs::metadata(path)?
+                    .modified()?
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|x| x.as_secs())
+                    .unwrap_or(0);
+                Ok(NetworkSnapshot {
+                    version: old.version,
+                    created_at: Timestamp::from(modified),
+                    users: old.users,
+                    follows: old.follows,
+                    users_metadata: old.users_metadata,
+                    added_out_edges_since: old.added_out_edges_since,
+                    contact_list_creation: old.contact_list_creation,
+                })
+            }
+            SNAPSHOT_VERSION => Ok(bincode::deserialize(&bytes)?),
+            _ => Err(PersistError::UnsupportedVersion(version)),
+        }
+    }
+
+    pub fn created_at(&self) -> Timestamp {
+        self.created_at
+    }
+}
+
+/// Read the snapshot in `from` and write it to `to`, in the format of its extension
+pub fn convert(from: &Path, to: &Path) -> Result<CacheFormat, PersistError> {
+    let format = CacheFormat::from_path(to);
+    NetworkSnapshot::read_from(from)?.write_to(to, format)?;
+    Ok(format)
+}
+
+/// Generation 0 is the path itself, generation n is `<path>.n`
+pub fn generation_path(path: &Path, generation: usize) -> PathBuf {
+    if generation == 0 {
+        return path.to_path_buf();
+    }
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{generation}"));
+    PathBuf::from(name)
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+/// Write the snapshot to `<path>.tmp`, then rotate `path` → `<path>.1` → `<path>.2`...
+/// keeping at most `generations` older copies besides the newest one
+pub fn save_rotating(
+    snapshot: &NetworkSnapshot,
+    path: &Path,
+    generations: usize,
+    format: CacheFormat,
+) -> Result<(), PersistError> {
+    let tmp = tmp_path(path);
+    snapshot.write_to(&tmp, format)?;
+
+    for generation in (0..generations).rev() {
+        let from = generation_path(path, generation);
+        if from.is_file() {
+            fs::rename(from, generation_path(path, generation + 1))?;
+        }
+    }
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// Find the newest generation taken at or before `as_of`, among every generation present on disk
+///
+/// Returns the snapshot and which generation it came from
+pub fn generation_as_of(
+    path: &Path,
+    as_of: Timestamp,
+) -> Result<(NetworkSnapshot, usize), PersistError> {
+    let mut best: Option<(NetworkSnapshot, usize)> = None;
+    let mut generation = 0;
+    while generation_path(path, generation).is_file() {
+        let gen_path = generation_path(path, generation);
+        match NetworkSnapshot::read_from(&gen_path) {
+            Ok(snapshot) if snapshot.created_at <= as_of => {
+                if best
+                    .as_ref()
+                    .is_none_or(|(b, _)| snapshot.created_at > b.created_at)
+                {
+                    best = Some((snapshot, generation));
+                }
+            }
+            Ok(_) => (),
+            Err(err) => eprintln!("Cache {} load error: {}", gen_path.display(), err),
+        }
+        generation += 1;
+    }
+    best.ok_or(PersistError::NoGenerationAsOf(as_of))
+}
+
+/// Load the newest generation that can be read, falling back to older ones
+///
+/// Returns the network and which generation it came from
+pub fn load_newest(path: &Path, generations: usize) -> Option<(Network, usize)> {
+    for generation in 0..=generations {
+        let gen_path = generation_path(path, generation);
+        if !gen_path.is_file() {
+            continue;
+        }
+        match NetworkSnapshot::read_from(&gen_path).and_then(Network::from_snapshot) {
+            Ok(network) => return Some((network, generation)),
+            Err(err) => eprintln!("Cache {} load error: {}", gen_path.display(), err),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::PathMode;
+    use crate::test_utils::*;
+    use itertools::Itertools;
+
+    #[test]
+    fn save_rotating_keeps_the_older_generations() {
+        let users = pubkeys(4);
+        let path = temp_path("rotating.bin");
+        for i in 1..=4 {
+            let follows = (1..i).collect::<Vec<_>>();
+            let snapshot = network(&users, &[(0, &follows)]).to_snapshot();
+            save_rotating(&snapshot, &path, 2, CacheFormat::Bincode).unwrap();
+        }
+
+        let follows = |generation| {
+            NetworkSnapshot::read_from(&generation_path(&path, generation))
+                .unwrap()
+                .follows
+                .len()
+        };
+        assert_eq!((follows(0), follows(1), follows(2)), (3, 2, 1));
+        assert!(!generation_path(&path, 3).exists());
+        assert!(!tmp_path(&path).exists());
+    }
+
+    #[test]
+    fn load_newest_falls_back_to_older_generations() {
+        let users = pubkeys(3);
+        let path = temp_path("fallback.bin");
+        for follows in [&[1][..], &[1, 2]] {
+            let snapshot = network(&users, &[(0, follows)]).to_snapshot();
+            save_rotating(&snapshot, &path, 2, CacheFormat::Bincode).unwrap();
+        }
+        let (network, generation) = load_newest(&path, 2).unwrap();
+        assert_eq!((generation, network.edge_count()), (0, 2));
+
+        fs::write(&path, b"not a cache").unwrap();
+        let (network, generation) = load_newest(&path, 2).unwrap();
+        assert_eq!((generation, network.edge_count()), (1, 1));
+
+        fs::write(generation_path(&path, 1), b"not a cache").unwrap();
+        assert!(load_newest(&path, 2).is_none());
+    }
+
+    #[test]
+    fn generation_as_of_picks_the_newest_one_taken_by_then() {
+        let users = pubkeys(3);
+        let path = temp_path("as_of.bin");
+        // A chain of mutuals 0 - 1 - 2 last month, and 0 - 2 directly this month
+        let older = network(&users, &[(0, &[1]), (1, &[0, 2]), (2, &[1])]);
+        let newer = network(&users, &[(0, &[2]), (2, &[0])]);
+        for (network, created_at) in [(older, 1_000_000), (newer, 2_000_000)] {
+            let mut snapshot = network.to_snapshot();
+            snapshot.created_at = Timestamp::from(created_at);
+            save_rotating(&snapshot, &path, 2, CacheFormat::Bincode).unwrap();
+        }
+
+        let degree_as_of = |as_of: u64| {
+            let (snapshot, generation) = generation_as_of(&path, Timestamp::from(as_of)).unwrap();
+            let created_at = snapshot.created_at().as_u64();
+            let path = Network::from_snapshot(snapshot)
+                .unwrap()
+                .shortest_path(&users[0], &users[2], PathMode::Mutual)
+                .unwrap();
+            (generation, created_at, path.len() - 1)
+        };
+        assert_eq!(degree_as_of(1_500_000), (1, 1_000_000, 2));
+        assert_eq!(degree_as_of(1_000_000), (1, 1_000_000, 2));
+        assert_eq!(degree_as_of(3_000_000), (0, 2_000_000, 1));
+
+        let err = generation_as_of(&path, Timestamp::from(999_999)).unwrap_err();
+        assert!(matches!(err, PersistError::NoGenerationAsOf(_)));
+        assert!(err
+            .to_string()
+            .starts_with("No cache generation taken at or before"));
+    }
+
+    /// Everything in a snapshot, with the follows as public keys and in a set order
+    type Summary = (
+        Vec<(PublicKey, PublicKey)>,
+        Vec<(PublicKey, Option<(String, Timestamp)>)>,
+        Vec<(PublicKey, Timestamp)>,
+        Vec<(PublicKey, Timestamp)>,
+    );
+
+    fn summary(network: &Network) -> Summary {
+        let snapshot = network.to_snapshot();
+        let user = |x: u32| snapshot.users[x as usize];
+        let follows = snapshot.follows.iter().map(|(a, b)| (user(*a), user(*b)));
+        (
+            follows.sorted().collect(),
+            snapshot.users_metadata.into_iter().sorted().collect(),
+            snapshot
+                .added_out_edges_since
+                .into_iter()
+                .sorted()
+                .collect(),
+            snapshot
+                .contact_list_creation
+                .into_iter()
+                .sorted()
+                .collect(),
+        )
+    }
+
+    /// Mutuals and one-way follows, with names for some users
+    fn fixture() -> Network {
+        let keys = keys(8);
+        let users = keys.iter().map(|x| x.public_key()).collect_vec();
+        let mut network = network(
+            &users,
+            &[
+                (0, &[1, 2, 3]),
+                (1, &[0, 2]),
+                (2, &[0, 4, 5]),
+                (4, &[2, 6]),
+                (6, &[7]),
+            ],
+        );
+        for (i, name) in [(0, "alice"), (2, "bob"), (7, "carol")] {
+            network.add_user_metadata(
+                users[i],
+                Metadata::new().name(name),
+                Timestamp::from(500 + i as u64),
+            );
+        }
+        network
+    }
+
+    #[test]
+    fn every_format_round_trips() {
+        let network = fixture();
+        for (name, format) in [
+            ("round_trip.bin", CacheFormat::Bincode),
+            ("round_trip.zst", CacheFormat::BincodeZstd),
+            ("round_trip.json", CacheFormat::Json),
+        ] {
+            let path = temp_path(name);
+            assert_eq!(CacheFormat::from_path(&path), format);
+            network.to_snapshot().write_to(&path, format).unwrap();
+            assert_eq!(CacheFormat::detect(&fs::read(&path).unwrap()), format);
+            let loaded =
+                Network::from_snapshot(NetworkSnapshot::read_from(&path).unwrap()).unwrap();
+            assert_eq!(summary(&loaded), summary(&network), "{format:?}");
+        }
+    }
+
+    #[test]
+    fn convert_transcodes_between_formats() {
+        let network = fixture();
+        let json = temp_path("convert.json");
+        let zst = temp_path("convert.zst");
+        let back = temp_path("convert_back.json");
+        network
+            .to_snapshot()
+            .write_to(&json, CacheFormat::Json)
+            .unwrap();
+
+        assert_eq!(convert(&json, &zst).unwrap(), CacheFormat::BincodeZstd);
+        assert_eq!(convert(&zst, &back).unwrap(), CacheFormat::Json);
+        let loaded = Network::from_snapshot(NetworkSnapshot::read_from(&back).unwrap()).unwrap();
+        assert_eq!(summary(&loaded), summary(&network));
+        assert!(fs::metadata(&zst).unwrap().len() < fs::metadata(&json).unwrap().len());
+    }
+
+    #[test]
+    fn corrupted_caches_are_told_apart() {
+        let path = temp_path("corrupted.zst");
+        let mut bytes = ZSTD_MAGIC.to_vec();
+        bytes.extend(b"garbage");
+        fs::write(&path, bytes).unwrap();
+        let err = NetworkSnapshot::read_from(&path).unwrap_err();
+        assert_eq!(err.to_string(), "Corrupted cache: invalid zstd data");
+
+        let path = temp_path("future.json");
+        fs::write(&path, br#"{"version": 99}"#).unwrap();
+        let err = NetworkSnapshot::read_from(&path).unwrap_err();
+        assert!(matches!(err, PersistError::UnsupportedVersion(99)));
+    }
+}
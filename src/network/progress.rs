@@ -0,0 +1,105 @@
+/// Steps of the crawls of a FollowNetwork, for whoever wants to show how far along they are
+///
+/// Without a callback they're logged to stderr as they always were. Listen mode keeps the last
+/// one of each running recommendation, for the "status" command
+use std::fmt::{self, Display, Formatter};
+
+use crate::network::follow::LevelIdx;
+
+/// Called with each step of add_level and add_metadata, see FollowNetwork::set_progress
+pub type ProgressCallback = Box<dyn FnMut(ProgressEvent) + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressEvent {
+    /// Fetching the contact lists of the level before `level`, in `chunks` chunks. `skipped`
+    /// users already had theirs in the network
+    LevelStarted {
+        level: LevelIdx,
+        chunks: usize,
+        skipped: usize,
+    },
+    /// Fetching the metadata of `level`, in `chunks` chunks. `skipped` users already had it in
+    /// the network
+    MetadataStarted {
+        level: LevelIdx,
+        chunks: usize,
+        skipped: usize,
+    },
+    /// `done` of the `total` chunks of the step running on `level` fetched
+    ChunkDone {
+        level: LevelIdx,
+        done: usize,
+        total: usize,
+    },
+    /// `level` cut down from `from` to `to` users, see FollowNetwork::set_max_level_size
+    LevelTruncated {
+        level: LevelIdx,
+        from: usize,
+        to: usize,
+    },
+    LevelFinished {
+        level: LevelIdx,
+        users: usize,
+    },
+    MetadataFinished {
+        level: LevelIdx,
+    },
+}
+
+impl ProgressEvent {
+    /// Log to stderr, the default when there's no callback
+    pub fn log(&self) {
+        match self {
+            ProgressEvent::LevelStarted {
+                chunks, skipped, ..
+            } => {
+                eprintln!("add_level: Getting next level on network");
+                if *skipped > 0 {
+                    eprintln!("add_level: Skipping {skipped} users already in the network");
+                }
+                eprintln!("0/{chunks}");
+            }
+            ProgressEvent::MetadataStarted {
+                chunks, skipped, ..
+            } => {
+                eprintln!("add_metadata: Getting metadata");
+                if *skipped > 0 {
+                    eprintln!("add_metadata: Skipping {skipped} users already in the network");
+                }
+                eprintln!("0/{chunks}");
+            }
+            ProgressEvent::ChunkDone { done, total, .. } => eprintln!("{done}/{total}"),
+            ProgressEvent::LevelTruncated { level, from, to } => {
+                eprintln!("add_level: Keeping {to} of {from} users in level {level}")
+            }
+            ProgressEvent::LevelFinished { .. } => eprintln!("add_level: Finished"),
+            ProgressEvent::MetadataFinished { .. } => (),
+        }
+    }
+}
+
+/// Like "fetching the contact lists for level 2"
+impl Display for ProgressEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ProgressEvent::LevelStarted { level, .. } => {
+                write!(f, "fetching the contact lists for level {level}")
+            }
+            ProgressEvent::MetadataStarted { level, .. } => {
+                write!(f, "fetching the profiles of level {level}")
+            }
+            ProgressEvent::ChunkDone { level, done, total } => {
+                write!(f, "{done} of {total} chunks fetched for level {level}")
+            }
+            ProgressEvent::LevelTruncated { level, from, to } => {
+                write!(f, "level {level} cut down from {from} to {to} users")
+            }
+            ProgressEvent::LevelFinished { level, users } => {
+                write!(f, "level {level} added, with {users} users")
+            }
+            ProgressEvent::MetadataFinished { level } => {
+                write!(f, "profiles of level {level} fetched")
+            }
+        }
+    }
+}
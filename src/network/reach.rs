@@ -0,0 +1,173 @@
+/// Distances from a root user through follows, kept up to date as contact lists change
+use std::collections::{HashMap, VecDeque};
+
+use crate::network::*;
+use nostr_sdk::prelude::*;
+
+/// Deepest distance tracked from the root
+pub const ROOT_MAX_DEPTH: usize = 3;
+
+#[derive(Debug, Clone)]
+pub struct RootDistances {
+    root: PublicKey,
+    max_depth: usize,
+    distances: HashMap<PublicKey, usize>,
+}
+
+impl RootDistances {
+    pub fn new(root: PublicKey, max_depth: usize, network: &Network) -> RootDistances {
+        let mut distances = RootDistances {
+            root,
+            max_depth,
+            distances: HashMap::new(),
+        };
+        distances.recompute(network);
+        distances
+    }
+
+    pub fn root(&self) -> PublicKey {
+        self.root
+    }
+
+    /// None when the user is farther than the max depth, or not reachable
+    pub fn distance(&self, user: &PublicKey) -> Option<usize> {
+        self.distances.get(user).copied()
+    }
+
+    /// BFS from the root, throwing away the old distances
+    pub fn recompute(&mut self, network: &Network) {
+        self.distances.clear();
+        self.distances.insert(self.root, 0);
+        self.propagate(network, self.root);
+    }
+
+    /// Lower the distances of the users reachable from `start`, whose distance is already right
+    fn propagate(&mut self, network: &Network, start: PublicKey) {
+        let mut queue = VecDeque::from([start]);
+        while let Some(user) = queue.pop_front() {
+            let distance = self.distances[&user];
+            if distance >= self.max_depth {
+                continue;
+            }
+            for follow in network.get_user_contacts(&user) {
+                if self.distances.get(follow).is_none_or(|x| *x > distance + 1) {
+                    self.distances.insert(*follow, distance + 1);
+                    queue.push_back(*follow);
+                }
+            }
+        }
+    }
+
+    /// Called after `user` started following `follow`. Can only bring users closer
+    pub fn follow_added(&mut self, network: &Network, user: &PublicKey, follow: &PublicKey) {
+        let Some(distance) = self.distance(user) else {
+            return;
+        };
+        if distance < self.max_depth && self.distance(follow).is_none_or(|x| x > distance + 1) {
+            self.distances.insert(*follow, distance + 1);
+            self.propagate(network, *follow);
+        }
+    }
+
+    /// Called after `user` stopped following each of `follows`
+    ///
+    /// Distances only change if a follow had no other way as short as through `user`. Then
+    /// everything reachable from it may be farther, so it's all recomputed
+    pub fn follows_removed<'a>(
+        &mut self,
+        network: &Network,
+        user: &PublicKey,
+        follows: impl IntoIterator<Item = &'a PublicKey>,
+    ) {
+        let Some(distance) = self.distance(user) else {
+            return;
+        };
+        let dirty = follows.into_iter().any(|follow| {
+            self.distance(follow) == Some(distance + 1)
+                && !network
+                    .get_user_followers(follow)
+                    .into_iter()
+                    .any(|x| self.distance(&x) == Some(distance))
+        });
+        if dirty {
+            self.recompute(network);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    /// Distances of every user, from the network and from a BFS from scratch
+    fn both_distances(
+        network: &Network,
+        users: &[PublicKey],
+    ) -> (Vec<Option<usize>>, Vec<Option<usize>>) {
+        let fresh = RootDistances::new(users[0], ROOT_MAX_DEPTH, network);
+        users
+            .iter()
+            .map(|x| (network.distance_from_root(x), fresh.distance(x)))
+            .unzip()
+    }
+
+    #[test]
+    fn distances_follow_added_and_removed_follows() {
+        let users = pubkeys(6);
+        // A chain of follows 0 > 1 > 2 > 3 > 4 > 5
+        let mut network = network(
+            &users,
+            &[(0, &[1]), (1, &[2]), (2, &[3]), (3, &[4]), (4, &[5])],
+        );
+        network.set_root(Some(users[0]));
+        let distances = |network: &Network| both_distances(network, &users).0;
+        assert_eq!(
+            distances(&network),
+            [Some(0), Some(1), Some(2), Some(3), None, None]
+        );
+
+        // A shortcut brings the end of the chain closer
+        network.update_contact_list(users[1], &[users[2], users[4]], &Timestamp::from(2000));
+        assert_eq!(
+            distances(&network),
+            [Some(0), Some(1), Some(2), Some(3), Some(2), Some(3)]
+        );
+
+        // Without it, they're as far as before
+        network.update_contact_list(users[1], &[users[2]], &Timestamp::from(3000));
+        assert_eq!(
+            distances(&network),
+            [Some(0), Some(1), Some(2), Some(3), None, None]
+        );
+
+        // Following nobody, nobody is reached
+        network.update_contact_list(users[0], &[], &Timestamp::from(4000));
+        assert_eq!(distances(&network), [Some(0), None, None, None, None, None]);
+    }
+
+    #[test]
+    fn distances_match_a_bfs_after_every_change() {
+        let users = pubkeys(12);
+        let mut network = Network::new();
+        network.set_root(Some(users[0]));
+        // A linear congruential generator, so every run makes the same changes
+        let mut seed: u64 = 0x5eed;
+        let mut random = |below: usize| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) as usize % below
+        };
+        for step in 0..300 {
+            let user = users[random(users.len())];
+            let follows = (0..random(4))
+                .map(|_| users[random(users.len())])
+                .filter(|x| *x != user)
+                .collect::<Vec<_>>();
+            network.update_contact_list(user, &follows, &Timestamp::from(1000 + step));
+            let (kept, fresh) = both_distances(&network, &users);
+            assert_eq!(kept, fresh, "after step {step}");
+        }
+    }
+}
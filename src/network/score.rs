@@ -0,0 +1,144 @@
+/// How well connected a user is: the share of a sample of accounts, by default the most
+/// followed, that the user reaches in a few mutual hops
+use itertools::Itertools;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::{self, Display, Formatter};
+
+use crate::network::*;
+use nostr_sdk::prelude::*;
+
+/// Accounts in the sample of scores, when no list is configured
+pub const SCORE_SAMPLE: usize = 1000;
+/// Most mutual hops to reach an account of the sample
+pub const SCORE_DEPTH: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectivityScore {
+    /// Accounts of the sample reached
+    pub reached: usize,
+    /// Accounts in the sample, not counting the user
+    pub sample: usize,
+    /// Most mutual hops
+    pub depth: usize,
+}
+
+impl ConnectivityScore {
+    /// Share of the sample reached, from 0 to 100
+    pub fn percent(&self) -> f64 {
+        if self.sample == 0 {
+            return 0.0;
+        }
+        self.reached as f64 * 100.0 / self.sample as f64
+    }
+}
+
+/// Like "420 of 1000 accounts within 3 mutual hops"
+impl Display for ConnectivityScore {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} accounts within {} mutual hops",
+            self.reached, self.sample, self.depth
+        )
+    }
+}
+
+impl Network {
+    /// The `size` users with the most followers in the network, ties broken by public key so it
+    /// doesn't change between runs
+    pub fn reference_sample(&self, size: usize) -> Vec<PublicKey> {
+        let mut followers: HashMap<PublicKey, usize> = HashMap::new();
+        for (_, follow) in self.iter_follows() {
+            *followers.entry(follow).or_default() += 1;
+        }
+        followers
+            .into_iter()
+            .sorted_by_key(|(user, count)| (std::cmp::Reverse(*count), *user))
+            .take(size)
+            .map(|(user, _)| user)
+            .collect_vec()
+    }
+
+    /// Score of `user` against `sample`, with the mutuals already in the network. The user
+    /// doesn't count as part of the sample
+    pub fn connectivity_score(
+        &self,
+        user: &PublicKey,
+        sample: &[PublicKey],
+        depth: usize,
+    ) -> ConnectivityScore {
+        let mut targets: HashSet<&PublicKey> = sample.iter().filter(|x| *x != user).collect();
+        let size = targets.len();
+        // Breadth first through mutuals, stopping once every account of the sample is reached
+        let mut distances = HashMap::from([(*user, 0)]);
+        let mut queue = VecDeque::from([*user]);
+        while let Some(current) = queue.pop_front() {
+            if targets.is_empty() {
+                break;
+            }
+            let distance = distances[&current];
+            if distance >= depth {
+                continue;
+            }
+            for mutual in self.mutuals_of(&current) {
+                if !distances.contains_key(mutual) {
+                    distances.insert(*mutual, distance + 1);
+                    targets.remove(mutual);
+                    queue.push_back(*mutual);
+                }
+            }
+        }
+        ConnectivityScore {
+            reached: size - targets.len(),
+            sample: size,
+            depth,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::*;
+
+    #[test]
+    fn sample_is_the_most_followed_with_ties_by_key() {
+        let users = pubkeys(5);
+        let network = network(
+            &users,
+            &[
+                (0, &[3, 4]),
+                (1, &[3, 4, 2]),
+                (2, &[3]),
+                (3, &[1]),
+                (4, &[1]),
+            ],
+        );
+        assert_eq!(
+            network.reference_sample(2),
+            [users[3], users[1].min(users[4])]
+        );
+        assert_eq!(network.reference_sample(100).len(), 4);
+        assert_eq!(network.reference_sample(0), []);
+    }
+
+    #[test]
+    fn score_counts_the_sample_reached_within_the_depth() {
+        // Mutuals 0 - 1 - 2 - 3, and 4 only followed by 0
+        let users = pubkeys(5);
+        let network = network(
+            &users,
+            &[(0, &[1, 4]), (1, &[0, 2]), (2, &[1, 3]), (3, &[2])],
+        );
+        let sample = [users[0], users[2], users[3], users[4]];
+        let score = |depth| network.connectivity_score(&users[0], &sample, depth);
+        assert_eq!(score(1).reached, 0);
+        assert_eq!(score(2).reached, 1);
+        let full = score(3);
+        assert_eq!((full.reached, full.sample, full.depth), (2, 3, 3));
+        assert_eq!(full.to_string(), "2 of 3 accounts within 3 mutual hops");
+        assert!((full.percent() - 200.0 / 3.0).abs() < 1e-9);
+
+        let empty = network.connectivity_score(&users[0], &[users[0]], 3);
+        assert_eq!((empty.sample, empty.percent()), (0, 0.0));
+    }
+}
@@ -0,0 +1,126 @@
+/// How the follows of the network are spread among its users
+use itertools::Itertools;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+
+use crate::network::*;
+use nostr_sdk::prelude::*;
+
+/// Summary of the follows per user, in or out
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DegreeSummary {
+    pub min: usize,
+    pub max: usize,
+    pub mean: f64,
+    pub median: f64,
+}
+
+impl DegreeSummary {
+    fn of(degrees: &mut [usize]) -> DegreeSummary {
+        if degrees.is_empty() {
+            return DegreeSummary::default();
+        }
+        degrees.sort_unstable();
+        let len = degrees.len();
+        let median = if len.is_multiple_of(2) {
+            (degrees[len / 2 - 1] + degrees[len / 2]) as f64 / 2.0
+        } else {
+            degrees[len / 2] as f64
+        };
+        DegreeSummary {
+            min: degrees[0],
+            max: degrees[len - 1],
+            mean: degrees.iter().sum::<usize>() as f64 / len as f64,
+            median,
+        }
+    }
+}
+
+/// A user followed by many others
+#[derive(Debug, Clone, Serialize)]
+pub struct Hub {
+    pub npub: String,
+    pub name: Option<String>,
+    pub followers: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DegreeStats {
+    pub users: usize,
+    pub follows: usize,
+    /// Pairs of users following each other
+    pub mutuals: usize,
+    /// Users followed, per user
+    pub out_degree: DegreeSummary,
+    /// Followers, per user
+    pub in_degree: DegreeSummary,
+    /// Most followed users, most first
+    pub hubs: Vec<Hub>,
+}
+
+impl Display for DegreeStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} users, {} follows, {} mutual pairs",
+            self.users, self.follows, self.mutuals
+        )?;
+        for (label, summary) in [
+            ("Following", &self.out_degree),
+            ("Followers", &self.in_degree),
+        ] {
+            writeln!(
+                f,
+                "{label}: min {}, max {}, mean {:.1}, median {:.1}",
+                summary.min, summary.max, summary.mean, summary.median
+            )?;
+        }
+        for (i, hub) in self.hubs.iter().enumerate() {
+            writeln!(
+                f,
+                "{}. {} ({} followers)",
+                i + 1,
+                hub.name.as_deref().unwrap_or(&hub.npub),
+                hub.followers
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Network {
+    /// Follows out of and into every user, counting the users without any, and the `top` users
+    /// with the most followers. Mute list entries aren't counted
+    pub fn degree_stats(&self, top: usize) -> DegreeStats {
+        let mut out_degree: HashMap<&PublicKey, usize> =
+            self.iter_users().map(|x| (x, 0)).collect();
+        let mut in_degree = out_degree.clone();
+        let mut follows = 0;
+        for (user, follow) in self.iter_follows() {
+            follows += 1;
+            *out_degree.get_mut(&user).expect("Follow of unknown user") += 1;
+            *in_degree.get_mut(&follow).expect("Follow to unknown user") += 1;
+        }
+        let hubs = in_degree
+            .iter()
+            .filter(|(_, followers)| **followers > 0)
+            // Ties broken by pubkey, so the hubs don't change between runs
+            .sorted_by_key(|(user, followers)| (std::cmp::Reverse(**followers), **user))
+            .take(top)
+            .map(|(user, followers)| Hub {
+                npub: user.to_bech32().unwrap(),
+                name: self.name(user),
+                followers: *followers,
+            })
+            .collect_vec();
+        DegreeStats {
+            users: out_degree.len(),
+            follows,
+            mutuals: self.mutual_count(),
+            out_degree: DegreeSummary::of(&mut out_degree.into_values().collect_vec()),
+            in_degree: DegreeSummary::of(&mut in_degree.into_values().collect_vec()),
+            hubs,
+        }
+    }
+}
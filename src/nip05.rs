@@ -0,0 +1,148 @@
+/// NIP-05 identifiers, like jack@cash.app, that mentions give instead of npubs
+///
+/// An identifier is resolved by asking its domain for /.well-known/nostr.json. Answers are kept
+/// for a while, so asking about the same users again doesn't ask their domains again
+use regex::Regex;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+use nostr::nips::nip05;
+use nostr_sdk::prelude::*;
+
+/// A domain not answering in this long fails the resolution
+pub const NIP05_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a resolution is answered again without asking the domain
+pub const NIP05_TTL: Duration = Duration::from_secs(60 * 60);
+/// How long a failed resolution is answered again, so a domain that was down for a moment isn't
+/// taken as failing for long
+pub const NIP05_FAILURE_TTL: Duration = Duration::from_secs(60);
+
+/// Identifiers in `text` in the order they appear, lowercase, with their offset in it. The name
+/// can be left out for the root of the domain, as in _@domain
+pub fn nip05_identifiers(text: &str) -> Vec<(usize, String)> {
+    static NIP05_REGEX: OnceLock<Regex> = OnceLock::new();
+    let nip05_regex = NIP05_REGEX.get_or_init(|| {
+        Regex::new(r"\b[a-zA-Z0-9._-]+@[a-zA-Z0-9-]+(?:\.[a-zA-Z0-9-]+)*\.[a-zA-Z]{2,}\b").unwrap()
+    });
+    nip05_regex
+        .find_iter(text)
+        .map(|x| (x.start(), x.as_str().to_lowercase()))
+        .collect()
+}
+
+/// Resolution of each identifier, or why it failed, and when it was made
+type Nip05Cache = HashMap<String, (Result<PublicKey, String>, Instant)>;
+
+/// Resolutions of identifiers, kept for NIP05_TTL, or NIP05_FAILURE_TTL when they failed
+#[derive(Debug, Default)]
+pub struct Nip05Resolver {
+    cache: Mutex<Nip05Cache>,
+}
+
+/// Whether a resolution made at `resolved_at` is still answered
+fn is_fresh(result: &Result<PublicKey, String>, resolved_at: Instant) -> bool {
+    let ttl = match result {
+        Ok(_) => NIP05_TTL,
+        Err(_) => NIP05_FAILURE_TTL,
+    };
+    resolved_at.elapsed() < ttl
+}
+
+impl Nip05Resolver {
+    /// The public key of `identifier`, or why it wasn't found
+    pub async fn resolve(&self, identifier: &str) -> Result<PublicKey, String> {
+        self.resolve_with(identifier, || async {
+            match tokio::time::timeout(NIP05_TIMEOUT, nip05::profile(identifier, None)).await {
+                Ok(Ok(profile)) => Ok(profile.public_key),
+                Ok(Err(err)) => Err(err.to_string()),
+                Err(_) => Err("timed out".to_string()),
+            }
+        })
+        .await
+    }
+
+    /// The cached resolution of `identifier`, or the one of `fetch` when there's none
+    async fn resolve_with<F, Fut>(&self, identifier: &str, fetch: F) -> Result<PublicKey, String>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<PublicKey, String>>,
+    {
+        if let Some((result, resolved_at)) = self.cache.lock().await.get(identifier) {
+            if is_fresh(result, *resolved_at) {
+                return result.clone();
+            }
+        }
+        let result = fetch().await;
+        let mut cache = self.cache.lock().await;
+        cache.retain(|_, (result, resolved_at)| is_fresh(result, *resolved_at));
+        cache.insert(identifier.to_string(), (result.clone(), Instant::now()));
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn identifiers_are_found_where_they_are_written() {
+        let text = "how far is Jack@Cash.app from _@fiatjaf.com, or bob@sub.example.co.uk?";
+        assert_eq!(
+            nip05_identifiers(text),
+            [
+                (11, "jack@cash.app".to_string()),
+                (30, "_@fiatjaf.com".to_string()),
+                (48, "bob@sub.example.co.uk".to_string()),
+            ]
+        );
+        // Without a domain ending, or a name
+        assert!(nip05_identifiers("@jack, jack@localhost, @cash.app, a@b.c").is_empty());
+    }
+
+    #[tokio::test]
+    async fn answers_are_cached_and_failures_only_briefly() {
+        let resolver = Nip05Resolver::default();
+        let user = pubkeys(1)[0];
+        let fetches = AtomicUsize::new(0);
+        let resolve = |identifier: &'static str, result: Result<PublicKey, String>| {
+            let (resolver, fetches) = (&resolver, &fetches);
+            async move {
+                resolver
+                    .resolve_with(identifier, || async {
+                        fetches.fetch_add(1, Ordering::SeqCst);
+                        result
+                    })
+                    .await
+            }
+        };
+        let failure = || Err("timed out".to_string());
+
+        assert_eq!(resolve("jack@cash.app", Ok(user)).await, Ok(user));
+        assert_eq!(resolve("jack@cash.app", failure()).await, Ok(user));
+        assert_eq!(resolve("bob@down.example", failure()).await, failure());
+        assert_eq!(resolve("bob@down.example", Ok(user)).await, failure());
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+
+        // As if a minute went by, the failure is asked again and the answer isn't
+        let ago = |after: Duration| Instant::now().checked_sub(after).unwrap();
+        for (result, resolved_at) in resolver.cache.lock().await.values_mut() {
+            *resolved_at = ago(NIP05_FAILURE_TTL + Duration::from_secs(1));
+            assert_eq!(is_fresh(result, *resolved_at), result.is_ok());
+        }
+        assert_eq!(resolve("bob@down.example", Ok(user)).await, Ok(user));
+        assert_eq!(resolve("jack@cash.app", failure()).await, Ok(user));
+        assert_eq!(fetches.load(Ordering::SeqCst), 3);
+
+        // And an hour later, the answer is asked again too
+        for (_, resolved_at) in resolver.cache.lock().await.values_mut() {
+            *resolved_at = ago(NIP05_TTL + Duration::from_secs(1));
+        }
+        assert_eq!(resolve("jack@cash.app", failure()).await, failure());
+        assert_eq!(fetches.load(Ordering::SeqCst), 4);
+    }
+}
@@ -0,0 +1,467 @@
+/// Replies waiting to be published, sent one at a time so relays don't take the bot for a spammer
+///
+/// Replies are signed when queued and the queue is saved on every change, so the ones not sent
+/// before a restart are sent after it. A reply counts as sent once a relay accepts it, otherwise
+/// it's tried again later, waiting longer each time
+///
+/// Relays requiring more proof of work than a reply has aren't sent it, see pow
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+use crate::alerts::{AlertClass, Alerter};
+use crate::pow::{self, PowRequirements};
+use crate::supervisor::TaskContext;
+
+use async_utility::futures_util::future::join_all;
+use nostr_sdk::pool;
+use nostr_sdk::prelude::rand::Rng;
+use nostr_sdk::prelude::*;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct QueuedEvent {
+    event: Event,
+    attempts: u32,
+    /// Not published again before this, after being refused
+    #[serde(default)]
+    retry_at: Option<Timestamp>,
+    /// Why the relays refused the last attempt
+    #[serde(default)]
+    errors: Vec<String>,
+}
+
+/// Longest wait before publishing a refused reply again
+const MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+
+/// Send the event to each relay apart, with the reason of each one refusing it. The pool only
+/// tells that none accepted it, and adds its own text to the reasons of the relays
+async fn publish(relays: Vec<(Url, Relay)>, event: &Event) -> Output<EventId> {
+    let sends = relays.into_iter().map(|(url, relay)| async move {
+        let sent = relay
+            .send_event(event.clone(), RelaySendOptions::default())
+            .await;
+        (url, sent)
+    });
+    let mut output = Output {
+        val: event.id,
+        success: HashSet::new(),
+        failed: HashMap::new(),
+    };
+    for (url, sent) in join_all(sends).await {
+        match sent {
+            Ok(_) => {
+                output.success.insert(url);
+            }
+            Err(pool::relay::Error::EventNotPublished(reason)) => {
+                output.failed.insert(url, Some(reason));
+            }
+            Err(err) => {
+                output.failed.insert(url, Some(err.to_string()));
+            }
+        }
+    }
+    output
+}
+
+/// The reasons every relay refused the event, or None when one accepted it
+fn refusals(output: &Output<EventId>) -> Option<Vec<String>> {
+    if !output.success.is_empty() {
+        return None;
+    }
+    if output.failed.is_empty() {
+        return Some(vec!["no relay to send to".to_string()]);
+    }
+    Some(
+        output
+            .failed
+            .iter()
+            .map(|(url, err)| format!("{url}: {}", err.as_deref().unwrap_or("no reason")))
+            .collect(),
+    )
+}
+
+/// How fast the outbox is drained
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutboxPace {
+    /// Time between publishes, plus a random jitter of up to half of it
+    pub interval: Duration,
+    /// Publishes that no relay accepted are given up after this many
+    pub max_attempts: u32,
+}
+
+#[derive(Debug)]
+pub struct Outbox {
+    path: PathBuf,
+    queue: Mutex<VecDeque<QueuedEvent>>,
+    pow: PowRequirements,
+}
+
+impl Outbox {
+    /// Read the queue left by the last run, if any
+    pub fn load(path: PathBuf) -> Outbox {
+        let queue = match fs::read_to_string(&path) {
+            Ok(text) => match serde_json::from_str::<VecDeque<QueuedEvent>>(&text) {
+                Ok(queue) => {
+                    if !queue.is_empty() {
+                        println!("{} replies left to send in {}", queue.len(), path.display());
+                    }
+                    queue
+                }
+                Err(err) => {
+                    eprintln!(
+                        "Outbox {} parse error, starting empty: {}",
+                        path.display(),
+                        err
+                    );
+                    VecDeque::new()
+                }
+            },
+            Err(_err) => VecDeque::new(),
+        };
+        Outbox {
+            path,
+            queue: Mutex::new(queue),
+            pow: PowRequirements::default(),
+        }
+    }
+
+    /// Bits of proof of work replies need for every relay to accept them
+    pub fn pow_required(&self) -> u8 {
+        self.pow.required()
+    }
+
+    pub async fn push(&self, event: Event) {
+        let mut queue = self.queue.lock().await;
+        println!("Queued reply {}", event.id);
+        queue.push_back(QueuedEvent {
+            event,
+            attempts: 0,
+            retry_at: None,
+            errors: vec![],
+        });
+        self.save(&queue);
+    }
+
+    fn save(&self, queue: &VecDeque<QueuedEvent>) {
+        if let Err(err) = fs::write(&self.path, serde_json::to_string(queue).unwrap()) {
+            eprintln!("Outbox {} write error: {}", self.path.display(), err);
+        }
+    }
+
+    /// Publish the queued events at the outbox pace of the settings, until shutdown. The admin is
+    /// alerted of the replies no relay accepted
+    pub async fn run(
+        self: Arc<Self>,
+        client: Arc<Client>,
+        alerter: Arc<Alerter>,
+        mut context: TaskContext,
+    ) {
+        loop {
+            let jitter = rand::thread_rng().gen_range(1.0..1.5);
+            if !context
+                .sleep(|x| Some(x.outbox.interval.mul_f64(jitter)))
+                .await
+            {
+                return;
+            }
+            let pace = context.settings().outbox;
+
+            // Only this task takes from the queue, so the event stays at its index while publishing
+            let Some((index, queued)) = ({
+                let now = Timestamp::now();
+                let queue = self.queue.lock().await;
+                queue
+                    .iter()
+                    .find_position(|x| x.retry_at.is_none_or(|x| x <= now))
+                    .map(|(i, x)| (i, x.clone()))
+            }) else {
+                continue;
+            };
+            let id = queued.event.id;
+            self.pow.refresh(&client).await;
+            let refusing = self.pow.refusing(pow::event_difficulty(&queued.event));
+            let targets = client
+                .relays()
+                .await
+                .into_iter()
+                .filter(|(url, relay)| relay.flags().has_write() && !refusing.contains(url))
+                .collect_vec();
+            if !refusing.is_empty() {
+                eprintln!(
+                    "Sending reply {id} without {} relays requiring more proof of work",
+                    refusing.len()
+                );
+            }
+            let output = publish(targets, &queued.event).await;
+            for (url, err) in &output.failed {
+                let reason = err.as_deref().unwrap_or("no reason");
+                if self.pow.learn(url, reason).is_some() {
+                    eprintln!("Relay {url} requires proof of work for reply {id}: {reason}");
+                } else {
+                    eprintln!("Relay {url} refused reply {id}: {reason}");
+                }
+            }
+            let errors = refusals(&output);
+
+            let mut queue = self.queue.lock().await;
+            queue.remove(index);
+            match errors {
+                None => {
+                    println!("Sent event {id}");
+                    alerter.success(AlertClass::RepliesRefused).await;
+                }
+                Some(errors) if queued.attempts + 1 < pace.max_attempts => {
+                    let backoff = pace
+                        .interval
+                        .saturating_mul(2u32.saturating_pow(queued.attempts + 1))
+                        .min(MAX_BACKOFF);
+                    queue.push_back(QueuedEvent {
+                        attempts: queued.attempts + 1,
+                        retry_at: Some(Timestamp::now() + backoff),
+                        errors,
+                        ..queued
+                    });
+                }
+                Some(errors) => {
+                    let detail = format!(
+                        "reply {id} not accepted by any relay in {} attempts: {}",
+                        pace.max_attempts,
+                        errors.join(", ")
+                    );
+                    eprintln!("Reply {id} failed: {detail}");
+                    alerter.error(AlertClass::RepliesRefused, detail).await;
+                }
+            }
+            self.save(&queue);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::supervisor::{Supervisor, TaskSettings};
+    use crate::test_utils::*;
+    use std::time::Instant;
+
+    fn reply(text: &str) -> Event {
+        EventBuilder::text_note(text, [])
+            .to_event(&Keys::generate())
+            .unwrap()
+    }
+
+    fn settings(interval: Duration, max_attempts: u32) -> TaskSettings {
+        TaskSettings {
+            outbox: OutboxPace {
+                interval,
+                max_attempts,
+            },
+            watchdog: None,
+            prune_interval: None,
+            contact_list_ttl: None,
+            autosave_interval: None,
+        }
+    }
+
+    /// Run the outbox until `done` holds for the relays, or panic after `limit`
+    async fn run_until(
+        outbox: &Arc<Outbox>,
+        relays: &[&FakeRelay],
+        settings: TaskSettings,
+        limit: Duration,
+        done: impl Fn(&[&FakeRelay]) -> bool,
+    ) -> Duration {
+        let client = Arc::new(client(relays).await);
+        let mut supervisor = Supervisor::new(settings);
+        let running = outbox.clone();
+        supervisor.spawn("outbox", move |context| {
+            running.run(client, Arc::new(Alerter::disabled()), context)
+        });
+        let start = Instant::now();
+        while !done(relays) {
+            assert!(start.elapsed() < limit, "outbox still running");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let elapsed = start.elapsed();
+        supervisor.shutdown().await;
+        elapsed
+    }
+
+    #[tokio::test]
+    async fn unsent_replies_survive_a_restart() {
+        let path = temp_path("outbox-restart.json");
+        let replies = [reply("first"), reply("second")];
+        let outbox = Outbox::load(path.clone());
+        for event in replies.clone() {
+            outbox.push(event).await;
+        }
+
+        let loaded = Outbox::load(path.clone());
+        let ids = loaded
+            .queue
+            .lock()
+            .await
+            .iter()
+            .map(|x| x.event.id)
+            .collect_vec();
+        assert_eq!(ids, replies.iter().map(|x| x.id).collect_vec());
+
+        fs::write(&path, "not json").unwrap();
+        assert!(Outbox::load(path).queue.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn replies_are_sent_one_interval_apart() {
+        let relay = FakeRelay::start(vec![]).await;
+        let outbox = Arc::new(Outbox::load(temp_path("outbox-pace.json")));
+        let replies = [reply("first"), reply("second"), reply("third")];
+        for event in replies.clone() {
+            outbox.push(event).await;
+        }
+
+        let interval = Duration::from_millis(200);
+        let elapsed = run_until(
+            &outbox,
+            &[&relay],
+            settings(interval, 3),
+            Duration::from_secs(10),
+            |relays| relays[0].published().len() == 3,
+        )
+        .await;
+        assert!(elapsed >= interval * 3);
+        // In the order they were queued, each once
+        let published = relay.published().iter().map(|x| x.id).collect_vec();
+        assert_eq!(published, replies.iter().map(|x| x.id).collect_vec());
+        assert!(outbox.queue.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn refused_reply_is_retried_until_the_attempts_run_out() {
+        let relay = FakeRelay::start(vec![]).await;
+        relay.reject(Some("blocked: spam"));
+        let path = temp_path("outbox-retry.json");
+        let outbox = Arc::new(Outbox::load(path.clone()));
+        let event = reply("refused");
+        outbox.push(event.clone()).await;
+
+        let interval = Duration::from_millis(100);
+        run_until(
+            &outbox,
+            &[&relay],
+            settings(interval, 2),
+            Duration::from_secs(10),
+            |relays| relays[0].published().len() == 2,
+        )
+        .await;
+        assert!(relay.published().iter().all(|x| x.id == event.id));
+        // Given up, also in the saved queue
+        assert!(outbox.queue.lock().await.is_empty());
+        assert!(Outbox::load(path).queue.lock().await.is_empty());
+    }
+
+    #[test]
+    fn refusals_are_the_reasons_of_every_relay_when_none_accepted() {
+        let (first, second) = (
+            Url::parse("wss://first.example").unwrap(),
+            Url::parse("wss://second.example").unwrap(),
+        );
+        let output = |success: &[&Url], failed: &[(&Url, Option<&str>)]| Output {
+            val: EventId::all_zeros(),
+            success: success.iter().map(|x| (*x).clone()).collect(),
+            failed: failed
+                .iter()
+                .map(|(url, err)| ((*url).clone(), err.map(|x| x.to_string())))
+                .collect(),
+        };
+
+        assert_eq!(refusals(&output(&[&first, &second], &[])), None);
+        assert_eq!(
+            refusals(&output(&[&first], &[(&second, Some("blocked: spam"))])),
+            None
+        );
+        assert_eq!(
+            refusals(&output(&[], &[])),
+            Some(vec!["no relay to send to".to_string()])
+        );
+        let mut refused = refusals(&output(
+            &[],
+            &[(&first, Some("pow: 20 bits needed")), (&second, None)],
+        ))
+        .unwrap();
+        refused.sort();
+        assert_eq!(
+            refused,
+            [
+                "wss://first.example/: pow: 20 bits needed",
+                "wss://second.example/: no reason"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reply_accepted_by_one_relay_is_sent() {
+        let (accepting, refusing) = (
+            FakeRelay::start(vec![]).await,
+            FakeRelay::start(vec![]).await,
+        );
+        refusing.reject(Some("blocked: spam"));
+        let outbox = Arc::new(Outbox::load(temp_path("outbox-partial.json")));
+        let event = reply("partly refused");
+        outbox.push(event.clone()).await;
+
+        let interval = Duration::from_millis(100);
+        run_until(
+            &outbox,
+            &[&accepting, &refusing],
+            settings(interval, 3),
+            Duration::from_secs(10),
+            |_| outbox.queue.try_lock().is_ok_and(|x| x.is_empty()),
+        )
+        .await;
+        // Not retried for the relay that refused it
+        tokio::time::sleep(interval * 4).await;
+        assert_eq!(accepting.published().len(), 1);
+        assert_eq!(refusing.published().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reply_refused_by_every_relay_keeps_their_reasons() {
+        let (first, second) = (
+            FakeRelay::start(vec![]).await,
+            FakeRelay::start(vec![]).await,
+        );
+        first.reject(Some("blocked: spam"));
+        second.reject(Some("rate-limited: slow down"));
+        let outbox = Arc::new(Outbox::load(temp_path("outbox-refused.json")));
+        outbox.push(reply("refused everywhere")).await;
+
+        run_until(
+            &outbox,
+            &[&first, &second],
+            settings(Duration::from_millis(100), 3),
+            Duration::from_secs(10),
+            |_| {
+                outbox
+                    .queue
+                    .try_lock()
+                    .is_ok_and(|x| x.front().is_some_and(|x| x.attempts == 1))
+            },
+        )
+        .await;
+        let queue = outbox.queue.lock().await;
+        let mut expected = [
+            format!("{}: blocked: spam", first.url),
+            format!("{}: rate-limited: slow down", second.url),
+        ];
+        expected.sort();
+        let mut errors = queue[0].errors.clone();
+        errors.sort();
+        assert_eq!(errors, expected);
+        assert!(queue[0].retry_at.is_some());
+    }
+}
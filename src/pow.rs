@@ -0,0 +1,228 @@
+/// Proof of work (NIP-13) on replies, for relays that refuse events without it
+///
+/// Relays tell the difficulty they require in their NIP-11 document, or in the "pow:" reason of
+/// the OK message refusing an event. Replies are mined up to a configured difficulty and time.
+/// When a relay requires more, they're published only to the relays that don't
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use nostr_sdk::prelude::*;
+
+/// Hashes tried between checks of the time budget, as reading the clock is slower than hashing
+const HASHES_PER_CHECK: u128 = 4096;
+
+/// How much work goes into replies
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PowLimits {
+    /// Most leading zero bits mined. 0 never mines
+    pub max_difficulty: u8,
+    /// Mining is given up after this long
+    pub budget: Duration,
+}
+
+/// Difficulty asked for by the reason of a "pow:" refusal, like "pow: difficulty 20 is less than
+/// 28". Relays word it differently, so it's the biggest number in it
+pub fn difficulty_from_reason(reason: &str) -> Option<u8> {
+    reason
+        .strip_prefix("pow:")?
+        .split(|c: char| !c.is_ascii_digit())
+        .filter_map(|x| x.parse::<u8>().ok())
+        .max()
+}
+
+/// Difficulty required by the NIP-11 document of a relay, None when it requires none
+pub fn difficulty_from_document(document: &RelayInformationDocument) -> Option<u8> {
+    let difficulty = document.limitation.as_ref()?.min_pow_difficulty?;
+    (difficulty > 0).then(|| difficulty.min(u8::MAX as i32) as u8)
+}
+
+/// Leading zero bits of the id of `event`
+pub fn event_difficulty(event: &Event) -> u8 {
+    nip13::get_leading_zero_bits(event.id)
+}
+
+/// Difficulty each relay requires, from their NIP-11 documents and their refusals
+#[derive(Debug, Default)]
+pub struct PowRequirements {
+    relays: Mutex<HashMap<Url, u8>>,
+}
+
+impl PowRequirements {
+    /// Read the difficulty in the NIP-11 documents of the relays of `client`, which are fetched on
+    /// connection. A refusal asking for more is kept
+    pub async fn refresh(&self, client: &Client) {
+        let mut documents = vec![];
+        for (url, relay) in client.relays().await {
+            if let Some(difficulty) = difficulty_from_document(&relay.document().await) {
+                documents.push((url, difficulty));
+            }
+        }
+        for (url, difficulty) in documents {
+            self.raise(url, difficulty);
+        }
+    }
+
+    /// Remember the difficulty `url` asked for when refusing an event with `reason`
+    pub fn learn(&self, url: &Url, reason: &str) -> Option<u8> {
+        let difficulty = difficulty_from_reason(reason)?;
+        self.raise(url.clone(), difficulty);
+        Some(difficulty)
+    }
+
+    fn raise(&self, url: Url, difficulty: u8) {
+        let mut relays = self.relays.lock().unwrap();
+        let known = relays.entry(url.clone()).or_default();
+        if difficulty > *known {
+            eprintln!("Relay {url} requires {difficulty} bits of proof of work");
+            *known = difficulty;
+        }
+    }
+
+    /// Most difficulty any relay requires, 0 when none does
+    pub fn required(&self) -> u8 {
+        let relays = self.relays.lock().unwrap();
+        relays.values().copied().max().unwrap_or_default()
+    }
+
+    /// Relays requiring more than `difficulty`, which refuse an event with only that much work
+    pub fn refusing(&self, difficulty: u8) -> Vec<Url> {
+        let relays = self.relays.lock().unwrap();
+        relays
+            .iter()
+            .filter(|(_, required)| **required > difficulty)
+            .map(|(url, _)| url.clone())
+            .collect()
+    }
+}
+
+/// `unsigned` with a nonce tag giving its id `difficulty` leading zero bits, or None when finding
+/// it took longer than `budget`. Blocks for up to `budget`, keep it out of the async runtime
+pub fn mine(
+    mut unsigned: UnsignedEvent,
+    difficulty: u8,
+    budget: Duration,
+) -> Option<UnsignedEvent> {
+    let start = Instant::now();
+    let mut tags = unsigned.tags.clone();
+    let mut nonce: u128 = 0;
+    loop {
+        nonce += 1;
+        if nonce.is_multiple_of(HASHES_PER_CHECK) && start.elapsed() > budget {
+            return None;
+        }
+        tags.push(Tag::pow(nonce, difficulty));
+        let id = EventId::new(
+            &unsigned.pubkey,
+            &unsigned.created_at,
+            &unsigned.kind,
+            &tags,
+            &unsigned.content,
+        );
+        if id.check_pow(difficulty) {
+            unsigned.id = Some(id);
+            unsigned.tags = tags;
+            return Some(unsigned);
+        }
+        tags.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_utils::EventFactory;
+    use crate::test_utils::*;
+
+    fn url(name: &str) -> Url {
+        Url::parse(&format!("wss://{name}.example")).unwrap()
+    }
+
+    #[test]
+    fn difficulty_is_read_from_refusals_and_documents() {
+        for (reason, difficulty) in [
+            ("pow: difficulty 20 is less than 28", Some(28)),
+            ("pow: 16 bits needed", Some(16)),
+            ("pow:24", Some(24)),
+            ("pow: required", None),
+            ("pow: 999", None),
+            ("blocked: pow 20", None),
+            ("rate-limited: 30 per minute", None),
+        ] {
+            assert_eq!(difficulty_from_reason(reason), difficulty, "{reason}");
+        }
+
+        let document = |min_pow_difficulty| RelayInformationDocument {
+            limitation: Some(Limitation {
+                min_pow_difficulty,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert_eq!(difficulty_from_document(&document(Some(12))), Some(12));
+        assert_eq!(
+            difficulty_from_document(&document(Some(300))),
+            Some(u8::MAX)
+        );
+        assert_eq!(difficulty_from_document(&document(Some(0))), None);
+        assert_eq!(difficulty_from_document(&document(None)), None);
+        assert_eq!(
+            difficulty_from_document(&RelayInformationDocument::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn requirements_keep_the_most_each_relay_asked_for() {
+        let requirements = PowRequirements::default();
+        assert_eq!(requirements.required(), 0);
+        assert_eq!(requirements.learn(&url("one"), "pow: 20 bits"), Some(20));
+        assert_eq!(requirements.learn(&url("one"), "pow: 10 bits"), Some(10));
+        assert_eq!(requirements.learn(&url("two"), "blocked: spam"), None);
+        requirements.learn(&url("two"), "pow: 8");
+        assert_eq!(requirements.required(), 20);
+        assert_eq!(requirements.refusing(8), [url("one")]);
+        assert!(requirements.refusing(20).is_empty());
+        let mut refusing = requirements.refusing(0);
+        refusing.sort();
+        assert_eq!(refusing, [url("one"), url("two")]);
+    }
+
+    #[test]
+    fn mined_event_has_the_difficulty_and_is_signed_as_is() {
+        let keys = keys(1);
+        let unsigned = EventBuilder::text_note("reply", []).to_unsigned_event(keys[0].public_key());
+        let mined = mine(unsigned, 8, Duration::from_secs(30)).unwrap();
+        let event = mined.sign(&keys[0]).unwrap();
+        assert!(event.verify().is_ok());
+        assert!(event_difficulty(&event) >= 8);
+        assert!(event.tags.iter().any(|x| x.kind() == TagKind::Nonce));
+
+        // Out of time long before 64 bits
+        let unsigned = EventBuilder::text_note("reply", []).to_unsigned_event(keys[0].public_key());
+        assert!(mine(unsigned, 64, Duration::from_millis(50)).is_none());
+    }
+
+    #[tokio::test]
+    async fn replies_are_mined_only_within_the_limits() {
+        let relay = FakeRelay::start(vec![]).await;
+        let client = client(&[&relay]).await;
+        let factory = EventFactory {
+            pow: PowLimits {
+                max_difficulty: 10,
+                budget: Duration::from_secs(30),
+            },
+            ..Default::default()
+        };
+        let note = || factory.note("hello");
+
+        let mined = factory.sign(&client, note(), 6).await.unwrap();
+        assert!(event_difficulty(&mined) >= 6);
+        let none = factory.sign(&client, note(), 0).await.unwrap();
+        assert!(none.tags.iter().all(|x| x.kind() != TagKind::Nonce));
+        // Over the most, signed without
+        let over = factory.sign(&client, note(), 11).await.unwrap();
+        assert!(over.tags.iter().all(|x| x.kind() != TagKind::Nonce));
+        assert!(over.verify().is_ok());
+    }
+}
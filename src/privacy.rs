@@ -0,0 +1,73 @@
+/// Helpers to keep mention contents out of logs and stored state
+use regex::Regex;
+
+/// Raw content shown in debug logs is cut at this many characters
+pub const MAX_LOGGED_CONTENT: usize = 100;
+
+/// Replace bech32 keys and profiles in the text, so neither secrets nor identities leak into logs
+pub fn redact_keys(content: &str) -> String {
+    let key_regex = Regex::new(r"(nsec|ncryptsec|npub|nprofile)1[a-zA-Z0-9]+").unwrap();
+    key_regex
+        .replace_all(content, |caps: &regex::Captures| {
+            format!("[{} redacted]", &caps[1])
+        })
+        .into_owned()
+}
+
+/// Cut the text at `max` characters, marking when it was cut
+pub fn truncate_chars(content: &str, max: usize) -> String {
+    if content.chars().count() <= max {
+        return content.to_string();
+    }
+    let mut cut: String = content.chars().take(max).collect();
+    cut.push('…');
+    cut
+}
+
+/// The form in which raw mention content may be logged, when content logging is enabled
+pub fn loggable_content(content: &str) -> String {
+    truncate_chars(&redact_keys(content), MAX_LOGGED_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use nostr_sdk::prelude::*;
+
+    #[test]
+    fn keys_are_redacted() {
+        let keys = &keys(1)[0];
+        let nsec = keys.secret_key().unwrap().to_bech32().unwrap();
+        let npub = keys.public_key().to_bech32().unwrap();
+        assert_eq!(
+            redact_keys(&format!("my key is {nsec}, oops")),
+            "my key is [nsec redacted], oops"
+        );
+        assert_eq!(
+            redact_keys(&format!("how far is nostr:{npub} from {npub}?")),
+            "how far is nostr:[npub redacted] from [npub redacted]?"
+        );
+        let nprofile = Nip19Profile::new(keys.public_key(), ["wss://relay.damus.io"])
+            .unwrap()
+            .to_bech32()
+            .unwrap();
+        assert_eq!(redact_keys(&nprofile), "[nprofile redacted]");
+        assert_eq!(redact_keys("no keys here"), "no keys here");
+    }
+
+    #[test]
+    fn long_content_is_cut() {
+        assert_eq!(truncate_chars("short", 10), "short");
+        assert_eq!(truncate_chars("exactly 10", 10), "exactly 10");
+        assert_eq!(truncate_chars("ãããããããããããã", 10), "ãããããããããã…");
+    }
+
+    #[test]
+    fn keys_at_the_cut_are_redacted_whole() {
+        let nsec = keys(1)[0].secret_key().unwrap().to_bech32().unwrap();
+        let logged = loggable_content(&format!("{}{nsec}", "a".repeat(90)));
+        assert!(!logged.contains("nsec1"));
+        assert_eq!(logged.chars().count(), MAX_LOGGED_CONTENT + 1);
+    }
+}
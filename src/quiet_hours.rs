@@ -0,0 +1,302 @@
+/// Hours of the day when the bot doesn't send anything on its own, like alerts to the admin.
+/// Replies to mentions still go out
+///
+/// Times are on the wall clock of a time zone, with its DST rules: the local one of the machine,
+/// one configured by name, or a fixed UTC offset
+use chrono::{DateTime, FixedOffset, Local, Timelike};
+use chrono_tz::Tz;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use nostr_sdk::prelude::*;
+
+/// A window like 23:00-08:00, which may cross midnight
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuietHours {
+    /// Minutes since midnight the window starts, included
+    start: u32,
+    /// Minutes since midnight the window ends, excluded
+    end: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QuietHoursError {
+    Window(String),
+    Offset(String),
+    TimeZone(String),
+    /// Both an offset and a time zone are configured
+    TwoClocks,
+}
+
+impl Display for QuietHoursError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            QuietHoursError::Window(x) => write!(f, "invalid quiet hours {x}: use HH:MM-HH:MM"),
+            QuietHoursError::Offset(x) => {
+                write!(f, "invalid quiet hours offset {x}: use +HH:MM or -HH:MM")
+            }
+            QuietHoursError::TimeZone(x) => write!(
+                f,
+                "unknown quiet hours time zone {x}: use a name like America/Sao_Paulo"
+            ),
+            QuietHoursError::TwoClocks => {
+                write!(f, "quiet hours take either a UTC offset or a time zone")
+            }
+        }
+    }
+}
+
+impl std::error::Error for QuietHoursError {}
+
+/// Minutes since midnight of HH:MM
+fn parse_time(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.trim().split_once(':')?;
+    if minutes.len() != 2 {
+        return None;
+    }
+    let (hours, minutes) = (hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?);
+    (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
+
+impl FromStr for QuietHours {
+    type Err = QuietHoursError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || QuietHoursError::Window(s.to_string());
+        let (start, end) = s.split_once('-').ok_or_else(error)?;
+        let start = parse_time(start).ok_or_else(error)?;
+        let end = parse_time(end).ok_or_else(error)?;
+        if start == end {
+            return Err(error());
+        }
+        Ok(QuietHours { start, end })
+    }
+}
+
+impl Display for QuietHours {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+impl QuietHours {
+    /// Whether the minute of the day is in the window
+    pub fn contains(&self, minute: u32) -> bool {
+        if self.start < self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+
+    /// Whether it's quiet at the time, in the given clock
+    pub fn is_quiet(&self, time: Timestamp, clock: Clock) -> bool {
+        self.contains(clock.minute_of_day(time))
+    }
+}
+
+/// Which time of day the quiet hours are in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Clock {
+    /// The local time of the machine
+    #[default]
+    Local,
+    /// Minutes ahead of UTC, behind when negative
+    UtcOffset(i32),
+    /// A time zone, with its DST rules
+    Zone(Tz),
+}
+
+impl Clock {
+    /// Clock from an offset like -03:00 or +05:30, or a time zone like America/Sao_Paulo. Local
+    /// time without either, and both can't be given
+    pub fn from_config(
+        offset: Option<&str>,
+        time_zone: Option<&str>,
+    ) -> Result<Clock, QuietHoursError> {
+        match (offset, time_zone) {
+            (None, None) => Ok(Clock::Local),
+            (Some(offset), None) => Clock::from_offset(offset),
+            (None, Some(zone)) => zone
+                .parse::<Tz>()
+                .map(Clock::Zone)
+                .map_err(|_| QuietHoursError::TimeZone(zone.to_string())),
+            (Some(_), Some(_)) => Err(QuietHoursError::TwoClocks),
+        }
+    }
+
+    fn from_offset(offset: &str) -> Result<Clock, QuietHoursError> {
+        let error = || QuietHoursError::Offset(offset.to_string());
+        let (sign, time) = if let Some(time) = offset.strip_prefix('+') {
+            (1, time)
+        } else if let Some(time) = offset.strip_prefix('-') {
+            (-1, time)
+        } else {
+            return Err(error());
+        };
+        let minutes = parse_time(time).ok_or_else(error)?;
+        Ok(Clock::UtcOffset(sign * minutes as i32))
+    }
+
+    /// Minutes since midnight on the wall clock at the time. Across a DST change the wall clock
+    /// skips or repeats an hour, and so does the window: it starts at the first minute after a
+    /// skipped start, and holds in both passes of a repeated hour
+    pub fn minute_of_day(&self, time: Timestamp) -> u32 {
+        let Some(utc) = DateTime::from_timestamp(time.as_u64() as i64, 0) else {
+            return 0;
+        };
+        let (hour, minute) = match self {
+            Clock::Local => {
+                let local = utc.with_timezone(&Local);
+                (local.hour(), local.minute())
+            }
+            Clock::UtcOffset(minutes) => match FixedOffset::east_opt(minutes * 60) {
+                Some(offset) => {
+                    let fixed = utc.with_timezone(&offset);
+                    (fixed.hour(), fixed.minute())
+                }
+                None => (utc.hour(), utc.minute()),
+            },
+            Clock::Zone(zone) => {
+                let zoned = utc.with_timezone(zone);
+                (zoned.hour(), zoned.minute())
+            }
+        };
+        hour * 60 + minute
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Timestamp of a UTC time
+    fn utc(time: &str) -> Timestamp {
+        let time = DateTime::parse_from_rfc3339(time).unwrap();
+        Timestamp::from(time.timestamp() as u64)
+    }
+
+    fn new_york() -> Clock {
+        Clock::from_config(None, Some("America/New_York")).unwrap()
+    }
+
+    #[test]
+    fn parses_windows_offsets_and_time_zones() {
+        let hours = "23:00-08:30".parse::<QuietHours>().unwrap();
+        assert_eq!(hours.to_string(), "23:00-08:30");
+        for invalid in [
+            "23:00",
+            "24:00-08:00",
+            "23:60-08:00",
+            "23:0-08:00",
+            "08:00-08:00",
+        ] {
+            assert_eq!(
+                invalid.parse::<QuietHours>(),
+                Err(QuietHoursError::Window(invalid.to_string()))
+            );
+        }
+
+        assert_eq!(Clock::from_config(None, None), Ok(Clock::Local));
+        assert_eq!(
+            Clock::from_config(Some("-03:00"), None),
+            Ok(Clock::UtcOffset(-180))
+        );
+        assert_eq!(
+            Clock::from_config(Some("+05:30"), None),
+            Ok(Clock::UtcOffset(330))
+        );
+        assert_eq!(
+            Clock::from_config(Some("03:00"), None),
+            Err(QuietHoursError::Offset("03:00".to_string()))
+        );
+        assert_eq!(new_york(), Clock::Zone(Tz::America__New_York));
+        assert_eq!(
+            Clock::from_config(None, Some("Mars/Olympus")),
+            Err(QuietHoursError::TimeZone("Mars/Olympus".to_string()))
+        );
+        assert_eq!(
+            Clock::from_config(Some("-05:00"), Some("America/New_York")),
+            Err(QuietHoursError::TwoClocks)
+        );
+    }
+
+    #[test]
+    fn window_includes_its_start_and_excludes_its_end() {
+        let overnight = "23:00-08:00".parse::<QuietHours>().unwrap();
+        let daytime = "09:00-17:00".parse::<QuietHours>().unwrap();
+        for (minute, in_overnight, in_daytime) in [
+            (22 * 60 + 59, false, false),
+            (23 * 60, true, false),
+            (0, true, false),
+            (8 * 60 - 1, true, false),
+            (8 * 60, false, false),
+            (9 * 60, false, true),
+            (17 * 60 - 1, false, true),
+            (17 * 60, false, false),
+        ] {
+            assert_eq!(overnight.contains(minute), in_overnight, "{minute}");
+            assert_eq!(daytime.contains(minute), in_daytime, "{minute}");
+        }
+    }
+
+    #[test]
+    fn offset_clock_shifts_the_utc_time() {
+        let hours = "23:00-08:00".parse::<QuietHours>().unwrap();
+        let clock = Clock::UtcOffset(-180);
+        // 01:59 and 02:00 UTC are 22:59 and 23:00 at -03:00
+        assert!(!hours.is_quiet(utc("2024-06-01T01:59:00Z"), clock));
+        assert!(hours.is_quiet(utc("2024-06-01T02:00:00Z"), clock));
+        assert!(hours.is_quiet(utc("2024-06-01T10:59:00Z"), clock));
+        assert!(!hours.is_quiet(utc("2024-06-01T11:00:00Z"), clock));
+    }
+
+    #[test]
+    fn time_zone_follows_the_dst_changes() {
+        let hours = "23:00-08:00".parse::<QuietHours>().unwrap();
+        // 03:30 UTC is 22:30 in winter and 23:30 in summer
+        assert!(!hours.is_quiet(utc("2024-01-15T03:30:00Z"), new_york()));
+        assert!(hours.is_quiet(utc("2024-07-15T03:30:00Z"), new_york()));
+        // The fixed winter offset would still be out of the window in summer
+        assert!(!hours.is_quiet(utc("2024-07-15T03:30:00Z"), Clock::UtcOffset(-300)));
+        // The window ends at 08:00 of the wall clock on both sides of the change
+        assert!(hours.is_quiet(utc("2024-03-09T12:59:00Z"), new_york()));
+        assert!(!hours.is_quiet(utc("2024-03-09T13:00:00Z"), new_york()));
+        assert!(hours.is_quiet(utc("2024-03-10T11:59:00Z"), new_york()));
+        assert!(!hours.is_quiet(utc("2024-03-10T12:00:00Z"), new_york()));
+    }
+
+    #[test]
+    fn window_edges_in_a_skipped_or_repeated_hour() {
+        // On 2024-03-10 New York skips from 02:00 EST to 03:00 EDT, at 07:00 UTC
+        let skipped_start = "02:30-06:00".parse::<QuietHours>().unwrap();
+        assert!(!skipped_start.is_quiet(utc("2024-03-10T06:59:00Z"), new_york()));
+        assert!(skipped_start.is_quiet(utc("2024-03-10T07:00:00Z"), new_york()));
+        let skipped_end = "22:00-02:30".parse::<QuietHours>().unwrap();
+        assert!(skipped_end.is_quiet(utc("2024-03-10T06:59:00Z"), new_york()));
+        assert!(!skipped_end.is_quiet(utc("2024-03-10T07:00:00Z"), new_york()));
+
+        // On 2024-11-03 it goes back from 02:00 EDT to 01:00 EST, at 06:00 UTC
+        let repeated_end = "22:00-01:30".parse::<QuietHours>().unwrap();
+        for (time, quiet) in [
+            ("2024-11-03T05:29:00Z", true),
+            ("2024-11-03T05:30:00Z", false),
+            ("2024-11-03T06:00:00Z", true),
+            ("2024-11-03T06:29:00Z", true),
+            ("2024-11-03T06:30:00Z", false),
+        ] {
+            assert_eq!(
+                repeated_end.is_quiet(utc(time), new_york()),
+                quiet,
+                "{time}"
+            );
+        }
+    }
+}
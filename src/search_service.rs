@@ -0,0 +1,600 @@
+/// Searches shared by every frontend, so the CLI, the listener, the leaderboard and the self test
+/// answer with the same verification, caching and options
+///
+/// Searches for the same pair of users made while one is running wait for it, and take its
+/// answer
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{watch, Mutex};
+
+use crate::external_graph::ExternalGraph;
+use crate::history::{History, HistoryRecord, Outcome};
+use crate::network::follow::{
+    FollowNetwork, LevelIdx, RankConfig, RankReasons, RecommendationError,
+};
+use crate::network::progress::ProgressEvent;
+use crate::network::score::{ConnectivityScore, SCORE_DEPTH, SCORE_SAMPLE};
+use crate::network::{Network, PathMode};
+use crate::nip05::Nip05Resolver;
+use crate::sep_degrees::{
+    closest_member, connectivity_score, from_pubkeys, Degree, SearchOptions, SearchStats,
+    SepDegreeError,
+};
+use crate::user::User;
+
+use nostr_sdk::prelude::*;
+
+pub struct SeparationRequest {
+    pub from: PublicKey,
+    pub to: PublicKey,
+    /// Replace the options of the service for this search
+    pub options: Option<SearchOptions>,
+    /// The mention asking for it, recorded in the history
+    pub request_id: Option<EventId>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SeparationResponse {
+    pub result: Result<(Degree, Vec<PublicKey>), SepDegreeError>,
+    pub stats: SearchStats,
+    /// The answer was found by an earlier search
+    pub cached: bool,
+}
+
+/// How long connectivity scores are answered again without computing them
+pub const SCORE_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Most users in each level crawled for a recommendation, see FollowNetwork::set_max_level_size
+pub const RECOMMEND_LEVEL_SIZE: usize = 300;
+/// A recommendation is given up after this long
+pub const RECOMMEND_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+/// Users recommended at once
+pub const RECOMMEND_COUNT: usize = 5;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ScoreResponse {
+    pub score: ConnectivityScore,
+    /// Share of the other users scored in the last SCORE_TTL with a lower score, from 0 to 100.
+    /// None when no one else was
+    pub percentile: Option<f64>,
+    /// The score was computed by an earlier request
+    pub cached: bool,
+}
+
+#[derive(Debug, Clone)]
+struct CachedPath {
+    found_at: Instant,
+    degree: Degree,
+    /// From the first user of the pair key to the second
+    path: Vec<PublicKey>,
+    stats: SearchStats,
+}
+
+type PairKey = (PublicKey, PublicKey);
+
+/// The same key for both directions of a pair
+fn pair_key(a: PublicKey, b: PublicKey) -> PairKey {
+    if a <= b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+/// A search running for a pair of users
+#[derive(Clone)]
+struct InFlight {
+    from: PublicKey,
+    options: SearchOptions,
+    /// Closed without an answer when the search is dropped
+    answer: watch::Receiver<Option<SeparationResponse>>,
+}
+
+/// Takes the search of a pair out of the ones in flight however it ends
+struct InFlightGuard<'a> {
+    in_flight: &'a std::sync::Mutex<HashMap<PairKey, InFlight>>,
+    key: PairKey,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.in_flight.lock().unwrap().remove(&self.key);
+    }
+}
+
+pub struct SearchService {
+    client: Arc<Client>,
+    network: Arc<Mutex<Network>>,
+    options: SearchOptions,
+    /// How long found paths are answered from the cache, None disables it
+    cache_ttl: Option<Duration>,
+    cache: Mutex<HashMap<PairKey, CachedPath>>,
+    /// A std mutex, as it's unlocked when a search is dropped, see InFlightGuard
+    in_flight: std::sync::Mutex<HashMap<PairKey, InFlight>>,
+    history: Option<History>,
+    external: Option<ExternalGraph>,
+    scores: Mutex<HashMap<PublicKey, (ConnectivityScore, Instant)>>,
+    /// Sample of the scores, the most followed users of the network when empty
+    score_reference: Vec<PublicKey>,
+    /// Last step of the running recommendation of each user. A std mutex, as it's locked by the
+    /// progress callback of the crawl, which isn't async
+    recommendations: Arc<std::sync::Mutex<HashMap<PublicKey, ProgressEvent>>>,
+    nip05: Nip05Resolver,
+}
+
+impl SearchService {
+    pub fn new(
+        client: Arc<Client>,
+        network: Arc<Mutex<Network>>,
+        options: SearchOptions,
+        cache_ttl: Option<Duration>,
+    ) -> SearchService {
+        SearchService {
+            client,
+            network,
+            options,
+            cache_ttl,
+            cache: Mutex::new(HashMap::new()),
+            in_flight: std::sync::Mutex::new(HashMap::new()),
+            history: None,
+            external: None,
+            scores: Mutex::new(HashMap::new()),
+            score_reference: vec![],
+            recommendations: Arc::default(),
+            nip05: Nip05Resolver::default(),
+        }
+    }
+
+    /// Record the searches with a request id
+    pub fn with_history(self, history: History) -> SearchService {
+        SearchService {
+            history: Some(history),
+            ..self
+        }
+    }
+
+    /// Ask `external` for the contact lists relays don't have
+    pub fn with_external_graph(self, external: Option<ExternalGraph>) -> SearchService {
+        SearchService { external, ..self }
+    }
+
+    /// Score users against `reference` instead of the most followed users of the network
+    pub fn with_score_reference(self, reference: Vec<PublicKey>) -> SearchService {
+        SearchService {
+            score_reference: reference,
+            ..self
+        }
+    }
+
+    pub fn client(&self) -> &Arc<Client> {
+        &self.client
+    }
+
+    pub fn network(&self) -> &Arc<Mutex<Network>> {
+        &self.network
+    }
+
+    pub fn options(&self) -> SearchOptions {
+        self.options
+    }
+
+    /// Degrees of separation and a verified path between the users of the request
+    pub async fn separation(&self, request: SeparationRequest) -> SeparationResponse {
+        let response = self.search(&request).await;
+        if let (Some(history), Some(id)) = (&self.history, request.request_id) {
+            history.append(&HistoryRecord {
+                request: id,
+                at: Timestamp::now(),
+                from: request.from,
+                to: request.to,
+                options: request.options.unwrap_or(self.options),
+                relays: self.client.relays().await.into_keys().collect(),
+                cache_saved_at: history.cache_saved_at(),
+                outcome: Outcome::new(&response.result, &response.stats),
+            });
+        }
+        response
+    }
+
+    async fn search(&self, request: &SeparationRequest) -> SeparationResponse {
+        if let Some(response) = self.cached(request).await {
+            return response;
+        }
+        let key = pair_key(request.from, request.to);
+        let options = request.options.unwrap_or(self.options);
+        let (answer, _running) = loop {
+            let running = {
+                let mut in_flight = self.in_flight.lock().unwrap();
+                match in_flight.get(&key) {
+                    // A search dropped before answering may not have been taken out yet
+                    Some(running) if running.answer.has_changed().is_ok() => running.clone(),
+                    _ => {
+                        let (sender, receiver) = watch::channel(None);
+                        let running = InFlight {
+                            from: request.from,
+                            options,
+                            answer: receiver,
+                        };
+                        in_flight.insert(key, running);
+                        let guard = InFlightGuard {
+                            in_flight: &self.in_flight,
+                            key,
+                        };
+                        break (sender, guard);
+                    }
+                }
+            };
+            // Only the answer of the same search is taken, where mutual paths go both ways
+            if running.options != options
+                || (running.from != request.from && options.path_mode != PathMode::Mutual)
+            {
+                return self.run(request, options).await;
+            }
+            let mut receiver = running.answer;
+            let Ok(Some(mut response)) =
+                receiver.wait_for(Option::is_some).await.map(|x| x.clone())
+            else {
+                continue;
+            };
+            if running.from != request.from {
+                if let Ok((_, path)) = &mut response.result {
+                    path.reverse();
+                }
+            }
+            response.cached = true;
+            return response;
+        };
+
+        // A search may have found the path since the cache was checked
+        let response = match self.cached(request).await {
+            Some(response) => response,
+            None => self.run(request, options).await,
+        };
+        answer.send_replace(Some(response.clone()));
+        response
+    }
+
+    async fn run(&self, request: &SeparationRequest, options: SearchOptions) -> SeparationResponse {
+        let mut stats = SearchStats::default();
+        let result = from_pubkeys(
+            request.from,
+            request.to,
+            &self.client,
+            &self.network,
+            options,
+            self.external.as_ref(),
+            &mut stats,
+        )
+        .await;
+        // Only mutual paths are cached, directed ones don't go both ways, so they'd answer the
+        // pair wrongly, and paths of other modes would answer mutual searches
+        if let (Some(ttl), Ok((degree, path)), PathMode::Mutual) =
+            (self.cache_ttl, &result, options.path_mode)
+        {
+            let key = pair_key(request.from, request.to);
+            let mut path = path.clone();
+            if key.0 != request.from {
+                path.reverse();
+            }
+            let mut cache = self.cache.lock().await;
+            cache.retain(|_, x| x.found_at.elapsed() < ttl);
+            cache.insert(
+                key,
+                CachedPath {
+                    found_at: Instant::now(),
+                    degree: *degree,
+                    path,
+                    stats: stats.clone(),
+                },
+            );
+        }
+
+        SeparationResponse {
+            result,
+            stats,
+            cached: false,
+        }
+    }
+
+    async fn cached(&self, request: &SeparationRequest) -> Option<SeparationResponse> {
+        let ttl = self.cache_ttl?;
+        let options = request.options.unwrap_or(self.options);
+        if options.path_mode != PathMode::Mutual {
+            return None;
+        }
+        let key = pair_key(request.from, request.to);
+        let found = self.cache.lock().await.get(&key).cloned()?;
+        if found.found_at.elapsed() >= ttl || found.degree > options.max_depth() {
+            return None;
+        }
+        let mut path = found.path;
+        if key.0 != request.from {
+            path.reverse();
+        }
+        Some(SeparationResponse {
+            result: Ok((found.degree, path)),
+            stats: found.stats,
+            cached: true,
+        })
+    }
+
+    /// Connectivity score of `user`, see sep_degrees::connectivity_score. Scores are kept for
+    /// SCORE_TTL
+    pub async fn score(&self, user: PublicKey) -> Result<ScoreResponse, SepDegreeError> {
+        let cached = {
+            let mut scores = self.scores.lock().await;
+            scores.retain(|_, (_, at)| at.elapsed() < SCORE_TTL);
+            scores.get(&user).map(|(score, _)| *score)
+        };
+        let score = match cached {
+            Some(score) => score,
+            None => {
+                let sample = if self.score_reference.is_empty() {
+                    self.network.lock().await.reference_sample(SCORE_SAMPLE)
+                } else {
+                    self.score_reference.clone()
+                };
+                let score = connectivity_score(
+                    &self.client,
+                    &self.network,
+                    user,
+                    &sample,
+                    SCORE_DEPTH,
+                    self.options,
+                )
+                .await?;
+                self.scores
+                    .lock()
+                    .await
+                    .insert(user, (score, Instant::now()));
+                score
+            }
+        };
+        let scores = self.scores.lock().await;
+        let others = scores
+            .iter()
+            .filter(|(other, _)| **other != user)
+            .map(|(_, (score, _))| score.percent())
+            .collect::<Vec<_>>();
+        let percentile = (!others.is_empty()).then(|| {
+            let lower = others.iter().filter(|x| **x < score.percent()).count();
+            lower as f64 * 100.0 / others.len() as f64
+        });
+        Ok(ScoreResponse {
+            score,
+            percentile,
+            cached: cached.is_some(),
+        })
+    }
+
+    /// The RECOMMEND_COUNT best ranked users for `user` to follow, best first, from a crawl of
+    /// levels capped to RECOMMEND_LEVEL_SIZE users and given up after RECOMMEND_TIMEOUT
+    pub async fn recommend(
+        &self,
+        user: PublicKey,
+    ) -> Result<Vec<(PublicKey, i32, Vec<RankReasons>)>, RecommendationError> {
+        let ranks = match tokio::time::timeout(RECOMMEND_TIMEOUT, self.rank_for(user)).await {
+            Ok(ranks) => ranks,
+            Err(_) => Err(RecommendationError::TimedOut),
+        };
+        self.recommendations.lock().unwrap().remove(&user);
+        ranks
+    }
+
+    /// Public keys of the NIP-05 `identifiers`, in their order
+    pub async fn resolve_nip05(
+        &self,
+        identifiers: &[String],
+    ) -> Result<Vec<PublicKey>, SepDegreeError> {
+        let mut users = vec![];
+        for identifier in identifiers {
+            match self.nip05.resolve(identifier).await {
+                Ok(user) => users.push(user),
+                Err(err) => {
+                    eprintln!("NIP-05 {identifier} resolution error: {err}");
+                    return Err(SepDegreeError::Nip05ResolutionFailed(identifier.clone()));
+                }
+            }
+        }
+        Ok(users)
+    }
+
+    /// How far along the running recommendation for `user` is, None when there's none
+    pub fn recommend_progress(&self, user: &PublicKey) -> Option<ProgressEvent> {
+        self.recommendations.lock().unwrap().get(user).copied()
+    }
+
+    /// Recommendations running now
+    pub fn running_recommendations(&self) -> usize {
+        self.recommendations.lock().unwrap().len()
+    }
+
+    async fn rank_for(
+        &self,
+        user: PublicKey,
+    ) -> Result<Vec<(PublicKey, i32, Vec<RankReasons>)>, RecommendationError> {
+        let center = user;
+        let user = User::new(user, &self.client)
+            .await
+            .map_err(|err| RecommendationError::Client(err.to_string()))?;
+        let config = RankConfig {
+            max_level_size: Some(RECOMMEND_LEVEL_SIZE),
+            max_results: Some(RECOMMEND_COUNT),
+            ..Default::default()
+        };
+        // Their follows, the follows of those, and the contact lists of the candidates, from the
+        // network as far as it knows them
+        let depth = LevelIdx(3);
+        let mut user_network = FollowNetwork::from_network(
+            user,
+            self.client.clone(),
+            self.network.clone(),
+            self.options.timeouts,
+            &config,
+            self.options.contact_list_ttl,
+            depth,
+        )
+        .await
+        .map_err(|err| RecommendationError::Client(err.to_string()))?;
+        let recommendations = self.recommendations.clone();
+        user_network.set_progress(Some(Box::new(move |event| {
+            recommendations.lock().unwrap().insert(center, event);
+        })));
+        while user_network.level(depth).is_none() {
+            user_network
+                .add_level()
+                .await
+                .map_err(|err| RecommendationError::Client(err.to_string()))?;
+        }
+        user_network
+            .add_mute_list()
+            .await
+            .map_err(|err| RecommendationError::Client(err.to_string()))?;
+        // Ranks are sorted from the worst
+        let mut ranks = user_network.generate_user_ranks(&config).await?;
+        ranks.reverse();
+        Ok(ranks)
+    }
+
+    /// The member of `members` closest to `user`, see sep_degrees::closest_member
+    pub async fn closest_member(
+        &self,
+        user: PublicKey,
+        members: &[PublicKey],
+    ) -> Result<(Degree, Vec<PublicKey>), SepDegreeError> {
+        let mut stats = SearchStats::default();
+        closest_member(
+            user,
+            members,
+            &self.client,
+            &self.network,
+            self.options,
+            self.external.as_ref(),
+            &mut stats,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use itertools::Itertools;
+
+    async fn service(relay: &FakeRelay) -> SearchService {
+        SearchService::new(
+            Arc::new(client(&[relay]).await),
+            Arc::new(Mutex::new(Network::new())),
+            SearchOptions::default(),
+            None,
+        )
+    }
+
+    fn request(from: PublicKey, to: PublicKey) -> SeparationRequest {
+        SeparationRequest {
+            from,
+            to,
+            options: None,
+            request_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn search_waiting_for_another_takes_its_answer_without_the_cache() {
+        let keys = keys(4);
+        let users = keys.iter().map(|x| x.public_key()).collect_vec();
+
+        let alone = FakeRelay::start(chain(&keys)).await;
+        alone.delay(users[3], Duration::from_millis(300));
+        let response = service(&alone)
+            .await
+            .separation(request(users[0], users[3]))
+            .await;
+        assert_eq!(response.result.unwrap().1, users);
+
+        let relay = FakeRelay::start(chain(&keys)).await;
+        relay.delay(users[3], Duration::from_millis(300));
+        let service = service(&relay).await;
+        let (first, second) = tokio::join!(
+            service.separation(request(users[0], users[3])),
+            service.separation(request(users[3], users[0])),
+        );
+        let (first, second) = if first.cached {
+            (second, first)
+        } else {
+            (first, second)
+        };
+        assert!(!first.cached && second.cached);
+        let (degree, path) = first.result.unwrap();
+        let (other_degree, mut other_path) = second.result.unwrap();
+        other_path.reverse();
+        assert_eq!((degree, path), (other_degree, other_path));
+        // The relay was asked as much as for a single search
+        assert_eq!(relay.queries().len(), alone.queries().len());
+        assert!(service.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn dropped_search_isnt_waited_for() {
+        let keys = keys(4);
+        let users = keys.iter().map(|x| x.public_key()).collect_vec();
+        let relay = FakeRelay::start(chain(&keys)).await;
+        relay.delay(users[3], Duration::from_secs(30));
+        let service = service(&relay).await;
+
+        let dropped = tokio::time::timeout(
+            Duration::from_millis(300),
+            service.separation(request(users[0], users[3])),
+        )
+        .await;
+        assert!(dropped.is_err());
+        assert!(service.in_flight.lock().unwrap().is_empty());
+
+        relay.delay(users[3], Duration::ZERO);
+        let response = tokio::time::timeout(
+            Duration::from_secs(10),
+            service.separation(request(users[0], users[3])),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.result.unwrap().1, users);
+    }
+
+    #[tokio::test]
+    async fn scores_are_kept_and_ranked_against_the_others() {
+        // The chain 0 - 1 - 2 - 3, 4 follows nobody and 5 has no contact list
+        let keys = keys(6);
+        let users = keys.iter().map(|x| x.public_key()).collect_vec();
+        let mut events = chain(&keys[..4]);
+        events.push(contact_list(&keys[4], &[], 1000));
+        let relay = FakeRelay::start(events).await;
+        let service = service(&relay)
+            .await
+            .with_score_reference(vec![users[1], users[2], users[3], users[5]]);
+
+        let first = service.score(users[0]).await.unwrap();
+        assert_eq!(
+            (first.score.reached, first.score.sample, first.score.depth),
+            (3, 4, SCORE_DEPTH)
+        );
+        assert_eq!((first.percentile, first.cached), (None, false));
+
+        // 3 isn't part of its own sample
+        let last = service.score(users[3]).await.unwrap();
+        assert_eq!((last.score.reached, last.score.sample), (2, 3));
+        assert_eq!(last.percentile, Some(0.0));
+
+        let queries = relay.queries().len();
+        let again = service.score(users[0]).await.unwrap();
+        assert_eq!(again.score, first.score);
+        assert_eq!((again.percentile, again.cached), (Some(100.0), true));
+        assert_eq!(relay.queries().len(), queries);
+
+        assert!(matches!(
+            service.score(users[4]).await,
+            Err(SepDegreeError::EmptyContactList(_))
+        ));
+    }
+}
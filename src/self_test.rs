@@ -0,0 +1,299 @@
+/// Search between two known users, to monitor that answers didn't drift
+///
+/// The config is a TOML file with the reference query:
+/// ```toml
+/// from = "npub1..."
+/// to = "npub1..."
+/// min_degree = 1
+/// max_degree = 3
+/// budget_secs = 120
+/// ```
+use serde::Deserialize;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::time::{Duration, Instant};
+
+use crate::search_service::{SearchService, SeparationRequest, SeparationResponse};
+use crate::sep_degrees::{Degree, SearchOptions};
+
+use nostr_sdk::prelude::*;
+
+#[derive(Debug, Deserialize)]
+pub struct SelfTestConfig {
+    pub from: String,
+    pub to: String,
+    pub min_degree: Degree,
+    pub max_degree: Degree,
+    #[serde(default = "default_budget_secs")]
+    pub budget_secs: u64,
+}
+
+fn default_budget_secs() -> u64 {
+    120
+}
+
+#[derive(Debug)]
+pub enum SelfTestConfigError {
+    ConfigMissing(std::io::Error),
+    ConfigParse(toml::de::Error),
+    InvalidPubkey(String),
+}
+
+impl Display for SelfTestConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SelfTestConfigError::ConfigMissing(error) => {
+                write!(f, "Self test config missing: {}", error)
+            }
+            SelfTestConfigError::ConfigParse(error) => {
+                write!(f, "Self test config parse error:\n{}", error)
+            }
+            SelfTestConfigError::InvalidPubkey(key) => write!(f, "Invalid public key {key}"),
+        }
+    }
+}
+
+impl std::error::Error for SelfTestConfigError {}
+
+pub fn load_config(path: &str) -> Result<SelfTestConfig, SelfTestConfigError> {
+    let text = fs::read_to_string(path).map_err(SelfTestConfigError::ConfigMissing)?;
+    let config: SelfTestConfig = toml::from_str(&text).map_err(SelfTestConfigError::ConfigParse)?;
+    for key in [&config.from, &config.to] {
+        if PublicKey::parse(key).is_err() {
+            return Err(SelfTestConfigError::InvalidPubkey(key.clone()));
+        }
+    }
+    Ok(config)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SelfTestOutcome {
+    Ok,
+    SearchError(String),
+    OverBudget,
+    OutOfRange,
+}
+
+/// What happened in a self test, printed as a single line
+#[derive(Debug, Clone)]
+pub struct SelfTestReport {
+    pub outcome: SelfTestOutcome,
+    pub degree: Option<Degree>,
+    pub expected: (Degree, Degree),
+    pub duration: Duration,
+    pub relays_connected: usize,
+    pub relays: usize,
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.outcome == SelfTestOutcome::Ok
+    }
+}
+
+impl Display for SelfTestReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let status = match &self.outcome {
+            SelfTestOutcome::Ok => "ok".to_string(),
+            SelfTestOutcome::SearchError(error) => format!("fail error=\"{error}\""),
+            SelfTestOutcome::OverBudget => "fail over_budget".to_string(),
+            SelfTestOutcome::OutOfRange => "fail out_of_range".to_string(),
+        };
+        write!(
+            f,
+            "{} degree={} expected={}..={} duration={:.1}s relays={}/{}",
+            status,
+            self.degree.map_or("none".to_string(), |x| x.to_string()),
+            self.expected.0,
+            self.expected.1,
+            self.duration.as_secs_f64(),
+            self.relays_connected,
+            self.relays
+        )
+    }
+}
+
+/// Run the reference search, stopping it once the budget is over. Nothing is published
+pub async fn run(config: &SelfTestConfig, service: &SearchService) -> SelfTestReport {
+    // Validated when loading
+    let from = PublicKey::parse(&config.from).unwrap();
+    let to = PublicKey::parse(&config.to).unwrap();
+
+    let start = Instant::now();
+    let budget = Duration::from_secs(config.budget_secs);
+    // The search splits the budget among its fetches, the timeout still catches what's left over
+    let options = service.options();
+    let request = SeparationRequest {
+        from,
+        to,
+        options: Some(SearchOptions {
+            budget: Some(options.budget.map_or(budget, |x| x.min(budget))),
+            ..options
+        }),
+        request_id: None,
+    };
+    let res = tokio::time::timeout(budget, service.separation(request)).await;
+    let duration = start.elapsed();
+
+    let (outcome, degree) = match res {
+        Err(_elapsed) => (SelfTestOutcome::OverBudget, None),
+        Ok(SeparationResponse {
+            result: Err(err), ..
+        }) => (SelfTestOutcome::SearchError(err.to_string()), None),
+        Ok(SeparationResponse {
+            result: Ok((degree, _)),
+            ..
+        }) if degree < config.min_degree || degree > config.max_degree => {
+            (SelfTestOutcome::OutOfRange, Some(degree))
+        }
+        Ok(SeparationResponse {
+            result: Ok((degree, _)),
+            ..
+        }) => (SelfTestOutcome::Ok, Some(degree)),
+    };
+
+    let relays = service.client().relays().await;
+    let mut relays_connected = 0;
+    for relay in relays.values() {
+        if relay.is_connected().await {
+            relays_connected += 1;
+        }
+    }
+
+    SelfTestReport {
+        outcome,
+        degree,
+        expected: (config.min_degree, config.max_degree),
+        duration,
+        relays_connected,
+        relays: relays.len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Network;
+    use crate::test_utils::*;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    fn config(users: &[PublicKey], from: usize, to: usize, range: (u32, u32)) -> SelfTestConfig {
+        SelfTestConfig {
+            from: users[from].to_bech32().unwrap(),
+            to: users[to].to_bech32().unwrap(),
+            min_degree: Degree(range.0),
+            max_degree: Degree(range.1),
+            budget_secs: 10,
+        }
+    }
+
+    async fn run_against(relay: &FakeRelay, config: &SelfTestConfig) -> SelfTestReport {
+        let service = SearchService::new(
+            Arc::new(client(&[relay]).await),
+            Arc::new(Mutex::new(Network::new())),
+            SearchOptions::default(),
+            None,
+        );
+        run(config, &service).await
+    }
+
+    #[tokio::test]
+    async fn self_test_checks_the_degree_range() {
+        // A chain of mutuals 0 - 1 - 2, and 3 without a contact list
+        let keys = keys(4);
+        let users = keys.iter().map(|x| x.public_key()).collect::<Vec<_>>();
+        let relay = FakeRelay::start(contact_lists(&keys, &[&[1], &[0, 2], &[1]])).await;
+
+        let report = run_against(&relay, &config(&users, 0, 2, (1, 2))).await;
+        assert_eq!(
+            (report.outcome.clone(), report.degree),
+            (SelfTestOutcome::Ok, Some(Degree(2)))
+        );
+        assert!(report.passed());
+        assert_eq!((report.relays_connected, report.relays), (1, 1));
+
+        let report = run_against(&relay, &config(&users, 0, 2, (3, 4))).await;
+        assert_eq!(
+            (report.outcome.clone(), report.degree),
+            (SelfTestOutcome::OutOfRange, Some(Degree(2)))
+        );
+        assert!(!report.passed());
+
+        let report = run_against(&relay, &config(&users, 3, 2, (1, 2))).await;
+        assert!(matches!(report.outcome, SelfTestOutcome::SearchError(_)));
+        assert_eq!(report.degree, None);
+
+        assert!(relay.published().is_empty());
+    }
+
+    #[tokio::test]
+    async fn self_test_fails_over_budget() {
+        let keys = keys(3);
+        let users = keys.iter().map(|x| x.public_key()).collect::<Vec<_>>();
+        let relay = FakeRelay::start(contact_lists(&keys, &[&[1], &[0, 2], &[1]])).await;
+        relay.delay(users[0], Duration::from_secs(3));
+
+        let config = SelfTestConfig {
+            budget_secs: 1,
+            ..config(&users, 0, 2, (1, 2))
+        };
+        let report = run_against(&relay, &config).await;
+        assert_eq!(
+            (report.outcome.clone(), report.degree),
+            (SelfTestOutcome::OverBudget, None)
+        );
+        assert!(report.duration < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn report_is_a_single_line() {
+        let report = SelfTestReport {
+            outcome: SelfTestOutcome::SearchError("Relay error".to_string()),
+            degree: None,
+            expected: (Degree(1), Degree(3)),
+            duration: Duration::from_millis(2540),
+            relays_connected: 4,
+            relays: 5,
+        };
+        assert_eq!(
+            report.to_string(),
+            "fail error=\"Relay error\" degree=none expected=1..=3 duration=2.5s relays=4/5"
+        );
+        let report = SelfTestReport {
+            outcome: SelfTestOutcome::Ok,
+            degree: Some(Degree(2)),
+            ..report
+        };
+        assert_eq!(
+            report.to_string(),
+            "ok degree=2 expected=1..=3 duration=2.5s relays=4/5"
+        );
+    }
+
+    #[test]
+    fn config_with_an_invalid_key_is_refused() {
+        let users = pubkeys(1);
+        let path = temp_path("self_test.toml");
+        let text = format!(
+            "from = \"{}\"\nto = \"npub1nope\"\nmin_degree = 1\nmax_degree = 3\n",
+            users[0].to_bech32().unwrap()
+        );
+        fs::write(&path, text).unwrap();
+        assert!(matches!(
+            load_config(path.to_str().unwrap()),
+            Err(SelfTestConfigError::InvalidPubkey(key)) if key == "npub1nope"
+        ));
+
+        let text = format!(
+            "from = \"{0}\"\nto = \"{0}\"\nmin_degree = 1\nmax_degree = 3\n",
+            users[0].to_bech32().unwrap()
+        );
+        fs::write(&path, text).unwrap();
+        let config = load_config(path.to_str().unwrap()).unwrap();
+        assert_eq!(
+            (config.min_degree, config.max_degree, config.budget_secs),
+            (Degree(1), Degree(3), 120)
+        );
+    }
+}
@@ -1,23 +1,41 @@
 /// Algorithms used in the find degrees of separation functionality
+use async_utility::futures_util::stream::{FuturesUnordered, StreamExt};
 use itertools::Itertools;
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
 
 use crate::client_utils::{self, *};
+use crate::external_graph::ExternalGraph;
 use crate::map_intersect;
-use crate::network::Network;
+use crate::network::diff::ContactListDelta;
+use crate::network::filters::SkipCounts;
+use crate::network::names::NameError;
+use crate::network::score::ConnectivityScore;
+use crate::network::{ContactListState, ContactListUpdate, Network, PathMode};
+use crate::search_service::{SearchService, SeparationRequest, SeparationResponse};
 
 use nostr_sdk::prelude::*;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum SepDegreeError {
     TooFewArguments,
     TooMuchArguments,
-    NostrClientError(nostr_sdk::client::Error),
-    NotFound,
-    MissingContactList(PublicKey),
+    /// In an Arc, so the answer of a search can be given to the searches waiting for it
+    NostrClientError(Arc<nostr_sdk::client::Error>),
+    NotFound(SearchFrontier),
+    /// Contact list of the user not found in any of the relays checked
+    MissingContactList(PublicKey, Vec<Url>),
+    /// The contact list of the user was found, and follows nobody
+    EmptyContactList(PublicKey),
+    /// A name given instead of an npub didn't match a single user
+    Name(NameError),
+    /// Every path found had a broken hop, in this many searches
+    VerificationFailed(usize),
+    /// The domain of the NIP-05 identifier didn't tell its public key
+    Nip05ResolutionFailed(String),
 }
 
 impl std::fmt::Display for SepDegreeError {
@@ -26,76 +44,460 @@ impl std::fmt::Display for SepDegreeError {
             SepDegreeError::TooFewArguments => write!(f, "Too few arguments"),
             SepDegreeError::TooMuchArguments => write!(f, "Too much arguments"),
             SepDegreeError::NostrClientError(error) => write!(f, "{}", error),
-            SepDegreeError::NotFound => write!(f, "Separation not found"),
-            SepDegreeError::MissingContactList(public_key) => {
+            SepDegreeError::NotFound(_) => write!(f, "Separation not found"),
+            SepDegreeError::MissingContactList(public_key, _) => {
                 write!(
                     f,
                     "Missing contact list of {}",
                     public_key.to_bech32().unwrap()
                 )
             }
+            SepDegreeError::EmptyContactList(public_key) => {
+                write!(f, "{} follows nobody", public_key.to_bech32().unwrap())
+            }
+            SepDegreeError::Name(error) => write!(f, "{}", error),
+            SepDegreeError::VerificationFailed(searches) => {
+                write!(f, "No path verified in {searches} searches")
+            }
+            SepDegreeError::Nip05ResolutionFailed(identifier) => {
+                write!(f, "Couldn't resolve the NIP-05 identifier {identifier}")
+            }
         }
     }
 }
 
 impl std::error::Error for SepDegreeError {}
 
-pub async fn main(vals: impl IntoIterator<Item = &str>, client: &Client, network: &Mutex<Network>) {
+impl SepDegreeError {
+    /// What went wrong, without the users or identifiers involved, to be logged
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SepDegreeError::TooFewArguments => "too few arguments",
+            SepDegreeError::TooMuchArguments => "too much arguments",
+            SepDegreeError::NostrClientError(_) => "client error",
+            SepDegreeError::NotFound(_) => "not found",
+            SepDegreeError::MissingContactList(..) => "missing contact list",
+            SepDegreeError::EmptyContactList(_) => "empty contact list",
+            SepDegreeError::Name(_) => "name not resolved",
+            SepDegreeError::VerificationFailed(_) => "verification failed",
+            SepDegreeError::Nip05ResolutionFailed(_) => "NIP-05 not resolved",
+        }
+    }
+}
+
+/// Degrees of separation, the hops between mutuals in a path. A path of n users has n - 1
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Degree(pub u32);
+
+impl Degree {
+    pub fn of_path(path: &[PublicKey]) -> Degree {
+        Degree(path.len().saturating_sub(1) as u32)
+    }
+
+    pub fn next(self) -> Degree {
+        Degree(self.0 + 1)
+    }
+}
+
+impl std::fmt::Display for Degree {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Why a search stopped without finding a path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// Reached the most hops searched
+    DepthLimit(Degree),
+    /// One of the sides had no more mutuals to go through
+    Exhausted,
+    /// The time budget of the search ran out
+    BudgetSpent,
+    /// Only the network was searched, without asking relays
+    Offline,
+}
+
+/// How far a search that didn't find a path got, from each side
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchFrontier {
+    /// Users reached through mutuals from each target, including it
+    pub explored: (usize, usize),
+    /// Users to fetch the contact lists of next, on each side
+    pub border: (usize, usize),
+    /// Users whose contact list wasn't found in the relays
+    pub missing_contact_lists: usize,
+    pub stop: StopReason,
+    /// No chain of mutuals in the network joins the targets, so searching deeper wouldn't help
+    /// unless more contact lists are found
+    pub disconnected: bool,
+}
+
+impl SearchFrontier {
+    /// Summary of the search for who asked it
+    pub fn explain(&self) -> String {
+        let stop = match self.stop {
+            StopReason::DepthLimit(hops) => format!("The search stopped at {hops} hops"),
+            StopReason::Exhausted => {
+                "The search ran out of mutuals to go through on one side".to_string()
+            }
+            StopReason::BudgetSpent => "The search ran out of time".to_string(),
+            StopReason::Offline => "Only the cached network was searched".to_string(),
+        };
+        let stop = if self.disconnected {
+            format!("{stop}, and the users are in disconnected parts of the known network")
+        } else {
+            stop
+        };
+        format!(
+            "Reached {} users from the first and {} from the second through mutuals, with {} and {} more left to check. {} contact lists were missing. {}",
+            self.explored.0,
+            self.explored.1,
+            self.border.0,
+            self.border.1,
+            self.missing_contact_lists,
+            stop
+        )
+    }
+}
+
+/// Work done by a search
+#[derive(Debug, Clone, Default)]
+pub struct SearchStats {
+    /// Contact lists received from relays
+    pub profiles_scanned: usize,
+    /// Least number of relays that returned a contact list of the path, when corroboration is checked
+    pub corroborating_relays: Option<usize>,
+    /// The path was returned by fewer relays than required
+    pub low_confidence: bool,
+    /// Users whose contact list came from the external graph, as no relay had it
+    pub external_contact_lists: HashSet<PublicKey>,
+    /// Some contact list of the path came from the external graph
+    pub external_in_path: bool,
+    /// Users not gone through, see Network::set_search_filters
+    pub skipped: SkipCounts,
+}
+
+impl SearchStats {
+    /// Flag the answer when its path goes through contact lists of the external graph
+    fn check_external(&mut self, path: &[PublicKey]) {
+        // The last user's contact list isn't needed for the path
+        self.external_in_path = path
+            .iter()
+            .take(path.len().saturating_sub(1))
+            .any(|x| self.external_contact_lists.contains(x));
+    }
+
+    fn report_skipped(&self) {
+        if self.skipped.total() > 0 {
+            eprintln!("Skipped {} users: {}", self.skipped.total(), self.skipped);
+        }
+    }
+}
+
+/// Contact lists fetched longer ago than this are fetched again, unless configured otherwise
+pub const DEFAULT_CONTACT_LIST_TTL_SECS: u64 = 24 * 60 * 60;
+
+/// Contact list max age from seconds, where 0 means they never get stale
+pub fn contact_list_ttl_from_secs(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// Search budget from seconds, where 0 means no budget
+pub fn search_budget_from_secs(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// Settings of a search
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct SearchOptions {
+    pub timeouts: QueryTimeouts,
+    /// Every contact list in the path must be returned by this many relays, or the answer is
+    /// marked as low confidence. 0 skips the check
+    pub min_corroborating_relays: usize,
+    /// Where the users to search are read from in mentions
+    pub target_precedence: TargetPrecedence,
+    /// Contact lists in the network fetched longer ago are fetched again. None keeps them forever
+    pub contact_list_ttl: Option<Duration>,
+    /// Most time a search takes, split among its fetches. None doesn't limit it
+    pub budget: Option<Duration>,
+    /// Don't go through the users in the mute list of the first user
+    pub avoid_muted: bool,
+    /// Fetch the metadata of the users along with their contact lists, so the names of the path
+    /// are known once it's found. Uses more bandwidth
+    pub prefetch_metadata: bool,
+    #[serde(default)]
+    pub offline: OfflineAnswers,
+    /// Most hops searched. None searches up to DEFAULT_MAX_DEPTH
+    #[serde(default)]
+    pub max_depth: Option<Degree>,
+    /// What joins the users of a path
+    #[serde(default)]
+    pub path_mode: PathMode,
+    /// Among paths as short, prefer the ones that don't go through this many of the top bridges
+    /// of the network, see Network::hubs. 0 doesn't look for them
+    #[serde(default)]
+    pub avoid_hubs: usize,
+}
+
+impl SearchOptions {
+    pub fn max_depth(&self) -> Degree {
+        self.max_depth.unwrap_or(DEFAULT_MAX_DEPTH)
+    }
+}
+
+/// Most hops searched unless configured otherwise
+pub const DEFAULT_MAX_DEPTH: Degree = Degree(7);
+/// Most hops a search can be configured to go, as each hop makes it much bigger
+pub const MAX_DEPTH_CEILING: Degree = Degree(12);
+
+/// Whether searches answer from the network without asking relays
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OfflineAnswers {
+    /// Only paths whose contact lists are all fresh are answered without relays
+    #[default]
+    Off,
+    /// Any path in the network is answered as is, relays are searched when there's none
+    First,
+    /// Relays are never asked
+    Only,
+}
+
+/// Below this much budget left, no more contact lists are fetched
+const MIN_CHUNK_BUDGET: Duration = Duration::from_secs(1);
+
+/// Settings given in the command line, which replace the ones in a config file
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SearchOverrides {
+    pub timeouts: Option<QueryTimeouts>,
+    pub min_corroborating_relays: Option<usize>,
+    pub contact_list_ttl: Option<Option<Duration>>,
+    pub budget: Option<Option<Duration>>,
+    pub avoid_muted: Option<bool>,
+    pub prefetch_metadata: Option<bool>,
+    pub avoid_hubs: Option<usize>,
+    pub max_depth: Option<Degree>,
+    pub path_mode: Option<PathMode>,
+}
+
+impl SearchOverrides {
+    pub fn apply(&self, options: SearchOptions) -> SearchOptions {
+        SearchOptions {
+            timeouts: self.timeouts.unwrap_or(options.timeouts),
+            min_corroborating_relays: self
+                .min_corroborating_relays
+                .unwrap_or(options.min_corroborating_relays),
+            target_precedence: options.target_precedence,
+            contact_list_ttl: self.contact_list_ttl.unwrap_or(options.contact_list_ttl),
+            budget: self.budget.unwrap_or(options.budget),
+            avoid_muted: self.avoid_muted.unwrap_or(options.avoid_muted),
+            prefetch_metadata: self.prefetch_metadata.unwrap_or(options.prefetch_metadata),
+            offline: options.offline,
+            max_depth: self.max_depth.or(options.max_depth),
+            path_mode: self.path_mode.unwrap_or(options.path_mode),
+            avoid_hubs: self.avoid_hubs.unwrap_or(options.avoid_hubs),
+        }
+    }
+}
+
+/// Answer of a search, as printed with --json
+#[derive(Debug, Clone, Serialize)]
+pub struct SepDegreeAnswer {
+    pub degree: Degree,
+    pub path: Vec<String>,
+    pub corroborating_relays: Option<usize>,
+    pub low_confidence: bool,
+    pub external_source: bool,
+}
+
+pub async fn main(
+    vals: impl IntoIterator<Item = &str>,
+    service: &SearchService,
+    json: bool,
+    explain: bool,
+) {
     let vals = vals
         .into_iter()
-        .map(|x| PublicKey::parse(x).expect("Pubkey parse error"))
+        .map(|x| match PublicKey::parse(x) {
+            Ok(pubkey) => pubkey,
+            Err(err) => {
+                eprintln!("Invalid npub {x}: {err}");
+                std::process::exit(2);
+            }
+        })
         .collect_vec();
 
-    let (degree, path) = find_sep_degrees(&client, &network, vals[0], vals[1], 300)
-        .await
-        .unwrap();
-
-    while !verify_path(&client, &network, path.clone()).await.unwrap() {
-        find_sep_degrees(&client, &network, vals[0], vals[1], 300)
-            .await
-            .unwrap();
-    }
+    let SeparationResponse { result, stats, .. } = service
+        .separation(SeparationRequest {
+            from: vals[0],
+            to: vals[1],
+            options: None,
+            request_id: None,
+        })
+        .await;
+    let (degree, path) = match result {
+        Ok(x) => x,
+        Err(SepDegreeError::NotFound(frontier)) => {
+            println!("Separation not found");
+            if explain {
+                println!("{}", frontier.explain());
+            }
+            return;
+        }
+        Err(err @ SepDegreeError::VerificationFailed(_)) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("Search failed: {err}");
+            std::process::exit(2);
+        }
+    };
 
-    println!("degrees: {degree}");
     let path = path
         .into_iter()
         .map(|x| x.to_bech32().unwrap())
         .collect_vec();
+    if json {
+        let answer = SepDegreeAnswer {
+            degree,
+            path,
+            corroborating_relays: stats.corroborating_relays,
+            low_confidence: stats.low_confidence,
+            external_source: stats.external_in_path,
+        };
+        println!("{}", serde_json::to_string_pretty(&answer).unwrap());
+        return;
+    }
+    println!("degrees: {degree}");
     println!("{:?}", path);
+    if let (true, Some(relays)) = (stats.low_confidence, stats.corroborating_relays) {
+        println!(
+            "Low confidence: some contact list in the path was returned by only {relays} relays"
+        );
+    }
+    if stats.external_in_path {
+        println!("Some contact list in the path came from the external graph, not from relays");
+    }
     return;
 }
 
+/// Count the relays that return the contact lists of the path, when options ask for it
+///
+/// Failing to fetch only logs, leaving the answer unmarked
+pub async fn check_corroboration(
+    client: &Client,
+    path: &[PublicKey],
+    options: SearchOptions,
+    stats: &mut SearchStats,
+) {
+    if options.min_corroborating_relays == 0 {
+        return;
+    }
+    // The last user's contact list isn't needed for the path
+    let users = path.iter().take(path.len().saturating_sub(1)).copied();
+    match client_utils::get_contact_list_sources(users, client, options.timeouts.contact_lists)
+        .await
+    {
+        Ok(sources) => {
+            let least = path
+                .iter()
+                .take(path.len().saturating_sub(1))
+                .map(|x| sources.get(x).map_or(0, |relays| relays.len()))
+                .min();
+            if let Some(least) = least {
+                stats.corroborating_relays = Some(least);
+                stats.low_confidence = least < options.min_corroborating_relays;
+            }
+        }
+        Err(err) => eprintln!("Corroboration fetch error: {err}"),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn from_pubkeys(
     pubkey: PublicKey,
     other: PublicKey,
     client: &Client,
     network: &Arc<Mutex<Network>>,
-) -> Result<(u32, Vec<PublicKey>), SepDegreeError> {
-    let (degree, path) = find_sep_degrees(&client, network, pubkey, other, 300).await?;
+    options: SearchOptions,
+    external: Option<&ExternalGraph>,
+    stats: &mut SearchStats,
+) -> Result<(Degree, Vec<PublicKey>), SepDegreeError> {
+    // Paths of the network aren't verified, as that asks relays
+    if options.offline != OfflineAnswers::Off {
+        let net_lock = network.lock().await;
+        if let Some(path) = net_lock
+            .shortest_path(&pubkey, &other, options.path_mode)
+            .filter(|x| Degree::of_path(x) <= options.max_depth())
+        {
+            eprintln!("Path found in the network, answering offline");
+            return Ok((Degree::of_path(&path), path));
+        }
+        if options.offline == OfflineAnswers::Only {
+            let components = net_lock.connected_components(options.path_mode == PathMode::Mutual);
+            return Err(SepDegreeError::NotFound(SearchFrontier {
+                explored: (0, 0),
+                border: (0, 0),
+                missing_contact_lists: 0,
+                stop: StopReason::Offline,
+                disconnected: !components.connected(&pubkey, &other),
+            }));
+        }
+    }
+    let (mut degree, mut path) = find_sep_degrees(
+        client, network, pubkey, other, 300, options, external, stats,
+    )
+    .await?;
 
-    while !verify_path(client, network, path.clone()).await? {
-        find_sep_degrees(client, network, pubkey, other, 300).await?;
+    // verify_path replaces the contact lists of the path, so the next search goes around the
+    // broken hop
+    let mut searches = 1;
+    while !verify_path(
+        client,
+        network,
+        path.clone(),
+        Some(VERIFY_PATH_TIMEOUT),
+        options.path_mode,
+    )
+    .await?
+    {
+        if searches == VERIFY_PATH_SEARCHES {
+            return Err(SepDegreeError::VerificationFailed(searches));
+        }
+        searches += 1;
+        (degree, path) = find_sep_degrees(
+            client, network, pubkey, other, 300, options, external, stats,
+        )
+        .await?;
     }
+    check_corroboration(client, &path, options, stats).await;
+    stats.check_external(&path);
 
     Ok((degree, path))
 }
 
+/// Timeout of each contact list fetch made when verifying a path
+pub const VERIFY_PATH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Searches made for a path whose hops all verify before giving up
+pub const VERIFY_PATH_SEARCHES: usize = 3;
+
+/// Fetch again the contact lists of the users in the path and check that every hop is still mutual,
+/// or still joined as `mode` says
+///
+/// Each user is fetched in parallel and hops are checked as soon as both of their ends arrive,
+/// so a broken hop stops the verification without waiting for the remaining fetches
 pub async fn verify_path(
     client: &Client,
     network: &Mutex<Network>,
     path: Vec<PublicKey>,
+    timeout: Option<Duration>,
+    mode: PathMode,
 ) -> Result<bool, SepDegreeError> {
-    let mut follows = match client_utils::get_following_multiple_users_with_timestamp_and_timeout(
-        path.clone(),
-        &client,
-        None,
-    )
-    .await
-    {
-        Ok(ok) => ok,
-        Err(err) => return Err(SepDegreeError::NostrClientError(err)),
-    };
-
     let _allow_del_lock = {
         let lock = network.lock().await;
         lock.get_delete_lock()
@@ -108,27 +510,410 @@ pub async fn verify_path(
         path.iter().map(|x| x.to_bech32()).collect_vec()
     );
 
-    let mut net_lock = network.lock().await;
-    for (user, (contact_list, time)) in follows.iter() {
-        net_lock.update_contact_list(*user, contact_list, time);
-    }
+    let fetch = |user: PublicKey| async move {
+        let res = client_utils::get_following_multiple_users_with_timestamp_and_timeout(
+            vec![user],
+            client,
+            timeout,
+        )
+        .await;
+        (user, res)
+    };
+    let mut fetches: FuturesUnordered<_> = path.iter().unique().map(|x| fetch(*x)).collect();
 
-    for (i, j) in (0..path.len()).zip(1..path.len()) {
-        if !net_lock.are_users_mutuals(&path[i], &path[j]) {
-            return Ok(false);
+    let mut fetched = HashSet::new();
+    // Users still not in the network after a fetch, fetched once more
+    let mut refetched = HashSet::new();
+    let mut verified = vec![false; path.len().saturating_sub(1)];
+    while let Some((user, res)) = fetches.next().await {
+        let follows = match res {
+            Ok(ok) => ok,
+            Err(err) => return Err(SepDegreeError::NostrClientError(Arc::new(err))),
+        };
+        fetched.insert(user);
+
+        let mut net_lock = network.lock().await;
+        for (user, (contact_list, time)) in follows.iter() {
+            // A lagging relay may answer with an older contact list than the network has
+            if net_lock.update_contact_list(*user, contact_list, time)
+                == ContactListUpdate::IgnoredOlder
+            {
+                eprintln!("Kept a newer contact list of {user} than the one fetched");
+            }
+        }
+
+        for (hop, (i, j)) in (0..path.len()).zip(1..path.len()).enumerate() {
+            if verified[hop] || !fetched.contains(&path[i]) || !fetched.contains(&path[j]) {
+                continue;
+            }
+            let linked = match mode {
+                PathMode::Mutual => net_lock.are_users_mutuals(&path[i], &path[j]),
+                PathMode::Directed => net_lock
+                    .contains_user(&path[i])
+                    .then(|| net_lock.get_user_contacts(&path[i]).any(|x| *x == path[j])),
+                PathMode::Either => (net_lock.contains_user(&path[i])
+                    && net_lock.contains_user(&path[j]))
+                .then(|| net_lock.hop(&path[i], &path[j]).is_some()),
+            };
+            let linked = match linked {
+                Some(linked) => linked,
+                None => {
+                    let unknown = [path[i], path[j]]
+                        .into_iter()
+                        .filter(|x| !net_lock.contains_user(x) && !refetched.contains(x))
+                        .collect_vec();
+                    for user in &unknown {
+                        eprintln!(
+                            "{} not in the network, fetching again",
+                            user.to_bech32().unwrap()
+                        );
+                        fetched.remove(user);
+                        refetched.insert(*user);
+                        fetches.push(fetch(*user));
+                    }
+                    if !unknown.is_empty() {
+                        continue;
+                    }
+                    false
+                }
+            };
+            if !linked {
+                eprintln!(
+                    "Broken hop {} -> {}",
+                    path[i].to_bech32().unwrap(),
+                    path[j].to_bech32().unwrap()
+                );
+                return Ok(false);
+            }
+            verified[hop] = true;
         }
     }
 
     Ok(true)
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn find_sep_degrees(
     client: &Client,
     network: &Mutex<Network>,
     target_1: PublicKey,
     target_2: PublicKey,
     chunk_size: u32,
-) -> Result<(u32, Vec<PublicKey>), SepDegreeError> {
+    options: SearchOptions,
+    external: Option<&ExternalGraph>,
+    stats: &mut SearchStats,
+) -> Result<(Degree, Vec<PublicKey>), SepDegreeError> {
+    // A path in the network needs no fetches, when all of its contact lists are fresh. Mute
+    // lists may not be in the network, so searches avoiding them always crawl
+    if !options.avoid_muted {
+        let net_lock = network.lock().await;
+        if let Some(path) = net_lock.shortest_path(&target_1, &target_2, options.path_mode) {
+            if Degree::of_path(&path) <= options.max_depth()
+                && path
+                    .iter()
+                    .all(|x| net_lock.is_contact_list_fresh(x, options.contact_list_ttl))
+            {
+                eprintln!("Path found in the network, without fetching");
+                return Ok((Degree::of_path(&path), path));
+            }
+        }
+    }
+    find_sep_degrees_multi(
+        client,
+        network,
+        target_1,
+        &[target_2],
+        chunk_size,
+        options,
+        external,
+        stats,
+    )
+    .await
+}
+
+/// Closest member of `members` to `user` and the path to it, see find_sep_degrees_multi
+pub async fn closest_member(
+    user: PublicKey,
+    members: &[PublicKey],
+    client: &Client,
+    network: &Arc<Mutex<Network>>,
+    options: SearchOptions,
+    external: Option<&ExternalGraph>,
+    stats: &mut SearchStats,
+) -> Result<(Degree, Vec<PublicKey>), SepDegreeError> {
+    let (mut degree, mut path) = find_sep_degrees_multi(
+        client, network, user, members, 300, options, external, stats,
+    )
+    .await?;
+
+    // See from_pubkeys
+    let mut searches = 1;
+    while !verify_path(
+        client,
+        network,
+        path.clone(),
+        Some(VERIFY_PATH_TIMEOUT),
+        options.path_mode,
+    )
+    .await?
+    {
+        if searches == VERIFY_PATH_SEARCHES {
+            return Err(SepDegreeError::VerificationFailed(searches));
+        }
+        searches += 1;
+        (degree, path) = find_sep_degrees_multi(
+            client, network, user, members, 300, options, external, stats,
+        )
+        .await?;
+    }
+    check_corroboration(client, &path, options, stats).await;
+    stats.check_external(&path);
+
+    Ok((degree, path))
+}
+
+/// Accounts two users both follow and are both mutuals with, with their names when the network
+/// has their metadata
+#[derive(Debug, Clone)]
+pub struct CommonConnections {
+    pub follows: Vec<(PublicKey, Option<String>)>,
+    pub mutuals: Vec<(PublicKey, Option<String>)>,
+}
+
+/// Fetch the contact lists of the users that aren't fresh in the network
+async fn load_contact_lists(
+    client: &Client,
+    network: &Mutex<Network>,
+    users: &[PublicKey],
+    options: SearchOptions,
+) -> Result<(), SepDegreeError> {
+    let missing = {
+        let net_lock = network.lock().await;
+        users
+            .iter()
+            .filter(|x| !net_lock.is_contact_list_fresh(x, options.contact_list_ttl))
+            .copied()
+            .collect_vec()
+    };
+    for chunk in missing.chunks(300) {
+        let follows = client_utils::get_following_multiple_users_with_timestamp_and_timeout(
+            chunk.iter().copied(),
+            client,
+            options.timeouts.contact_lists,
+        )
+        .await
+        .map_err(|x| SepDegreeError::NostrClientError(Arc::new(x)))?;
+        let mut net_lock = network.lock().await;
+        for (user, (contacts, time)) in &follows {
+            net_lock.update_contact_list(*user, contacts, time);
+        }
+    }
+    Ok(())
+}
+
+/// What `a` and `b` have in common. Mutuals need the contact lists of the common follows, so
+/// those are fetched too
+pub async fn common_connections(
+    client: &Client,
+    network: &Mutex<Network>,
+    a: PublicKey,
+    b: PublicKey,
+    options: SearchOptions,
+) -> Result<CommonConnections, SepDegreeError> {
+    load_contact_lists(client, network, &[a, b], options).await?;
+    let follows = {
+        let net_lock = network.lock().await;
+        for user in [a, b] {
+            match net_lock.contact_list_state(&user) {
+                ContactListState::Known(_) => (),
+                ContactListState::Empty(_) => return Err(SepDegreeError::EmptyContactList(user)),
+                ContactListState::Unknown => {
+                    let relays = client.relays().await.into_keys().collect_vec();
+                    return Err(SepDegreeError::MissingContactList(user, relays));
+                }
+            }
+        }
+        net_lock.common_follows(&a, &b)
+    };
+    load_contact_lists(client, network, &follows, options).await?;
+
+    let net_lock = network.lock().await;
+    let with_names = |users: Vec<PublicKey>| {
+        users
+            .into_iter()
+            .map(|x| (x, net_lock.name(&x)))
+            .collect_vec()
+    };
+    Ok(CommonConnections {
+        mutuals: with_names(net_lock.common_mutuals(&a, &b)),
+        follows: with_names(follows),
+    })
+}
+
+/// Follows `user` added and removed since the network last had their contact list, always
+/// fetching the newest one
+pub async fn unfollows(
+    client: &Client,
+    network: &Mutex<Network>,
+    user: PublicKey,
+    options: SearchOptions,
+) -> Result<ContactListDelta, SepDegreeError> {
+    let follows = client_utils::get_following_multiple_users_with_timestamp_and_timeout(
+        [user],
+        client,
+        options.timeouts.contact_lists,
+    )
+    .await
+    .map_err(|x| SepDegreeError::NostrClientError(Arc::new(x)))?;
+    match follows.get(&user) {
+        Some((contacts, time)) => Ok(network
+            .lock()
+            .await
+            .apply_contact_list_tracked(user, contacts, time)),
+        None => {
+            let relays = client.relays().await.into_keys().collect_vec();
+            Err(SepDegreeError::MissingContactList(user, relays))
+        }
+    }
+}
+
+/// How many of `sample` `user` reaches within `depth` mutual hops, see
+/// Network::connectivity_score. Mutuals need both contact lists, so the ones of the users within
+/// `depth - 1` hops, of their follows and of the sample are fetched when they aren't fresh
+pub async fn connectivity_score(
+    client: &Client,
+    network: &Mutex<Network>,
+    user: PublicKey,
+    sample: &[PublicKey],
+    depth: usize,
+    options: SearchOptions,
+) -> Result<ConnectivityScore, SepDegreeError> {
+    load_contact_lists(client, network, &[user], options).await?;
+    match network.lock().await.contact_list_state(&user) {
+        ContactListState::Known(_) => (),
+        ContactListState::Empty(_) => return Err(SepDegreeError::EmptyContactList(user)),
+        ContactListState::Unknown => {
+            let relays = client.relays().await.into_keys().collect_vec();
+            return Err(SepDegreeError::MissingContactList(user, relays));
+        }
+    }
+    let mut seen = HashSet::from([user]);
+    let mut level = vec![user];
+    for _ in 1..depth {
+        let follows = {
+            let net_lock = network.lock().await;
+            level
+                .iter()
+                .flat_map(|x| net_lock.get_user_contacts(x))
+                .filter(|x| !seen.contains(*x))
+                .unique()
+                .copied()
+                .collect_vec()
+        };
+        load_contact_lists(client, network, &follows, options).await?;
+        let net_lock = network.lock().await;
+        level = level
+            .iter()
+            .flat_map(|x| net_lock.mutuals_of(x))
+            .copied()
+            .filter(|x| seen.insert(*x))
+            .collect_vec();
+    }
+    // The last level was in the follows fetched, so the last hop only needs the sample
+    load_contact_lists(client, network, sample, options).await?;
+    Ok(network
+        .lock()
+        .await
+        .connectivity_score(&user, sample, depth))
+}
+
+/// Users in the mute list of `user`, other than the targets. A failed fetch only logs, so the
+/// search goes on without avoiding them
+async fn muted_by(
+    client: &Client,
+    network: &Mutex<Network>,
+    user: PublicKey,
+    targets: &[PublicKey],
+    options: SearchOptions,
+) -> HashSet<PublicKey> {
+    match client_utils::get_mute_lists_multiple_users_with_timeout(
+        [user],
+        client,
+        options.timeouts.contact_lists,
+    )
+    .await
+    {
+        Ok(mut mute_lists) => {
+            if let Some((muted, time)) = mute_lists.remove(&user) {
+                network.lock().await.update_mute_list(user, &muted, &time);
+            }
+        }
+        Err(err) => eprintln!("Mute list fetch error: {err}"),
+    }
+    let net_lock = network.lock().await;
+    net_lock
+        .get_user_muted(&user)
+        .into_iter()
+        .filter(|x| !targets.contains(x))
+        .collect()
+}
+
+/// Contact lists of `users` the external graph has, recording them in `stats`. Errors only log,
+/// leaving the user without a contact list
+async fn from_external(
+    external: &ExternalGraph,
+    users: &[PublicKey],
+    stats: &mut SearchStats,
+) -> HashMap<PublicKey, (Vec<PublicKey>, Timestamp)> {
+    let fetches = users
+        .iter()
+        .map(|user| async move { (*user, external.source.contact_list(*user).await) });
+    let mut found = HashMap::new();
+    for (user, res) in async_utility::futures_util::future::join_all(fetches).await {
+        match res {
+            Ok(Some(contact_list)) => {
+                eprintln!("Contact list of {user} taken from the external graph");
+                stats.external_contact_lists.insert(user);
+                found.insert(user, contact_list);
+            }
+            Ok(None) => (),
+            Err(err) => eprintln!("External graph error for {user}: {err}"),
+        }
+    }
+    found
+}
+
+/// Users from `user` back to the root of its side, not including `user`. Each level maps its
+/// users to the one they're mutual with in the level before, and the root maps to itself
+fn backtrack(levels: &[HashMap<PublicKey, PublicKey>], user: &PublicKey) -> Vec<PublicKey> {
+    let mut path = vec![];
+    let mut current = *user;
+    for level in levels.iter().skip(1).rev() {
+        current = *level
+            .get(&current)
+            .expect("Missing back in backtrack construction");
+        path.push(current);
+    }
+    path
+}
+
+/// Search from `target_1` and from all of `targets_2` at once, finding the path to the
+/// closest of them. The path starts in `target_1` and ends in one of `targets_2`
+///
+/// The contact list of `target_1` must be found, and the one of some of `targets_2`. Those
+/// no relay has are asked to `external`, if given
+#[allow(clippy::too_many_arguments)]
+pub async fn find_sep_degrees_multi(
+    client: &Client,
+    network: &Mutex<Network>,
+    target_1: PublicKey,
+    targets_2: &[PublicKey],
+    chunk_size: u32,
+    options: SearchOptions,
+    external: Option<&ExternalGraph>,
+    stats: &mut SearchStats,
+) -> Result<(Degree, Vec<PublicKey>), SepDegreeError> {
+    let deadline = options.budget.map(|x| Instant::now() + x);
     let _prevent_del_lock = {
         let lock = network.lock().await;
         lock.get_delete_lock()
@@ -140,7 +925,9 @@ pub async fn find_sep_degrees(
     {
         let mut net_lock = network.lock().await;
         net_lock.add_user(target_1);
-        net_lock.add_user(target_2);
+        for target_2 in targets_2 {
+            net_lock.add_user(*target_2);
+        }
     }
 
     // Build levels
@@ -150,119 +937,190 @@ pub async fn find_sep_degrees(
     mutual_levels_1.push(map1);
 
     let mut mutual_levels_2: Vec<HashMap<PublicKey, PublicKey>> = Vec::new();
-    let mut map2 = HashMap::new();
-    map2.insert(target_2, target_2);
+    let map2: HashMap<PublicKey, PublicKey> = targets_2.iter().map(|x| (*x, *x)).collect();
     mutual_levels_2.push(map2);
 
+    // Every user reached by each side, with the level it's in
+    let mut reached_1: HashMap<PublicKey, usize> = HashMap::from([(target_1, 0)]);
+    let mut reached_2: HashMap<PublicKey, usize> = targets_2.iter().map(|x| (*x, 0)).collect();
+
     // Build next level
-    let mut follows = match client_utils::get_following_multiple_users_with_timestamp_and_timeout(
-        vec![target_1, target_2],
-        &client,
-        None,
-    )
-    .await
-    {
-        Ok(ok) => ok,
-        Err(err) => return Err(SepDegreeError::NostrClientError(err)),
+    let (mut follows, checked_relays) =
+        match client_utils::get_following_multiple_users_with_relay_discovery(
+            targets_2
+                .iter()
+                .copied()
+                .chain([target_1])
+                .unique()
+                .collect_vec(),
+            client,
+            options.timeouts.contact_lists,
+        )
+        .await
+        {
+            Ok(ok) => ok,
+            Err(err) => return Err(SepDegreeError::NostrClientError(Arc::new(err))),
+        };
+    stats.profiles_scanned += follows.len();
+    if let Some(external) = external {
+        let missing = targets_2
+            .iter()
+            .chain([&target_1])
+            .filter(|x| !follows.contains_key(x))
+            .copied()
+            .unique()
+            .collect_vec();
+        follows.extend(from_external(external, &missing, stats).await);
+    }
+    let muted = if options.avoid_muted {
+        muted_by(client, network, target_1, targets_2, options).await
+    } else {
+        HashSet::new()
+    };
+    let hubs = if options.avoid_hubs > 0 {
+        network.lock().await.hubs(options.avoid_hubs)
+    } else {
+        HashSet::new()
     };
-    let mut border1 = follows
-        .clone()
-        .remove(&target_1)
-        .ok_or(SepDegreeError::MissingContactList(target_1))?
-        .0;
-    let mut border2 = follows
-        .remove(&target_2)
-        .ok_or(SepDegreeError::MissingContactList(target_2))?
-        .0;
+    let filters = network.lock().await.search_filters().clone();
+    // Users caught by the filters, counted once in the stats
+    let mut skipped: HashSet<PublicKey> = HashSet::new();
+    if !follows.contains_key(&target_1) {
+        return Err(SepDegreeError::MissingContactList(target_1, checked_relays));
+    }
+    // Other paths than mutual ones don't go through the follows of the last user
+    if options.path_mode == PathMode::Mutual && !targets_2.iter().any(|x| follows.contains_key(x)) {
+        return Err(SepDegreeError::MissingContactList(
+            targets_2[0],
+            checked_relays,
+        ));
+    }
+    // A user who follows nobody has no mutuals, so there's no path to search for
+    {
+        let mut net_lock = network.lock().await;
+        for (user, (contacts, time)) in &follows {
+            net_lock.update_contact_list(*user, contacts, time);
+        }
+        if let ContactListState::Empty(_) = net_lock.contact_list_state(&target_1) {
+            return Err(SepDegreeError::EmptyContactList(target_1));
+        }
+        let found_2 = targets_2
+            .iter()
+            .filter(|x| follows.contains_key(x))
+            .collect_vec();
+        let is_empty =
+            |x: &&PublicKey| matches!(net_lock.contact_list_state(x), ContactListState::Empty(_));
+        if options.path_mode == PathMode::Mutual && found_2.iter().all(is_empty) {
+            return Err(SepDegreeError::EmptyContactList(*found_2[0]));
+        }
+    }
+    let mut border1 = follows.clone().remove(&target_1).unwrap().0;
+    let mut border2 = targets_2
+        .iter()
+        .filter_map(|x| follows.remove(x))
+        .flat_map(|(x, _)| x)
+        .unique()
+        .collect_vec();
 
     // Advance 1 level at time and check for colisions
-    let mut current_distance = 0u32;
-    for i in (1..=2).cycle() {
-        // Handle finding a match, if any
-        let mut intersection = map_intersect::intersection_map(
-            mutual_levels_1
-                .last()
-                .expect("Error in building mutual levels 1"),
-            mutual_levels_2
-                .last()
-                .expect("Error in building mutual levels 2"),
+    let mut current_distance = Degree(0);
+    let mut missing_contact_lists = 0;
+    let frontier = |levels_1: &Vec<HashMap<PublicKey, PublicKey>>,
+                    levels_2: &Vec<HashMap<PublicKey, PublicKey>>,
+                    border: (usize, usize),
+                    missing_contact_lists: usize,
+                    stop: StopReason| {
+        let explored =
+            |levels: &Vec<HashMap<PublicKey, PublicKey>>| levels.iter().map(|x| x.len()).sum();
+        SearchFrontier {
+            explored: (explored(levels_1), explored(levels_2)),
+            border,
+            missing_contact_lists,
+            stop,
+            disconnected: false,
+        }
+    };
+    // Why the search stops, checked after the users reached by the last level are compared, so a
+    // path of exactly the most hops is still found
+    let mut stop: Option<StopReason> = None;
+    // The sides take turns, starting with 1, until a path is found or the search stops
+    let mut i = 2;
+    loop {
+        i = 3 - i;
+
+        // Handle finding a match, if any. Users skipped on the way (muted, timed out or without a
+        // contact list) can make a side reach a user the other side reached levels ago, so all
+        // the users reached are compared, not just the last levels
+        let closest = map_intersect::intersection_map(&reached_1, &reached_2).min_by_key(
+            |(user, level_1, level_2)| (**level_1 + **level_2, hubs.contains(*user), **user),
         );
 
-        if let Some((user_match, back1, back2)) = intersection.next() {
-            match current_distance {
-                0 => {
-                    assert_eq!(target_1, target_2);
-                    return Ok((0, vec![target_1]));
-                }
-                1 => {
-                    assert!(target_1 == *user_match || target_2 == *user_match);
-                    return Ok((1, vec![target_1, target_2]));
-                }
-                2 => {
-                    assert!(target_1 != *user_match || target_2 != *user_match);
-                    return Ok((2, vec![target_1, *user_match, target_2]));
-                }
-                n => {
-                    let mut backtrack1 = Vec::new();
-                    let mut backtrack2 = Vec::new();
-                    {
-                        let mut current_back = back1;
-                        let mut index = mutual_levels_1.len() - 2;
-                        while current_back != &target_1 {
-                            backtrack1.push(current_back);
-                            current_back = mutual_levels_1[index]
-                                .get(current_back)
-                                .expect("Missing back in backtrack construction");
-                            index -= 1;
-                        }
-                    }
-                    {
-                        let mut current_back = back2;
-                        let mut index = mutual_levels_2.len() - 2;
-                        while current_back != &target_2 {
-                            backtrack2.push(current_back);
-                            current_back = mutual_levels_2[index]
-                                .get(current_back)
-                                .expect("Missing back in backtrack construction");
-                            index -= 1;
-                        }
-                    }
+        if let Some((user_match, level_1, level_2)) = closest {
+            // With many targets on side 2, any of them can be the end, so the ends are found
+            // by going back through the levels of each side instead of being compared
+            let mut to_return = backtrack(&mutual_levels_1[..=*level_1], user_match);
+            to_return.reverse();
+            to_return.push(*user_match);
+            to_return.extend(backtrack(&mutual_levels_2[..=*level_2], user_match));
+            let degree = Degree((level_1 + level_2) as u32);
+            debug_assert_eq!(Degree::of_path(&to_return), degree);
+            stats.report_skipped();
+            return Ok((degree, to_return));
+        }
 
-                    let mut to_return = vec![target_1];
-                    to_return.extend(backtrack1.into_iter().rev());
-                    to_return.push(*user_match);
-                    to_return.extend(backtrack2.into_iter());
-                    to_return.push(target_2);
-                    return Ok((n, to_return));
-                }
-            }
+        if let Some(stop) = stop {
+            let mut frontier = frontier(
+                &mutual_levels_1,
+                &mutual_levels_2,
+                (border1.len(), border2.len()),
+                missing_contact_lists,
+                stop,
+            );
+            let components = network
+                .lock()
+                .await
+                .connected_components(options.path_mode == PathMode::Mutual);
+            frontier.disconnected = !targets_2.iter().any(|x| components.connected(&target_1, x));
+            stats.report_skipped();
+            return Err(SepDegreeError::NotFound(frontier));
+        }
+
+        // Directed paths are only searched from the first user, as finding who follows the
+        // second one would need relays to search every contact list
+        if options.path_mode == PathMode::Directed && i == 2 {
+            continue;
         }
 
         // Advance levels 1 or 2
-        let (mutual_levels_i, border_i) = if i == 1 {
-            (&mut mutual_levels_1, &mut border1)
+        let (mutual_levels_i, border_i, reached_i) = if i == 1 {
+            (&mut mutual_levels_1, &mut border1, &mut reached_1)
         } else {
-            (&mut mutual_levels_2, &mut border2)
+            (&mut mutual_levels_2, &mut border2, &mut reached_2)
         };
 
         let mut next_map_i: HashMap<PublicKey, PublicKey> = HashMap::new();
         let mut new_border_i: HashSet<PublicKey> = HashSet::new();
+        // Users of timed out chunks, fetched again the next time this side advances
+        let mut requeued: Vec<PublicKey> = vec![];
+        // Chunks left unfetched because too little of the budget was left
+        let mut skipped_chunks = false;
 
         // Add contact list users in border
         let mut now = 1;
         let total = border_i.len().div_ceil(chunk_size as usize);
         let border_chunks = {
             let mut net_lock = network.lock().await;
-            // Ignore users that already have follow in the newtwork
+            // Ignore users whose contact list in the network is fresh
             border_i
                 .iter()
-                .filter(|x| !net_lock.does_user_follow(x))
+                .filter(|x| !net_lock.is_contact_list_fresh(x, options.contact_list_ttl))
                 .chunks(chunk_size as usize)
                 .into_iter()
                 .map(|x| x.collect_vec())
                 .collect_vec()
         };
-        for chunk in border_chunks {
+        let chunks_count = border_chunks.len();
+        for (index, chunk) in border_chunks.into_iter().enumerate() {
             eprintln!("current: {now}/{total}");
 
             let chunk = {
@@ -270,72 +1128,777 @@ pub async fn find_sep_degrees(
                 chunk.into_iter().map(|x| *x).collect_vec()
             };
 
-            let mut res_contacts =
-                match client_utils::get_following_multiple_users_with_timestamp_and_timeout(
+            // Share what's left of the budget among the chunks left, keeping a share for what
+            // comes after this level
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left < MIN_CHUNK_BUDGET {
+                        eprintln!(
+                            "Search budget spent, skipping {} chunks",
+                            chunks_count - index
+                        );
+                        skipped_chunks = true;
+                        break;
+                    }
+                    let share = left / (chunks_count - index + 1) as u32;
+                    Some(
+                        options
+                            .timeouts
+                            .contact_lists
+                            .map_or(share, |x| x.min(share)),
+                    )
+                }
+                None => options.timeouts.contact_lists,
+            };
+
+            let chunk_start = Instant::now();
+            let res = if options.prefetch_metadata {
+                client_utils::get_following_and_metadata_multiple_users_with_timeout(
                     chunk.clone(),
                     &client,
-                    None,
+                    timeout,
                 )
                 .await
-                {
-                    Ok(ok) => ok,
-                    Err(err) => return Err(SepDegreeError::NostrClientError(err)),
-                };
+            } else {
+                client_utils::get_following_multiple_users_with_timestamp_and_timeout(
+                    chunk.clone(),
+                    client,
+                    timeout,
+                )
+                .await
+                .map(|x| (x, HashMap::new()))
+            };
+            let (mut res_contacts, metadata) = match res {
+                Ok(ok) => ok,
+                // Nothing arrived in time, which is a timed out chunk like any other
+                Err(err) if deadline.is_some() && client_utils::is_timeout(&err) => {
+                    (HashMap::new(), HashMap::new())
+                }
+                Err(err) => return Err(SepDegreeError::NostrClientError(Arc::new(err))),
+            };
+            stats.profiles_scanned += res_contacts.len();
+            if !metadata.is_empty() {
+                network
+                    .lock()
+                    .await
+                    .extend_users_metadata(metadata.into_iter().map(|(x, y)| (x, Some(y))));
+            }
+            // Users missing from a chunk cut by the budget may have a contact list
+            let timed_out =
+                deadline.is_some() && timeout.is_some_and(|x| chunk_start.elapsed() >= x);
+
+            if let Some(external) = external.filter(|x| x.use_for_hops) {
+                let missing = chunk
+                    .iter()
+                    .filter(|x| !res_contacts.contains_key(x))
+                    .copied()
+                    .collect_vec();
+                if !timed_out && !missing.is_empty() {
+                    res_contacts.extend(from_external(external, &missing, stats).await);
+                }
+            }
 
             for user in chunk {
                 let mut net_lock = network.lock().await;
                 let (contacts, time) = match res_contacts.remove(&user) {
                     Some(s) => s,
+                    None if timed_out => {
+                        requeued.push(user);
+                        continue;
+                    }
                     None => {
                         eprintln!("Didn't find user {user} contact list");
+                        missing_contact_lists += 1;
                         continue;
                     }
                 };
-                net_lock.add_contact_list(user, contacts.iter(), &time);
+                net_lock.update_contact_list(user, contacts.iter(), &time);
             }
             now += 1;
         }
+        if !requeued.is_empty() {
+            eprintln!("Fetching {} users again in the next level", requeued.len());
+        }
 
-        // Add users to next level if they follow someone from the previous one
-        // Create new border with their's contact lists
-        for user in &mut *border_i {
-            let mut flag_in_next_level = false;
-            let mut new_border_i_user = Vec::new();
-            let mut net_lock = network.lock().await;
-
-            for follow in net_lock.get_user_contacts(user) {
-                if match mutual_levels_i.last() {
-                    Some(last_level) => last_level.contains_key(&follow),
-                    None => false,
-                } {
-                    // Make sure to only add mutuals in the next level
-                    if net_lock.are_users_mutuals(user, follow) {
-                        flag_in_next_level = true;
-                        next_map_i.insert(*user, *follow);
-                    }
-                } else {
-                    // Add newly found user
-                    if !mutual_levels_i.iter().any(|x| x.contains_key(follow)) {
-                        new_border_i_user.push(follow);
+        if options.path_mode != PathMode::Mutual {
+            // Every user followed by the last level is in the next one, whether they follow back
+            // or not, and with either direction the users following it that the network knows.
+            // The users they follow are the new border
+            let net_lock = network.lock().await;
+            if let Some(last_level) = mutual_levels_i.last() {
+                for user in last_level.keys() {
+                    let followers = match options.path_mode {
+                        PathMode::Either => net_lock.get_user_followers(user),
+                        _ => vec![],
+                    };
+                    for follow in net_lock.get_user_contacts(user).chain(&followers) {
+                        if skipped.contains(follow) {
+                            continue;
+                        }
+                        if let Some(reason) = filters.check(&net_lock, follow) {
+                            skipped.insert(*follow);
+                            stats.skipped.add(reason);
+                            continue;
+                        }
+                        if !reached_i.contains_key(follow) && !muted.contains(follow) {
+                            // Through a user that isn't a hub when there's one
+                            let parent = next_map_i.entry(*follow).or_insert(*user);
+                            if hubs.contains(parent) && !hubs.contains(user) {
+                                *parent = *user;
+                            }
+                        }
                     }
                 }
             }
-            if flag_in_next_level {
-                new_border_i.extend(new_border_i_user);
+            for user in next_map_i.keys() {
+                new_border_i.extend(
+                    net_lock
+                        .get_user_contacts(user)
+                        .filter(|x| !reached_i.contains_key(x) && !next_map_i.contains_key(x)),
+                );
+            }
+        } else {
+            // Add users to next level if they follow someone from the previous one
+            // Create new border with their's contact lists
+            for user in &mut *border_i {
+                if muted.contains(user) {
+                    continue;
+                }
+                let mut flag_in_next_level = false;
+                let mut new_border_i_user = Vec::new();
+                let net_lock = network.lock().await;
+                if skipped.contains(user) {
+                    continue;
+                }
+                if let Some(reason) = filters.check(&net_lock, user) {
+                    skipped.insert(*user);
+                    stats.skipped.add(reason);
+                    continue;
+                }
+
+                for follow in net_lock.get_user_contacts(user) {
+                    if match mutual_levels_i.last() {
+                        Some(last_level) => last_level.contains_key(follow),
+                        None => false,
+                    } {
+                        // Make sure to only add mutuals in the next level
+                        if net_lock.are_users_mutuals(user, follow) == Some(true) {
+                            flag_in_next_level = true;
+                            // Through a user that isn't a hub when there's one
+                            match next_map_i.get(user) {
+                                Some(parent) if !hubs.contains(parent) => (),
+                                _ => {
+                                    next_map_i.insert(*user, *follow);
+                                }
+                            }
+                        }
+                    } else {
+                        // Add newly found user
+                        if !reached_i.contains_key(follow) {
+                            new_border_i_user.push(follow);
+                        }
+                    }
+                }
+                if flag_in_next_level {
+                    new_border_i.extend(new_border_i_user);
+                }
             }
         }
 
+        // Users fetched again keep the side going
+        let exhausted = next_map_i.is_empty() && requeued.is_empty();
+        for user in next_map_i.keys() {
+            reached_i.entry(*user).or_insert(mutual_levels_i.len());
+        }
         mutual_levels_i.push(next_map_i);
+        new_border_i.extend(requeued);
         *border_i = new_border_i.into_iter().collect_vec();
 
-        current_distance += 1;
+        current_distance = current_distance.next();
+
+        // Avoid growing too big. Without new users on one side, nothing can match anymore
+        stop = if skipped_chunks {
+            Some(StopReason::BudgetSpent)
+        } else if exhausted {
+            Some(StopReason::Exhausted)
+        } else if current_distance >= options.max_depth() {
+            Some(StopReason::DepthLimit(current_distance))
+        } else if deadline.is_some_and(|x| Instant::now() >= x) {
+            Some(StopReason::BudgetSpent)
+        } else {
+            None
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[tokio::test]
+    async fn verify_path_stops_at_a_broken_first_hop_without_waiting_for_the_others() {
+        let keys = keys(4);
+        let users = keys.iter().map(|x| x.public_key()).collect_vec();
+        let relay = FakeRelay::start(chain(&keys)).await;
+        // 0 unfollowed 1 since the path was found
+        relay.add_events([contact_list(&keys[0], &[], 2000)]);
+        relay.delay(users[3], Duration::from_secs(5));
+        let client = client(&[&relay]).await;
+        let network = Mutex::new(Network::new());
+
+        let start = Instant::now();
+        let verified = verify_path(
+            &client,
+            &network,
+            users.clone(),
+            Some(Duration::from_secs(10)),
+            PathMode::Mutual,
+        )
+        .await
+        .unwrap();
+        assert!(!verified);
+        assert!(start.elapsed() < Duration::from_secs(3));
+        assert_eq!(
+            network.lock().await.contact_list_state(&users[0]),
+            ContactListState::Empty(Timestamp::from(2000))
+        );
+    }
+
+    #[tokio::test]
+    async fn verify_path_waits_for_every_hop() {
+        let keys = keys(4);
+        let users = keys.iter().map(|x| x.public_key()).collect_vec();
+        let relay = FakeRelay::start(chain(&keys)).await;
+        relay.delay(users[3], Duration::from_millis(500));
+        let client = client(&[&relay]).await;
+        let network = Mutex::new(Network::new());
+
+        let verified = verify_path(
+            &client,
+            &network,
+            users.clone(),
+            Some(Duration::from_secs(10)),
+            PathMode::Mutual,
+        )
+        .await
+        .unwrap();
+        assert!(verified);
+        assert_eq!(
+            network.lock().await.are_users_mutuals(&users[2], &users[3]),
+            Some(true)
+        );
+    }
 
-        // Avoid growing too big
-        if current_distance == 7 {
-            return Err(SepDegreeError::NotFound);
+    #[tokio::test]
+    async fn path_through_a_user_never_in_the_network_is_fetched_again_and_broken() {
+        // 2 has no contact list and nobody follows them, so they're never added to the network
+        let keys = keys(3);
+        let users = keys.iter().map(|x| x.public_key()).collect_vec();
+        let relay = FakeRelay::start(chain(&keys[..2])).await;
+        let client = client(&[&relay]).await;
+        let network = Mutex::new(Network::new());
+
+        let verified = verify_path(
+            &client,
+            &network,
+            users.clone(),
+            Some(Duration::from_secs(10)),
+            PathMode::Mutual,
+        )
+        .await
+        .unwrap();
+        assert!(!verified);
+        assert!(!network.lock().await.contains_user(&users[2]));
+        let fetches = relay
+            .queries()
+            .iter()
+            .flatten()
+            .filter(|x| x.authors.as_ref().is_some_and(|x| x.contains(&users[2])))
+            .count();
+        assert_eq!(fetches, 2);
+    }
+
+    #[tokio::test]
+    async fn path_below_the_corroboration_minimum_is_low_confidence() {
+        let keys = keys(3);
+        let users = keys.iter().map(|x| x.public_key()).collect_vec();
+        let lists = chain(&keys);
+        // The list of 1 is only on the first relay
+        let first = FakeRelay::start(lists.clone()).await;
+        let second = FakeRelay::start(vec![lists[0].clone(), lists[2].clone()]).await;
+        let client = client(&[&first, &second]).await;
+
+        let corroboration = |min_corroborating_relays| {
+            let client = &client;
+            let users = &users;
+            async move {
+                let options = SearchOptions {
+                    min_corroborating_relays,
+                    ..Default::default()
+                };
+                let mut stats = SearchStats::default();
+                check_corroboration(client, users, options, &mut stats).await;
+                (stats.corroborating_relays, stats.low_confidence)
+            }
+        };
+        assert_eq!(corroboration(0).await, (None, false));
+        assert_eq!(corroboration(1).await, (Some(1), false));
+        assert_eq!(corroboration(2).await, (Some(1), true));
+
+        // Found on both relays once the list of 1 is on the second one too
+        second.add_events([lists[1].clone()]);
+        assert_eq!(corroboration(2).await, (Some(2), false));
+    }
+
+    /// Search from `from` to `to` with a new network, asking `relay`
+    async fn search(
+        relay: &FakeRelay,
+        from: PublicKey,
+        to: PublicKey,
+        options: SearchOptions,
+    ) -> Result<(Degree, Vec<PublicKey>), SepDegreeError> {
+        let client = client(&[relay]).await;
+        let network = Mutex::new(Network::new());
+        let mut stats = SearchStats::default();
+        find_sep_degrees_multi(
+            &client,
+            &network,
+            from,
+            &[to],
+            50,
+            options,
+            None,
+            &mut stats,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn not_found_frontier_counts_what_the_search_went_through() {
+        // A chain of mutuals 0 - 1 - 2, where 0 also follows 5, who has no contact list, and
+        // apart from them 3 and 4 are mutuals
+        let keys = keys(6);
+        let users = keys.iter().map(|x| x.public_key()).collect_vec();
+        let relay =
+            FakeRelay::start(contact_lists(&keys, &[&[1, 5], &[0, 2], &[1], &[4], &[3]])).await;
+
+        let Err(SepDegreeError::NotFound(frontier)) =
+            search(&relay, users[0], users[3], SearchOptions::default()).await
+        else {
+            panic!("expected the search to fail");
+        };
+        assert_eq!(
+            frontier,
+            SearchFrontier {
+                explored: (3, 2),
+                border: (0, 0),
+                missing_contact_lists: 1,
+                stop: StopReason::Exhausted,
+                disconnected: true,
+            }
+        );
+        assert_eq!(
+            frontier.explain(),
+            "Reached 3 users from the first and 2 from the second through mutuals, with 0 and 0 more left to check. 1 contact lists were missing. The search ran out of mutuals to go through on one side, and the users are in disconnected parts of the known network"
+        );
+    }
+
+    #[tokio::test]
+    async fn path_of_the_most_hops_is_found_and_a_longer_one_isnt() {
+        let keys = keys(6);
+        let users = keys.iter().map(|x| x.public_key()).collect_vec();
+        let relay = FakeRelay::start(chain(&keys)).await;
+
+        for max_depth in 1..=4 {
+            let options = SearchOptions {
+                max_depth: Some(Degree(max_depth)),
+                ..Default::default()
+            };
+            let to = max_depth as usize;
+            let (degree, path) = search(&relay, users[0], users[to], options).await.unwrap();
+            assert_eq!((degree, path), (Degree(max_depth), users[..=to].to_vec()));
+            match search(&relay, users[0], users[to + 1], options).await {
+                Err(SepDegreeError::NotFound(frontier)) => {
+                    assert_eq!(frontier.stop, StopReason::DepthLimit(Degree(max_depth)));
+                }
+                other => panic!("max depth {max_depth}: {other:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn search_between_disconnected_clusters_runs_out_instead_of_panicking() {
+        // Two circles of mutuals, 0 - 1 - 2 and 3 - 4 - 5, with no one in between
+        let keys = keys(6);
+        let users = keys.iter().map(|x| x.public_key()).collect_vec();
+        let relay = FakeRelay::start(contact_lists(
+            &keys,
+            &[&[1, 2], &[0, 2], &[0, 1], &[4, 5], &[3, 5], &[3, 4]],
+        ))
+        .await;
+
+        for path_mode in [PathMode::Mutual, PathMode::Either, PathMode::Directed] {
+            let options = SearchOptions {
+                path_mode,
+                max_depth: Some(MAX_DEPTH_CEILING),
+                ..Default::default()
+            };
+            match search(&relay, users[0], users[3], options).await {
+                Err(SepDegreeError::NotFound(frontier)) => {
+                    assert_eq!(frontier.stop, StopReason::Exhausted, "{path_mode:?}");
+                    assert!(frontier.disconnected, "{path_mode:?}");
+                    // Directed searches never advance from the second user, whose border is kept
+                    assert_eq!(frontier.border.0, 0, "{path_mode:?}");
+                }
+                other => panic!("{path_mode:?}: {other:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn closest_member_of_a_list() {
+        let keys = keys(6);
+        let users = keys.iter().map(|x| x.public_key()).collect_vec();
+        let relay = FakeRelay::start(chain(&keys)).await;
+        let client = client(&[&relay]).await;
+        let network = Arc::new(Mutex::new(Network::new()));
+
+        let closest = |user: usize, members: &[usize]| {
+            let members = members.iter().map(|x| users[*x]).collect_vec();
+            let (client, network, user) = (&client, &network, users[user]);
+            async move {
+                let mut stats = SearchStats::default();
+                let options = SearchOptions::default();
+                closest_member(user, &members, client, network, options, None, &mut stats)
+                    .await
+                    .unwrap()
+            }
+        };
+        // Already in the list
+        assert_eq!(closest(2, &[5, 2]).await, (Degree(0), vec![users[2]]));
+        // Next to a member
+        assert_eq!(
+            closest(0, &[5, 1]).await,
+            (Degree(1), vec![users[0], users[1]])
+        );
+        // Far from all of them, ending at the closest
+        assert_eq!(closest(0, &[5, 3]).await, (Degree(3), users[..4].to_vec()));
+    }
+
+    #[tokio::test]
+    async fn stalled_chunk_doesnt_take_more_than_the_budget() {
+        // 0 - 1 - 2 - 3 are a chain of mutuals, and 0 is also mutuals with 4, whose contact list
+        // takes far longer than the whole budget
+        let keys = keys(5);
+        let users = keys.iter().map(|x| x.public_key()).collect_vec();
+        let relay = FakeRelay::start(contact_lists(
+            &keys,
+            &[&[1, 4], &[0, 2], &[1, 3], &[2], &[0]],
+        ))
+        .await;
+        relay.delay(users[4], Duration::from_secs(30));
+
+        let budget = Duration::from_secs(3);
+        let options = SearchOptions {
+            budget: Some(budget),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let result = search(&relay, users[0], users[3], options).await;
+        assert!(start.elapsed() < budget + Duration::from_secs(1));
+        match result {
+            Err(SepDegreeError::NotFound(frontier)) => {
+                assert_eq!(frontier.stop, StopReason::BudgetSpent)
+            }
+            other => panic!("expected the budget to run out, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn degree_of_a_path_counts_its_hops() {
+        let users = pubkeys(3);
+        assert_eq!(Degree::of_path(&[]), Degree(0));
+        assert_eq!(Degree::of_path(&users[..1]), Degree(0));
+        assert_eq!(Degree::of_path(&users[..2]), Degree(1));
+        assert_eq!(Degree::of_path(&users), Degree(2));
+        assert_eq!(Degree(0).next(), Degree(1));
+        assert!(Degree(1) < Degree(2));
+        // Plain numbers in JSON
+        assert_eq!(serde_json::to_string(&Degree(2)).unwrap(), "2");
+        assert_eq!(serde_json::from_str::<Degree>("3").unwrap(), Degree(3));
+    }
+
+    #[tokio::test]
+    async fn searched_degree_is_the_hops_of_the_path() {
+        let keys = keys(4);
+        let users = keys.iter().map(|x| x.public_key()).collect_vec();
+        let relay = FakeRelay::start(chain(&keys)).await;
+        for to in 0..users.len() {
+            let (degree, path) = search(&relay, users[0], users[to], SearchOptions::default())
+                .await
+                .unwrap();
+            assert_eq!(degree, Degree(to as u32));
+            assert_eq!(Degree::of_path(&path), degree);
+            assert_eq!(path, users[..=to]);
+        }
+    }
+
+    #[tokio::test]
+    async fn prefetched_metadata_comes_in_the_contact_list_queries() {
+        let keys = keys(4);
+        let users = keys.iter().map(|x| x.public_key()).collect_vec();
+        let mut events = chain(&keys);
+        events.extend(keys.iter().map(|x| metadata(x, "hop", 1000)));
+        let relay = FakeRelay::start(events).await;
+        let client = client(&[&relay]).await;
+        let network = Mutex::new(Network::new());
+        let options = SearchOptions {
+            prefetch_metadata: true,
+            ..Default::default()
+        };
+
+        let (_, path) = find_sep_degrees_multi(
+            &client,
+            &network,
+            users[0],
+            &[users[3]],
+            50,
+            options,
+            None,
+            &mut SearchStats::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(path, users);
+        // The hops between the ends were fetched in chunks
+        let net_lock = network.lock().await;
+        assert!(path[1..path.len() - 1]
+            .iter()
+            .all(|x| net_lock.get_pubkey_metadata(x).is_some()));
+        // Metadata is only asked for along with contact lists, never on its own
+        let filters = relay.queries().into_iter().flatten().collect_vec();
+        assert!(filters
+            .iter()
+            .filter_map(|x| x.kinds.as_ref())
+            .all(|x| !x.contains(&Kind::Metadata) || x.contains(&Kind::ContactList)));
+        assert!(filters
+            .iter()
+            .any(|x| x.kinds.as_ref().is_some_and(|x| x.len() == 2)));
+    }
+
+    #[tokio::test]
+    async fn empty_contact_list_is_told_apart_from_a_missing_one() {
+        // 0 follows nobody, 1 follows 3 and 2 has no contact list
+        let keys = keys(4);
+        let users = keys.iter().map(|x| x.public_key()).collect_vec();
+        let relay = FakeRelay::start(contact_lists(&keys, &[&[], &[3]])).await;
+        let client = client(&[&relay]).await;
+
+        let follows = client_utils::get_following_multiple_users_with_timestamp_and_timeout(
+            users[..3].to_vec(),
+            &client,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            follows.get(&users[0]),
+            Some(&(vec![], Timestamp::from(1000)))
+        );
+        assert!(!follows.contains_key(&users[2]));
+        let mut network = Network::new();
+        for (user, (contacts, time)) in &follows {
+            network.update_contact_list(*user, contacts, time);
+        }
+        assert_eq!(
+            network.contact_list_state(&users[0]),
+            ContactListState::Empty(Timestamp::from(1000))
+        );
+        assert!(network.does_user_follow(&users[0]));
+        assert_eq!(
+            network.contact_list_state(&users[2]),
+            ContactListState::Unknown
+        );
+        assert!(!network.does_user_follow(&users[2]));
+
+        let options = SearchOptions::default();
+        assert!(matches!(
+            search(&relay, users[0], users[1], options).await,
+            Err(SepDegreeError::EmptyContactList(user)) if user == users[0]
+        ));
+        assert!(matches!(
+            search(&relay, users[2], users[1], options).await,
+            Err(SepDegreeError::MissingContactList(user, _)) if user == users[2]
+        ));
+    }
+
+    #[tokio::test]
+    async fn searched_degree_is_the_shortest_in_an_asymmetric_graph() {
+        // 0 has many mutuals and 7 a single one, with longer paths between them than the shortest
+        // one, and pairs whose paths meet at each level
+        let keys = keys(12);
+        let users = keys.iter().map(|x| x.public_key()).collect_vec();
+        let mutuals = [
+            (0, 1),
+            (0, 2),
+            (0, 3),
+            (0, 4),
+            (0, 5),
+            (0, 6),
+            (7, 8),
+            (8, 9),
+            (9, 10),
+            (10, 1),
+            (8, 11),
+            (11, 3),
+            (2, 5),
+        ];
+        let follows = (0..users.len())
+            .map(|i| {
+                mutuals
+                    .iter()
+                    .filter_map(|(a, b)| match i {
+                        _ if i == *a => Some(*b),
+                        _ if i == *b => Some(*a),
+                        _ => None,
+                    })
+                    .collect_vec()
+            })
+            .collect_vec();
+        let follows = follows.iter().map(|x| &x[..]).collect_vec();
+        let relay = FakeRelay::start(contact_lists(&keys, &follows)).await;
+        let all = network(&users, &follows.iter().copied().enumerate().collect_vec());
+
+        for (from, to) in [(0, 7), (7, 0), (7, 5), (6, 9), (10, 11), (4, 8)] {
+            let shortest = all
+                .shortest_path(&users[from], &users[to], PathMode::Mutual)
+                .unwrap();
+            let (degree, path) = search(&relay, users[from], users[to], SearchOptions::default())
+                .await
+                .unwrap();
+            assert_eq!(degree, Degree::of_path(&shortest), "from {from} to {to}");
+            assert_eq!(Degree::of_path(&path), degree);
         }
     }
 
-    println!("{:#.4?}", network);
-    todo!()
+    #[tokio::test]
+    async fn read_only_cache_answers_from_the_network_before_the_relays() {
+        // The relays know 0 - 1 - 2 - 3, the cached network only the contact lists of 0 and 1
+        let keys = keys(4);
+        let users = keys.iter().map(|x| x.public_key()).collect_vec();
+        let relay = FakeRelay::start(chain(&keys)).await;
+        let client = client(&[&relay]).await;
+        let cached = network(&users, &[(0, &[1]), (1, &[0, 2])]);
+        let network = Arc::new(Mutex::new(cached));
+        let answer = |to: usize, offline: OfflineAnswers| {
+            let (client, network, users) = (&client, &network, &users);
+            async move {
+                let options = SearchOptions {
+                    offline,
+                    ..Default::default()
+                };
+                let mut stats = SearchStats::default();
+                from_pubkeys(
+                    users[0], users[to], client, network, options, None, &mut stats,
+                )
+                .await
+            }
+        };
+
+        // A cached path is answered without asking the relays
+        for offline in [OfflineAnswers::First, OfflineAnswers::Only] {
+            let (degree, path) = answer(1, offline).await.unwrap();
+            assert_eq!((degree, path), (Degree(1), users[..2].to_vec()));
+        }
+        assert!(relay.queries().is_empty());
+
+        // Without one, only the relays that may be asked are
+        match answer(3, OfflineAnswers::Only).await {
+            Err(SepDegreeError::NotFound(frontier)) => {
+                assert_eq!(frontier.stop, StopReason::Offline);
+                assert!(frontier.disconnected);
+            }
+            other => panic!("{other:?}"),
+        }
+        assert!(relay.queries().is_empty());
+        let (degree, path) = answer(3, OfflineAnswers::First).await.unwrap();
+        assert_eq!((degree, path), (Degree(3), users.clone()));
+        assert!(!relay.queries().is_empty());
+    }
+
+    #[tokio::test]
+    async fn broken_paths_are_searched_around_a_few_times() {
+        let keys = keys(5);
+        let users = keys.iter().map(|x| x.public_key()).collect_vec();
+        // Newer than the cached contact lists
+        let lists = |follows: &[(usize, &[usize])]| {
+            follows
+                .iter()
+                .map(|(user, contacts)| {
+                    let contacts = contacts.iter().map(|x| users[*x]).collect_vec();
+                    contact_list(&keys[*user], &contacts, 2000)
+                })
+                .collect_vec()
+        };
+        async fn search(
+            relay: &FakeRelay,
+            cached: Network,
+            users: &[PublicKey],
+        ) -> Result<(Degree, Vec<PublicKey>), SepDegreeError> {
+            let client = client(&[relay]).await;
+            let network = Arc::new(Mutex::new(cached));
+            let mut stats = SearchStats::default();
+            let options = SearchOptions::default();
+            from_pubkeys(
+                users[0], users[3], &client, &network, options, None, &mut stats,
+            )
+            .await
+        }
+
+        // 1 unfollowed 3, so the path through it is broken and the longer one through 2 and 4
+        // is answered
+        let relay = FakeRelay::start(lists(&[
+            (0, &[1, 2]),
+            (1, &[0]),
+            (2, &[0, 4]),
+            (3, &[1, 4]),
+            (4, &[2, 3]),
+        ]))
+        .await;
+        let cached = network(&users, &[(0, &[1, 2]), (1, &[0, 3]), (3, &[1, 4])]);
+        let (degree, path) = search(&relay, cached, &users).await.unwrap();
+        assert_eq!(
+            (degree, path),
+            (Degree(3), vec![users[0], users[2], users[4], users[3]])
+        );
+
+        // Every one of 1, 2 and 4 unfollowed 3, one more than the searches made
+        let relay = FakeRelay::start(lists(&[
+            (0, &[1, 2, 4]),
+            (1, &[0]),
+            (2, &[0]),
+            (3, &[1, 2, 4]),
+            (4, &[0]),
+        ]))
+        .await;
+        let cached = network(
+            &users,
+            &[
+                (0, &[1, 2, 4]),
+                (1, &[0, 3]),
+                (2, &[0, 3]),
+                (3, &[1, 2, 4]),
+                (4, &[0, 3]),
+            ],
+        );
+        assert_eq!(VERIFY_PATH_SEARCHES, 3);
+        match search(&relay, cached, &users).await {
+            Err(SepDegreeError::VerificationFailed(searches)) => {
+                assert_eq!(searches, VERIFY_PATH_SEARCHES)
+            }
+            other => panic!("{other:?}"),
+        }
+    }
 }
@@ -0,0 +1,215 @@
+/// Background tasks of the listener, told when the settings they run with change and stopped
+/// together
+///
+/// Tasks wait between runs with TaskContext::sleep, which starts waiting again with the new
+/// period when the settings change, so a new interval applies right away instead of after the
+/// old one. Shutting down wakes every task from its wait and joins them
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::outbox::OutboxPace;
+use crate::watchdog::WatchdogConfig;
+
+/// Settings of the background tasks, from the listen config
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TaskSettings {
+    pub outbox: OutboxPace,
+    /// None disables the watchdog
+    pub watchdog: Option<WatchdogConfig>,
+    /// None disables pruning
+    pub prune_interval: Option<Duration>,
+    /// Contact lists fetched longer ago are dropped when pruning
+    pub contact_list_ttl: Option<Duration>,
+    /// None disables autosaving
+    pub autosave_interval: Option<Duration>,
+}
+
+/// What a task gets from the supervisor
+pub struct TaskContext {
+    settings: watch::Receiver<TaskSettings>,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl TaskContext {
+    /// The settings as they are now
+    pub fn settings(&self) -> TaskSettings {
+        *self.settings.borrow()
+    }
+
+    /// Wait for the `period` of the settings, waiting again from the start with the new one when
+    /// they change. A None period waits until it isn't None. Returns false when shutting down,
+    /// then the task should return
+    pub async fn sleep(&mut self, period: impl Fn(&TaskSettings) -> Option<Duration>) -> bool {
+        loop {
+            if *self.shutdown.borrow() {
+                return false;
+            }
+            let period = period(&self.settings.borrow_and_update());
+            let wait = async {
+                match period {
+                    Some(period) => tokio::time::sleep(period).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = wait => return true,
+                changed = self.settings.changed() => {
+                    if changed.is_err() {
+                        return false;
+                    }
+                }
+                _ = self.shutdown.changed() => return false,
+            }
+        }
+    }
+}
+
+pub struct Supervisor {
+    settings: watch::Sender<TaskSettings>,
+    shutdown: watch::Sender<bool>,
+    tasks: Vec<(&'static str, JoinHandle<()>)>,
+}
+
+impl Supervisor {
+    pub fn new(settings: TaskSettings) -> Supervisor {
+        Supervisor {
+            settings: watch::Sender::new(settings),
+            shutdown: watch::Sender::new(false),
+            tasks: vec![],
+        }
+    }
+
+    /// Run `task` in the background until shutdown
+    pub fn spawn<F, Fut>(&mut self, name: &'static str, task: F)
+    where
+        F: FnOnce(TaskContext) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let context = TaskContext {
+            settings: self.settings.subscribe(),
+            shutdown: self.shutdown.subscribe(),
+        };
+        self.tasks.push((name, tokio::task::spawn(task(context))));
+    }
+
+    /// Tell the tasks of the new settings. Returns whether they changed
+    pub fn update(&self, settings: TaskSettings) -> bool {
+        self.settings.send_if_modified(|current| {
+            if *current == settings {
+                return false;
+            }
+            println!("Background task settings changed: {settings:?}");
+            *current = settings;
+            true
+        })
+    }
+
+    /// Stop every task, waiting for the ones in the middle of a run to finish it
+    pub async fn shutdown(self) {
+        self.shutdown.send_replace(true);
+        for (name, task) in self.tasks {
+            if let Err(err) = task.await {
+                eprintln!("Background task {name} error: {err}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    fn settings(prune_interval: Option<Duration>) -> TaskSettings {
+        TaskSettings {
+            outbox: OutboxPace {
+                interval: Duration::from_secs(1),
+                max_attempts: 1,
+            },
+            watchdog: None,
+            prune_interval,
+            contact_list_ttl: None,
+            autosave_interval: None,
+        }
+    }
+
+    /// Spawn a task counting the runs at the prune interval
+    fn counting(supervisor: &mut Supervisor) -> Arc<AtomicUsize> {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counted = runs.clone();
+        supervisor.spawn("counting", |mut context| async move {
+            while context.sleep(|x| x.prune_interval).await {
+                counted.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        runs
+    }
+
+    async fn wait_for(runs: &AtomicUsize, limit: Duration) -> bool {
+        let start = Instant::now();
+        while runs.load(Ordering::SeqCst) == 0 {
+            if start.elapsed() > limit {
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        true
+    }
+
+    #[tokio::test]
+    async fn new_interval_applies_without_waiting_for_the_old_one() {
+        let mut supervisor = Supervisor::new(settings(Some(Duration::from_secs(60))));
+        let runs = counting(&mut supervisor);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!supervisor.update(settings(Some(Duration::from_secs(60)))));
+        assert!(supervisor.update(settings(Some(Duration::from_millis(20)))));
+        assert!(wait_for(&runs, Duration::from_secs(5)).await);
+        supervisor.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn disabled_task_waits_until_enabled() {
+        let mut supervisor = Supervisor::new(settings(None));
+        let runs = counting(&mut supervisor);
+        assert!(!wait_for(&runs, Duration::from_millis(200)).await);
+        supervisor.update(settings(Some(Duration::from_millis(20))));
+        assert!(wait_for(&runs, Duration::from_secs(5)).await);
+
+        // And stops running when disabled again
+        supervisor.update(settings(None));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let stopped = runs.load(Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), stopped);
+        supervisor.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn shutdown_wakes_waiting_tasks_and_lets_running_ones_finish() {
+        let mut supervisor = Supervisor::new(settings(Some(Duration::from_millis(10))));
+        let waiting = counting(&mut supervisor);
+        // Disabled, waiting forever
+        supervisor.spawn("disabled", |mut context| async move {
+            while context.sleep(|x| x.autosave_interval).await {}
+        });
+        let finished = Arc::new(AtomicUsize::new(0));
+        let running = finished.clone();
+        supervisor.spawn("running", |mut context| async move {
+            while context.sleep(|_| Some(Duration::from_millis(10))).await {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                running.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        assert!(wait_for(&waiting, Duration::from_secs(5)).await);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let start = Instant::now();
+        supervisor.shutdown().await;
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(finished.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,52 @@
+/// Named lists of users, e.g. the members of a community
+///
+/// The config is a TOML file where each key is a list name and its value is a list of npubs:
+/// ```toml
+/// friends = ["npub1...", "npub1..."]
+/// ```
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+
+use itertools::Itertools;
+use nostr_sdk::prelude::*;
+
+pub const DEFAULT_LISTS_PATH: &str = "lists.toml";
+
+#[derive(Debug)]
+pub enum TargetListError {
+    ConfigMissing(std::io::Error),
+    ConfigParse(toml::de::Error),
+    ListNotFound(String),
+    InvalidPubkey(String),
+}
+
+impl Display for TargetListError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            TargetListError::ConfigMissing(error) => write!(f, "Lists config missing: {}", error),
+            TargetListError::ConfigParse(error) => {
+                write!(f, "Lists config parse error:\n{}", error)
+            }
+            TargetListError::ListNotFound(name) => write!(f, "List {name} not found"),
+            TargetListError::InvalidPubkey(key) => write!(f, "Invalid public key {key}"),
+        }
+    }
+}
+
+impl std::error::Error for TargetListError {}
+
+/// Read the list called `name`, keeping the order of the members and dropping repeated ones
+pub fn load_target_list(path: &str, name: &str) -> Result<Vec<PublicKey>, TargetListError> {
+    let text = fs::read_to_string(path).map_err(TargetListError::ConfigMissing)?;
+    let mut lists: HashMap<String, Vec<String>> =
+        toml::from_str(&text).map_err(TargetListError::ConfigParse)?;
+    let members = lists
+        .remove(name)
+        .ok_or(TargetListError::ListNotFound(name.to_string()))?;
+    members
+        .iter()
+        .map(|x| PublicKey::parse(x).map_err(|_| TargetListError::InvalidPubkey(x.clone())))
+        .collect::<Result<Vec<_>, _>>()
+        .map(|x| x.into_iter().unique().collect_vec())
+}
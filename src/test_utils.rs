@@ -0,0 +1,251 @@
+/// Fixtures shared by the tests: users, their events, networks built from them and a relay
+/// answering with them
+use async_utility::futures_util::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::client_utils::{build_client, QueryTimeouts};
+use crate::network::follow::{FollowNetwork, LevelIdx, RankConfig};
+use crate::network::Network;
+use crate::user::User;
+use nostr_sdk::prelude::*;
+
+/// The same keys on every run, so fixtures and their order don't change between runs
+pub fn keys(n: usize) -> Vec<Keys> {
+    (1..=n)
+        .map(|i| {
+            let mut secret = [0u8; 32];
+            secret[24..].copy_from_slice(&(i as u64).to_be_bytes());
+            Keys::new(SecretKey::from_slice(&secret).unwrap())
+        })
+        .collect()
+}
+
+/// Public keys of keys(n)
+pub fn pubkeys(n: usize) -> Vec<PublicKey> {
+    keys(n).iter().map(|x| x.public_key()).collect()
+}
+
+/// Kind 3 event of `author` following `follows`
+pub fn contact_list(author: &Keys, follows: &[PublicKey], created_at: u64) -> Event {
+    let tags = follows.iter().map(|x| Tag::public_key(*x));
+    EventBuilder::new(Kind::ContactList, "", tags)
+        .custom_created_at(Timestamp::from(created_at))
+        .to_event(author)
+        .unwrap()
+}
+
+/// Contact lists of the first keys, the one of keys[i] following the users at indices follows[i],
+/// all created at the same time
+pub fn contact_lists(keys: &[Keys], follows: &[&[usize]]) -> Vec<Event> {
+    follows
+        .iter()
+        .enumerate()
+        .map(|(i, x)| {
+            let follows = x.iter().map(|x| keys[*x].public_key()).collect::<Vec<_>>();
+            contact_list(&keys[i], &follows, 1000)
+        })
+        .collect()
+}
+
+/// Contact lists of a chain of mutuals, keys[i] following keys[i - 1] and keys[i + 1]
+pub fn chain(keys: &[Keys]) -> Vec<Event> {
+    (0..keys.len())
+        .map(|i| {
+            let follows = [i.checked_sub(1), Some(i + 1)]
+                .into_iter()
+                .flatten()
+                .filter_map(|x| keys.get(x).map(|x| x.public_key()))
+                .collect::<Vec<_>>();
+            contact_list(&keys[i], &follows, 1000)
+        })
+        .collect()
+}
+
+/// Kind 0 event of `author` named `name`
+pub fn metadata(author: &Keys, name: &str, created_at: u64) -> Event {
+    EventBuilder::metadata(&Metadata::new().name(name))
+        .custom_created_at(Timestamp::from(created_at))
+        .to_event(author)
+        .unwrap()
+}
+
+/// Network with the contact lists of `follows`, given as indices into `users`, all created at
+/// the same time
+pub fn network(users: &[PublicKey], follows: &[(usize, &[usize])]) -> Network {
+    let mut network = Network::new();
+    for (user, contacts) in follows {
+        let contacts = contacts.iter().map(|x| users[*x]).collect::<Vec<_>>();
+        network.update_contact_list(users[*user], &contacts, &Timestamp::from(1000));
+    }
+    network
+}
+
+/// Path in the temporary directory only used by this test run, removed if it already exists
+pub fn temp_path(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("six-degrees-bot-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    _ = std::fs::remove_file(&path);
+    path
+}
+
+/// Relay on a local port answering queries from the events it's given, for testing what asks
+/// relays. Connect to it with client
+pub struct FakeRelay {
+    pub url: Url,
+    state: Arc<FakeRelayState>,
+}
+
+#[derive(Default)]
+struct FakeRelayState {
+    events: Mutex<Vec<Event>>,
+    /// Queries for any of these authors are answered after the delay
+    delays: Mutex<HashMap<PublicKey, Duration>>,
+    /// Filters of every query received
+    queries: Mutex<Vec<Vec<Filter>>>,
+    /// Events published to the relay, accepted or not
+    published: Mutex<Vec<Event>>,
+    /// Message of the OK false sent to published events, None accepts them
+    reject: Mutex<Option<String>>,
+}
+
+impl FakeRelay {
+    pub async fn start(events: Vec<Event>) -> FakeRelay {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("ws://{}", listener.local_addr().unwrap())).unwrap();
+        let state = Arc::new(FakeRelayState::default());
+        state.events.lock().unwrap().extend(events);
+        let accepting = state.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, accepting.clone()));
+            }
+        });
+        FakeRelay { url, state }
+    }
+
+    pub fn add_events(&self, events: impl IntoIterator<Item = Event>) {
+        self.state.events.lock().unwrap().extend(events);
+    }
+
+    pub fn delay(&self, author: PublicKey, delay: Duration) {
+        self.state.delays.lock().unwrap().insert(author, delay);
+    }
+
+    pub fn queries(&self) -> Vec<Vec<Filter>> {
+        self.state.queries.lock().unwrap().clone()
+    }
+
+    pub fn published(&self) -> Vec<Event> {
+        self.state.published.lock().unwrap().clone()
+    }
+
+    pub fn reject(&self, message: Option<&str>) {
+        *self.state.reject.lock().unwrap() = message.map(|x| x.to_string());
+    }
+}
+
+async fn serve(stream: TcpStream, state: Arc<FakeRelayState>) {
+    // Also reached by requests for the relay information document, which aren't answered
+    let Ok(socket) = tokio_tungstenite::accept_async(stream).await else {
+        return;
+    };
+    let (mut sink, mut messages) = socket.split();
+    let (sender, mut outgoing) = mpsc::unbounded_channel::<String>();
+    let writer = tokio::spawn(async move {
+        while let Some(message) = outgoing.recv().await {
+            if sink.send(Message::Text(message)).await.is_err() {
+                break;
+            }
+        }
+    });
+    while let Some(Ok(message)) = messages.next().await {
+        let Message::Text(text) = message else {
+            continue;
+        };
+        match ClientMessage::from_json(&text) {
+            Ok(ClientMessage::Req {
+                subscription_id,
+                filters,
+            }) => {
+                state.queries.lock().unwrap().push(filters.clone());
+                let events = state
+                    .events
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|x| filters.iter().any(|f| f.match_event(x)))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                let delay = {
+                    let delays = state.delays.lock().unwrap();
+                    filters
+                        .iter()
+                        .flat_map(|x| x.authors.iter().flatten())
+                        .filter_map(|x| delays.get(x).copied())
+                        .max()
+                };
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    if let Some(delay) = delay {
+                        tokio::time::sleep(delay).await;
+                    }
+                    for event in events {
+                        let message = RelayMessage::event(subscription_id.clone(), event);
+                        _ = sender.send(message.as_json());
+                    }
+                    _ = sender.send(RelayMessage::eose(subscription_id).as_json());
+                });
+            }
+            Ok(ClientMessage::Event(event)) => {
+                let reject = state.reject.lock().unwrap().clone();
+                state.published.lock().unwrap().push(*event.clone());
+                let ok = match reject {
+                    Some(message) => RelayMessage::ok(event.id(), false, message),
+                    None => RelayMessage::ok(event.id(), true, ""),
+                };
+                _ = sender.send(ok.as_json());
+            }
+            _ => (),
+        }
+    }
+    writer.abort();
+}
+
+/// Client connected to the relays, with new keys
+pub async fn client(relays: &[&FakeRelay]) -> Client {
+    let urls = relays.iter().map(|x| x.url.clone()).collect::<Vec<_>>();
+    build_client(&Keys::generate(), &urls, None, &[])
+        .await
+        .unwrap()
+}
+
+/// FollowNetwork centered on `center` over `network`, with the levels up to `depth` told by the
+/// contact lists in it. The metadata of `center` is added to the relay
+pub async fn follow_network(
+    relay: &FakeRelay,
+    center: &Keys,
+    network: Network,
+    depth: usize,
+) -> FollowNetwork {
+    relay.add_events([metadata(center, "center", 1000)]);
+    let client = Arc::new(client(&[relay]).await);
+    let user = User::new(center.public_key(), &client).await.unwrap();
+    FollowNetwork::from_network(
+        user,
+        client,
+        Arc::new(tokio::sync::Mutex::new(network)),
+        QueryTimeouts::from_secs(2, 2),
+        &RankConfig::default(),
+        None,
+        LevelIdx(depth),
+    )
+    .await
+    .unwrap()
+}
@@ -0,0 +1,222 @@
+/// Reconnects relays whose websocket died without the client noticing, in long listen runs
+///
+/// A relay is dead when it's disconnected, or hasn't answered any query for `idle_after`. Dead
+/// relays are reconnected, and once every relay has been dead for `reset_after`, the client
+/// drops all of its connections and connects again from scratch
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::client_utils::relay_health;
+use crate::supervisor::TaskContext;
+
+use nostr_sdk::prelude::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WatchdogConfig {
+    /// Time between checks
+    pub interval: Duration,
+    pub idle_after: Duration,
+    pub reset_after: Duration,
+}
+
+/// What the watchdog saw of a relay
+#[derive(Debug, Clone)]
+pub struct RelayStatus {
+    pub url: Url,
+    pub connected: bool,
+    /// Time since the relay last answered or was reconnected
+    pub idle: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchdogAction {
+    Reconnect(Vec<Url>),
+    /// Every relay has been dead for too long
+    ResetClient,
+}
+
+/// When to reconnect, without touching the client
+#[derive(Debug)]
+pub struct Watchdog {
+    config: WatchdogConfig,
+    all_dead_since: Option<Instant>,
+}
+
+impl Watchdog {
+    pub fn new(config: WatchdogConfig) -> Watchdog {
+        Watchdog {
+            config,
+            all_dead_since: None,
+        }
+    }
+
+    /// Check with `config` from now on, still counting the time every relay has been dead
+    pub fn set_config(&mut self, config: WatchdogConfig) {
+        self.config = config;
+    }
+
+    pub fn check(&mut self, relays: &[RelayStatus], now: Instant) -> Option<WatchdogAction> {
+        let is_dead = |x: &&RelayStatus| !x.connected || x.idle >= self.config.idle_after;
+        let dead = relays
+            .iter()
+            .filter(is_dead)
+            .map(|x| x.url.clone())
+            .collect::<Vec<_>>();
+        if dead.is_empty() || dead.len() < relays.len() {
+            self.all_dead_since = None;
+        } else {
+            let since = *self.all_dead_since.get_or_insert(now);
+            if now.duration_since(since) >= self.config.reset_after {
+                self.all_dead_since = None;
+                return Some(WatchdogAction::ResetClient);
+            }
+        }
+        (!dead.is_empty()).then_some(WatchdogAction::Reconnect(dead))
+    }
+}
+
+/// Check the relays of the client every interval of the watchdog settings, until shutdown
+pub async fn run(client: Arc<Client>, mut context: TaskContext) {
+    let started = Instant::now();
+    let mut state: Option<Watchdog> = None;
+    while context.sleep(|x| x.watchdog.map(|x| x.interval)).await {
+        let Some(config) = context.settings().watchdog else {
+            continue;
+        };
+        let watchdog = state.get_or_insert_with(|| Watchdog::new(config));
+        watchdog.set_config(config);
+        let mut relays = vec![];
+        for (url, relay) in client.relays().await {
+            let health = relay_health().get(&url);
+            // Disconnected on purpose for failing, see maintain_relays
+            if health.as_ref().is_some_and(|x| x.disabled_since.is_some()) {
+                continue;
+            }
+            let last_active = health.and_then(|x| x.last_active).unwrap_or(started);
+            relays.push(RelayStatus {
+                connected: relay.is_connected().await,
+                idle: last_active.elapsed(),
+                url,
+            });
+        }
+
+        match watchdog.check(&relays, Instant::now()) {
+            Some(WatchdogAction::Reconnect(urls)) => {
+                for url in urls {
+                    eprintln!("Watchdog: reconnecting relay {url}");
+                    if let Err(err) = client.disconnect_relay(url.clone()).await {
+                        eprintln!("Relay {url} disconnect error: {err}");
+                    }
+                    if let Err(err) = client.connect_relay(url.clone()).await {
+                        eprintln!("Relay {url} reconnect error: {err}");
+                    }
+                    relay_health().record_reconnect(&url);
+                }
+            }
+            Some(WatchdogAction::ResetClient) => {
+                eprintln!(
+                    "Watchdog: every relay dead for {}s, reconnecting the client",
+                    config.reset_after.as_secs()
+                );
+                if let Err(err) = client.disconnect().await {
+                    eprintln!("Client disconnect error: {err}");
+                }
+                client.connect_with_timeout(Duration::from_secs(10)).await;
+                for relay in &relays {
+                    relay_health().record_reconnect(&relay.url);
+                }
+            }
+            None => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> WatchdogConfig {
+        WatchdogConfig {
+            interval: Duration::from_secs(60),
+            idle_after: Duration::from_secs(600),
+            reset_after: Duration::from_secs(1800),
+        }
+    }
+
+    fn url(n: usize) -> Url {
+        Url::parse(&format!("wss://relay{n}.example")).unwrap()
+    }
+
+    /// Status of relays 0, 1, ..., each connected or not, idle for the seconds given
+    fn statuses(relays: &[(bool, u64)]) -> Vec<RelayStatus> {
+        relays
+            .iter()
+            .enumerate()
+            .map(|(i, (connected, idle))| RelayStatus {
+                url: url(i),
+                connected: *connected,
+                idle: Duration::from_secs(*idle),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn only_dead_relays_are_reconnected() {
+        let now = Instant::now();
+        let mut watchdog = Watchdog::new(config());
+        assert_eq!(watchdog.check(&[], now), None);
+        assert_eq!(
+            watchdog.check(&statuses(&[(true, 0), (true, 599)]), now),
+            None
+        );
+        assert_eq!(
+            watchdog.check(&statuses(&[(true, 0), (true, 600), (false, 0)]), now),
+            Some(WatchdogAction::Reconnect(vec![url(1), url(2)]))
+        );
+    }
+
+    #[test]
+    fn client_is_reset_once_every_relay_is_dead_long_enough() {
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let dead = statuses(&[(false, 0), (true, 700)]);
+        let reconnect = Some(WatchdogAction::Reconnect(vec![url(0), url(1)]));
+        let mut watchdog = Watchdog::new(config());
+
+        assert_eq!(watchdog.check(&dead, at(0)), reconnect);
+        assert_eq!(watchdog.check(&dead, at(1799)), reconnect);
+        assert_eq!(
+            watchdog.check(&dead, at(1800)),
+            Some(WatchdogAction::ResetClient)
+        );
+        // The time counts again from the reset
+        assert_eq!(watchdog.check(&dead, at(1860)), reconnect);
+        assert_eq!(watchdog.check(&dead, at(3599)), reconnect);
+        assert_eq!(
+            watchdog.check(&dead, at(3660)),
+            Some(WatchdogAction::ResetClient)
+        );
+    }
+
+    #[test]
+    fn a_relay_coming_back_restarts_the_time_every_relay_is_dead() {
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let dead = statuses(&[(false, 0), (false, 0)]);
+        let mut watchdog = Watchdog::new(config());
+
+        watchdog.check(&dead, at(0));
+        assert_eq!(
+            watchdog.check(&statuses(&[(true, 0), (false, 0)]), at(1000)),
+            Some(WatchdogAction::Reconnect(vec![url(1)]))
+        );
+        assert_ne!(
+            watchdog.check(&dead, at(1900)),
+            Some(WatchdogAction::ResetClient)
+        );
+        assert_eq!(
+            watchdog.check(&dead, at(3700)),
+            Some(WatchdogAction::ResetClient)
+        );
+    }
+}